@@ -0,0 +1,6 @@
+CREATE TABLE subscriptions (
+    id SERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    email TEXT NOT NULL UNIQUE,
+    status TEXT NOT NULL
+);
@@ -0,0 +1,8 @@
+ALTER TABLE senders DROP CONSTRAINT senders_pkey;
+ALTER TABLE senders ADD COLUMN id SERIAL PRIMARY KEY;
+ALTER TABLE senders ADD CONSTRAINT senders_username_key UNIQUE (username);
+ALTER TABLE senders ADD COLUMN display_name TEXT;
+UPDATE senders SET display_name = username;
+ALTER TABLE senders ALTER COLUMN display_name SET NOT NULL;
+ALTER TABLE senders ADD COLUMN enabled BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE senders ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
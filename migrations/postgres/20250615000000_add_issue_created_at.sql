@@ -0,0 +1,5 @@
+-- Issues so far were published the moment they were created
+ALTER TABLE newsletter_issues ADD COLUMN created_at TIMESTAMPTZ;
+UPDATE newsletter_issues SET created_at = published_at;
+ALTER TABLE newsletter_issues ALTER COLUMN created_at SET NOT NULL;
+CREATE INDEX issue_delivery_queue_issue_id ON issue_delivery_queue (issue_id);
@@ -0,0 +1,6 @@
+-- draft, scheduled, published or cancelled
+ALTER TABLE newsletter_issues ADD COLUMN status TEXT NOT NULL DEFAULT 'published';
+ALTER TABLE newsletter_issues ADD COLUMN scheduled_for TIMESTAMPTZ;
+-- Drafts are not published yet
+ALTER TABLE newsletter_issues ALTER COLUMN published_at DROP NOT NULL;
+CREATE INDEX newsletter_issues_status_scheduled_for ON newsletter_issues (status, scheduled_for);
@@ -0,0 +1,16 @@
+-- Keys senders issue for machine clients, e.g. a CI pipeline publishing release notes
+CREATE TABLE api_keys (
+    id SERIAL PRIMARY KEY,
+    sender_id INTEGER NOT NULL REFERENCES senders (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    -- The start of the key, shown so the sender can tell their keys apart
+    prefix TEXT NOT NULL,
+    -- SHA-256 of the whole key, the key itself is only ever shown once
+    key_hash TEXT NOT NULL UNIQUE,
+    -- Space separated, e.g. `newsletters:publish subscribers:read`
+    scopes TEXT NOT NULL,
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX api_keys_sender_id ON api_keys (sender_id);
@@ -0,0 +1,2 @@
+-- Every sender could manage the others so far, they all keep doing so
+ALTER TABLE senders ADD COLUMN role TEXT NOT NULL DEFAULT 'admin';
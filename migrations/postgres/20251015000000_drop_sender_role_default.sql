@@ -0,0 +1,3 @@
+-- The default only existed to backfill the senders that predate roles,
+-- left on the column it would make admins of senders inserted without one
+ALTER TABLE senders ALTER COLUMN role DROP DEFAULT;
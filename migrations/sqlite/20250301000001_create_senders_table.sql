@@ -0,0 +1,4 @@
+CREATE TABLE senders (
+    username TEXT PRIMARY KEY,
+    pwd TEXT NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE subscription_tokens (
+    token TEXT PRIMARY KEY,
+    subscription_id INTEGER NOT NULL REFERENCES subscriptions (id),
+    expires_at TEXT NOT NULL,
+    used_at TEXT
+);
+CREATE INDEX subscription_tokens_subscription_id ON subscription_tokens (subscription_id);
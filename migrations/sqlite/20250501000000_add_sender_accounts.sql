@@ -0,0 +1,13 @@
+-- SQLite cannot change a primary key in place, so the table is rebuilt
+CREATE TABLE senders_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    display_name TEXT NOT NULL,
+    pwd TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TEXT NOT NULL
+);
+INSERT INTO senders_new (username, display_name, pwd, enabled, created_at)
+    SELECT username, username, pwd, TRUE, strftime('%Y-%m-%dT%H:%M:%fZ', 'now') FROM senders;
+DROP TABLE senders;
+ALTER TABLE senders_new RENAME TO senders;
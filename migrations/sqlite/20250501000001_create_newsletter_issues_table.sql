@@ -0,0 +1,8 @@
+CREATE TABLE newsletter_issues (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sender_id INTEGER REFERENCES senders (id) ON DELETE SET NULL,
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    published_at TEXT NOT NULL
+);
@@ -0,0 +1,14 @@
+CREATE TABLE issue_delivery_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    issue_id INTEGER NOT NULL REFERENCES newsletter_issues (id),
+    subscription_id INTEGER NOT NULL REFERENCES subscriptions (id),
+    email TEXT NOT NULL,
+    -- queued, sent or failed
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    execute_after TEXT NOT NULL,
+    -- Set while a worker is sending, a crashed worker's lease simply runs out
+    claimed_until TEXT,
+    last_error TEXT
+);
+CREATE INDEX issue_delivery_queue_status_execute_after ON issue_delivery_queue (status, execute_after);
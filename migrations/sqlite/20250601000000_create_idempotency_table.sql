@@ -0,0 +1,13 @@
+CREATE TABLE idempotency (
+    sender_id INTEGER NOT NULL REFERENCES senders (id) ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL,
+    -- SHA-256 of the request, a key cannot be reused for another request
+    request_hash TEXT NOT NULL,
+    -- All NULL while the first request is still being processed
+    response_status_code INTEGER,
+    response_headers TEXT,
+    response_body BLOB,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (sender_id, idempotency_key)
+);
+CREATE INDEX idempotency_created_at ON idempotency (created_at);
@@ -0,0 +1,4 @@
+-- Issues so far were published the moment they were created
+ALTER TABLE newsletter_issues ADD COLUMN created_at TEXT NOT NULL DEFAULT '';
+UPDATE newsletter_issues SET created_at = published_at;
+CREATE INDEX issue_delivery_queue_issue_id ON issue_delivery_queue (issue_id);
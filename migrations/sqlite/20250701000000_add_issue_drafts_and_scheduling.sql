@@ -0,0 +1,10 @@
+-- draft, scheduled, published or cancelled
+ALTER TABLE newsletter_issues ADD COLUMN status TEXT NOT NULL DEFAULT 'published';
+ALTER TABLE newsletter_issues ADD COLUMN scheduled_for TEXT;
+-- Drafts are not published yet. SQLite cannot drop a NOT NULL constraint,
+-- the column is swapped for a nullable one instead of rebuilding the table.
+ALTER TABLE newsletter_issues ADD COLUMN published_at_nullable TEXT;
+UPDATE newsletter_issues SET published_at_nullable = published_at;
+ALTER TABLE newsletter_issues DROP COLUMN published_at;
+ALTER TABLE newsletter_issues RENAME COLUMN published_at_nullable TO published_at;
+CREATE INDEX newsletter_issues_status_scheduled_for ON newsletter_issues (status, scheduled_for);
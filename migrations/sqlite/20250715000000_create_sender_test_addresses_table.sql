@@ -0,0 +1,6 @@
+-- Where a sender may send test emails of an issue to
+CREATE TABLE sender_test_addresses (
+    sender_id INTEGER NOT NULL REFERENCES senders (id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    PRIMARY KEY (sender_id, email)
+);
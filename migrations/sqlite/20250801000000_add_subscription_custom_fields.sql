@@ -0,0 +1,2 @@
+-- Free-form values newsletter templates can use, as a JSON object of strings
+ALTER TABLE subscriptions ADD COLUMN custom_fields TEXT NOT NULL DEFAULT '{}';
@@ -0,0 +1,9 @@
+-- The sessions of the admin pages
+CREATE TABLE sessions (
+    -- SHA-256 of the key in the session cookie, a copy of the table does not let anyone in
+    key_hash TEXT NOT NULL PRIMARY KEY,
+    -- JSON object of the session values
+    state TEXT NOT NULL,
+    expires_at TEXT NOT NULL
+);
+CREATE INDEX sessions_expires_at ON sessions (expires_at);
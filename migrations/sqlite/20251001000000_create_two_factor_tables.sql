@@ -0,0 +1,16 @@
+-- At most one authenticator app per sender
+CREATE TABLE sender_totp (
+    sender_id INTEGER PRIMARY KEY REFERENCES senders (id) ON DELETE CASCADE,
+    -- Base32, the authenticator app has to be able to compute the codes too
+    secret TEXT NOT NULL,
+    confirmed_at TEXT,
+    last_used_step INTEGER,
+    created_at TEXT NOT NULL
+);
+-- One-time codes for when the app is lost, only their SHA-256 is kept
+CREATE TABLE sender_recovery_codes (
+    sender_id INTEGER NOT NULL REFERENCES senders (id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    used_at TEXT,
+    PRIMARY KEY (sender_id, code_hash)
+);
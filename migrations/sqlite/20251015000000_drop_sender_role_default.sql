@@ -0,0 +1,20 @@
+-- The default only existed to backfill the senders that predate roles,
+-- left on the column it would make admins of senders inserted without one.
+-- SQLite cannot drop a default, so the table is rebuilt. Migrations run with foreign keys off,
+-- dropping the old table leaves what belongs to each sender in place for the new one.
+CREATE TABLE senders_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    display_name TEXT NOT NULL,
+    pwd TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TEXT NOT NULL,
+    role TEXT NOT NULL
+);
+INSERT INTO senders_new (id, username, display_name, pwd, enabled, created_at, role)
+    SELECT id, username, display_name, pwd, enabled, created_at, role FROM senders;
+-- Ids of deleted senders are not handed out again
+DELETE FROM sqlite_sequence WHERE name = 'senders_new';
+INSERT INTO sqlite_sequence (name, seq) SELECT 'senders_new', seq FROM sqlite_sequence WHERE name = 'senders';
+DROP TABLE senders;
+ALTER TABLE senders_new RENAME TO senders;
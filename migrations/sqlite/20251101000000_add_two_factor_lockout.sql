@@ -0,0 +1,3 @@
+-- Wrong codes count per sender, not per login: a new login or request starts no new count
+ALTER TABLE sender_totp ADD COLUMN failed_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sender_totp ADD COLUMN locked_until TEXT;
@@ -0,0 +1,96 @@
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::SecretString;
+use sha2::Digest;
+
+/// Every key starts with it, so a leaked one is easy to recognise (and to scan for).
+pub const API_KEY_PREFIX: &str = "nlk_";
+const PREFIX_LENGTH: usize = 8;
+const SECRET_LENGTH: usize = 32;
+
+/// What an API key may be used for. People logged in with their password may do everything.
+/// There is no `subscribers:write` on purpose: no sender route changes subscribers (subscribing,
+/// confirming and unsubscribing belong to the subscribers themselves), a scope nothing checks
+/// would only look like a restriction. It comes with the first such route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiScope {
+    NewslettersPublish,
+    SubscribersRead,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 2] = [ApiScope::NewslettersPublish, ApiScope::SubscribersRead];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::NewslettersPublish => "newsletters:publish",
+            ApiScope::SubscribersRead => "subscribers:read",
+        }
+    }
+}
+
+impl std::str::FromStr for ApiScope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|scope| scope.as_str() == value)
+            .ok_or_else(|| format!("unknown scope `{}`", value))
+    }
+}
+
+impl std::fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A freshly issued key: `key` is handed to the sender once, only `hash` is stored.
+pub struct NewApiKey {
+    pub key: SecretString,
+    pub prefix: String,
+    pub hash: String,
+}
+
+/// `nlk_<8 characters>_<32 characters>`, the first part is the visible prefix.
+pub fn generate_api_key() -> NewApiKey {
+    let mut rng = thread_rng();
+    let mut random = |length| -> String {
+        std::iter::repeat_with(|| rng.sample(Alphanumeric)).map(char::from).take(length).collect()
+    };
+    let prefix = format!("{}{}", API_KEY_PREFIX, random(PREFIX_LENGTH));
+    let key = format!("{}_{}", prefix, random(SECRET_LENGTH));
+    let hash = api_key_hash(&key);
+    NewApiKey { key: SecretString::from(key), prefix, hash }
+}
+
+/// The keys are long and random, a plain SHA-256 is enough: there is nothing to brute force.
+pub fn api_key_hash(key: &str) -> String {
+    hex::encode(sha2::Sha256::digest(key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::ExposeSecret;
+
+    use super::{api_key_hash, generate_api_key, ApiScope, API_KEY_PREFIX};
+
+    #[test]
+    fn a_key_starts_with_its_prefix_and_matches_its_hash() {
+        let new_key = generate_api_key();
+        let key = new_key.key.expose_secret();
+
+        assert!(new_key.prefix.starts_with(API_KEY_PREFIX));
+        assert!(key.starts_with(&format!("{}_", new_key.prefix)));
+        assert_eq!(key.len(), API_KEY_PREFIX.len() + 8 + 1 + 32);
+        assert_eq!(api_key_hash(key), new_key.hash);
+        assert_ne!(generate_api_key().hash, new_key.hash);
+    }
+
+    #[test]
+    fn scopes_round_trip_through_their_names() {
+        for scope in ApiScope::ALL {
+            assert_eq!(scope.as_str().parse::<ApiScope>(), Ok(scope));
+        }
+        assert!("newsletters:delete".parse::<ApiScope>().is_err());
+    }
+}
@@ -0,0 +1,25 @@
+use actix_web::http::header::HeaderMap;
+use base64::Engine;
+use secrecy::SecretString;
+
+use super::{AuthError, Credentials};
+
+/// Read the credentials of an `Authorization: Basic ...` header.
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, AuthError> {
+    let header = headers
+        .get("Authorization")
+        .ok_or(AuthError::MissingAuthorizationHeader)?
+        .to_str()?;
+    // lose the Basic keyword and decode the base64 encoded string
+    let encoded = header.split_whitespace().nth(1).unwrap_or_default();
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+    let decoded_str = String::from_utf8(decoded)?;
+
+    let (username, password) = decoded_str
+        .split_once(':')
+        .ok_or(AuthError::InvalidCredentials)?;
+    Ok(Credentials {
+        username: username.to_string(),
+        password: SecretString::from(password.to_string()),
+    })
+}
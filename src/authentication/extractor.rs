@@ -0,0 +1,195 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_session::{Session, SessionExt};
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+
+use crate::clock::Clock;
+use crate::configuration::TwoFactorProperties;
+use crate::storage::{Sender, Storage};
+
+use super::{api_key_hash, basic_authentication, validate_credentials, verify_second_factor, ApiScope, AuthError, PasswordHashing, Permission, SESSION_SENDER_ID};
+
+/// Where a sender with two-factor authentication puts the current code, next to their Basic credentials.
+pub const TWO_FACTOR_CODE_HEADER: &str = "Two-Factor-Code";
+
+/// How the sender of a request proved who they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthMethod {
+    Basic,
+    Session,
+    /// Only good for what its scopes allow.
+    ApiKey { key_id: i32, scopes: Vec<ApiScope> },
+    /// Basic credentials of a sender who has to set up two-factor authentication
+    /// (`auth.two_factor.required`) and has not yet: only good for doing so.
+    BasicAwaitingTwoFactor,
+}
+
+/// The sender a request comes from. Declaring it as a handler argument is what protects a route:
+/// without valid credentials the handler never runs and the client gets the uniform
+/// `401`/`400` of `AuthError`. Extractors run side by side, a body that does not parse may be
+/// reported before credentials that are wrong.
+///
+/// An `Authorization` header decides on its own, `Basic` credentials or a `Bearer` API key.
+/// Without one the session cookie of the admin pages is accepted, it is `SameSite=Lax` so other
+/// sites cannot make a browser send it along with a `POST`.
+#[derive(Debug)]
+pub struct AuthenticatedSender {
+    pub sender: Sender,
+    pub method: AuthMethod,
+}
+
+impl std::ops::Deref for AuthenticatedSender {
+    type Target = Sender;
+
+    fn deref(&self) -> &Sender {
+        &self.sender
+    }
+}
+
+impl AuthenticatedSender {
+    /// The check every protected handler starts with: the sender's role must allow `permission`,
+    /// and an API key must also have its scope. Roles are read on every request, a change
+    /// applies at once to the sender's sessions and keys.
+    pub fn require_permission(&self, permission: Permission) -> Result<(), AuthError> {
+        self.require_two_factor_setup()?;
+        if self.role < permission.minimum_role() {
+            return Err(AuthError::Forbidden(format!("The `{}` role cannot {}", self.role, permission)));
+        }
+        match permission.scope() {
+            Some(scope) => self.require_scope(scope),
+            None => self.require_sign_in(),
+        }
+    }
+
+    /// Routes an API key may call say which scope it needs, the sender's password or session allows everything.
+    pub fn require_scope(&self, scope: ApiScope) -> Result<(), AuthError> {
+        match &self.method {
+            AuthMethod::ApiKey { scopes, .. } if !scopes.contains(&scope) => {
+                Err(AuthError::Forbidden(format!("The API key does not have the `{}` scope", scope)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// For what no scope covers, e.g. managing senders and keys: API keys are refused.
+    fn require_sign_in(&self) -> Result<(), AuthError> {
+        self.require_password()?;
+        self.require_two_factor_setup()
+    }
+
+    /// The sender's password or session, even if they still have to set up two-factor authentication:
+    /// for doing just that.
+    pub fn require_password(&self) -> Result<(), AuthError> {
+        match self.method {
+            AuthMethod::ApiKey { .. } => Err(AuthError::Forbidden("API keys cannot be used here, log in with your password".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    fn require_two_factor_setup(&self) -> Result<(), AuthError> {
+        match self.method {
+            AuthMethod::BasicAwaitingTwoFactor => Err(AuthError::Forbidden(
+                "Two-factor authentication is required, set it up through `/senders/me/two-factor` first".to_string()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromRequest for AuthenticatedSender {
+    type Error = AuthError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, AuthError>>>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let request = request.clone();
+        Box::pin(async move {
+            let storage = request.app_data::<web::Data<dyn Storage>>()
+                .expect("The storage is registered as app data")
+                .clone();
+            let authenticated = authenticate(&request, storage.get_ref()).await;
+            if let Err(e) = &authenticated {
+                tracing::warn!("Failed to authenticate request: {:?}", e);
+            }
+            authenticated
+        })
+    }
+}
+
+async fn authenticate(request: &HttpRequest, storage: &dyn Storage) -> Result<AuthenticatedSender, AuthError> {
+    let Some(authorization) = request.headers().get(header::AUTHORIZATION) else {
+        let sender = session_sender(&request.get_session(), storage).await?
+            .ok_or(AuthError::MissingAuthorizationHeader)?;
+        return Ok(AuthenticatedSender { sender, method: AuthMethod::Session });
+    };
+    let mut parts = authorization.to_str()?.split_whitespace();
+    let scheme = parts.next().unwrap_or_default();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        let clock = request.app_data::<web::Data<dyn Clock>>()
+            .expect("The clock is registered as app data");
+        return api_key_authentication(parts.next().unwrap_or_default(), storage, clock.get_ref()).await;
+    }
+    let credentials = basic_authentication(request.headers())?;
+    let password_hashing = request.app_data::<web::Data<PasswordHashing>>()
+        .expect("The password hashing parameters are registered as app data");
+    let sender = validate_credentials(credentials, storage, password_hashing).await?;
+    let two_factor = request.app_data::<web::Data<TwoFactorProperties>>()
+        .expect("The two-factor settings are registered as app data");
+    let clock = request.app_data::<web::Data<dyn Clock>>()
+        .expect("The clock is registered as app data");
+    let method = basic_second_factor(request, &sender, storage, two_factor, clock.now()).await?;
+    Ok(AuthenticatedSender { sender, method })
+}
+
+/// A password alone is not enough for a sender with two-factor authentication,
+/// the current code has to come along in the `Two-Factor-Code` header.
+async fn basic_second_factor(
+    request: &HttpRequest,
+    sender: &Sender,
+    storage: &dyn Storage,
+    two_factor: &TwoFactorProperties,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<AuthMethod, AuthError> {
+    let enrolment = storage.find_totp_enrolment(sender.id).await?
+        .filter(|enrolment| enrolment.confirmed_at.is_some());
+    let Some(enrolment) = enrolment else {
+        return Ok(if two_factor.required { AuthMethod::BasicAwaitingTwoFactor } else { AuthMethod::Basic });
+    };
+    let code = request.headers().get(TWO_FACTOR_CODE_HEADER)
+        .and_then(|code| code.to_str().ok())
+        .ok_or(AuthError::SecondFactorRequired)?;
+    if !verify_second_factor(storage, &enrolment, code, now).await? {
+        return Err(AuthError::SecondFactorRequired);
+    }
+    Ok(AuthMethod::Basic)
+}
+
+/// Unknown, expired, and keys of a disabled sender all look the same from the outside.
+async fn api_key_authentication(key: &str, storage: &dyn Storage, clock: &dyn Clock) -> Result<AuthenticatedSender, AuthError> {
+    let now = clock.now();
+    let api_key = storage.find_api_key(&api_key_hash(key)).await?
+        .filter(|api_key| api_key.expires_at.is_none_or(|expires_at| expires_at > now))
+        .ok_or(AuthError::InvalidToken)?;
+    let sender = storage.find_sender_by_id(api_key.sender_id).await?
+        .filter(|sender| sender.enabled)
+        .ok_or(AuthError::InvalidToken)?;
+    storage.touch_api_key(api_key.id, now).await?;
+    // A scope this version does not know about grants nothing
+    let scopes = api_key.scopes.iter().filter_map(|scope| scope.parse().ok()).collect();
+    Ok(AuthenticatedSender { sender, method: AuthMethod::ApiKey { key_id: api_key.id, scopes } })
+}
+
+/// The sender logged in to `session`. A session whose sender was deleted or disabled
+/// since they logged in is ended.
+pub async fn session_sender(session: &Session, storage: &dyn Storage) -> Result<Option<Sender>, AuthError> {
+    let Some(sender_id) = session.get::<i32>(SESSION_SENDER_ID)? else {
+        return Ok(None);
+    };
+    match storage.find_sender_by_id(sender_id).await? {
+        Some(sender) if sender.enabled => Ok(Some(sender)),
+        _ => {
+            session.purge();
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,91 @@
+mod api_key;
+mod basic;
+mod extractor;
+mod password;
+mod permission;
+mod session;
+mod two_factor;
+pub use api_key::*;
+pub use basic::*;
+pub use extractor::*;
+pub use password::*;
+pub use permission::*;
+pub use session::*;
+pub use two_factor::*;
+
+use actix_web::{http::header::{self, HeaderValue}, HttpResponse, HttpResponseBuilder, ResponseError};
+use secrecy::SecretString;
+use serde_json::json;
+
+use crate::storage::StorageError;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Missing Authorization header")]
+    MissingAuthorizationHeader,
+    #[error("The 'Authorization' header was not a valid UTF8 string.")]
+    InvalidAuthorizationHeaderUTFString(#[from] actix_web::http::header::ToStrError),
+    #[error("Failed to decode Authorization header")]
+    DecodeAuthorizationHeaderError(#[from] base64::DecodeError),
+    #[error("Failed to parse Authorization header")]
+    ParseAuthorizationHeaderError(#[from] std::string::FromUtf8Error),
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("Invalid API key")]
+    InvalidToken,
+    #[error("A valid two-factor code is required in the `Two-Factor-Code` header")]
+    SecondFactorRequired,
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Failed to read the session")]
+    SessionError(#[from] actix_session::SessionGetError),
+    #[error("Failed to hash or verify the password")]
+    PasswordHash(#[from] argon2::password_hash::Error),
+    #[error("The password hashing task failed")]
+    TaskFailed(#[from] tokio::task::JoinError),
+    #[error("Failed to access the senders store")]
+    StorageError(#[from] StorageError),
+    #[error("The stored TOTP secret is unusable")]
+    TotpError(#[from] totp_rs::TotpUrlError),
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: SecretString,
+}
+
+/// Every protected route answers the same way: `401` and the challenges of both schemes when
+/// credentials are missing or wrong, `400` when they cannot be parsed, `403` when the sender
+/// is known but not allowed to do this.
+impl ResponseError for AuthError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AuthError::MissingAuthorizationHeader | AuthError::InvalidCredentials | AuthError::SecondFactorRequired => {
+                challenge(HttpResponse::Unauthorized(), r#"Bearer realm="publish""#)
+                    .json(json!({ "error": self.to_string() }))
+            }
+            AuthError::InvalidToken => {
+                challenge(HttpResponse::Unauthorized(), r#"Bearer realm="publish", error="invalid_token""#)
+                    .json(json!({ "error": self.to_string() }))
+            }
+            AuthError::InvalidAuthorizationHeaderUTFString(_)
+            | AuthError::DecodeAuthorizationHeaderError(_)
+            | AuthError::ParseAuthorizationHeaderError(_) => {
+                challenge(HttpResponse::BadRequest(), r#"Bearer realm="publish""#)
+                    .json(json!({ "error": self.to_string() }))
+            }
+            AuthError::Forbidden(_) => {
+                HttpResponse::Forbidden().json(json!({ "error": self.to_string() }))
+            }
+            _ => HttpResponse::InternalServerError().finish(),
+        }
+    }
+}
+
+/// Basic comes first, it is what a client that only knows one scheme should try.
+fn challenge(mut response: HttpResponseBuilder, bearer: &'static str) -> HttpResponseBuilder {
+    response
+        .append_header((header::WWW_AUTHENTICATE, HeaderValue::from_static(r#"Basic realm="publish""#)))
+        .append_header((header::WWW_AUTHENTICATE, HeaderValue::from_static(bearer)));
+    response
+}
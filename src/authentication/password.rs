@@ -0,0 +1,147 @@
+use argon2::password_hash::{Output, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use rand::distributions::{Alphanumeric, DistString};
+use secrecy::{ExposeSecret, SecretString};
+use sha3::Digest;
+
+use crate::storage::{Sender, Storage};
+
+use super::{AuthError, Credentials};
+
+/// Hashes sender passwords into Argon2id PHC strings with the configured cost.
+/// The work happens on the blocking thread pool so it never stalls the executor.
+pub struct PasswordHashing {
+    params: Params,
+    // Checked against when the username does not exist,
+    // so an unknown user takes as long to reject as a wrong password.
+    dummy_hash: String,
+}
+
+impl PasswordHashing {
+    pub fn new(params: Params) -> Result<Self, AuthError> {
+        let dummy_password = SecretString::from(Alphanumeric.sample_string(&mut rand::thread_rng(), 32));
+        let dummy_hash = hash_password(&params, &dummy_password)?;
+        Ok(Self { params, dummy_hash })
+    }
+
+    pub async fn hash(&self, password: SecretString) -> Result<String, AuthError> {
+        let params = self.params.clone();
+        tokio::task::spawn_blocking(move || hash_password(&params, &password)).await?
+    }
+}
+
+fn hash_password(params: &Params, password: &SecretString) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password(password.expose_secret().as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// The cost parameters are read back from the PHC string itself,
+/// so hashes made before a configuration change keep verifying.
+fn verify_password(expected_hash: &str, password: &SecretString) -> Result<bool, AuthError> {
+    let expected_hash = PasswordHash::new(expected_hash)?;
+    match Argon2::default().verify_password(password.expose_secret().as_bytes(), &expected_hash) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Senders created before the switch to Argon2 have an unsalted SHA3-256 hex digest.
+fn is_legacy_hash(stored: &str) -> bool {
+    !stored.starts_with('$')
+}
+
+fn verify_legacy_password(expected_hash: &str, password: &SecretString) -> bool {
+    let actual = sha3::Sha3_256::digest(password.expose_secret().as_bytes());
+    match (hex::decode(expected_hash).ok().and_then(|bytes| Output::new(&bytes).ok()), Output::new(&actual)) {
+        // `Output` compares in constant time
+        (Some(expected), Ok(actual)) => expected == actual,
+        _ => false,
+    }
+}
+
+/// Returns the authenticated sender, disabled senders are rejected like a wrong password.
+/// A legacy SHA3 hash is replaced by an Argon2id one once the password is known to be right.
+#[tracing::instrument(
+    name = "Validating sender credentials",
+    skip(credentials, storage, hashing),
+    fields(username = %credentials.username)
+)]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    storage: &dyn Storage,
+    hashing: &PasswordHashing,
+) -> Result<Sender, AuthError> {
+    let sender = storage.find_sender(&credentials.username).await?;
+    let expected_hash = match &sender {
+        Some(sender) => sender.pwd.clone(),
+        None => hashing.dummy_hash.clone(),
+    };
+    let legacy = is_legacy_hash(&expected_hash);
+    let dummy_hash = hashing.dummy_hash.clone();
+    let password = credentials.password;
+    let (verified, password) = tokio::task::spawn_blocking(move || {
+        let verified = if legacy {
+            // Spend the same time as an Argon2 check so legacy accounts do not stand out
+            let _ = verify_password(&dummy_hash, &password);
+            Ok(verify_legacy_password(&expected_hash, &password))
+        } else {
+            verify_password(&expected_hash, &password)
+        };
+        (verified, password)
+    })
+    .await?;
+    let Some(sender) = sender else {
+        return Err(AuthError::InvalidCredentials);
+    };
+    if !verified? || !sender.enabled {
+        return Err(AuthError::InvalidCredentials);
+    }
+    if legacy {
+        let upgraded = hashing.hash(password).await?;
+        storage.update_sender_password(&sender.username, &upgraded).await?;
+        tracing::info!("Upgraded a legacy SHA3 password hash to Argon2id");
+    }
+    Ok(sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::Params;
+    use secrecy::SecretString;
+    use sha3::Digest;
+
+    use super::{hash_password, is_legacy_hash, verify_legacy_password, verify_password};
+
+    fn params() -> Params {
+        Params::new(Params::MIN_M_COST, 1, 1, None).unwrap()
+    }
+
+    #[test]
+    fn argon2id_hashes_are_salted_phc_strings() {
+        let password = SecretString::from("correct horse");
+        let first = hash_password(&params(), &password).unwrap();
+        let second = hash_password(&params(), &password).unwrap();
+        assert!(first.starts_with("$argon2id$v=19$m=8,t=1,p=1$"));
+        assert_ne!(first, second);
+        assert!(!is_legacy_hash(&first));
+    }
+
+    #[test]
+    fn argon2id_hashes_only_verify_the_right_password() {
+        let hash = hash_password(&params(), &SecretString::from("correct horse")).unwrap();
+        assert!(verify_password(&hash, &SecretString::from("correct horse")).unwrap());
+        assert!(!verify_password(&hash, &SecretString::from("battery staple")).unwrap());
+    }
+
+    #[test]
+    fn legacy_sha3_hashes_are_still_recognised() {
+        let legacy = format!("{:x}", sha3::Sha3_256::digest("admin".as_bytes()));
+        assert!(is_legacy_hash(&legacy));
+        assert!(verify_legacy_password(&legacy, &SecretString::from("admin")));
+        assert!(!verify_legacy_password(&legacy, &SecretString::from("not admin")));
+        assert!(!verify_legacy_password("not hex", &SecretString::from("admin")));
+    }
+}
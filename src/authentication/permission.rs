@@ -0,0 +1,70 @@
+use crate::storage::SenderRole;
+
+use super::ApiScope;
+
+/// What a handler is about to do, see `AuthenticatedSender::require_permission`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ReadSubscribers,
+    /// Drafts, previews, test sends and the issues too.
+    PublishNewsletters,
+    ManageSenders,
+    /// Their own password, test addresses, API keys and two-factor authentication.
+    ManageOwnAccount,
+}
+
+impl Permission {
+    /// The least role allowed to do it.
+    pub fn minimum_role(self) -> SenderRole {
+        match self {
+            Permission::ReadSubscribers => SenderRole::Viewer,
+            Permission::PublishNewsletters => SenderRole::Editor,
+            Permission::ManageSenders => SenderRole::Admin,
+            Permission::ManageOwnAccount => SenderRole::Viewer,
+        }
+    }
+
+    /// The scope an API key needs for it, `None` when no key may do it.
+    pub fn scope(self) -> Option<ApiScope> {
+        match self {
+            Permission::ReadSubscribers => Some(ApiScope::SubscribersRead),
+            Permission::PublishNewsletters => Some(ApiScope::NewslettersPublish),
+            Permission::ManageSenders | Permission::ManageOwnAccount => None,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Permission::ReadSubscribers => "read subscribers",
+            Permission::PublishNewsletters => "publish newsletters",
+            Permission::ManageSenders => "manage senders",
+            Permission::ManageOwnAccount => "manage their own account",
+        }
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::SenderRole;
+
+    use super::Permission;
+
+    #[test]
+    fn each_role_can_do_what_the_roles_below_it_can() {
+        let allowed = |role: SenderRole| -> Vec<Permission> {
+            [Permission::ManageOwnAccount, Permission::ReadSubscribers, Permission::PublishNewsletters, Permission::ManageSenders].into_iter()
+                .filter(|permission| role >= permission.minimum_role())
+                .collect()
+        };
+
+        assert_eq!(allowed(SenderRole::Viewer), vec![Permission::ManageOwnAccount, Permission::ReadSubscribers]);
+        assert_eq!(allowed(SenderRole::Editor), vec![Permission::ManageOwnAccount, Permission::ReadSubscribers, Permission::PublishNewsletters]);
+        assert_eq!(allowed(SenderRole::Admin), vec![Permission::ManageOwnAccount, Permission::ReadSubscribers, Permission::PublishNewsletters, Permission::ManageSenders]);
+    }
+}
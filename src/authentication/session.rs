@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_session::storage::{generate_session_key, LoadError, SaveError, SessionKey, SessionStore as SessionMiddlewareStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use chrono::{DateTime, Utc};
+use sha2::Digest;
+
+use crate::clock::Clock;
+use crate::storage::SessionStore;
+
+/// The key the logged in sender's id is kept under.
+pub const SESSION_SENDER_ID: &str = "sender_id";
+/// The sender who gave the right password but still owes the second factor.
+pub const SESSION_PENDING_SENDER_ID: &str = "pending_sender_id";
+
+type SessionState = HashMap<String, String>;
+
+/// Where `SessionMiddleware` keeps the state of the admin pages' sessions: the storage backend,
+/// or an `InMemorySessions` of its own. Only a hash of the key in the cookie is ever stored.
+#[derive(Clone)]
+pub struct SessionBackend {
+    store: Arc<dyn SessionStore>,
+    clock: Arc<dyn Clock>,
+}
+
+impl SessionBackend {
+    pub fn new(store: Arc<dyn SessionStore>, clock: Arc<dyn Clock>) -> Self {
+        Self { store, clock }
+    }
+
+    fn expires_at(&self, ttl: &Duration) -> DateTime<Utc> {
+        self.clock.now() + chrono::Duration::seconds(ttl.whole_seconds())
+    }
+}
+
+fn key_hash(key: &SessionKey) -> String {
+    hex::encode(sha2::Sha256::digest(key.as_ref().as_bytes()))
+}
+
+impl SessionMiddlewareStore for SessionBackend {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let state = self.store.load_session(&key_hash(session_key), self.clock.now()).await
+            .map_err(|e| LoadError::Other(e.into()))?;
+        state.map(|state| serde_json::from_str(&state))
+            .transpose()
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let state = serde_json::to_string(&session_state).map_err(|e| SaveError::Serialization(e.into()))?;
+        let session_key = generate_session_key();
+        self.store.save_session(&key_hash(&session_key), &state, self.expires_at(ttl), self.clock.now()).await
+            .map_err(|e| SaveError::Other(e.into()))?;
+        Ok(session_key)
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, UpdateError> {
+        let hash = key_hash(&session_key);
+        let now = self.clock.now();
+        let exists = self.store.load_session(&hash, now).await
+            .map_err(|e| UpdateError::Other(e.into()))?
+            .is_some();
+        // An expired session is not brought back, its state goes under a new key
+        if !exists {
+            return self.save(session_state, ttl).await.map_err(|e| match e {
+                SaveError::Serialization(e) => UpdateError::Serialization(e),
+                SaveError::Other(e) => UpdateError::Other(e),
+            });
+        }
+        let state = serde_json::to_string(&session_state).map_err(|e| UpdateError::Serialization(e.into()))?;
+        self.store.save_session(&hash, &state, self.expires_at(ttl), now).await
+            .map_err(|e| UpdateError::Other(e.into()))?;
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        Ok(self.store.extend_session(&key_hash(session_key), self.expires_at(ttl)).await?)
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        Ok(self.store.delete_session(&key_hash(session_key)).await?)
+    }
+}
@@ -0,0 +1,161 @@
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
+use sha2::Digest;
+use totp_rs::{Algorithm, Secret, TotpUrlError, TOTP};
+
+use crate::storage::{Storage, TotpEnrolment};
+
+use super::AuthError;
+
+/// RFC 6238 defaults, the only ones every authenticator app understands.
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+/// RFC 4226 recommends 160 bits.
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_HALF_LENGTH: usize = 5;
+/// Wrong codes in a row, over every login and request, before the second factor is locked.
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+const LOCKOUT_MINUTES: i64 = 15;
+
+/// A fresh secret, base32 encoded.
+pub fn generate_totp_secret() -> String {
+    let mut secret = [0u8; SECRET_BYTES];
+    thread_rng().fill_bytes(&mut secret);
+    match Secret::Raw(secret.to_vec()).to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!("`to_encoded` always encodes"),
+    }
+}
+
+/// The `otpauth://totp/...` URI authenticator apps read (usually from a QR code).
+pub fn totp_uri(secret: &str, issuer: &str, username: &str) -> Result<String, TotpUrlError> {
+    Ok(totp(secret, Some(issuer.to_string()), username.to_string())?.get_url())
+}
+
+fn totp(secret: &str, issuer: Option<String>, account_name: String) -> Result<TOTP, TotpUrlError> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes()
+        .map_err(|_| TotpUrlError::Secret(secret.to_string()))?;
+    // Each step is tried on its own, the one that matches is remembered
+    TOTP::new(Algorithm::SHA1, DIGITS, 0, STEP_SECONDS, secret, issuer, account_name)
+}
+
+/// The time step of `code` if it is valid at `now`, allowing one step of clock drift either way.
+pub fn matching_totp_step(secret: &str, code: &str, now: DateTime<Utc>) -> Result<Option<i64>, TotpUrlError> {
+    let totp = totp(secret, None, String::new())?;
+    let current = now.timestamp() / STEP_SECONDS as i64;
+    Ok((current - 1..=current + 1).find(|step| totp.check(code, *step as u64 * STEP_SECONDS)))
+}
+
+/// `RECOVERY_CODES` codes like `k3x9a-7bq2m`, shown to the sender once.
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = thread_rng();
+    let mut half = || -> String {
+        std::iter::repeat_with(|| rng.sample(Alphanumeric))
+            .map(|c| char::from(c).to_ascii_lowercase())
+            .take(RECOVERY_CODE_HALF_LENGTH)
+            .collect()
+    };
+    (0..RECOVERY_CODES).map(|_| format!("{}-{}", half(), half())).collect()
+}
+
+/// Like API keys the codes are random enough for a plain SHA-256.
+pub fn recovery_code_hash(code: &str) -> String {
+    hex::encode(sha2::Sha256::digest(code.trim().to_ascii_lowercase().as_bytes()))
+}
+
+/// Checks a code from the authenticator app, or else a recovery code, and uses it up.
+/// Returns `false` for a wrong code and for one already used. After `MAX_FAILED_ATTEMPTS`
+/// of those in a row every code is `false` for `LOCKOUT_MINUTES`, the right one too,
+/// whether it comes from the login form or along with Basic credentials.
+pub async fn verify_second_factor(
+    storage: &dyn Storage,
+    enrolment: &TotpEnrolment,
+    code: &str,
+    now: DateTime<Utc>,
+) -> Result<bool, AuthError> {
+    let locked_until = now + chrono::Duration::minutes(LOCKOUT_MINUTES);
+    if !storage.start_second_factor_attempt(enrolment.sender_id, MAX_FAILED_ATTEMPTS, now, locked_until).await? {
+        tracing::warn!("Sender {} is locked out after too many wrong codes", enrolment.sender_id);
+        return Ok(false);
+    }
+    let code = code.trim();
+    let accepted = if code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit()) {
+        match matching_totp_step(&enrolment.secret, code, now)? {
+            Some(step) => storage.use_totp_step(enrolment.sender_id, step).await?,
+            None => false,
+        }
+    } else {
+        storage.use_recovery_code(enrolment.sender_id, &recovery_code_hash(code), now).await?
+    };
+    if accepted {
+        storage.clear_second_factor_attempts(enrolment.sender_id).await?;
+    }
+    Ok(accepted)
+}
+
+/// Replaces the sender's recovery codes with new ones, returned to be shown once.
+pub async fn renew_recovery_codes(storage: &dyn Storage, sender_id: i32) -> Result<Vec<String>, AuthError> {
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| recovery_code_hash(code)).collect();
+    storage.replace_recovery_codes(sender_id, &hashes).await?;
+    Ok(codes)
+}
+
+/// Turns two-factor authentication on once the sender shows a code from their app,
+/// that code is used up. Returns the recovery codes, `None` if the code is wrong.
+pub async fn confirm_enrolment(
+    storage: &dyn Storage,
+    enrolment: &TotpEnrolment,
+    code: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<Vec<String>>, AuthError> {
+    let Some(step) = matching_totp_step(&enrolment.secret, code.trim(), now)? else {
+        return Ok(None);
+    };
+    // Someone else (another tab?) confirmed it in the meantime
+    if !storage.confirm_totp_enrolment(enrolment.sender_id, now).await? {
+        return Ok(None);
+    }
+    storage.use_totp_step(enrolment.sender_id, step).await?;
+    Ok(Some(renew_recovery_codes(storage, enrolment.sender_id).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{generate_recovery_codes, generate_totp_secret, matching_totp_step, recovery_code_hash, totp_uri};
+
+    #[test]
+    fn codes_are_accepted_one_step_either_side_of_now() {
+        // The SHA-1 secret of the RFC 6238 test vectors, "12345678901234567890"
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        let now = Utc.timestamp_opt(59, 0).unwrap();
+
+        // 94287082 in the RFC, with 8 digits
+        assert_eq!(matching_totp_step(secret, "287082", now).unwrap(), Some(1));
+        assert_eq!(matching_totp_step(secret, "287082", now + chrono::Duration::seconds(30)).unwrap(), Some(1));
+        assert_eq!(matching_totp_step(secret, "287082", now + chrono::Duration::seconds(61)).unwrap(), None);
+        assert_eq!(matching_totp_step(secret, "000000", now).unwrap(), None);
+    }
+
+    #[test]
+    fn the_uri_names_the_issuer_and_the_sender() {
+        let secret = generate_totp_secret();
+
+        let uri = totp_uri(&secret, "Newsletter", "admin").unwrap();
+
+        assert_eq!(uri, format!("otpauth://totp/Newsletter:admin?secret={}&issuer=Newsletter", secret));
+    }
+
+    #[test]
+    fn recovery_codes_are_distinct_and_typed_loosely() {
+        let codes = generate_recovery_codes();
+
+        assert_eq!(codes.len(), 10);
+        assert!(codes.iter().all(|code| code.len() == 11 && code.as_bytes()[5] == b'-'));
+        assert_eq!(codes.iter().collect::<std::collections::HashSet<_>>().len(), 10);
+        assert_eq!(recovery_code_hash(&format!(" {} ", codes[0].to_uppercase())), recovery_code_hash(&codes[0]));
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+/// Where "now" comes from for everything that runs on a schedule.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// The system time moved forward by however much `advance` was told,
+/// so tests can fast-forward to a scheduled send instead of waiting for it.
+#[derive(Debug, Default)]
+pub struct AdjustableClock {
+    offset: Mutex<chrono::Duration>,
+}
+
+impl AdjustableClock {
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.offset.lock().expect("Mutex poisoned") += by;
+    }
+}
+
+impl Clock for AdjustableClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now() + *self.offset.lock().expect("Mutex poisoned")
+    }
+}
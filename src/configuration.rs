@@ -1,38 +1,583 @@
 
+use std::path::{Path, PathBuf};
+
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use validator::ValidateEmail;
+
+use crate::templating::{ConfirmationTemplate, NewsletterLayout, TemplateError};
+
+/// Everything that stops the configuration from being used, reported at once
+/// so a deploy does not have to be retried once per typo.
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigurationError {
+    #[error("Failed to read the configuration: {0}")]
+    Load(#[from] config::ConfigError),
+    #[error("Invalid configuration:\n{}", .0.iter().map(|problem| format!("  - {}", problem)).collect::<Vec<_>>().join("\n"))]
+    Invalid(Vec<String>),
+}
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Properties {
-    pub server_port: u16,
-    pub server_host: String,
-    pub author: String,
-    pub specific_properties: SomeProperties,
+    pub server: ServerProperties,
     pub email_client: EmailClientProperties,
+    pub storage: StorageProperties,
+    pub database: DatabaseProperties,
+    pub auth: AuthProperties,
+    pub delivery: DeliveryProperties,
+    pub idempotency: IdempotencyProperties,
+    pub scheduler: SchedulerProperties,
+    pub content_limits: ContentLimitsProperties,
+    pub session: SessionProperties,
+    #[serde(default)]
+    pub templates: TemplateProperties,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ServerProperties{
+    pub host: String,
+    pub port: u16,
+    // The public address used in the links we email
     pub base_url: String,
 }
 
 #[derive(serde::Deserialize, Clone)]
-pub struct SomeProperties{
-    pub first: String,
-    pub second: String,
+pub struct AuthProperties{
+    // How long a confirmation link stays valid
+    pub confirmation_token_expiry_minutes: i64,
+    // Key used to sign the unsubscribe links, only `local.yaml` has one:
+    // set it with `APP_AUTH__UNSUBSCRIBE_SECRET` in production
+    pub unsubscribe_secret: SecretString,
+    pub password_hashing: PasswordHashingProperties,
+    // Created on startup when there are no senders at all,
+    // set it with `APP_AUTH__BOOTSTRAP_ADMIN__USERNAME` / `..._PASSWORD` in production
+    pub bootstrap_admin: Option<BootstrapAdminProperties>,
+    pub two_factor: TwoFactorProperties,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct TwoFactorProperties{
+    // Senders without an authenticator app can do nothing but set one up
+    pub required: bool,
+    // Shown next to the username in the authenticator app
+    pub issuer: String,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct BootstrapAdminProperties{
+    pub username: String,
+    pub display_name: String,
+    pub password: SecretString,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct PasswordHashingProperties{
+    // Argon2id cost parameters, memory is in KiB
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl PasswordHashingProperties {
+    pub fn params(&self) -> Result<argon2::Params, argon2::Error> {
+        argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DeliveryProperties{
+    // Attempts per recipient before the task is marked as failed
+    pub max_attempts: u32,
+    // The wait after the first failure, doubled after every further one
+    pub base_backoff_milliseconds: u64,
+    pub max_backoff_milliseconds: u64,
+    // How long the worker sleeps when the queue is empty
+    pub poll_interval_milliseconds: u64,
+    // How long a claimed task stays invisible to the other workers
+    pub lease_seconds: u64,
+    // Tasks claimed, and sent, at once; the provider splits them further if it has to
+    pub batch_size: u32,
+}
+
+impl DeliveryProperties {
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.poll_interval_milliseconds)
+    }
+
+    pub fn lease(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.lease_seconds as i64)
+    }
+
+    /// Exponential backoff after the `attempts`-th failed attempt, capped at `max_backoff_milliseconds`.
+    pub fn backoff(&self, attempts: u32) -> chrono::Duration {
+        let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+        let milliseconds = self.base_backoff_milliseconds
+            .saturating_mul(factor)
+            .min(self.max_backoff_milliseconds);
+        chrono::Duration::milliseconds(milliseconds as i64)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SchedulerProperties{
+    // How often scheduled issues are checked for being due
+    pub poll_interval_milliseconds: u64,
+}
+
+impl SchedulerProperties {
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.poll_interval_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ContentLimitsProperties{
+    // Minimums count characters, maximums count UTF-8 bytes
+    pub min_title_characters: usize,
+    pub max_title_bytes: usize,
+    // For `content.html`, `content.text` and `content.markdown`, and what is rendered from the markdown
+    pub min_body_characters: usize,
+    pub max_body_bytes: usize,
+}
+
+/// RFC 5322 caps a header line, the subject included, at 998 bytes.
+pub const MAX_HEADER_LINE_BYTES: usize = 998;
+
+#[derive(serde::Deserialize, Clone)]
+pub struct TemplateProperties{
+    // Files replacing the built-in confirmation email in `templates/`
+    pub confirmation_email_html: Option<String>,
+    pub confirmation_email_text: Option<String>,
+    // The page around a newsletter written in markdown, instead of `templates/newsletter_layout.html`
+    pub newsletter_layout_html: Option<String>,
+    // Copy the rules of `<style>` blocks onto the elements they match, most email clients drop the blocks
+    #[serde(default = "default_inline_css")]
+    pub inline_css: bool,
+}
+
+fn default_inline_css() -> bool {
+    true
+}
+
+/// What a configuration without a `templates` section gets: the built-in templates, with the CSS inlined.
+impl Default for TemplateProperties {
+    fn default() -> Self {
+        Self {
+            confirmation_email_html: None,
+            confirmation_email_text: None,
+            newsletter_layout_html: None,
+            inline_css: default_inline_css(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct IdempotencyProperties{
+    // How long the response to an `Idempotency-Key` is kept for replays
+    pub expiry_minutes: u64,
+    // How long a repeated request waits for the first one to finish before a 409
+    pub in_flight_wait_milliseconds: u64,
+}
+
+impl IdempotencyProperties {
+    pub fn expiry(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.expiry_minutes as i64)
+    }
+
+    pub fn in_flight_wait(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.in_flight_wait_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SessionProperties{
+    // Where the sessions of the admin pages are kept
+    pub store: SessionStoreKind,
+    // Key used to sign the session cookie, only `local.yaml` has one:
+    // set it with `APP_SESSION__SECRET` in production
+    pub secret: SecretString,
+    // A session nobody used for this long is logged out
+    pub idle_timeout_minutes: i64,
+    // Only send the session cookie over HTTPS
+    pub secure_cookie: bool,
+}
+
+impl SessionProperties {
+    pub fn idle_timeout(&self) -> actix_web::cookie::time::Duration {
+        actix_web::cookie::time::Duration::minutes(self.idle_timeout_minutes)
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStoreKind{
+    // Lost on restart, and not shared between instances
+    InMemory,
+    // The storage backend, whichever it is
+    Storage,
 }
 
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientProperties{
-    pub base_url: String,
+    pub provider: EmailProviderKind,
     pub sender: String,
+    // Only used by the `mailjet` provider
+    pub base_url: String,
+    // Mailjet API credentials, keep them out of the files and set
+    // `APP_EMAIL_CLIENT__API_KEY` / `APP_EMAIL_CLIENT__API_SECRET` instead
+    pub api_key: Option<SecretString>,
+    pub api_secret: Option<SecretString>,
+    // Only used by the `smtp` provider
+    pub smtp: SmtpProperties,
+    // Only used by the `file_outbox` provider, where the `.eml` files are written
+    pub outbox_dir: String,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailProviderKind{
+    Mailjet,
+    Smtp,
+    FileOutbox,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpProperties{
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    // Leave both unset for relays that do not authenticate
+    pub username: Option<String>,
+    pub password: Option<SecretString>,
+    pub auth_mechanism: SmtpAuthMechanism,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls{
+    // Plain text, only for local stand-ins like MailHog
+    None,
+    Starttls,
+    // TLS from the first byte (usually port 465)
+    Implicit,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpAuthMechanism{
+    Plain,
+    Login,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct StorageProperties{
+    pub backend: StorageBackend,
+    // Only used by the `sqlite` backend
+    pub sqlite_path: String,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend{
+    InMemory,
+    Sqlite,
+    Postgres,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DatabaseProperties{
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    // Only `local.yaml` has one: set it with `APP_DATABASE__PASSWORD` in production
+    pub password: Option<SecretString>,
+    pub database_name: String,
+    // Size of the connection pool
+    pub max_connections: u32,
+    pub require_ssl: bool,
+}
+
+impl Properties {
+    /// Check the values serde cannot: URLs, addresses, ports and durations.
+    /// Only the settings of the selected email provider and storage backend are checked.
+    pub fn validate(&self) -> Result<(), ConfigurationError> {
+        let mut problems = Vec::new();
+
+        if !is_valid_host(&self.server.host) {
+            problems.push(format!("server.host `{}` is not an IP address or a host name", self.server.host));
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must be between 1 and 65535".to_string());
+        }
+        check_url(&mut problems, "server.base_url", &self.server.base_url);
+
+        let email = &self.email_client;
+        if !email.sender.validate_email() {
+            problems.push(format!("email_client.sender `{}` is not a valid email address", email.sender));
+        }
+        match email.provider {
+            EmailProviderKind::Mailjet => {
+                check_url(&mut problems, "email_client.base_url", &email.base_url);
+                for (name, value) in [("email_client.api_key", &email.api_key), ("email_client.api_secret", &email.api_secret)] {
+                    if value.as_ref().is_none_or(|value| value.expose_secret().is_empty()) {
+                        problems.push(format!("{} is required by the mailjet provider", name));
+                    }
+                }
+            }
+            EmailProviderKind::Smtp => {
+                if !is_valid_host(&email.smtp.host) {
+                    problems.push(format!("email_client.smtp.host `{}` is not an IP address or a host name", email.smtp.host));
+                }
+                if email.smtp.port == 0 {
+                    problems.push("email_client.smtp.port must be between 1 and 65535".to_string());
+                }
+                if email.smtp.username.is_some() != email.smtp.password.is_some() {
+                    problems.push("email_client.smtp.username and email_client.smtp.password must be set together".to_string());
+                }
+            }
+            EmailProviderKind::FileOutbox => {
+                if email.outbox_dir.trim().is_empty() {
+                    problems.push("email_client.outbox_dir must not be empty".to_string());
+                }
+            }
+        }
+
+        match self.storage.backend {
+            StorageBackend::InMemory => {}
+            StorageBackend::Sqlite => {
+                if self.storage.sqlite_path.trim().is_empty() {
+                    problems.push("storage.sqlite_path must not be empty".to_string());
+                }
+            }
+            StorageBackend::Postgres => {
+                let database = &self.database;
+                if !is_valid_host(&database.host) {
+                    problems.push(format!("database.host `{}` is not an IP address or a host name", database.host));
+                }
+                if database.port == 0 {
+                    problems.push("database.port must be between 1 and 65535".to_string());
+                }
+                if database.password.as_ref().is_none_or(|password| password.expose_secret().is_empty()) {
+                    problems.push("database.password is required by the postgres backend".to_string());
+                }
+                if database.database_name.trim().is_empty() {
+                    problems.push("database.database_name must not be empty".to_string());
+                }
+                if database.max_connections == 0 {
+                    problems.push("database.max_connections must be positive".to_string());
+                }
+            }
+        }
+
+        if self.auth.confirmation_token_expiry_minutes <= 0 {
+            problems.push("auth.confirmation_token_expiry_minutes must be positive".to_string());
+        }
+        if self.auth.unsubscribe_secret.expose_secret().len() < 32 {
+            problems.push("auth.unsubscribe_secret must be at least 32 bytes long".to_string());
+        }
+        if self.session.secret.expose_secret().len() < 64 {
+            problems.push("session.secret must be at least 64 bytes long".to_string());
+        }
+        if self.session.idle_timeout_minutes <= 0 {
+            problems.push("session.idle_timeout_minutes must be positive".to_string());
+        }
+        if let Some(admin) = &self.auth.bootstrap_admin {
+            if admin.username.trim().is_empty() {
+                problems.push("auth.bootstrap_admin.username must not be empty".to_string());
+            }
+            if admin.password.expose_secret().is_empty() {
+                problems.push("auth.bootstrap_admin.password must not be empty".to_string());
+            }
+        }
+        if self.auth.two_factor.issuer.trim().is_empty() || self.auth.two_factor.issuer.contains(':') {
+            problems.push("auth.two_factor.issuer must not be empty nor contain `:`".to_string());
+        }
+        if let Err(e) = self.auth.password_hashing.params() {
+            problems.push(format!("auth.password_hashing is not a valid set of Argon2 parameters: {}", e));
+        }
+
+        let delivery = &self.delivery;
+        for (name, value) in [
+            ("delivery.max_attempts", delivery.max_attempts as u64),
+            ("delivery.base_backoff_milliseconds", delivery.base_backoff_milliseconds),
+            ("delivery.poll_interval_milliseconds", delivery.poll_interval_milliseconds),
+            ("delivery.lease_seconds", delivery.lease_seconds),
+            ("delivery.batch_size", delivery.batch_size as u64),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be positive", name));
+            }
+        }
+        if delivery.max_backoff_milliseconds < delivery.base_backoff_milliseconds {
+            problems.push("delivery.max_backoff_milliseconds must not be less than delivery.base_backoff_milliseconds".to_string());
+        }
+
+        if self.idempotency.expiry_minutes == 0 {
+            problems.push("idempotency.expiry_minutes must be positive".to_string());
+        }
+        if self.scheduler.poll_interval_milliseconds == 0 {
+            problems.push("scheduler.poll_interval_milliseconds must be positive".to_string());
+        }
+        let limits = &self.content_limits;
+        if limits.max_title_bytes == 0 || limits.max_title_bytes > MAX_HEADER_LINE_BYTES {
+            problems.push(format!("content_limits.max_title_bytes must be between 1 and {}", MAX_HEADER_LINE_BYTES));
+        }
+        if limits.max_body_bytes == 0 {
+            problems.push("content_limits.max_body_bytes must be positive".to_string());
+        }
+        // A character takes at least one byte
+        if limits.min_title_characters > limits.max_title_bytes {
+            problems.push("content_limits.min_title_characters must not be more than content_limits.max_title_bytes".to_string());
+        }
+        if limits.min_body_characters > limits.max_body_bytes {
+            problems.push("content_limits.min_body_characters must not be more than content_limits.max_body_bytes".to_string());
+        }
+        for (name, outcome) in [
+            ("the confirmation email", ConfirmationTemplate::load(&self.templates).map(|_| ())),
+            ("the newsletter layout", NewsletterLayout::load(&self.templates).map(|_| ())),
+        ] {
+            match outcome {
+                Ok(()) => {}
+                Err(TemplateError::Read(path, e)) => problems.push(format!("templates: cannot read `{}`: {}", path, e)),
+                Err(e) => problems.push(format!("templates: {} is not a valid template: {}", name, e)),
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigurationError::Invalid(problems))
+        }
+    }
 }
 
+fn is_valid_host(host: &str) -> bool {
+    host.parse::<std::net::IpAddr>().is_ok()
+        || (!host.is_empty()
+            && host.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+            }))
+}
+
+fn check_url(problems: &mut Vec<String>, name: &str, value: &str) {
+    match reqwest::Url::parse(value) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+        Ok(_) => problems.push(format!("{} `{}` must be an http(s) URL", name, value)),
+        Err(e) => problems.push(format!("{} `{}` is not a valid URL: {}", name, value, e)),
+    }
+}
 
-pub fn get_configuration() -> Result<Properties, config::ConfigError> {
+impl DatabaseProperties {
+    /// Options for connecting to the server itself, used to create fresh
+    /// logical databases.
+    pub fn without_db(&self) -> PgConnectOptions {
+        let ssl_mode = if self.require_ssl {
+            PgSslMode::Require
+        } else {
+            // Try an encrypted connection, fallback to unencrypted if it fails
+            PgSslMode::Prefer
+        };
+        let options = PgConnectOptions::new()
+            .host(&self.host)
+            .port(self.port)
+            .username(&self.username)
+            .ssl_mode(ssl_mode);
+        match &self.password {
+            Some(password) => options.password(password.expose_secret()),
+            None => options,
+        }
+    }
 
-    // Initialise our configuration reader
-    let properties = config::Config::builder()
-    // Add configuration values from a file named `configuration.yaml`.
-    .add_source(
-        config::File::new("src/configuration.yaml", config::FileFormat::Yaml)
-    )
-    .build()?;
+    pub fn with_db(&self) -> PgConnectOptions {
+        self.without_db().database(&self.database_name)
+    }
+}
+
+/// The possible runtime environment for our application.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Environment {
+    Local,
+    Production,
+}
+
+impl Environment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Local => "local",
+            Environment::Production => "production",
+        }
+    }
+}
+
+impl TryFrom<String> for Environment {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "local" => Ok(Self::Local),
+            "production" => Ok(Self::Production),
+            other => Err(format!(
+                "{} is not a supported environment. Use either `local` or `production`.",
+                other
+            )),
+        }
+    }
+}
+
+/// The `configuration` directory: the one in the working directory if there is one,
+/// otherwise the one next to `Cargo.toml`, so `cargo run`/`cargo test` work from anywhere.
+fn configuration_directory() -> PathBuf {
+    let local = std::env::current_dir()
+        .map(|dir| dir.join("configuration"))
+        .unwrap_or_else(|_| PathBuf::from("configuration"));
+    if local.is_dir() {
+        local
+    } else {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("configuration")
+    }
+}
+
+pub fn get_configuration() -> Result<Properties, ConfigurationError> {
+    get_configuration_with(None)
+}
+
+/// Layers, from lowest to highest priority:
+/// `base.yaml`, `{APP_ENVIRONMENT}.yaml` (defaults to `local`),
+/// the optional `config_file` (the `--config` command line option)
+/// and finally `APP_`-prefixed environment variables.
+/// The result is validated as a whole before it is handed back.
+pub fn get_configuration_with(config_file: Option<&Path>) -> Result<Properties, ConfigurationError> {
+    let configuration_directory = configuration_directory();
+    // Detect the running environment.
+    // Default to `local` if unspecified.
+    let environment: Environment = std::env::var("APP_ENVIRONMENT")
+        .unwrap_or_else(|_| "local".into())
+        .try_into()
+        .map_err(config::ConfigError::Message)?;
+    let environment_filename = format!("{}.yaml", environment.as_str());
+
+    let mut builder = config::Config::builder()
+        .add_source(config::File::from(configuration_directory.join("base.yaml")))
+        .add_source(config::File::from(configuration_directory.join(environment_filename)));
+    if let Some(config_file) = config_file {
+        builder = builder.add_source(config::File::from(config_file));
+    }
+    let properties = builder
+        // Add in settings from environment variables (with a prefix of APP and '__' as separator)
+        // E.g. `APP_SERVER__PORT=5001` would set `Properties.server.port`
+        .add_source(
+            config::Environment::with_prefix("APP")
+                .prefix_separator("_")
+                .separator("__")
+        )
+        .build()?;
     // Try to convert the configuration values it read into
-    // our Settings type
-    properties.try_deserialize::<Properties>()
-}
\ No newline at end of file
+    // our Properties type
+    let properties = properties.try_deserialize::<Properties>()?;
+    properties.validate()?;
+    Ok(properties)
+}
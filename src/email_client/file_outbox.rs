@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+
+use super::{build_message, Email, EmailError, EmailProvider};
+
+/// Development provider: every email is written as an RFC 5322 `.eml` file
+/// into a directory instead of being sent.
+#[derive(Debug)]
+pub struct FileOutboxProvider{
+    directory: PathBuf,
+}
+
+impl FileOutboxProvider {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self { directory: directory.into() }
+    }
+}
+
+#[async_trait]
+impl EmailProvider for FileOutboxProvider {
+    async fn send_email(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let message = build_message(email)?;
+        tokio::fs::create_dir_all(&self.directory).await?;
+        // Timestamp first so the files sort in sending order
+        let file_name = format!("{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+            uuid::Uuid::new_v4());
+        let path = self.directory.join(file_name);
+        tokio::fs::write(&path, message.formatted()).await?;
+        tracing::info!("Email written to {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use claims::assert_ok;
+
+    use crate::email_client::{EmailClient, FileOutboxProvider};
+
+    #[tokio::test]
+    async fn send_email_writes_an_eml_file_to_the_outbox() {
+        // Arrange
+        let directory = std::env::temp_dir().join(format!("zero2prod-outbox-{}", uuid::Uuid::new_v4()));
+        let email_client = EmailClient::new("sender@example.com".to_string(), Box::new(FileOutboxProvider::new(&directory)));
+        let headers = HashMap::from([("List-Unsubscribe-Post", "List-Unsubscribe=One-Click")]);
+
+        // Act
+        let outcome = email_client
+            .send_email("reader@example.com", "Hello", "<p>html body</p>", "text body", headers)
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let files: Vec<_> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let eml = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(eml.contains("From: \"Newsletter Admin\" <sender@example.com>"));
+        assert!(eml.contains("To: reader@example.com"));
+        assert!(eml.contains("Subject: Hello"));
+        assert!(eml.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+        assert!(eml.contains("multipart/alternative"));
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
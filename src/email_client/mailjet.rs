@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use reqwest::{Client, Url};
+use secrecy::{ExposeSecret, SecretString};
+
+use super::{Email, EmailError, EmailProvider, FROM_NAME};
+
+/// Mailjet refuses a call with more messages than this.
+const MAX_MESSAGES_PER_CALL: usize = 50;
+
+#[derive(Serialize)]
+struct MailjetRequest<'a> {
+    #[serde(rename = "FromEmail")]
+    pub from_email: &'a str,
+    #[serde(rename = "FromName")]
+    pub from_name: &'a str,
+    #[serde(rename = "Subject")]
+    pub subject: &'a str,
+    #[serde(rename = "Text-part")]
+    pub text_part: &'a str,
+    #[serde(rename = "Html-part")]
+    pub html_part: &'a str,
+    #[serde(rename = "Recipients")]
+    pub recipients: [Recipient<'a>; 1],
+    #[serde(rename = "Headers", skip_serializing_if = "HashMap::is_empty")]
+    pub headers: &'a HashMap<&'a str, &'a str>,
+}
+
+impl<'a> From<&'a Email<'a>> for MailjetRequest<'a> {
+    fn from(email: &'a Email<'a>) -> Self {
+        Self {
+            from_email: email.from,
+            from_name: FROM_NAME,
+            subject: email.subject,
+            text_part: email.text_content,
+            html_part: email.html_content,
+            recipients: [Recipient { email: email.recipient }],
+            headers: email.headers,
+        }
+    }
+}
+
+/// Several messages in a single call, each with its own recipient and headers.
+#[derive(Serialize)]
+struct MailjetBatchRequest<'a> {
+    #[serde(rename = "Messages")]
+    pub messages: Vec<MailjetRequest<'a>>,
+}
+
+#[derive(Serialize)]
+struct Recipient<'a> {
+    email: &'a str,
+}
+
+/// Delivers through Mailjet's `v3/send` JSON API.
+/// The API credentials are `SecretString`s, so `Debug` only ever shows them redacted.
+#[derive(Debug)]
+pub struct MailjetProvider{
+    http_client: Client,
+    base_url: reqwest::Url,
+    api_key: SecretString,
+    api_secret: SecretString,
+}
+
+impl MailjetProvider {
+    /// Fails if either credential is missing or empty, Mailjet rejects every call without them,
+    /// or if `base_url` is not a URL.
+    pub fn new(
+        base_url: String,
+        api_key: Option<SecretString>,
+        api_secret: Option<SecretString>,
+    ) -> Result<Self, EmailError> {
+        let api_key = api_key
+            .filter(|key| !key.expose_secret().is_empty())
+            .ok_or(EmailError::MissingCredentials("email_client.api_key"))?;
+        let api_secret = api_secret
+            .filter(|secret| !secret.expose_secret().is_empty())
+            .ok_or(EmailError::MissingCredentials("email_client.api_secret"))?;
+        let base_url = Url::parse(&base_url)
+            .map_err(|_| EmailError::InvalidBaseUrl(base_url))?;
+        let http_client = Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .unwrap();
+        Ok(Self {
+            http_client,
+            base_url,
+            api_key,
+            api_secret,
+        })
+    }
+}
+
+impl MailjetProvider {
+    async fn post<T: Serialize>(&self, request: &T) -> Result<(), EmailError> {
+        let url = format!("{}v3/send", self.base_url);
+        self.http_client
+            .post(&url)
+            .basic_auth(self.api_key.expose_secret(), Some(self.api_secret.expose_secret()))
+            .header("Content-Type", "application/json")
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EmailProvider for MailjetProvider {
+    async fn send_email(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        self.post(&MailjetRequest::from(email)).await
+    }
+
+    /// Up to `MAX_MESSAGES_PER_CALL` messages per call. Mailjet accepts or rejects a call
+    /// as a whole, so the messages of a call it rejected (`400`) are sent one by one to find
+    /// out which recipients actually failed. After a timeout or a `5xx` the call may have gone
+    /// through anyway, and resending would only add to an outage: every message fails instead,
+    /// to be retried later.
+    async fn send_emails(&self, emails: &[Email<'_>]) -> Vec<Result<(), EmailError>> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for chunk in emails.chunks(MAX_MESSAGES_PER_CALL) {
+            if let [email] = chunk {
+                outcomes.push(self.send_email(email).await);
+                continue;
+            }
+            let request = MailjetBatchRequest {
+                messages: chunk.iter().map(MailjetRequest::from).collect(),
+            };
+            match self.post(&request).await {
+                Ok(()) => outcomes.extend(chunk.iter().map(|_| Ok(()))),
+                Err(e) if e.is_bounce() => {
+                    tracing::warn!("Mailjet rejected a batch of {} messages, sending them one by one: {}", chunk.len(), e);
+                    for email in chunk {
+                        outcomes.push(self.send_email(email).await);
+                    }
+                }
+                Err(e) => {
+                    let error = match std::error::Error::source(&e) {
+                        Some(source) => format!("{}: {}", e, source),
+                        None => e.to_string(),
+                    };
+                    tracing::warn!("A batch of {} messages failed: {}", chunk.len(), error);
+                    outcomes.extend(chunk.iter().map(|_| Err(EmailError::BatchFailed(error.clone()))));
+                }
+            }
+        }
+        outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::email_client::{EmailClient, MailjetProvider, OutgoingEmail};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::Fake;
+    use secrecy::SecretString;
+    use wiremock::matchers::{any, basic_auth, body_string_contains, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use claims::{assert_err, assert_ok};
+
+    /// Generate a random email subject
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+    /// Generate a random email content
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+    /// Generate a random subscriber email
+    fn email() -> String {
+        SafeEmail().fake()
+    }
+    /// A message of a batch, without extra headers.
+    fn message<'a>(recipient: &'a str, subject: &'a str, content: &'a str) -> OutgoingEmail<'a> {
+        OutgoingEmail { recipient, subject, html_content: content, text_content: content, headers: HashMap::new() }
+    }
+    /// Get a test instance of `EmailClient`.
+    fn email_client(base_url: String) -> EmailClient {
+        let provider = MailjetProvider::new(base_url, Some(SecretString::from("api-key")), Some(SecretString::from("api-secret")))
+            .unwrap();
+        EmailClient::new(email(), Box::new(provider))
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request_to_base_url() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .and(header("Content-Type", "application/json"))
+            .and(basic_auth("api-key", "api-secret"))
+            .and(path("/v3/send"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let _ = email_client(mock_server.uri())
+        .send_email(&email(), &subject(), &content(), &content(), HashMap::new())
+        .await;
+
+        //Assert
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        
+        Mock::given(any())
+            // Not a 200 anymore!
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(&email(), &subject(), &content(), &content(), HashMap::new())
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        
+        let response = ResponseTemplate::new(200)
+            // 3 minutes!
+            .set_delay(std::time::Duration::from_secs(10));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(&email(), &subject(), &content(), &content(), HashMap::new())
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[test]
+    fn mailjet_provider_requires_both_credentials() {
+        let key = || Some(SecretString::from("api-key"));
+        let secret = || Some(SecretString::from("api-secret"));
+        assert_err!(MailjetProvider::new("http://127.0.0.1".to_string(), None, secret()));
+        assert_err!(MailjetProvider::new("http://127.0.0.1".to_string(), key(), None));
+        assert_err!(MailjetProvider::new("http://127.0.0.1".to_string(), Some(SecretString::from("")), secret()));
+        assert_ok!(MailjetProvider::new("http://127.0.0.1".to_string(), key(), secret()));
+    }
+
+    #[test]
+    fn mailjet_provider_rejects_an_invalid_base_url() {
+        let outcome = MailjetProvider::new("not a url".to_string(), Some(SecretString::from("api-key")), Some(SecretString::from("api-secret")));
+        assert_err!(outcome);
+    }
+
+    #[test]
+    fn debug_output_does_not_leak_the_credentials() {
+        let debug_output = format!("{:?}", email_client("http://127.0.0.1".to_string()));
+        assert!(!debug_output.contains("api-key"));
+        assert!(!debug_output.contains("api-secret"));
+    }
+
+    #[tokio::test]
+    async fn send_emails_respects_the_per_call_message_limit() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v3/send"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+        let addresses: Vec<String> = (0..120).map(|i| format!("reader{}@example.com", i)).collect();
+        let (subject, content) = (subject(), content());
+        let messages: Vec<OutgoingEmail> = addresses.iter()
+            .map(|email| message(email, &subject, &content))
+            .collect();
+
+        // Act
+        let outcomes = email_client(mock_server.uri())
+            .send_emails(&messages)
+            .await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 120);
+        assert!(outcomes.iter().all(|outcome| outcome.is_ok()));
+        let requests = mock_server.received_requests().await.unwrap();
+        let batch_sizes: Vec<usize> = requests.iter().map(|request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let messages = body["Messages"].as_array().unwrap();
+            // Nobody sees who else got the newsletter
+            assert!(messages.iter().all(|message| message["Recipients"].as_array().unwrap().len() == 1));
+            messages.len()
+        }).collect();
+        assert_eq!(batch_sizes, vec![50, 50, 20]);
+    }
+
+    #[tokio::test]
+    async fn a_bad_address_only_fails_its_own_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(body_string_contains("bad@example.com"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let (subject, content) = (subject(), content());
+        let messages: Vec<OutgoingEmail> = ["good@example.com", "bad@example.com", "other@example.com"].into_iter()
+            .map(|email| message(email, &subject, &content))
+            .collect();
+
+        // Act
+        let outcomes = email_client(mock_server.uri())
+            .send_emails(&messages)
+            .await;
+
+        // Assert
+        assert_ok!(&outcomes[0]);
+        assert_err!(&outcomes[1]);
+        assert_ok!(&outcomes[2]);
+    }
+
+    #[tokio::test]
+    async fn a_batch_that_failed_on_the_server_is_not_resent_message_by_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let (subject, content) = (subject(), content());
+        let messages: Vec<OutgoingEmail> = ["one@example.com", "two@example.com", "three@example.com"].into_iter()
+            .map(|email| message(email, &subject, &content))
+            .collect();
+
+        // Act
+        let outcomes = email_client(mock_server.uri())
+            .send_emails(&messages)
+            .await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes.iter().all(|outcome| outcome.as_ref().is_err_and(|e| !e.is_bounce())));
+        // Mock verifies on Drop that there was a single call
+    }
+}
@@ -0,0 +1,171 @@
+mod file_outbox;
+mod mailjet;
+mod smtp;
+pub use file_outbox::*;
+pub use mailjet::*;
+pub use smtp::*;
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use lettre::message::{header::{HeaderName, HeaderValue}, Mailbox, MultiPart};
+use lettre::Message;
+
+/// The display name our emails are sent with.
+const FROM_NAME: &str = "Newsletter Admin";
+
+#[derive(thiserror::Error, Debug)]
+pub enum EmailError {
+    #[error("The Mailjet API request failed")]
+    Mailjet(#[from] reqwest::Error),
+    #[error("Invalid email provider base url: `{0}`")]
+    InvalidBaseUrl(String),
+    #[error("Missing email provider credentials: `{0}` is not configured")]
+    MissingCredentials(&'static str),
+    #[error("SMTP delivery failed")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Invalid email address")]
+    InvalidAddress(#[from] lettre::address::AddressError),
+    #[error("Invalid email header name: {0}")]
+    InvalidHeader(String),
+    #[error("Failed to build the email message")]
+    InvalidMessage(#[from] lettre::error::Error),
+    #[error("Failed to write the email to the outbox")]
+    Outbox(#[from] std::io::Error),
+    /// Every message of a call that failed as a whole gets its own copy of the error.
+    #[error("The batch the message was sent in failed: {0}")]
+    BatchFailed(String),
+}
+
+impl EmailError {
+    /// The recipient will never be accepted, trying again is pointless.
+    pub fn is_bounce(&self) -> bool {
+        match self {
+            EmailError::InvalidAddress(_) => true,
+            EmailError::Mailjet(e) => e.status() == Some(reqwest::StatusCode::BAD_REQUEST),
+            EmailError::Smtp(e) => e.is_permanent(),
+            _ => false,
+        }
+    }
+}
+
+/// A single outgoing message, independent of the provider delivering it.
+/// It always has exactly one recipient, so nobody ever sees who else got it.
+pub struct Email<'a> {
+    pub from: &'a str,
+    pub recipient: &'a str,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub headers: &'a HashMap<&'a str, &'a str>,
+}
+
+/// One message of a batch, with the content and headers personalised for its recipient.
+pub struct OutgoingEmail<'a> {
+    pub recipient: &'a str,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub headers: HashMap<&'a str, &'a str>,
+}
+
+/// Something that can deliver an `Email`: an HTTP API, an SMTP relay, a directory...
+#[async_trait]
+pub trait EmailProvider: std::fmt::Debug + Send + Sync {
+    async fn send_email(&self, email: &Email<'_>) -> Result<(), EmailError>;
+
+    /// Send several messages, one outcome per message in the same order.
+    /// Providers with a native batch API override this, the default sends them one by one.
+    async fn send_emails(&self, emails: &[Email<'_>]) -> Vec<Result<(), EmailError>> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for email in emails {
+            outcomes.push(self.send_email(email).await);
+        }
+        outcomes
+    }
+}
+
+/// `sender` is checked when the configuration is validated, see `Properties::validate`.
+#[derive(Debug)]
+pub struct EmailClient{
+    provider: Box<dyn EmailProvider>,
+    sender: String
+}
+
+impl EmailClient {
+    pub fn new(sender: String, provider: Box<dyn EmailProvider>) -> Self {
+        Self {
+            provider,
+            sender
+        }
+    }
+
+    /// `headers` are extra message headers, e.g. `List-Unsubscribe` for newsletters.
+    #[tracing::instrument(
+        name = "Sending an email",
+        skip(self, recipient, subject, html_content, text_content, headers),
+        fields(
+        %recipient,
+        )
+    )]
+    pub async fn send_email(
+        &self,
+        recipient: &str,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: HashMap<&str, &str>,
+    ) -> Result<(), EmailError> {
+        let email = Email {
+            from: &self.sender,
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            headers: &headers,
+        };
+        self.provider.send_email(&email).await
+    }
+
+    /// One message per recipient, each with its own content.
+    /// Returns one outcome per message, in order, so a bad address only fails itself.
+    #[tracing::instrument(
+        name = "Sending a batch of emails",
+        skip(self, messages),
+        fields(
+        messages = messages.len(),
+        )
+    )]
+    pub async fn send_emails(
+        &self,
+        messages: &[OutgoingEmail<'_>],
+    ) -> Vec<Result<(), EmailError>> {
+        let emails: Vec<Email<'_>> = messages.iter().map(|message| Email {
+            from: &self.sender,
+            recipient: message.recipient,
+            subject: message.subject,
+            html_content: message.html_content,
+            text_content: message.text_content,
+            headers: &message.headers,
+        }).collect();
+        self.provider.send_emails(&emails).await
+    }
+}
+
+/// Render an `Email` as an RFC 5322 message with a text and an html alternative.
+pub(crate) fn build_message(email: &Email<'_>) -> Result<Message, EmailError> {
+    let mut builder = Message::builder()
+        .from(Mailbox::new(Some(FROM_NAME.to_string()), email.from.parse()?))
+        .subject(email.subject)
+        .to(Mailbox::new(None, email.recipient.parse()?));
+    for (name, value) in email.headers {
+        let name = HeaderName::new_from_ascii(name.to_string())
+            .map_err(|_| EmailError::InvalidHeader(name.to_string()))?;
+        builder = builder.raw_header(HeaderValue::new(name, value.to_string()));
+    }
+    let message = builder.multipart(MultiPart::alternative_plain_html(
+        email.text_content.to_string(),
+        email.html_content.to_string(),
+    ))?;
+    Ok(message)
+}
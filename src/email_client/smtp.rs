@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use secrecy::ExposeSecret;
+
+use crate::configuration::{SmtpAuthMechanism, SmtpProperties, SmtpTls};
+
+use super::{build_message, Email, EmailError, EmailProvider};
+
+/// Delivers through any SMTP relay, including local stand-ins like MailHog.
+#[derive(Debug)]
+pub struct SmtpProvider{
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpProvider {
+    pub fn new(properties: &SmtpProperties) -> Result<Self, EmailError> {
+        let mut builder = match properties.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&properties.host),
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&properties.host)?,
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&properties.host)?,
+        }
+        .port(properties.port)
+        .timeout(Some(std::time::Duration::from_secs(10)));
+        if let (Some(username), Some(password)) = (&properties.username, &properties.password) {
+            let mechanism = match properties.auth_mechanism {
+                SmtpAuthMechanism::Plain => Mechanism::Plain,
+                SmtpAuthMechanism::Login => Mechanism::Login,
+            };
+            builder = builder
+                .credentials(Credentials::new(username.clone(), password.expose_secret().to_string()))
+                .authentication(vec![mechanism]);
+        }
+        Ok(Self { transport: builder.build() })
+    }
+}
+
+#[async_trait]
+impl EmailProvider for SmtpProvider {
+    async fn send_email(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let message = build_message(email)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use claims::{assert_err, assert_ok};
+    use secrecy::SecretString;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use crate::configuration::{SmtpAuthMechanism, SmtpProperties, SmtpTls};
+    use crate::email_client::{EmailClient, SmtpProvider};
+
+    /// A bare-bones plain text SMTP server that accepts a single message
+    /// and hands back the whole conversation it had with the client.
+    async fn fake_smtp_server(reject_data: bool) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut transcript = String::new();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut in_data = false;
+            while let Ok(Some(line)) = lines.next_line().await {
+                transcript.push_str(&line);
+                transcript.push('\n');
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    if reject_data { b"554 Rejected\r\n" } else { b"250 Queued\r\n" }
+                } else if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 Authenticated\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            transcript
+        });
+        (port, handle)
+    }
+
+    fn email_client(port: u16) -> EmailClient {
+        let properties = SmtpProperties {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: SmtpTls::None,
+            username: Some("user".to_string()),
+            password: Some(SecretString::from("password")),
+            auth_mechanism: SmtpAuthMechanism::Plain,
+        };
+        EmailClient::new("sender@example.com".to_string(), Box::new(SmtpProvider::new(&properties).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn send_email_delivers_the_message_over_smtp() {
+        // Arrange
+        let (port, server) = fake_smtp_server(false).await;
+        let headers = HashMap::from([("List-Unsubscribe", "<http://127.0.0.1/unsubscribe>")]);
+
+        // Act
+        let outcome = email_client(port)
+            .send_email("reader@example.com", "Subject", "<p>html</p>", "text", headers)
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let transcript = server.await.unwrap();
+        assert!(transcript.contains("AUTH PLAIN"));
+        assert!(transcript.contains("RCPT TO:<reader@example.com>"));
+        assert!(transcript.contains("List-Unsubscribe: <http://127.0.0.1/unsubscribe>"));
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_rejects_the_message() {
+        // Arrange
+        let (port, _server) = fake_smtp_server(true).await;
+
+        // Act
+        let outcome = email_client(port)
+            .send_email("reader@example.com", "Subject", "<p>html</p>", "text", HashMap::new())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+}
@@ -0,0 +1,365 @@
+//! HTML written by hand goes through an allowlist before it reaches a subscriber,
+//! and the rules of `<style>` blocks can be copied onto the elements they match:
+//! a `style` attribute is the only styling most email clients keep.
+//! Template tags go through untouched, the result is still rendered per subscriber:
+//! `NewsletterTemplate` keeps what they print from undoing the sanitizing.
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{element, rewrite_str, text, ElementContentHandlers, RewriteStrSettings, Selector};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::markdown::ALLOWED_SCHEMES;
+use crate::templating::protect_template_tags;
+
+/// Not allowed, and what they contain goes with them. Anything else not allowed is unwrapped.
+const REMOVED_WITH_CONTENT: [&str; 3] = ["script", "style", "title"];
+/// The outline of a whole page, dropped without a warning: the newsletter is what is in the body.
+const DOCUMENT_TAGS: [&str; 4] = ["html", "head", "body", "meta"];
+/// What email layouts are still made of, on top of the tags and attributes ammonia allows.
+const EMAIL_TAGS: [&str; 2] = ["center", "font"];
+const EMAIL_ATTRIBUTES: [&str; 11] = [
+    "style", "class", "id", "align", "valign", "width", "height", "bgcolor", "border", "cellpadding", "cellspacing",
+];
+/// Declarations with any of these could load or run something (or hide that they do),
+/// they are dropped from `style` attributes.
+const UNSAFE_CSS: [&str; 7] = ["url(", "expression(", "javascript:", "behavior:", "-moz-binding", "\\", "/*"];
+/// Never displayed, a rule that matches them is not copied onto them.
+const NOT_RENDERED: [&str; 6] = ["head", "title", "meta", "link", "style", "script"];
+
+static SANITIZER: Lazy<ammonia::Builder<'static>> = Lazy::new(|| {
+    let mut builder = ammonia::Builder::default();
+    builder
+        .add_tags(EMAIL_TAGS)
+        .add_tag_attributes("font", ["color", "face", "size"])
+        .add_generic_attributes(EMAIL_ATTRIBUTES)
+        .clean_content_tags(REMOVED_WITH_CONTENT.into_iter().collect())
+        .url_schemes(ALLOWED_SCHEMES.into_iter().collect())
+        .attribute_filter(|_, attribute, value| match attribute {
+            "style" => Some(safe_style(value)),
+            _ => Some(Cow::Borrowed(value)),
+        });
+    builder
+});
+
+static CSS_COMMENT: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)/\*.*?\*/").unwrap());
+
+pub struct SanitizedHtml {
+    pub html: String,
+    /// What was taken out, one line per kind of thing, for the editor to check.
+    pub warnings: Vec<String>,
+}
+
+/// Tags, attributes and link schemes outside the allowlist are removed, the content of
+/// an unknown tag is kept. Scripts, event handlers and `javascript:` links never get through.
+pub fn sanitize(html: &str) -> SanitizedHtml {
+    let (html, tags) = protect_template_tags(html);
+    let warnings = removals(&html);
+    let cleaned = SANITIZER.clean(&html).to_string();
+    SanitizedHtml { html: tags.restore(cleaned), warnings }
+}
+
+/// What `SANITIZER` takes out of `html`, in words an editor can act on.
+fn removals(html: &str) -> Vec<String> {
+    let allowed_tags = SANITIZER.clone_tags();
+    let generic_attributes = SANITIZER.clone_generic_attributes();
+    let tag_attributes = SANITIZER.clone_tag_attributes();
+    let warnings = RefCell::new(Vec::new());
+    let warn = |warning: String| {
+        let mut warnings = warnings.borrow_mut();
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    };
+    let outcome = rewrite_str(html, RewriteStrSettings {
+        element_content_handlers: vec![element!("*", |element| {
+            let tag = element.tag_name().to_ascii_lowercase();
+            if DOCUMENT_TAGS.contains(&tag.as_str()) {
+                return Ok(());
+            }
+            if REMOVED_WITH_CONTENT.contains(&tag.as_str()) {
+                warn(format!("removed <{}> and its content", tag));
+                return Ok(());
+            }
+            if !allowed_tags.contains(tag.as_str()) {
+                warn(format!("removed <{}>, kept its content", tag));
+                return Ok(());
+            }
+            for attribute in element.attributes() {
+                let name = attribute.name();
+                let value = attribute.value();
+                let allowed = generic_attributes.contains(name.as_str())
+                    || tag_attributes.get(tag.as_str()).is_some_and(|attributes| attributes.contains(name.as_str()));
+                if name.starts_with("on") {
+                    warn(format!("removed the `{}` handler from <{}>", name, tag));
+                } else if !allowed {
+                    warn(format!("removed `{}` from <{}>", name, tag));
+                } else if let Some(scheme) = disallowed_scheme(&name, &value) {
+                    warn(format!("removed the `{}:` link from <{}>", scheme, tag));
+                } else if name == "style" && matches!(safe_style(&value), Cow::Owned(_)) {
+                    warn(format!("removed `url()`, `expression()` or the like from the style of <{}>", tag));
+                }
+            }
+            Ok(())
+        })],
+        strict: false,
+        ..RewriteStrSettings::new()
+    });
+    // Only the report is lost, the sanitizer does not depend on it
+    if let Err(e) = outcome {
+        tracing::warn!(error = %e, "Failed to list what the sanitizer removes");
+    }
+    warnings.into_inner()
+}
+
+fn disallowed_scheme(attribute: &str, url: &str) -> Option<String> {
+    if !matches!(attribute, "href" | "src") {
+        return None;
+    }
+    // Browsers ignore whitespace in a scheme, `java\tscript:` is still `javascript:`
+    let url: String = url.chars().filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control()).collect();
+    let (scheme, _) = url.split_once(':')?;
+    if scheme.contains(['/', '?', '#']) {
+        return None;
+    }
+    let scheme = scheme.to_ascii_lowercase();
+    (!ALLOWED_SCHEMES.contains(&scheme.as_str())).then_some(scheme)
+}
+
+fn safe_style(style: &str) -> Cow<'_, str> {
+    let declarations: Vec<&str> = style.split(';').filter(|declaration| !declaration.trim().is_empty()).collect();
+    let safe: Vec<&str> = declarations.iter().copied()
+        .filter(|declaration| {
+            let compact: String = declaration.chars().filter(|c| !c.is_whitespace()).collect();
+            let compact = compact.to_ascii_lowercase();
+            !UNSAFE_CSS.iter().any(|pattern| compact.contains(pattern))
+        })
+        .collect();
+    if safe.len() == declarations.len() {
+        Cow::Borrowed(style)
+    } else {
+        Cow::Owned(safe.join(";"))
+    }
+}
+
+/// A rule of a `<style>` block that can go in the `style` attribute of what it matches.
+struct Rule {
+    selector: Selector,
+    specificity: (usize, usize, usize),
+    declarations: String,
+}
+
+/// Every rule whose selector can be matched without a browser (no `:hover`, no `::before`)
+/// is copied onto the elements it matches, in order of specificity, with the element's own
+/// `style` last so it still wins. Blocks with a `media` attribute, at-rules and the rules that
+/// could not be copied stay in the first `<style>` block, the others are removed.
+pub fn inline_css(html: &str) -> String {
+    let (protected, tags) = protect_template_tags(html);
+    let stylesheet = RefCell::new(String::new());
+    let collected = rewrite_str(&protected, RewriteStrSettings {
+        element_content_handlers: vec![text!("style:not([media])", |chunk| {
+            stylesheet.borrow_mut().push_str(chunk.as_str());
+            Ok(())
+        })],
+        strict: false,
+        ..RewriteStrSettings::new()
+    });
+    if let Err(e) = collected {
+        tracing::warn!(error = %e, "Failed to read the style blocks, they were left as they are");
+        return html.to_string();
+    }
+    let (mut rules, leftover) = parse_stylesheet(&stylesheet.into_inner());
+    if rules.is_empty() {
+        return html.to_string();
+    }
+    // Stable, rules of the same specificity keep the order they were written in
+    rules.sort_by_key(|rule| rule.specificity);
+
+    // Handlers run in the order they are registered, for each element
+    let own_style = RefCell::new(None);
+    let mut handlers = vec![element!("*", |element| {
+        *own_style.borrow_mut() = element.get_attribute("style");
+        element.remove_attribute("style");
+        Ok(())
+    })];
+    for rule in &rules {
+        handlers.push((
+            Cow::Borrowed(&rule.selector),
+            ElementContentHandlers::default().element(|element: &mut Element| {
+                if !NOT_RENDERED.contains(&element.tag_name().as_str()) {
+                    append_style(element, &rule.declarations);
+                }
+                Ok(())
+            }),
+        ));
+    }
+    handlers.push(element!("*", |element| {
+        if let Some(style) = own_style.borrow_mut().take() {
+            append_style(element, &style);
+        }
+        Ok(())
+    }));
+    let leftover_kept = Cell::new(leftover.is_empty());
+    handlers.push(element!("style:not([media])", |element| {
+        if leftover_kept.replace(true) {
+            element.remove();
+        } else {
+            element.set_inner_content(&leftover, ContentType::Html);
+        }
+        Ok(())
+    }));
+
+    let inlined = rewrite_str(&protected, RewriteStrSettings {
+        element_content_handlers: handlers,
+        strict: false,
+        ..RewriteStrSettings::new()
+    });
+    match inlined {
+        Ok(inlined) => tags.restore(inlined),
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to inline the style blocks, they were left as they are");
+            html.to_string()
+        }
+    }
+}
+
+fn append_style(element: &mut Element, declarations: &str) {
+    let declarations = declarations.trim().trim_end_matches(';');
+    if declarations.is_empty() {
+        return;
+    }
+    let style = match element.get_attribute("style") {
+        Some(style) => format!("{}; {}", style, declarations),
+        None => declarations.to_string(),
+    };
+    element.set_attribute("style", &style).expect("`style` is a valid attribute name");
+}
+
+/// The rules that can be inlined, and the CSS that has to stay a stylesheet.
+fn parse_stylesheet(css: &str) -> (Vec<Rule>, String) {
+    let css = CSS_COMMENT.replace_all(css, "");
+    let mut rules = Vec::new();
+    let mut leftover = String::new();
+    let mut rest = css.trim();
+    while !rest.is_empty() {
+        let Some(open) = rest.find('{') else { break };
+        // `@import ...;` and the like have no block
+        if let Some(end) = rest.find(';').filter(|&end| rest.starts_with('@') && end < open) {
+            leftover.push_str(&rest[..=end]);
+            leftover.push('\n');
+            rest = rest[end + 1..].trim_start();
+            continue;
+        }
+        let Some(close) = matching_brace(rest, open) else {
+            leftover.push_str(rest);
+            break;
+        };
+        let prelude = rest[..open].trim();
+        let block = rest[open + 1..close].trim();
+        if prelude.starts_with('@') {
+            leftover.push_str(&rest[..=close]);
+            leftover.push('\n');
+        } else {
+            let declarations = block.split(';')
+                .map(str::trim)
+                .filter(|declaration| !declaration.is_empty())
+                .collect::<Vec<_>>()
+                .join("; ");
+            let mut kept = Vec::new();
+            for selector in prelude.split(',').map(str::trim) {
+                match selector.parse::<Selector>() {
+                    Ok(parsed) => rules.push(Rule {
+                        selector: parsed,
+                        specificity: specificity(selector),
+                        declarations: declarations.clone(),
+                    }),
+                    Err(_) => kept.push(selector),
+                }
+            }
+            if !kept.is_empty() {
+                leftover.push_str(&format!("{} {{ {} }}\n", kept.join(", "), block));
+            }
+        }
+        rest = rest[close + 1..].trim_start();
+    }
+    (rules, leftover)
+}
+
+fn matching_brace(css: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in css[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// (ids, classes and attributes, tags), close enough for the selectors newsletters use.
+fn specificity(selector: &str) -> (usize, usize, usize) {
+    let mut specificity = (0, 0, 0);
+    let compounds = selector.split(|c: char| c.is_whitespace() || matches!(c, '>' | '+' | '~'));
+    for compound in compounds.filter(|compound| !compound.is_empty()) {
+        specificity.0 += compound.matches('#').count();
+        specificity.1 += compound.matches(['.', '[', ':']).count();
+        if compound.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            specificity.2 += 1;
+        }
+    }
+    specificity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inline_css, sanitize};
+
+    #[test]
+    fn scripts_handlers_and_script_links_are_removed_with_a_warning() {
+        let sanitized = sanitize("<p onclick=\"steal()\">Hi</p><script>steal()</script><a href=\"javascript:steal()\">click</a><marquee>news</marquee>");
+
+        assert_eq!(sanitized.html, "<p>Hi</p><a rel=\"noopener noreferrer\">click</a>news");
+        assert_eq!(sanitized.warnings, vec![
+            "removed the `onclick` handler from <p>",
+            "removed <script> and its content",
+            "removed the `javascript:` link from <a>",
+            "removed <marquee>, kept its content",
+        ]);
+    }
+
+    #[test]
+    fn template_tags_and_email_markup_survive_sanitizing() {
+        let html = "<table width=\"600\"><tr><td style=\"color: red\">{% if fields.city %}Hi {{ username }}{% endif %}</td></tr></table><a href=\"{{ unsubscribe_url }}\">Unsubscribe</a>";
+
+        let sanitized = sanitize(html);
+
+        assert_eq!(sanitized.html, "<table width=\"600\"><tbody><tr><td style=\"color: red\">{% if fields.city %}Hi {{ username }}{% endif %}</td></tr></tbody></table><a href=\"{{ unsubscribe_url }}\" rel=\"noopener noreferrer\">Unsubscribe</a>");
+        assert!(sanitized.warnings.is_empty(), "{:?}", sanitized.warnings);
+    }
+
+    #[test]
+    fn styles_that_load_something_are_removed() {
+        let sanitized = sanitize("<p style=\"color: red; background: URL(https://tracker.example.com/pixel)\">Hi</p>");
+
+        assert_eq!(sanitized.html, "<p style=\"color: red\">Hi</p>");
+        assert_eq!(sanitized.warnings.len(), 1);
+    }
+
+    #[test]
+    fn style_rules_are_copied_onto_what_they_match() {
+        let html = "<style>p { color: red; } .big { font-size: 20px } p.note { color: blue } a:hover { color: green } @media (max-width: 600px) { p { color: black } }</style><p class=\"big note\" style=\"margin: 0\">Hi</p><p>{{ username }}</p>";
+
+        let inlined = inline_css(html);
+
+        assert_eq!(inlined, "<style>a:hover { color: green }\n@media (max-width: 600px) { p { color: black } }\n</style><p class=\"big note\" style=\"color: red; font-size: 20px; color: blue; margin: 0\">Hi</p><p style=\"color: red\">{{ username }}</p>");
+    }
+}
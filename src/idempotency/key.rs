@@ -0,0 +1,47 @@
+use super::IdempotencyError;
+
+const MAX_KEY_LENGTH: usize = 64;
+
+/// The client's `Idempotency-Key` header, e.g. a UUID.
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl TryFrom<String> for IdempotencyKey {
+    type Error = IdempotencyError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if s.is_empty() {
+            return Err(IdempotencyError::InvalidKey("it cannot be empty".to_string()));
+        }
+        if s.len() > MAX_KEY_LENGTH {
+            return Err(IdempotencyError::InvalidKey(format!("it must be at most {} characters long", MAX_KEY_LENGTH)));
+        }
+        Ok(Self(s))
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyKey;
+
+    #[test]
+    fn empty_key_is_rejected() {
+        assert!(IdempotencyKey::try_from(String::new()).is_err());
+    }
+
+    #[test]
+    fn too_long_key_is_rejected() {
+        assert!(IdempotencyKey::try_from("a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn uuid_key_is_accepted() {
+        assert!(IdempotencyKey::try_from(uuid::Uuid::new_v4().to_string()).is_ok());
+    }
+}
@@ -0,0 +1,20 @@
+mod key;
+mod persistence;
+pub use key::*;
+pub use persistence::*;
+
+use crate::storage::StorageError;
+
+#[derive(thiserror::Error, Debug)]
+pub enum IdempotencyError {
+    #[error("Invalid Idempotency-Key: {0}")]
+    InvalidKey(String),
+    #[error("The Idempotency-Key was already used for a different request")]
+    RequestMismatch,
+    #[error("A request with the same Idempotency-Key is still being processed")]
+    InFlight,
+    #[error("Failed to read the response to save it: {0}")]
+    ResponseBody(String),
+    #[error("Failed to access the idempotency store")]
+    StorageError(#[from] StorageError),
+}
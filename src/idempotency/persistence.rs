@@ -0,0 +1,204 @@
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+use crate::clock::Clock;
+use crate::configuration::IdempotencyProperties;
+use crate::storage::{IdempotencyRecord, SavedResponse, Storage};
+
+use super::{IdempotencyError, IdempotencyKey};
+
+/// How often a repeated request checks whether the first one has finished.
+const IN_FLIGHT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum NextAction {
+    StartProcessing,
+    ReturnSavedResponse(HttpResponse),
+}
+
+/// Reserve the key for this request, or find out what happened to the first request that used it.
+/// A repeat that arrives while the first request is still running waits for its response,
+/// up to `in_flight_wait`, then gives up with `IdempotencyError::InFlight`.
+pub async fn try_processing(
+    storage: &dyn Storage,
+    sender_id: i32,
+    key: &IdempotencyKey,
+    request_hash: &str,
+    properties: &IdempotencyProperties,
+    clock: &dyn Clock,
+) -> Result<NextAction, IdempotencyError> {
+    let deadline = tokio::time::Instant::now() + properties.in_flight_wait();
+    loop {
+        let now = clock.now();
+        let existing = storage
+            .reserve_idempotency_key(sender_id, key.as_ref(), request_hash, now, now - properties.expiry())
+            .await?;
+        match existing {
+            None => return Ok(NextAction::StartProcessing),
+            Some(IdempotencyRecord { request_hash: saved_hash, .. }) if saved_hash != request_hash => {
+                return Err(IdempotencyError::RequestMismatch);
+            }
+            Some(IdempotencyRecord { response: Some(response), .. }) => {
+                return Ok(NextAction::ReturnSavedResponse(replay(response)));
+            }
+            Some(IdempotencyRecord { response: None, .. }) => {
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(IdempotencyError::InFlight);
+                }
+                tokio::time::sleep(IN_FLIGHT_POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Persist the response for the repeats to come, and hand it back.
+/// By now the request has done its work, so the key stays reserved whatever happens here:
+/// released, a repeat would do it all again. Unsaved, repeats are turned away as `InFlight`
+/// until the key expires, while the client still gets its response.
+pub async fn save_response(
+    storage: &dyn Storage,
+    sender_id: i32,
+    key: &IdempotencyKey,
+    response: HttpResponse,
+) -> Result<HttpResponse, IdempotencyError> {
+    let (response_head, body) = response.into_parts();
+    let body = to_bytes(body).await
+        .map_err(|e| IdempotencyError::ResponseBody(e.to_string()))?;
+    let saved = SavedResponse {
+        status_code: response_head.status().as_u16(),
+        headers: response_head.headers().iter()
+            .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: body.to_vec(),
+    };
+    if let Err(e) = storage.save_idempotent_response(sender_id, key.as_ref(), &saved).await {
+        tracing::error!("Failed to save the response for Idempotency-Key `{}`, the key stays reserved: {:?}", key.as_ref(), e);
+    }
+    Ok(response_head.set_body(body).map_into_boxed_body())
+}
+
+fn replay(saved: SavedResponse) -> HttpResponse {
+    let status = StatusCode::from_u16(saved.status_code).unwrap_or(StatusCode::OK);
+    let mut response = HttpResponse::build(status);
+    for (name, value) in saved.headers {
+        response.append_header((name, value));
+    }
+    response.body(saved.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use actix_web::body::{to_bytes, BodySize, MessageBody};
+    use actix_web::web::Bytes;
+    use actix_web::HttpResponse;
+    use chrono::Utc;
+    use claims::{assert_err, assert_matches, assert_none};
+
+    use crate::clock::{AdjustableClock, SystemClock};
+    use crate::configuration::IdempotencyProperties;
+    use crate::idempotency::{IdempotencyError, IdempotencyKey};
+    use crate::in_memory::AppState;
+    use crate::storage::{IdempotencyStore, SavedResponse};
+
+    use super::{save_response, try_processing, NextAction};
+
+    /// A body that cannot be read.
+    struct BrokenBody;
+
+    impl MessageBody for BrokenBody {
+        type Error = std::io::Error;
+
+        fn size(&self) -> BodySize {
+            BodySize::Stream
+        }
+
+        fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+            Poll::Ready(Some(Err(std::io::Error::other("connection reset"))))
+        }
+    }
+
+    fn properties() -> IdempotencyProperties {
+        IdempotencyProperties { expiry_minutes: 60, in_flight_wait_milliseconds: 100 }
+    }
+
+    #[tokio::test]
+    async fn a_repeat_waits_for_the_first_request_then_gives_up() {
+        let storage = AppState::new();
+        let key = IdempotencyKey::try_from("key".to_string()).unwrap();
+        let now = Utc::now();
+        storage.reserve_idempotency_key(1, "key", "hash", now, now).await.unwrap();
+
+        let outcome = try_processing(&storage, 1, &key, "hash", &properties(), &SystemClock).await;
+
+        assert_matches!(outcome, Err(IdempotencyError::InFlight));
+    }
+
+    #[tokio::test]
+    async fn a_repeat_gets_the_response_once_the_first_request_finishes() {
+        let storage = AppState::new();
+        let key = IdempotencyKey::try_from("key".to_string()).unwrap();
+        let now = Utc::now();
+        storage.reserve_idempotency_key(1, "key", "hash", now, now).await.unwrap();
+        let saving = {
+            let storage = storage.clone();
+            async move {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                let response = SavedResponse { status_code: 202, headers: vec![], body: b"saved".to_vec() };
+                storage.save_idempotent_response(1, "key", &response).await.unwrap();
+            }
+        };
+
+        let properties = properties();
+        let (outcome, _) = tokio::join!(try_processing(&storage, 1, &key, "hash", &properties, &SystemClock), saving);
+
+        let Ok(NextAction::ReturnSavedResponse(response)) = outcome else {
+            panic!("The saved response was not replayed");
+        };
+        assert_eq!(response.status().as_u16(), 202);
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "saved");
+    }
+
+    #[tokio::test]
+    async fn an_expired_key_can_be_used_again() {
+        let storage = AppState::new();
+        let key = IdempotencyKey::try_from("key".to_string()).unwrap();
+        let long_ago = Utc::now() - chrono::Duration::days(1);
+        storage.reserve_idempotency_key(1, "key", "old hash", long_ago, long_ago).await.unwrap();
+
+        let outcome = try_processing(&storage, 1, &key, "new hash", &properties(), &SystemClock).await;
+
+        assert_matches!(outcome, Ok(NextAction::StartProcessing));
+        assert_err!(try_processing(&storage, 1, &key, "other hash", &properties(), &SystemClock).await);
+    }
+
+    #[tokio::test]
+    async fn keys_expire_by_the_application_clock() {
+        let storage = AppState::new();
+        let key = IdempotencyKey::try_from("key".to_string()).unwrap();
+        let clock = AdjustableClock::default();
+        assert_matches!(try_processing(&storage, 1, &key, "old hash", &properties(), &clock).await, Ok(NextAction::StartProcessing));
+
+        clock.advance(chrono::Duration::minutes(61));
+        let outcome = try_processing(&storage, 1, &key, "new hash", &properties(), &clock).await;
+
+        assert_matches!(outcome, Ok(NextAction::StartProcessing));
+    }
+
+    #[tokio::test]
+    async fn a_response_that_cannot_be_saved_keeps_the_key_reserved() {
+        let storage = AppState::new();
+        let key = IdempotencyKey::try_from("key".to_string()).unwrap();
+        try_processing(&storage, 1, &key, "hash", &properties(), &SystemClock).await.unwrap();
+
+        let outcome = save_response(&storage, 1, &key, HttpResponse::Accepted().body(BrokenBody)).await;
+
+        assert_matches!(outcome, Err(IdempotencyError::ResponseBody(_)));
+        let record = storage.find_idempotency_record(1, "key").await.unwrap().expect("The key was released");
+        assert_none!(record.response);
+        assert_matches!(try_processing(&storage, 1, &key, "hash", &properties(), &SystemClock).await, Err(IdempotencyError::InFlight));
+    }
+}
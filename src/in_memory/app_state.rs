@@ -1,25 +1,49 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::{Arc, Mutex, RwLock};
 
-use serde::Serialize;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 
-#[derive(Serialize,Clone)]
-pub struct Subscription{
-    pub id: i32,
-    pub username: String,
-    pub email: String,
-    pub status: String,
-}
+use crate::storage::{ApiKey, ApiKeyStore, ConfirmationToken, ConfirmationTokenStore, DeliveryCounts, DeliveryQueueStore, DeliveryTask, IdempotencyRecord, IdempotencyStore, NewsletterIssue, NewsletterIssueStore, Sender, SenderRole, SenderStore, StorageError, SavedResponse, SessionStore, Subscription, SubscriptionStore, TokenOutcome, TotpEnrolment, TwoFactorStore};
+
+use super::InMemorySessions;
 
-pub struct Sender{
-    pub username: String,
-    pub pwd: String,
+#[derive(Clone, Debug)]
+pub struct IdempotencyEntry{
+    pub sender_id: i32,
+    pub key: String,
+    pub record: IdempotencyRecord,
+    pub created_at: DateTime<Utc>,
 }
 
+/// The hash of each recovery code, and when it was used.
+type RecoveryCodes = Vec<(String, Option<DateTime<Utc>>)>;
+
 #[derive(Clone)]
 pub struct AppState{
     pub subscriptions: Arc<RwLock<Vec<Subscription>>>,
     pub senders: Arc<RwLock<Vec<Sender>>>,
+    pub confirmation_tokens: Arc<RwLock<Vec<ConfirmationToken>>>,
+    pub newsletter_issues: Arc<RwLock<Vec<NewsletterIssue>>>,
+    pub delivery_tasks: Arc<RwLock<Vec<DeliveryTask>>>,
+    pub idempotency: Arc<RwLock<Vec<IdempotencyEntry>>>,
+    pub sender_test_addresses: Arc<RwLock<HashMap<i32, BTreeSet<String>>>>,
+    pub api_keys: Arc<RwLock<Vec<ApiKey>>>,
+    pub totp_enrolments: Arc<RwLock<HashMap<i32, TotpEnrolment>>>,
+    pub recovery_codes: Arc<RwLock<HashMap<i32, RecoveryCodes>>>,
+    pub sessions: InMemorySessions,
     next_id: Arc<Mutex<i32>>,
+    // Every table has its own sequence in the databases, so here too
+    next_sender_id: Arc<Mutex<i32>>,
+    next_issue_id: Arc<Mutex<i32>>,
+    next_task_id: Arc<Mutex<i32>>,
+    next_api_key_id: Arc<Mutex<i32>>,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AppState {
@@ -27,15 +51,587 @@ impl AppState {
         let max_id = 0;
         Self {
             next_id: Arc::new(Mutex::new(max_id + 1)),
+            next_sender_id: Arc::new(Mutex::new(1)),
+            next_issue_id: Arc::new(Mutex::new(1)),
+            next_task_id: Arc::new(Mutex::new(1)),
+            next_api_key_id: Arc::new(Mutex::new(1)),
             subscriptions: Arc::new(RwLock::new(Vec::new())),
             senders: Arc::new(RwLock::new(Vec::new())),
+            confirmation_tokens: Arc::new(RwLock::new(Vec::new())),
+            newsletter_issues: Arc::new(RwLock::new(Vec::new())),
+            delivery_tasks: Arc::new(RwLock::new(Vec::new())),
+            idempotency: Arc::new(RwLock::new(Vec::new())),
+            sender_test_addresses: Arc::new(RwLock::new(HashMap::new())),
+            api_keys: Arc::new(RwLock::new(Vec::new())),
+            totp_enrolments: Arc::new(RwLock::new(HashMap::new())),
+            recovery_codes: Arc::new(RwLock::new(HashMap::new())),
+            sessions: InMemorySessions::default(),
         }
     }
     pub fn get_id(&self) -> i32 {
-        let mut next_id = self.next_id.lock().expect("mutex poisoned");
-        let id = *next_id;
-        *next_id += 1;
-        id
+        next_in_sequence(&self.next_id)
+    }
+
+}
+
+fn next_in_sequence(sequence: &Mutex<i32>) -> i32 {
+    let mut next_id = sequence.lock().expect("mutex poisoned");
+    let id = *next_id;
+    *next_id += 1;
+    id
+}
+
+#[async_trait]
+impl SubscriptionStore for AppState {
+    async fn add_subscription(&self, username: &str, email: &str, custom_fields: &BTreeMap<String, String>) -> Result<Subscription, StorageError> {
+        let subscription = Subscription{
+            id: self.get_id(),
+            username: username.to_string(),
+            email: email.to_string(),
+            status: "pending_confirmation".to_string(),
+            custom_fields: custom_fields.clone(),
+        };
+        self.subscriptions.write().expect("RwLock poisoned").push(subscription.clone());
+        Ok(subscription)
+    }
+
+    async fn find_subscription_by_id(&self, id: i32) -> Result<Option<Subscription>, StorageError> {
+        let subscriptions = self.subscriptions.read().expect("RwLock poisoned");
+        Ok(subscriptions.iter().find(|s| s.id == id).cloned())
+    }
+
+    async fn find_subscription_by_email(&self, email: &str) -> Result<Option<Subscription>, StorageError> {
+        let subscriptions = self.subscriptions.read().expect("RwLock poisoned");
+        Ok(subscriptions.iter().find(|s| s.email == email).cloned())
+    }
+
+    async fn update_subscription_status(&self, id: i32, status: &str) -> Result<bool, StorageError> {
+        let mut subscriptions = self.subscriptions.write().expect("RwLock poisoned");
+        match subscriptions.iter_mut().find(|s| s.id == id) {
+            Some(subscription) => {
+                subscription.status = status.to_string();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn confirmed_subscriptions(&self) -> Result<Vec<Subscription>, StorageError> {
+        let subscriptions = self.subscriptions.read().expect("RwLock poisoned");
+        Ok(subscriptions.iter().filter(|s| s.status == "confirmed").cloned().collect())
+    }
+}
+
+#[async_trait]
+impl SenderStore for AppState {
+    async fn add_sender(&self, username: &str, display_name: &str, pwd: &str, role: SenderRole) -> Result<Sender, StorageError> {
+        let sender = Sender{
+            id: next_in_sequence(&self.next_sender_id),
+            username: username.to_string(),
+            display_name: display_name.to_string(),
+            pwd: pwd.to_string(),
+            enabled: true,
+            role,
+            created_at: Utc::now(),
+        };
+        let mut senders = self.senders.write().expect("RwLock poisoned");
+        if senders.iter().any(|s| s.username == username) {
+            return Err(StorageError::UniqueViolation("senders.username".to_string()));
+        }
+        senders.push(sender.clone());
+        Ok(sender)
+    }
+
+    async fn find_sender(&self, username: &str) -> Result<Option<Sender>, StorageError> {
+        let senders = self.senders.read().expect("RwLock poisoned");
+        Ok(senders.iter().find(|s| s.username == username).cloned())
+    }
+
+    async fn find_sender_by_id(&self, id: i32) -> Result<Option<Sender>, StorageError> {
+        let senders = self.senders.read().expect("RwLock poisoned");
+        Ok(senders.iter().find(|s| s.id == id).cloned())
+    }
+
+    async fn list_senders(&self) -> Result<Vec<Sender>, StorageError> {
+        Ok(self.senders.read().expect("RwLock poisoned").clone())
+    }
+
+    async fn set_sender_enabled(&self, id: i32, enabled: bool) -> Result<bool, StorageError> {
+        let mut senders = self.senders.write().expect("RwLock poisoned");
+        match senders.iter_mut().find(|s| s.id == id) {
+            Some(sender) => {
+                sender.enabled = enabled;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn set_sender_role(&self, id: i32, role: SenderRole) -> Result<bool, StorageError> {
+        let mut senders = self.senders.write().expect("RwLock poisoned");
+        match senders.iter_mut().find(|s| s.id == id) {
+            Some(sender) => {
+                sender.role = role;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_sender(&self, id: i32) -> Result<bool, StorageError> {
+        let mut senders = self.senders.write().expect("RwLock poisoned");
+        let before = senders.len();
+        senders.retain(|s| s.id != id);
+        if senders.len() == before {
+            return Ok(false);
+        }
+        self.sender_test_addresses.write().expect("RwLock poisoned").remove(&id);
+        self.api_keys.write().expect("RwLock poisoned").retain(|k| k.sender_id != id);
+        self.totp_enrolments.write().expect("RwLock poisoned").remove(&id);
+        self.recovery_codes.write().expect("RwLock poisoned").remove(&id);
+        // Keep the issues, like `ON DELETE SET NULL` does in the databases
+        for issue in self.newsletter_issues.write().expect("RwLock poisoned").iter_mut() {
+            if issue.sender_id == Some(id) {
+                issue.sender_id = None;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn update_sender_password(&self, username: &str, pwd: &str) -> Result<bool, StorageError> {
+        let mut senders = self.senders.write().expect("RwLock poisoned");
+        match senders.iter_mut().find(|s| s.username == username) {
+            Some(sender) => {
+                sender.pwd = pwd.to_string();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn set_sender_test_addresses(&self, sender_id: i32, emails: &[String]) -> Result<(), StorageError> {
+        self.sender_test_addresses.write().expect("RwLock poisoned")
+            .insert(sender_id, emails.iter().cloned().collect());
+        Ok(())
+    }
+
+    async fn list_sender_test_addresses(&self, sender_id: i32) -> Result<Vec<String>, StorageError> {
+        let addresses = self.sender_test_addresses.read().expect("RwLock poisoned");
+        Ok(addresses.get(&sender_id).map(|emails| emails.iter().cloned().collect()).unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for AppState {
+    async fn add_api_key(&self, sender_id: i32, name: &str, prefix: &str, key_hash: &str, scopes: &[String], expires_at: Option<DateTime<Utc>>) -> Result<ApiKey, StorageError> {
+        let key = ApiKey{
+            id: next_in_sequence(&self.next_api_key_id),
+            sender_id,
+            name: name.to_string(),
+            prefix: prefix.to_string(),
+            key_hash: key_hash.to_string(),
+            scopes: scopes.to_vec(),
+            expires_at,
+            last_used_at: None,
+            created_at: Utc::now(),
+        };
+        self.api_keys.write().expect("RwLock poisoned").push(key.clone());
+        Ok(key)
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, StorageError> {
+        let keys = self.api_keys.read().expect("RwLock poisoned");
+        Ok(keys.iter().find(|k| k.key_hash == key_hash).cloned())
+    }
+
+    async fn list_api_keys(&self, sender_id: i32) -> Result<Vec<ApiKey>, StorageError> {
+        let keys = self.api_keys.read().expect("RwLock poisoned");
+        Ok(keys.iter().filter(|k| k.sender_id == sender_id).cloned().collect())
+    }
+
+    async fn delete_api_key(&self, sender_id: i32, id: i32) -> Result<bool, StorageError> {
+        let mut keys = self.api_keys.write().expect("RwLock poisoned");
+        let before = keys.len();
+        keys.retain(|k| !(k.sender_id == sender_id && k.id == id));
+        Ok(keys.len() < before)
+    }
+
+    async fn touch_api_key(&self, id: i32, used_at: DateTime<Utc>) -> Result<(), StorageError> {
+        if let Some(key) = self.api_keys.write().expect("RwLock poisoned").iter_mut().find(|k| k.id == id) {
+            key.last_used_at = Some(used_at);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TwoFactorStore for AppState {
+    async fn start_totp_enrolment(&self, sender_id: i32, secret: &str) -> Result<bool, StorageError> {
+        let mut enrolments = self.totp_enrolments.write().expect("RwLock poisoned");
+        if enrolments.get(&sender_id).is_some_and(|e| e.confirmed_at.is_some()) {
+            return Ok(false);
+        }
+        enrolments.insert(sender_id, TotpEnrolment {
+            sender_id,
+            secret: secret.to_string(),
+            confirmed_at: None,
+            last_used_step: None,
+            failed_attempts: 0,
+            locked_until: None,
+        });
+        Ok(true)
+    }
+
+    async fn find_totp_enrolment(&self, sender_id: i32) -> Result<Option<TotpEnrolment>, StorageError> {
+        Ok(self.totp_enrolments.read().expect("RwLock poisoned").get(&sender_id).cloned())
+    }
+
+    async fn confirm_totp_enrolment(&self, sender_id: i32, confirmed_at: DateTime<Utc>) -> Result<bool, StorageError> {
+        let mut enrolments = self.totp_enrolments.write().expect("RwLock poisoned");
+        match enrolments.get_mut(&sender_id) {
+            Some(enrolment) if enrolment.confirmed_at.is_none() => {
+                enrolment.confirmed_at = Some(confirmed_at);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn use_totp_step(&self, sender_id: i32, step: i64) -> Result<bool, StorageError> {
+        let mut enrolments = self.totp_enrolments.write().expect("RwLock poisoned");
+        match enrolments.get_mut(&sender_id) {
+            Some(enrolment) if enrolment.last_used_step.is_none_or(|last| last < step) => {
+                enrolment.last_used_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn start_second_factor_attempt(&self, sender_id: i32, max_attempts: i32, now: DateTime<Utc>, locked_until: DateTime<Utc>) -> Result<bool, StorageError> {
+        let mut enrolments = self.totp_enrolments.write().expect("RwLock poisoned");
+        match enrolments.get_mut(&sender_id) {
+            Some(enrolment) if enrolment.locked_until.is_none_or(|until| until <= now) => {
+                enrolment.failed_attempts += 1;
+                if enrolment.failed_attempts >= max_attempts {
+                    enrolment.failed_attempts = 0;
+                    enrolment.locked_until = Some(locked_until);
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn clear_second_factor_attempts(&self, sender_id: i32) -> Result<(), StorageError> {
+        if let Some(enrolment) = self.totp_enrolments.write().expect("RwLock poisoned").get_mut(&sender_id) {
+            enrolment.failed_attempts = 0;
+            enrolment.locked_until = None;
+        }
+        Ok(())
+    }
+
+    async fn delete_totp_enrolment(&self, sender_id: i32) -> Result<bool, StorageError> {
+        self.recovery_codes.write().expect("RwLock poisoned").remove(&sender_id);
+        Ok(self.totp_enrolments.write().expect("RwLock poisoned").remove(&sender_id).is_some())
+    }
+
+    async fn replace_recovery_codes(&self, sender_id: i32, code_hashes: &[String]) -> Result<(), StorageError> {
+        let codes = code_hashes.iter().map(|hash| (hash.clone(), None)).collect();
+        self.recovery_codes.write().expect("RwLock poisoned").insert(sender_id, codes);
+        Ok(())
+    }
+
+    async fn use_recovery_code(&self, sender_id: i32, code_hash: &str, used_at: DateTime<Utc>) -> Result<bool, StorageError> {
+        let mut recovery_codes = self.recovery_codes.write().expect("RwLock poisoned");
+        let unused = recovery_codes.get_mut(&sender_id)
+            .and_then(|codes| codes.iter_mut().find(|(hash, used)| hash == code_hash && used.is_none()));
+        match unused {
+            Some((_, used)) => {
+                *used = Some(used_at);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn count_unused_recovery_codes(&self, sender_id: i32) -> Result<i64, StorageError> {
+        let recovery_codes = self.recovery_codes.read().expect("RwLock poisoned");
+        let unused = recovery_codes.get(&sender_id)
+            .map_or(0, |codes| codes.iter().filter(|(_, used)| used.is_none()).count());
+        Ok(unused as i64)
+    }
+}
+
+#[async_trait]
+impl ConfirmationTokenStore for AppState {
+    async fn rotate_confirmation_token(&self, subscription_id: i32, token: &str, expires_at: DateTime<Utc>) -> Result<(), StorageError> {
+        let mut tokens = self.confirmation_tokens.write().expect("RwLock poisoned");
+        tokens.retain(|t| t.subscription_id != subscription_id);
+        tokens.push(ConfirmationToken{
+            token: token.to_string(),
+            subscription_id,
+            expires_at,
+            used_at: None,
+        });
+        Ok(())
+    }
+
+    async fn consume_confirmation_token(&self, token: &str, now: DateTime<Utc>) -> Result<TokenOutcome, StorageError> {
+        let mut tokens = self.confirmation_tokens.write().expect("RwLock poisoned");
+        let Some(stored) = tokens.iter_mut().find(|t| t.token == token) else {
+            return Ok(TokenOutcome::Unknown);
+        };
+        let outcome = stored.outcome(now);
+        if let TokenOutcome::Valid(_) = outcome {
+            stored.used_at = Some(now);
+        }
+        Ok(outcome)
+    }
+}
+
+impl AppState {
+    /// Queue one delivery task per confirmed subscriber. The caller holds the issues lock,
+    /// so the issue and its tasks appear together.
+    fn enqueue_deliveries(&self, issue_id: i32, now: DateTime<Utc>) {
+        let mut tasks = self.delivery_tasks.write().expect("RwLock poisoned");
+        let subscriptions = self.subscriptions.read().expect("RwLock poisoned");
+        for subscription in subscriptions.iter().filter(|s| s.status == "confirmed") {
+            tasks.push(DeliveryTask{
+                id: next_in_sequence(&self.next_task_id),
+                issue_id,
+                subscription_id: subscription.id,
+                email: subscription.email.clone(),
+                status: "queued".to_string(),
+                attempts: 0,
+                execute_after: now,
+                claimed_until: None,
+                last_error: None,
+            });
+        }
+    }
+
+    /// Counts the attempt, releases the task and applies `outcome`, if the lease is still the caller's.
+    fn record_delivery_outcome(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, outcome: impl FnOnce(&mut DeliveryTask)) -> bool {
+        let mut tasks = self.delivery_tasks.write().expect("RwLock poisoned");
+        match tasks.iter_mut().find(|t| t.id == id) {
+            Some(task) if task.claimed_until == Some(lease_until) && lease_until > now => {
+                task.attempts += 1;
+                task.claimed_until = None;
+                outcome(task);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn new_issue(&self, sender_id: i32, title: &str, text_content: &str, html_content: &str, status: &str, now: DateTime<Utc>) -> NewsletterIssue {
+        NewsletterIssue{
+            id: next_in_sequence(&self.next_issue_id),
+            sender_id: Some(sender_id),
+            title: title.to_string(),
+            text_content: text_content.to_string(),
+            html_content: html_content.to_string(),
+            status: status.to_string(),
+            scheduled_for: None,
+            created_at: now,
+            published_at: (status == "published").then_some(now),
+        }
+    }
+
+    /// Apply `change` to the issue if it can still be edited.
+    fn edit_issue(&self, id: i32, change: impl FnOnce(&mut NewsletterIssue)) -> bool {
+        let mut issues = self.newsletter_issues.write().expect("RwLock poisoned");
+        match issues.iter_mut().find(|i| i.id == id && i.is_editable()) {
+            Some(issue) => {
+                change(issue);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+impl NewsletterIssueStore for AppState {
+    async fn add_newsletter_issue(&self, sender_id: i32, title: &str, text_content: &str, html_content: &str) -> Result<NewsletterIssue, StorageError> {
+        let now = Utc::now();
+        let issue = self.new_issue(sender_id, title, text_content, html_content, "published", now);
+        let mut issues = self.newsletter_issues.write().expect("RwLock poisoned");
+        self.enqueue_deliveries(issue.id, now);
+        issues.push(issue.clone());
+        Ok(issue)
+    }
+
+    async fn find_newsletter_issue(&self, id: i32) -> Result<Option<NewsletterIssue>, StorageError> {
+        let issues = self.newsletter_issues.read().expect("RwLock poisoned");
+        Ok(issues.iter().find(|i| i.id == id).cloned())
+    }
+
+    async fn list_newsletter_issues(&self, limit: i64, offset: i64) -> Result<Vec<NewsletterIssue>, StorageError> {
+        let issues = self.newsletter_issues.read().expect("RwLock poisoned");
+        Ok(issues.iter().rev().skip(offset.max(0) as usize).take(limit.max(0) as usize).cloned().collect())
+    }
+
+    async fn count_newsletter_issues(&self) -> Result<i64, StorageError> {
+        Ok(self.newsletter_issues.read().expect("RwLock poisoned").len() as i64)
+    }
+
+    async fn add_draft_issue(&self, sender_id: i32, title: &str, text_content: &str, html_content: &str) -> Result<NewsletterIssue, StorageError> {
+        let issue = self.new_issue(sender_id, title, text_content, html_content, "draft", Utc::now());
+        self.newsletter_issues.write().expect("RwLock poisoned").push(issue.clone());
+        Ok(issue)
+    }
+
+    async fn update_draft_issue(&self, id: i32, title: &str, text_content: &str, html_content: &str) -> Result<bool, StorageError> {
+        Ok(self.edit_issue(id, |issue| {
+            issue.title = title.to_string();
+            issue.text_content = text_content.to_string();
+            issue.html_content = html_content.to_string();
+        }))
+    }
+
+    async fn schedule_issue(&self, id: i32, send_at: DateTime<Utc>) -> Result<bool, StorageError> {
+        Ok(self.edit_issue(id, |issue| {
+            issue.status = "scheduled".to_string();
+            issue.scheduled_for = Some(send_at);
+        }))
+    }
+
+    async fn cancel_issue(&self, id: i32) -> Result<bool, StorageError> {
+        Ok(self.edit_issue(id, |issue| {
+            issue.status = "cancelled".to_string();
+        }))
+    }
+
+    async fn publish_due_issue(&self, now: DateTime<Utc>) -> Result<Option<NewsletterIssue>, StorageError> {
+        let mut issues = self.newsletter_issues.write().expect("RwLock poisoned");
+        let due = issues.iter_mut()
+            .filter(|i| i.status == "scheduled" && i.scheduled_for.is_some_and(|at| at <= now))
+            .min_by_key(|i| (i.scheduled_for, i.id));
+        let Some(issue) = due else {
+            return Ok(None);
+        };
+        issue.status = "published".to_string();
+        issue.published_at = Some(now);
+        let issue = issue.clone();
+        self.enqueue_deliveries(issue.id, now);
+        Ok(Some(issue))
+    }
+}
+
+#[async_trait]
+impl DeliveryQueueStore for AppState {
+    async fn claim_delivery_tasks(&self, now: DateTime<Utc>, lease_until: DateTime<Utc>, limit: i64) -> Result<Vec<DeliveryTask>, StorageError> {
+        let mut tasks = self.delivery_tasks.write().expect("RwLock poisoned");
+        Ok(tasks.iter_mut()
+            .filter(|t| {
+                t.status == "queued"
+                    && t.execute_after <= now
+                    && t.claimed_until.is_none_or(|claimed_until| claimed_until <= now)
+            })
+            .take(limit.max(0) as usize)
+            .map(|task| {
+                task.claimed_until = Some(lease_until);
+                task.clone()
+            })
+            .collect())
+    }
+
+    async fn complete_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>) -> Result<bool, StorageError> {
+        Ok(self.record_delivery_outcome(id, lease_until, now, |task| {
+            task.status = "sent".to_string();
+            task.last_error = None;
+        }))
+    }
+
+    async fn retry_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, execute_after: DateTime<Utc>, error: &str) -> Result<bool, StorageError> {
+        Ok(self.record_delivery_outcome(id, lease_until, now, |task| {
+            task.execute_after = execute_after;
+            task.last_error = Some(error.to_string());
+        }))
+    }
+
+    async fn fail_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, error: &str) -> Result<bool, StorageError> {
+        Ok(self.record_delivery_outcome(id, lease_until, now, |task| {
+            task.status = "failed".to_string();
+            task.last_error = Some(error.to_string());
+        }))
+    }
+
+    async fn bounce_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, error: &str) -> Result<bool, StorageError> {
+        Ok(self.record_delivery_outcome(id, lease_until, now, |task| {
+            task.status = "bounced".to_string();
+            task.last_error = Some(error.to_string());
+        }))
+    }
+
+    async fn count_issue_deliveries(&self, issue_id: i32) -> Result<DeliveryCounts, StorageError> {
+        let tasks = self.delivery_tasks.read().expect("RwLock poisoned");
+        let mut counts = DeliveryCounts::default();
+        for task in tasks.iter().filter(|t| t.issue_id == issue_id) {
+            counts.add(&task.status, 1);
+        }
+        Ok(counts)
+    }
+
+    async fn count_pending_delivery_tasks(&self) -> Result<i64, StorageError> {
+        let tasks = self.delivery_tasks.read().expect("RwLock poisoned");
+        Ok(tasks.iter().filter(|t| t.status == "queued").count() as i64)
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for AppState {
+    async fn reserve_idempotency_key(&self, sender_id: i32, key: &str, request_hash: &str, now: DateTime<Utc>, expired_before: DateTime<Utc>) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let mut entries = self.idempotency.write().expect("RwLock poisoned");
+        entries.retain(|e| e.created_at >= expired_before);
+        if let Some(entry) = entries.iter().find(|e| e.sender_id == sender_id && e.key == key) {
+            return Ok(Some(entry.record.clone()));
+        }
+        entries.push(IdempotencyEntry{
+            sender_id,
+            key: key.to_string(),
+            record: IdempotencyRecord{ request_hash: request_hash.to_string(), response: None },
+            created_at: now,
+        });
+        Ok(None)
+    }
+
+    async fn find_idempotency_record(&self, sender_id: i32, key: &str) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let entries = self.idempotency.read().expect("RwLock poisoned");
+        Ok(entries.iter().find(|e| e.sender_id == sender_id && e.key == key).map(|e| e.record.clone()))
+    }
+
+    async fn save_idempotent_response(&self, sender_id: i32, key: &str, response: &SavedResponse) -> Result<(), StorageError> {
+        let mut entries = self.idempotency.write().expect("RwLock poisoned");
+        if let Some(entry) = entries.iter_mut().find(|e| e.sender_id == sender_id && e.key == key) {
+            entry.record.response = Some(response.clone());
+        }
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, sender_id: i32, key: &str) -> Result<(), StorageError> {
+        self.idempotency.write().expect("RwLock poisoned").retain(|e| !(e.sender_id == sender_id && e.key == key));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for AppState {
+    async fn load_session(&self, key_hash: &str, now: DateTime<Utc>) -> Result<Option<String>, StorageError> {
+        self.sessions.load_session(key_hash, now).await
+    }
+
+    async fn save_session(&self, key_hash: &str, state: &str, expires_at: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), StorageError> {
+        self.sessions.save_session(key_hash, state, expires_at, now).await
+    }
+
+    async fn extend_session(&self, key_hash: &str, expires_at: DateTime<Utc>) -> Result<(), StorageError> {
+        self.sessions.extend_session(key_hash, expires_at).await
+    }
+
+    async fn delete_session(&self, key_hash: &str) -> Result<(), StorageError> {
+        self.sessions.delete_session(key_hash).await
     }
-   
 }
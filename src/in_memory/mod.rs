@@ -1,2 +1,4 @@
 mod app_state;
-pub use app_state::*;
\ No newline at end of file
+mod sessions;
+pub use app_state::*;
+pub use sessions::*;
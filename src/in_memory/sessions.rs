@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::storage::{SessionStore, StorageError};
+
+#[derive(Clone, Debug)]
+struct SessionEntry {
+    state: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Sessions kept in the process, on their own or as part of `AppState`.
+/// They are lost on restart, and not shared between instances.
+#[derive(Clone, Default)]
+pub struct InMemorySessions {
+    sessions: Arc<RwLock<HashMap<String, SessionEntry>>>,
+}
+
+#[async_trait]
+impl SessionStore for InMemorySessions {
+    async fn load_session(&self, key_hash: &str, now: DateTime<Utc>) -> Result<Option<String>, StorageError> {
+        let sessions = self.sessions.read().expect("RwLock poisoned");
+        Ok(sessions.get(key_hash)
+            .filter(|session| session.expires_at >= now)
+            .map(|session| session.state.clone()))
+    }
+
+    async fn save_session(&self, key_hash: &str, state: &str, expires_at: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), StorageError> {
+        let mut sessions = self.sessions.write().expect("RwLock poisoned");
+        sessions.retain(|_, session| session.expires_at >= now);
+        sessions.insert(key_hash.to_string(), SessionEntry { state: state.to_string(), expires_at });
+        Ok(())
+    }
+
+    async fn extend_session(&self, key_hash: &str, expires_at: DateTime<Utc>) -> Result<(), StorageError> {
+        if let Some(session) = self.sessions.write().expect("RwLock poisoned").get_mut(key_hash) {
+            session.expires_at = expires_at;
+        }
+        Ok(())
+    }
+
+    async fn delete_session(&self, key_hash: &str) -> Result<(), StorageError> {
+        self.sessions.write().expect("RwLock poisoned").remove(key_hash);
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use actix_web::web::Data;
+use tracing::{field::display, Span};
+
+use crate::clock::Clock;
+use crate::configuration::DeliveryProperties;
+use crate::email_client::{EmailClient, EmailError, OutgoingEmail};
+use crate::routes::UnsubscribeSigner;
+use crate::storage::{DeliveryTask, Storage, StorageError};
+use crate::templating::{NewsletterTemplate, RecipientContext, RenderedEmail};
+
+#[derive(thiserror::Error, Debug)]
+pub enum DeliveryError {
+    #[error("Failed to send the newsletter email")]
+    SendEmailError(#[from] EmailError),
+    #[error("Newsletter issue {0} does not exist")]
+    IssueNotFound(i32),
+    #[error("Failed to render the newsletter: {0}")]
+    RenderError(String),
+    #[error("Failed to access the delivery queue")]
+    StorageError(#[from] StorageError),
+}
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// One subscriber's copy of an issue, ready to be sent.
+struct PersonalisedEmail {
+    email: RenderedEmail,
+    list_unsubscribe: String,
+}
+
+/// Drains the delivery queue filled by `publish_newsletter`, one recipient at a time.
+/// Any number of workers, in any number of processes, can share the same queue.
+pub struct DeliveryWorker {
+    pub storage: Data<dyn Storage>,
+    pub email_client: Data<EmailClient>,
+    pub base_url: String,
+    pub unsubscribe_signer: Data<UnsubscribeSigner>,
+    pub properties: DeliveryProperties,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl DeliveryWorker {
+    pub async fn run_until_stopped(self) {
+        loop {
+            match self.try_execute_tasks().await {
+                Ok(ExecutionOutcome::TaskCompleted) => {}
+                Ok(ExecutionOutcome::EmptyQueue) => {
+                    tokio::time::sleep(self.properties.poll_interval()).await;
+                }
+                // Most likely the store is unavailable, back off the same way
+                Err(_) => {
+                    tokio::time::sleep(self.properties.poll_interval()).await;
+                }
+            }
+        }
+    }
+
+    /// Claim a batch of due tasks and try to deliver them, one message per recipient.
+    /// Every task records its own outcome: a failed delivery is scheduled again with
+    /// an exponential backoff until `max_attempts` is reached, a bounce is never retried.
+    /// A storage error does not stop the batch, what was sent still gets recorded;
+    /// the first one is returned once the whole batch went through.
+    #[tracing::instrument(
+        skip_all,
+        fields(tasks = tracing::field::Empty),
+        err
+    )]
+    pub async fn try_execute_tasks(&self) -> Result<ExecutionOutcome, DeliveryError> {
+        let now = self.clock.now();
+        let tasks = self.storage
+            .claim_delivery_tasks(now, now + self.properties.lease(), self.properties.batch_size as i64)
+            .await?;
+        if tasks.is_empty() {
+            return Ok(ExecutionOutcome::EmptyQueue);
+        }
+        Span::current().record("tasks", display(tasks.len()));
+        // A batch can span several issues, each issue is sent with its own content
+        let mut tasks_by_issue: BTreeMap<i32, Vec<DeliveryTask>> = BTreeMap::new();
+        for task in tasks {
+            tasks_by_issue.entry(task.issue_id).or_default().push(task);
+        }
+        let mut first_error = None;
+        for (issue_id, tasks) in tasks_by_issue {
+            let outcomes = match self.deliver(issue_id, &tasks).await {
+                Ok(outcomes) => outcomes,
+                Err(e) => {
+                    tracing::error!("Failed to deliver issue {}, its tasks are tried again once their lease runs out: {:?}", issue_id, e);
+                    first_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            for (task, outcome) in tasks.iter().zip(outcomes) {
+                if let Err(e) = self.record_outcome(task, outcome).await {
+                    tracing::error!("Failed to record the outcome of delivery task {}: {:?}", task.id, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e.into()),
+            None => Ok(ExecutionOutcome::TaskCompleted),
+        }
+    }
+
+    /// One outcome per task. If the store cannot be read the tasks are left alone,
+    /// their lease runs out and they are claimed again later.
+    async fn deliver(&self, issue_id: i32, tasks: &[DeliveryTask]) -> Result<Vec<Result<(), DeliveryError>>, StorageError> {
+        let Some(issue) = self.storage.find_newsletter_issue(issue_id).await? else {
+            return Ok(tasks.iter().map(|_| Err(DeliveryError::IssueNotFound(issue_id))).collect());
+        };
+        // Checked when the issue was published, so only an issue stored before templating can fail here
+        let template = match NewsletterTemplate::compile(&issue.title, &issue.html_content, &issue.text_content) {
+            Ok(template) => template,
+            Err(e) => return Ok(tasks.iter().map(|_| Err(DeliveryError::RenderError(e.to_string()))).collect()),
+        };
+        let mut personalised = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            personalised.push(self.personalise(&template, task).await?);
+        }
+        // Every subscriber gets their own message carrying their own signed unsubscribe link.
+        let messages: Vec<OutgoingEmail> = tasks.iter().zip(&personalised)
+            .filter_map(|(task, personalised)| {
+                let personalised = personalised.as_ref().ok()?.as_ref()?;
+                Some(OutgoingEmail {
+                    recipient: &task.email,
+                    subject: &personalised.email.subject,
+                    html_content: &personalised.email.html,
+                    text_content: &personalised.email.text,
+                    headers: HashMap::from([
+                        ("List-Unsubscribe", personalised.list_unsubscribe.as_str()),
+                        ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click"),
+                    ]),
+                })
+            })
+            .collect();
+        let mut sent = self.email_client.send_emails(&messages).await.into_iter();
+        Ok(personalised.into_iter()
+            .map(|personalised| match personalised {
+                Ok(Some(_)) => sent.next().expect("One outcome per message").map_err(DeliveryError::from),
+                // Nothing to send is done too
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            })
+            .collect())
+    }
+
+    /// Render the issue with the subscriber's own details. `None` for a subscriber who
+    /// unsubscribed (or is gone) since the issue was queued: they are not sent anything.
+    async fn personalise(&self, template: &NewsletterTemplate, task: &DeliveryTask) -> Result<Result<Option<PersonalisedEmail>, DeliveryError>, StorageError> {
+        let subscription = match self.storage.find_subscription_by_id(task.subscription_id).await? {
+            Some(subscription) if subscription.status == "confirmed" => subscription,
+            _ => {
+                tracing::info!("Skipping delivery task {}, subscription {} is no longer confirmed", task.id, task.subscription_id);
+                return Ok(Ok(None));
+            }
+        };
+        let unsubscribe_url = self.unsubscribe_signer.unsubscribe_link(&self.base_url, task.subscription_id);
+        let recipient = RecipientContext {
+            username: &subscription.username,
+            email: &task.email,
+            unsubscribe_url: &unsubscribe_url,
+            fields: &subscription.custom_fields,
+        };
+        Ok(template.render(&recipient)
+            .map(|email| Some(PersonalisedEmail { email, list_unsubscribe: format!("<{}>", unsubscribe_url) }))
+            .map_err(|e| DeliveryError::RenderError(e.to_string())))
+    }
+
+    async fn record_outcome(&self, task: &DeliveryTask, outcome: Result<(), DeliveryError>) -> Result<(), StorageError> {
+        let lease_until = task.claimed_until.expect("Claimed tasks have a lease");
+        let now = self.clock.now();
+        let recorded = match outcome {
+            Ok(()) => self.storage.complete_delivery_task(task.id, lease_until, now).await?,
+            Err(e) => {
+                let error = error_chain_message(&e);
+                let attempts = task.attempts as u32 + 1;
+                if matches!(&e, DeliveryError::SendEmailError(e) if e.is_bounce()) {
+                    tracing::warn!("Delivery task {} bounced: {}", task.id, error);
+                    self.storage.bounce_delivery_task(task.id, lease_until, now, &error).await?
+                } else if matches!(&e, DeliveryError::RenderError(_)) {
+                    // The same template fails the same way every time
+                    tracing::error!("Giving up on delivery task {}: {}", task.id, error);
+                    self.storage.fail_delivery_task(task.id, lease_until, now, &error).await?
+                } else if attempts >= self.properties.max_attempts {
+                    tracing::error!("Giving up on delivery task {} after {} attempts: {}", task.id, attempts, error);
+                    self.storage.fail_delivery_task(task.id, lease_until, now, &error).await?
+                } else {
+                    tracing::warn!("Delivery task {} failed, retrying: {}", task.id, error);
+                    let execute_after = now + self.properties.backoff(attempts);
+                    self.storage.retry_delivery_task(task.id, lease_until, now, execute_after, &error).await?
+                }
+            }
+        };
+        if !recorded {
+            tracing::warn!("The lease on delivery task {} ran out, its outcome was not recorded", task.id);
+        }
+        Ok(())
+    }
+}
+
+/// `error: cause: cause...`, kept with the task so a failure can be diagnosed later.
+fn error_chain_message(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut current = e.source();
+    while let Some(cause) = current {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        current = cause.source();
+    }
+    message
+}
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use actix_web::web::Data;
+
+use crate::clock::Clock;
+use crate::storage::{Storage, StorageError};
+
+/// Publishes scheduled issues once they are due. The store guarantees every issue
+/// is published exactly once, so restarts and extra instances are harmless.
+pub struct IssueScheduler {
+    pub storage: Data<dyn Storage>,
+    pub clock: Arc<dyn Clock>,
+    pub poll_interval: std::time::Duration,
+}
+
+impl IssueScheduler {
+    pub async fn run_until_stopped(self) {
+        loop {
+            // A failure is retried on the next tick, the issue is still scheduled
+            let _ = self.publish_due_issues().await;
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Publish every issue due by now, returning how many there were.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn publish_due_issues(&self) -> Result<usize, StorageError> {
+        let mut published = 0;
+        while let Some(issue) = self.storage.publish_due_issue(self.clock.now()).await? {
+            tracing::info!("Published scheduled newsletter issue {}", issue.id);
+            published += 1;
+        }
+        Ok(published)
+    }
+}
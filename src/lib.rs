@@ -1,8 +1,19 @@
+pub mod authentication;
+pub mod clock;
 pub mod configuration;
+pub mod email_html;
+pub mod idempotency;
 pub mod routes;
 pub mod startup;
 pub mod in_memory;
+pub mod issue_delivery_worker;
+pub mod issue_scheduler;
+pub mod markdown;
+pub mod postgres;
+pub mod sqlite;
+pub mod storage;
 pub mod telemetry;
+pub mod templating;
 pub mod email_client;
 
 
@@ -1,16 +1,54 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
 use zero2prod::startup::Application;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
-use zero2prod::configuration::get_configuration;
+use zero2prod::configuration::get_configuration_with;
 
+#[derive(Parser)]
+#[command(name = "zero2prod", about = "A newsletter delivery service")]
+struct Cli {
+    /// An extra YAML file layered on top of `configuration/`, before the `APP_*` variables
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
+#[derive(Subcommand)]
+enum Command {
+    /// Work with the configuration without starting the server
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Load and validate the configuration, then exit
+    Check,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let subscriber = get_subscriber("zero2prod".into(), 
+    let cli = Cli::parse();
+    let configuration = match get_configuration_with(cli.config.as_deref()) {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::Config { action: ConfigAction::Check }) = cli.command {
+        println!("The configuration is valid");
+        return Ok(());
+    }
+
+    let subscriber = get_subscriber("zero2prod".into(),
         "info".into(), std::io::stdout);
     init_subscriber(subscriber);
-    
-    let configuration = get_configuration().expect("Failed to read configuration.");
+
     let application = Application::build(configuration).await?;
     application.run_until_stopped().await?;
     Ok(())
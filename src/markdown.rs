@@ -0,0 +1,207 @@
+//! Newsletters written in markdown get both of their parts from the same source:
+//! HTML that cannot carry markup of its own, and plain text with the links as footnotes.
+//! Template tags go through untouched, the result is still rendered per subscriber.
+
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd};
+
+use crate::templating::protect_template_tags;
+
+/// URL schemes a link or an image may use, anything without a scheme is relative.
+pub(crate) const ALLOWED_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+pub struct RenderedMarkdown {
+    pub html: String,
+    pub text: String,
+}
+
+pub fn render(markdown: &str) -> RenderedMarkdown {
+    let (markdown, tags) = protect_template_tags(markdown);
+    let html = to_html(&markdown);
+    let text = to_text(&markdown);
+    RenderedMarkdown {
+        html: tags.restore(html),
+        text: tags.restore(text),
+    }
+}
+
+fn parser(markdown: &str) -> Parser<'_> {
+    Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS)
+}
+
+/// Raw HTML is shown as text, and links to anything but the allowed schemes go nowhere.
+fn to_html(markdown: &str) -> String {
+    let events = parser(markdown).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        Event::Start(Tag::HtmlBlock) => Event::Start(Tag::Paragraph),
+        Event::End(TagEnd::HtmlBlock) => Event::End(TagEnd::Paragraph),
+        Event::Start(Tag::Link { link_type, dest_url, title, id }) => Event::Start(Tag::Link {
+            link_type, dest_url: safe_url(dest_url), title, id,
+        }),
+        Event::Start(Tag::Image { link_type, dest_url, title, id }) => Event::Start(Tag::Image {
+            link_type, dest_url: safe_url(dest_url), title, id,
+        }),
+        event => event,
+    });
+    let mut output = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut output, events);
+    output
+}
+
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    let scheme = url.split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| !scheme.contains(['/', '?', '#']));
+    match scheme {
+        Some(scheme) if !ALLOWED_SCHEMES.contains(&scheme.to_ascii_lowercase().as_str()) => CowStr::Borrowed("#"),
+        _ => url,
+    }
+}
+
+/// Paragraphs and list items on their own lines, headings marked the markdown way,
+/// and every link numbered with its address listed at the end.
+fn to_text(markdown: &str) -> String {
+    let mut text = String::with_capacity(markdown.len());
+    let mut footnotes: Vec<String> = Vec::new();
+    let mut links: Vec<String> = Vec::new();
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    let mut block_starts: Vec<usize> = Vec::new();
+    for event in parser(markdown) {
+        match event {
+            // Not underlined, a heading with a template tag has no known width until it is rendered
+            Event::Start(Tag::Heading { level, .. }) => {
+                text.push_str(&"#".repeat(level as usize));
+                text.push(' ');
+            }
+            Event::End(TagEnd::Heading(_)) => text.push_str("\n\n"),
+            Event::Start(Tag::BlockQuote(_)) => block_starts.push(text.len()),
+            Event::End(TagEnd::BlockQuote(_)) => {
+                let start = block_starts.pop().unwrap_or(0);
+                let quoted = text[start..].trim_end().lines()
+                    .map(|line| if line.is_empty() { ">".to_string() } else { format!("> {}", line) })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                text.truncate(start);
+                text.push_str(&quoted);
+                text.push_str("\n\n");
+            }
+            Event::End(TagEnd::Paragraph | TagEnd::CodeBlock) => text.push_str("\n\n"),
+            Event::Start(Tag::List(first)) => {
+                end_line(&mut text);
+                lists.push(first);
+            }
+            Event::End(TagEnd::List(_)) => {
+                lists.pop();
+                if lists.is_empty() {
+                    text.push('\n');
+                }
+            }
+            Event::Start(Tag::Item) => {
+                end_line(&mut text);
+                text.push_str(&"   ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(number)) => {
+                        text.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => text.push_str("- "),
+                }
+            }
+            Event::End(TagEnd::Item) => end_line(&mut text),
+            Event::Start(Tag::Link { dest_url, .. }) => {
+                links.push(dest_url.to_string());
+                block_starts.push(text.len());
+            }
+            Event::End(TagEnd::Link) => {
+                let url = links.pop().unwrap_or_default();
+                let start = block_starts.pop().unwrap_or(0);
+                // An autolink already shows its address
+                if text[start..] != url && text[start..].strip_prefix("mailto:") != Some(&url) {
+                    footnotes.push(url);
+                    text.push_str(&format!(" [{}]", footnotes.len()));
+                }
+            }
+            Event::Start(Tag::Image { .. }) => text.push_str("[image: "),
+            Event::End(TagEnd::Image) => text.push(']'),
+            Event::Text(content) | Event::Code(content) | Event::Html(content) | Event::InlineHtml(content) => {
+                text.push_str(&content);
+            }
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Rule => text.push_str("----------\n\n"),
+            Event::TaskListMarker(done) => text.push_str(if done { "[x] " } else { "[ ] " }),
+            _ => {}
+        }
+    }
+    let mut text = text.trim_end().to_string();
+    if !footnotes.is_empty() {
+        text.push_str("\n\n");
+        for (number, url) in footnotes.iter().enumerate() {
+            text.push_str(&format!("[{}] {}\n", number + 1, url));
+        }
+    }
+    collapse_blank_lines(text.trim_end())
+}
+
+fn end_line(text: &mut String) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+fn collapse_blank_lines(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank_lines += 1;
+            if blank_lines > 1 {
+                continue;
+            }
+        } else {
+            blank_lines = 0;
+        }
+        collapsed.push_str(line);
+        collapsed.push('\n');
+    }
+    collapsed.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn both_parts_come_from_the_same_source() {
+        let rendered = render("# Hello\n\nRead **the** [latest news](https://example.com/news).\n\n- one\n- two");
+
+        assert_eq!(rendered.html, "<h1>Hello</h1>\n<p>Read <strong>the</strong> <a href=\"https://example.com/news\">latest news</a>.</p>\n<ul>\n<li>one</li>\n<li>two</li>\n</ul>\n");
+        assert_eq!(rendered.text, "# Hello\n\nRead the latest news [1].\n\n- one\n- two\n\n[1] https://example.com/news");
+    }
+
+    #[test]
+    fn raw_html_and_script_links_are_neutralised() {
+        let rendered = render("<script>alert(1)</script>\n\nA [trap](javascript:alert(1)) and <b onclick=\"x()\">bold</b>");
+
+        assert!(!rendered.html.contains("<script"));
+        assert!(!rendered.html.contains("<b "));
+        assert!(!rendered.html.contains("javascript:"));
+        assert!(rendered.html.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn template_tags_survive_markdown() {
+        let rendered = render("Hi {{ username }}, *welcome*!\n\n{% if fields.city == \"Portland\" %}See you soon{% endif %}\n\n[Unsubscribe]({{ unsubscribe_url }})");
+
+        assert!(rendered.html.contains("<p>Hi {{ username }}, <em>welcome</em>!</p>"));
+        assert!(rendered.html.contains("{% if fields.city == \"Portland\" %}See you soon{% endif %}"));
+        assert!(rendered.html.contains("<a href=\"{{ unsubscribe_url }}\">Unsubscribe</a>"));
+        assert!(rendered.text.ends_with("Unsubscribe [1]\n\n[1] {{ unsubscribe_url }}"));
+    }
+
+    #[test]
+    fn ordered_and_nested_lists_keep_their_shape() {
+        let rendered = render("1. first\n2. second\n   - nested\n3. third");
+
+        assert_eq!(rendered.text, "1. first\n2. second\n   - nested\n3. third");
+    }
+}
@@ -0,0 +1,2 @@
+mod postgres_store;
+pub use postgres_store::*;
\ No newline at end of file
@@ -0,0 +1,178 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::authentication::{session_sender, AuthError, AuthMethod, AuthenticatedSender, Permission};
+use crate::configuration::{ContentLimitsProperties, IdempotencyProperties};
+use crate::idempotency::IdempotencyKey;
+use crate::storage::{Sender, Storage, StorageError};
+use crate::templating::{AdminPages, NewsletterLayout, TemplateError};
+
+use super::{error_chain_fmt, html_page, publish_once, Content, ContentError, Flash, NewsletterRequest, PublishError};
+
+#[derive(thiserror::Error)]
+pub enum AdminError {
+    #[error("Not logged in")]
+    LoginRequired,
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
+    #[error("Failed to publish the newsletter issue")]
+    PublishError(#[from]PublishError),
+    #[error("Failed to render the admin page")]
+    TemplateError(#[from]TemplateError),
+    #[error("Failed to access the store")]
+    StorageError(#[from]StorageError),
+}
+
+impl std::fmt::Debug for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AdminError::LoginRequired => Flash::LoginRequired.redirect("/login"),
+            AdminError::PublishError(err) => err.error_response(),
+            AdminError::AuthenticationError(err) => err.error_response(),
+            AdminError::TemplateError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+            AdminError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+/// Pages are for browsers, without a session they go to the login form rather than get a `401`.
+/// A role that does not allow `permission` gets the `403` of the API.
+async fn logged_in_sender(session: &Session, storage: &dyn Storage, permission: Permission) -> Result<AuthenticatedSender, AdminError> {
+    let sender = session_sender(session, storage).await?.ok_or(AdminError::LoginRequired)?;
+    let sender = AuthenticatedSender { sender, method: AuthMethod::Session };
+    sender.require_permission(permission)?;
+    Ok(sender)
+}
+
+pub async fn admin_dashboard(
+    request: HttpRequest,
+    session: Session,
+    storage: web::Data<dyn Storage>,
+    pages: web::Data<AdminPages>,
+) -> Result<HttpResponse, AdminError> {
+    let sender = logged_in_sender(&session, storage.get_ref(), Permission::ReadSubscribers).await?;
+    let subscribers = storage.confirmed_subscriptions().await?.len();
+    let issues = storage.count_newsletter_issues().await?;
+    let body = pages.render("admin/dashboard.html", context! {
+        sender => sender.username,
+        can_publish => sender.require_permission(Permission::PublishNewsletters).is_ok(),
+        flash => Flash::take(&request).map(Flash::message),
+        subscribers,
+        issues,
+    })?;
+    Ok(html_page(&request, HttpResponse::Ok(), body))
+}
+
+/// What the compose form posts, a field left empty was not given.
+#[derive(Deserialize, Serialize, Default)]
+pub struct ComposeForm {
+    title: String,
+    #[serde(default)]
+    markdown: String,
+    #[serde(default)]
+    html: String,
+    #[serde(default)]
+    text: String,
+    idempotency_key: String,
+}
+
+impl ComposeForm {
+    fn to_request(&self) -> NewsletterRequest {
+        let given = |part: &String| Some(part.clone()).filter(|part| !part.trim().is_empty());
+        NewsletterRequest {
+            title: self.title.clone(),
+            content: Content { text: given(&self.text), html: given(&self.html), markdown: given(&self.markdown) },
+        }
+    }
+}
+
+/// The form carries a fresh idempotency key, submitting it twice queues a single issue.
+fn compose_page(
+    request: &HttpRequest,
+    pages: &AdminPages,
+    sender: &Sender,
+    form: &ComposeForm,
+    errors: &[String],
+) -> Result<String, TemplateError> {
+    let idempotency_key = match form.idempotency_key.as_str() {
+        "" => uuid::Uuid::new_v4().to_string(),
+        key => key.to_string(),
+    };
+    pages.render("admin/newsletters.html", context! {
+        sender => sender.username,
+        flash => Flash::take(request).map(Flash::message),
+        form,
+        errors,
+        idempotency_key,
+    })
+}
+
+pub async fn compose_newsletter(
+    request: HttpRequest,
+    session: Session,
+    storage: web::Data<dyn Storage>,
+    pages: web::Data<AdminPages>,
+) -> Result<HttpResponse, AdminError> {
+    let sender = logged_in_sender(&session, storage.get_ref(), Permission::PublishNewsletters).await?;
+    let body = compose_page(&request, &pages, &sender, &ComposeForm::default(), &[])?;
+    Ok(html_page(&request, HttpResponse::Ok(), body))
+}
+
+/// Publishes like `POST /newsletters`. Content that does not pass comes back in the form, with what is wrong.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "Publishing a newsletter from the admin pages",
+    skip(request, form, session, storage, pages, layout, limits, idempotency, clock),
+    fields(%form.title)
+)]
+pub async fn publish_composed_newsletter(
+    request: HttpRequest,
+    form: web::Form<ComposeForm>,
+    session: Session,
+    storage: web::Data<dyn Storage>,
+    pages: web::Data<AdminPages>,
+    layout: web::Data<NewsletterLayout>,
+    limits: web::Data<ContentLimitsProperties>,
+    idempotency: web::Data<IdempotencyProperties>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, AdminError> {
+    let sender = logged_in_sender(&session, storage.get_ref(), Permission::PublishNewsletters).await?;
+    let req = form.to_request();
+    let content = match req.prepare(&layout, &limits) {
+        Ok(content) => content,
+        Err(e) => {
+            let errors = match e {
+                ContentError::Fields(errors) => errors.messages(),
+                ContentError::Template(problems) => problems,
+            };
+            let body = compose_page(&request, &pages, &sender, &form, &errors)?;
+            return Ok(html_page(&request, HttpResponse::BadRequest(), body));
+        }
+    };
+    let idempotency_key = IdempotencyKey::try_from(form.idempotency_key.clone())
+        .map_err(PublishError::from)?;
+    for warning in &content.warnings {
+        tracing::info!("Sanitized the html of `{}`: {}", req.title, warning);
+    }
+    Ok(publish_once(storage.get_ref(), &sender, Some(&idempotency_key), &req, &content, &idempotency, clock.get_ref(), |_issue| {
+        Flash::Published.redirect("/admin/newsletters")
+    }).await?)
+}
+
+pub async fn log_out(session: Session) -> HttpResponse {
+    session.purge();
+    Flash::LoggedOut.redirect("/login")
+}
@@ -0,0 +1,140 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use secrecy::ExposeSecret;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::authentication::{generate_api_key, ApiScope, AuthError, AuthenticatedSender, Permission};
+use crate::clock::Clock;
+use crate::storage::{Storage, StorageError};
+
+use super::error_chain_fmt;
+
+#[derive(thiserror::Error)]
+pub enum ApiKeyError {
+    #[error("Validation error(s): {0}")]
+    ValidationError(String),
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
+    #[error("API key not found")]
+    NotFound,
+    #[error("Failed to access the API keys store")]
+    StorageError(#[from]StorageError),
+}
+
+impl std::fmt::Debug for ApiKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ApiKeyError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ApiKeyError::ValidationError(errors) => {
+                HttpResponse::BadRequest().json(json!({ "message": errors }))
+            }
+            ApiKeyError::AuthenticationError(err) => err.error_response(),
+            ApiKeyError::NotFound => {
+                HttpResponse::NotFound().json(json!({ "error": self.to_string() }))
+            }
+            ApiKeyError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    /// What the key is for, e.g. `release notes from CI`.
+    name: String,
+    scopes: Vec<String>,
+    /// RFC 3339, a key without one is good until it is revoked.
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+const MAX_API_KEY_NAME_LENGTH: usize = 100;
+
+impl CreateApiKeyRequest {
+    /// The scopes, deduplicated, in the order they were asked for.
+    fn validate(&self, now: DateTime<Utc>) -> Result<Vec<ApiScope>, ApiKeyError> {
+        let mut problems = Vec::new();
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_API_KEY_NAME_LENGTH {
+            problems.push(format!("name: must be 1 to {} characters long", MAX_API_KEY_NAME_LENGTH));
+        }
+        let mut scopes = Vec::new();
+        for scope in &self.scopes {
+            match scope.parse::<ApiScope>() {
+                Ok(scope) if !scopes.contains(&scope) => scopes.push(scope),
+                Ok(_) => {}
+                Err(e) => problems.push(format!("scopes: {}", e)),
+            }
+        }
+        if self.scopes.is_empty() {
+            problems.push("scopes: at least one is needed".to_string());
+        }
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            problems.push("expires_at: must be in the future".to_string());
+        }
+        if problems.is_empty() {
+            Ok(scopes)
+        } else {
+            Err(ApiKeyError::ValidationError(problems.join("; ")))
+        }
+    }
+}
+
+/// The key itself is in this response only, it cannot be looked up later.
+#[tracing::instrument(
+    name = "Issuing an API key",
+    skip(current, req, storage, clock),
+    fields(name = %req.name)
+)]
+pub async fn create_api_key(
+    current: AuthenticatedSender,
+    req: web::Json<CreateApiKeyRequest>,
+    storage: web::Data<dyn Storage>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, ApiKeyError> {
+    current.require_permission(Permission::ManageOwnAccount)?;
+    let scopes = req.validate(clock.now())?;
+    let scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+    let new_key = generate_api_key();
+    let api_key = storage.add_api_key(current.id, req.name.trim(), &new_key.prefix, &new_key.hash, &scopes, req.expires_at).await?;
+    tracing::info!("`{}` issued the API key {}", current.username, api_key.prefix);
+    let mut body = serde_json::to_value(&api_key).expect("An API key is plain data");
+    body["key"] = json!(new_key.key.expose_secret());
+    Ok(HttpResponse::Created().json(body))
+}
+
+#[tracing::instrument(
+    name = "Listing a sender's API keys",
+    skip(current, storage)
+)]
+pub async fn list_api_keys(
+    current: AuthenticatedSender,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ApiKeyError> {
+    current.require_permission(Permission::ManageOwnAccount)?;
+    Ok(HttpResponse::Ok().json(storage.list_api_keys(current.id).await?))
+}
+
+/// Takes effect on the next request made with the key.
+#[tracing::instrument(
+    name = "Revoking an API key",
+    skip(current, storage)
+)]
+pub async fn revoke_api_key(
+    current: AuthenticatedSender,
+    key_id: web::Path<i32>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, ApiKeyError> {
+    current.require_permission(Permission::ManageOwnAccount)?;
+    if !storage.delete_api_key(current.id, key_id.into_inner()).await? {
+        return Err(ApiKeyError::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Why a field was rejected. `code` does not change, clients can match on it.
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Every problem of a request body, by field path (`title`, `content.html`...),
+/// so a client can show each one next to the field it belongs to.
+#[derive(Serialize, Debug, Default)]
+#[serde(transparent)]
+pub struct FieldErrors(BTreeMap<String, Vec<FieldError>>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &str, code: &'static str, message: String) {
+        self.0.entry(field.to_string()).or_default().push(FieldError { code, message });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The minimum is what a reader sees, characters. The maximum is what has to be
+    /// sent and stored, bytes: a character can take up to four of them in UTF-8.
+    pub fn check_length(&mut self, field: &str, value: &str, min_characters: usize, max_bytes: usize) {
+        let characters = value.chars().count();
+        if characters < min_characters {
+            self.add(field, "too_short", format!("must be at least {} characters long, it is {}", min_characters, characters));
+        }
+        if value.len() > max_bytes {
+            self.add(field, "too_long", format!("must be at most {} bytes long, it is {}", max_bytes, value.len()));
+        }
+    }
+
+    /// One `field: message` line per problem.
+    pub fn messages(&self) -> Vec<String> {
+        self.0.iter()
+            .flat_map(|(field, errors)| errors.iter().map(move |error| format!("{}: {}", field, error.message)))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for FieldErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.messages().join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FieldErrors;
+
+    #[test]
+    fn the_maximum_counts_bytes_and_the_minimum_characters() {
+        let mut errors = FieldErrors::default();
+
+        // 4 characters, 8 bytes
+        errors.check_length("short", "éééé", 5, 100);
+        errors.check_length("long", "éééé", 1, 7);
+        errors.check_length("fine", "éééé", 4, 8);
+
+        assert_eq!(errors.to_string(), "long: must be at most 7 bytes long, it is 8; short: must be at least 5 characters long, it is 4");
+    }
+}
@@ -0,0 +1,68 @@
+use actix_web::{cookie::{Cookie, SameSite}, http::header::{self, ContentType}, HttpRequest, HttpResponse};
+
+const FLASH_COOKIE: &str = "_flash";
+
+/// A one-off message for the next page an admin sees.
+/// Only its code travels in the cookie, so nobody can make our pages say something else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flash {
+    LoginFailed,
+    TwoFactorFailed,
+    LoginRequired,
+    LoggedOut,
+    Published,
+}
+
+impl Flash {
+    const ALL: [Flash; 5] = [Flash::LoginFailed, Flash::TwoFactorFailed, Flash::LoginRequired, Flash::LoggedOut, Flash::Published];
+
+    fn code(self) -> &'static str {
+        match self {
+            Flash::LoginFailed => "login_failed",
+            Flash::TwoFactorFailed => "two_factor_failed",
+            Flash::LoginRequired => "login_required",
+            Flash::LoggedOut => "logged_out",
+            Flash::Published => "published",
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Flash::LoginFailed => "Authentication failed.",
+            Flash::TwoFactorFailed => "Invalid code, please try again.",
+            Flash::LoginRequired => "Please log in to continue.",
+            Flash::LoggedOut => "You have successfully logged out.",
+            Flash::Published => "The newsletter issue has been published.",
+        }
+    }
+
+    /// A `303 See Other` to `location`, which shows the message.
+    pub fn redirect(self, location: &str) -> HttpResponse {
+        let cookie = Cookie::build(FLASH_COOKIE, self.code())
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish();
+        HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, location))
+            .cookie(cookie)
+            .finish()
+    }
+
+    /// The message left for this request, if any.
+    pub fn take(request: &HttpRequest) -> Option<Flash> {
+        let cookie = request.cookie(FLASH_COOKIE)?;
+        Self::ALL.into_iter().find(|flash| flash.code() == cookie.value())
+    }
+}
+
+/// An html page, which also clears the flash message it may have shown.
+pub(crate) fn html_page(request: &HttpRequest, mut response: actix_web::HttpResponseBuilder, body: String) -> HttpResponse {
+    response.content_type(ContentType::html());
+    if request.cookie(FLASH_COOKIE).is_some() {
+        let mut removal = Cookie::build(FLASH_COOKIE, "").path("/").finish();
+        removal.make_removal();
+        response.cookie(removal);
+    }
+    response.body(body)
+}
@@ -0,0 +1,216 @@
+use actix_session::{Session, SessionInsertError};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use minijinja::context;
+use secrecy::SecretString;
+use serde::Deserialize;
+
+use crate::authentication::{confirm_enrolment, generate_totp_secret, totp_uri, validate_credentials, verify_second_factor, AuthError, Credentials, PasswordHashing, SESSION_PENDING_SENDER_ID, SESSION_SENDER_ID};
+use crate::clock::Clock;
+use crate::configuration::TwoFactorProperties;
+use crate::storage::{Sender, Storage, StorageError};
+use crate::templating::{AdminPages, TemplateError};
+
+use super::{error_chain_fmt, html_page, Flash};
+
+#[derive(thiserror::Error)]
+pub enum LoginError {
+    #[error("Invalid sender credentials")]
+    InvalidCredentials,
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[source]AuthError),
+    #[error("Failed to start the session")]
+    SessionError(#[from]SessionInsertError),
+    #[error("Failed to render the login page")]
+    TemplateError(#[from]TemplateError),
+    #[error("Failed to access the store")]
+    StorageError(#[from]StorageError),
+}
+
+impl From<AuthError> for LoginError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            // Unknown username and wrong password look the same from the outside
+            AuthError::InvalidCredentials => LoginError::InvalidCredentials,
+            e => LoginError::AuthenticationError(e),
+        }
+    }
+}
+
+impl std::fmt::Debug for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for LoginError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            LoginError::InvalidCredentials => Flash::LoginFailed.redirect("/login"),
+            LoginError::AuthenticationError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+            LoginError::SessionError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+            LoginError::TemplateError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+            LoginError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: SecretString,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorForm {
+    code: String,
+}
+
+/// Wrong codes allowed per login, then the password has to be given again.
+const MAX_TWO_FACTOR_ATTEMPTS: u32 = 5;
+const SESSION_TWO_FACTOR_ATTEMPTS: &str = "two_factor_attempts";
+
+pub async fn login_form(request: HttpRequest, pages: web::Data<AdminPages>) -> Result<HttpResponse, LoginError> {
+    let flash = Flash::take(&request).map(Flash::message);
+    let body = pages.render("admin/login.html", context! { flash })?;
+    Ok(html_page(&request, HttpResponse::Ok(), body))
+}
+
+/// Starts a session for the sender, under a new key so one planted before the login is useless.
+/// A sender with two-factor authentication (or who has to set it up) only gets as far as the code form.
+#[tracing::instrument(
+    name = "Logging a sender in",
+    skip(form, session, storage, password_hashing, two_factor),
+    fields(username = %form.username)
+)]
+pub async fn login(
+    form: web::Form<LoginForm>,
+    session: Session,
+    storage: web::Data<dyn Storage>,
+    password_hashing: web::Data<PasswordHashing>,
+    two_factor: web::Data<TwoFactorProperties>,
+) -> Result<HttpResponse, LoginError> {
+    let form = form.into_inner();
+    let credentials = Credentials { username: form.username, password: form.password };
+    let sender = validate_credentials(credentials, storage.get_ref(), password_hashing.get_ref())
+        .await
+        .map_err(LoginError::from)
+        .map_err(|err| {
+            tracing::warn!("Failed to log in: {:?}", err);
+            err
+        })?;
+    session.renew();
+    let enrolled = storage.find_totp_enrolment(sender.id).await?
+        .is_some_and(|enrolment| enrolment.confirmed_at.is_some());
+    if enrolled || two_factor.required {
+        session.insert(SESSION_PENDING_SENDER_ID, sender.id)?;
+        return Ok(see_other("/login/two-factor"));
+    }
+    session.insert(SESSION_SENDER_ID, sender.id)?;
+    tracing::info!("`{}` logged in", sender.username);
+    Ok(see_other("/admin/dashboard"))
+}
+
+/// Asks for the code, or when two-factor authentication is required and the sender
+/// has none yet, shows the secret to set it up with first.
+pub async fn two_factor_form(
+    request: HttpRequest,
+    session: Session,
+    storage: web::Data<dyn Storage>,
+    pages: web::Data<AdminPages>,
+    two_factor: web::Data<TwoFactorProperties>,
+) -> Result<HttpResponse, LoginError> {
+    let Some(sender) = pending_sender(&session, storage.get_ref()).await? else {
+        return Ok(Flash::LoginRequired.redirect("/login"));
+    };
+    let flash = Flash::take(&request).map(Flash::message);
+    let body = match storage.find_totp_enrolment(sender.id).await? {
+        Some(enrolment) if enrolment.confirmed_at.is_some() => {
+            pages.render("admin/two_factor.html", context! { flash })?
+        }
+        enrolment => {
+            // The secret shown last time is kept, reloading must not break the app just set up
+            let secret = match enrolment {
+                Some(enrolment) => enrolment.secret,
+                None => {
+                    let secret = generate_totp_secret();
+                    storage.start_totp_enrolment(sender.id, &secret).await?;
+                    secret
+                }
+            };
+            let otpauth_uri = totp_uri(&secret, &two_factor.issuer, &sender.username).map_err(AuthError::from)?;
+            pages.render("admin/two_factor.html", context! { flash, enrol => context! { secret, otpauth_uri } })?
+        }
+    };
+    Ok(html_page(&request, HttpResponse::Ok(), body))
+}
+
+/// Finishes the login. A sender who just set up two-factor authentication gets their recovery codes.
+#[tracing::instrument(
+    name = "Checking a sender's second factor",
+    skip(request, form, session, storage, pages, clock)
+)]
+pub async fn two_factor_login(
+    request: HttpRequest,
+    form: web::Form<TwoFactorForm>,
+    session: Session,
+    storage: web::Data<dyn Storage>,
+    pages: web::Data<AdminPages>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, LoginError> {
+    let Some(sender) = pending_sender(&session, storage.get_ref()).await? else {
+        return Ok(Flash::LoginRequired.redirect("/login"));
+    };
+    let now = clock.now();
+    // `Some(recovery codes)` once the code is right, they are new only when the enrolment was just confirmed
+    let outcome = match storage.find_totp_enrolment(sender.id).await? {
+        Some(enrolment) if enrolment.confirmed_at.is_some() => {
+            verify_second_factor(storage.get_ref(), &enrolment, &form.code, now).await?.then_some(None)
+        }
+        Some(enrolment) => confirm_enrolment(storage.get_ref(), &enrolment, &form.code, now).await?.map(Some),
+        None => None,
+    };
+    let Some(recovery_codes) = outcome else {
+        let attempts = session.get::<u32>(SESSION_TWO_FACTOR_ATTEMPTS).map_err(AuthError::from)?.unwrap_or(0) + 1;
+        tracing::warn!("`{}` gave a wrong second factor ({} of {})", sender.username, attempts, MAX_TWO_FACTOR_ATTEMPTS);
+        if attempts >= MAX_TWO_FACTOR_ATTEMPTS {
+            session.purge();
+            return Ok(Flash::LoginFailed.redirect("/login"));
+        }
+        session.insert(SESSION_TWO_FACTOR_ATTEMPTS, attempts)?;
+        return Ok(Flash::TwoFactorFailed.redirect("/login/two-factor"));
+    };
+    session.renew();
+    session.remove(SESSION_PENDING_SENDER_ID);
+    session.remove(SESSION_TWO_FACTOR_ATTEMPTS);
+    session.insert(SESSION_SENDER_ID, sender.id)?;
+    tracing::info!("`{}` logged in", sender.username);
+    match recovery_codes {
+        Some(recovery_codes) => {
+            let body = pages.render("admin/two_factor.html", context! { recovery_codes })?;
+            Ok(html_page(&request, HttpResponse::Ok(), body))
+        }
+        None => Ok(see_other("/admin/dashboard")),
+    }
+}
+
+/// The sender who gave the right password, if they are still allowed in.
+async fn pending_sender(session: &Session, storage: &dyn Storage) -> Result<Option<Sender>, LoginError> {
+    let Some(sender_id) = session.get::<i32>(SESSION_PENDING_SENDER_ID).map_err(AuthError::from)? else {
+        return Ok(None);
+    };
+    Ok(storage.find_sender_by_id(sender_id).await?.filter(|sender| sender.enabled))
+}
+
+fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((actix_web::http::header::LOCATION, location))
+        .finish()
+}
@@ -1,11 +1,33 @@
+mod admin;
+mod api_keys;
+mod field_errors;
+mod flash;
 mod health_check;
+mod login;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_unsubscribe;
 mod newsletters;
+mod newsletter_drafts;
+mod newsletter_issues;
+mod newsletter_preview;
+mod senders;
+mod two_factor;
+pub use admin::*;
+pub use api_keys::*;
+pub use field_errors::*;
+pub use flash::*;
+pub use login::*;
 pub use newsletters::*;
+pub use newsletter_drafts::*;
+pub use newsletter_issues::*;
+pub use newsletter_preview::*;
+pub use senders::*;
+pub use two_factor::*;
 pub use health_check::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_unsubscribe::*;
 
 // TODO: Ask AI to explain this syntax later or simplify it
 pub fn error_chain_fmt(
@@ -0,0 +1,165 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{authentication::{AuthError, AuthenticatedSender, Permission}, clock::Clock, storage::{NewsletterIssue, Storage, StorageError}};
+
+use crate::configuration::ContentLimitsProperties;
+use crate::templating::NewsletterLayout;
+
+use super::{error_chain_fmt, ContentError, FieldErrors, NewsletterRequest};
+
+#[derive(thiserror::Error)]
+pub enum DraftError {
+    #[error("Validation error(s): {0}")]
+    ValidationError(String),
+    #[error("Invalid content: {0}")]
+    InvalidContent(FieldErrors),
+    #[error("Invalid template(s): {}", .0.join("; "))]
+    InvalidTemplate(Vec<String>),
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
+    #[error("Newsletter issue not found")]
+    NotFound,
+    #[error("The issue is already {0}, it can no longer be changed")]
+    NotEditable(String),
+    #[error("Failed to access the newsletter store")]
+    StorageError(#[from]StorageError),
+}
+
+impl From<ContentError> for DraftError {
+    fn from(e: ContentError) -> Self {
+        match e {
+            ContentError::Fields(errors) => DraftError::InvalidContent(errors),
+            ContentError::Template(problems) => DraftError::InvalidTemplate(problems),
+        }
+    }
+}
+
+impl std::fmt::Debug for DraftError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for DraftError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            DraftError::ValidationError(errors) => {
+                HttpResponse::BadRequest().json(json!({ "message": errors }))
+            }
+            DraftError::InvalidContent(errors) => {
+                HttpResponse::BadRequest().json(json!({ "message": "Validation error(s)", "errors": errors }))
+            }
+            DraftError::InvalidTemplate(problems) => {
+                HttpResponse::BadRequest().json(json!({ "message": "Invalid template(s)", "problems": problems }))
+            }
+            DraftError::AuthenticationError(err) => err.error_response(),
+            DraftError::NotFound => {
+                HttpResponse::NotFound().json(json!({ "error": self.to_string() }))
+            }
+            DraftError::NotEditable(_) => {
+                HttpResponse::Conflict().json(json!({ "error": self.to_string() }))
+            }
+            DraftError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleRequest {
+    /// RFC 3339, e.g. `2025-07-01T09:00:00Z`.
+    send_at: DateTime<Utc>,
+}
+
+/// Run a guarded change on an issue, telling a missing issue from one that moved on.
+async fn edit_issue(storage: &dyn Storage, issue_id: i32, changed: Result<bool, StorageError>) -> Result<NewsletterIssue, DraftError> {
+    let changed = changed?;
+    let issue = storage.find_newsletter_issue(issue_id).await?
+        .ok_or(DraftError::NotFound)?;
+    if !changed {
+        return Err(DraftError::NotEditable(issue.status));
+    }
+    Ok(issue)
+}
+
+#[tracing::instrument(
+    name = "Saving a newsletter draft",
+    skip(sender, req, storage, layout, limits),
+    fields(%req.title)
+)]
+pub async fn create_draft(
+    sender: AuthenticatedSender,
+    req: web::Json<NewsletterRequest>,
+    storage: web::Data<dyn Storage>,
+    layout: web::Data<NewsletterLayout>,
+    limits: web::Data<ContentLimitsProperties>,
+) -> Result<HttpResponse, DraftError> {
+    sender.require_permission(Permission::PublishNewsletters)?;
+    let content = req.prepare(&layout, &limits)?;
+    let issue = storage.add_draft_issue(sender.id, &req.title, &content.text, &content.html).await?;
+    Ok(HttpResponse::Created().json(issue))
+}
+
+#[tracing::instrument(
+    name = "Editing a newsletter draft",
+    skip(sender, req, storage, layout, limits),
+    fields(%req.title)
+)]
+pub async fn update_draft(
+    sender: AuthenticatedSender,
+    issue_id: web::Path<i32>,
+    req: web::Json<NewsletterRequest>,
+    storage: web::Data<dyn Storage>,
+    layout: web::Data<NewsletterLayout>,
+    limits: web::Data<ContentLimitsProperties>,
+) -> Result<HttpResponse, DraftError> {
+    sender.require_permission(Permission::PublishNewsletters)?;
+    let content = req.prepare(&layout, &limits)?;
+    let issue_id = issue_id.into_inner();
+    let changed = storage.update_draft_issue(issue_id, &req.title, &content.text, &content.html).await;
+    let issue = edit_issue(storage.get_ref(), issue_id, changed).await?;
+    Ok(HttpResponse::Ok().json(issue))
+}
+
+/// Scheduling an already scheduled issue moves it to the new time.
+#[tracing::instrument(
+    name = "Scheduling a newsletter issue",
+    skip(sender, req, storage, clock),
+    fields(send_at = %req.send_at)
+)]
+pub async fn schedule_draft(
+    sender: AuthenticatedSender,
+    issue_id: web::Path<i32>,
+    req: web::Json<ScheduleRequest>,
+    storage: web::Data<dyn Storage>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, DraftError> {
+    sender.require_permission(Permission::PublishNewsletters)?;
+    if req.send_at <= clock.now() {
+        return Err(DraftError::ValidationError("send_at must be in the future".to_string()));
+    }
+    let issue_id = issue_id.into_inner();
+    let changed = storage.schedule_issue(issue_id, req.send_at).await;
+    let issue = edit_issue(storage.get_ref(), issue_id, changed).await?;
+    Ok(HttpResponse::Ok().json(issue))
+}
+
+#[tracing::instrument(
+    name = "Cancelling a newsletter issue",
+    skip(sender, storage)
+)]
+pub async fn cancel_draft(
+    sender: AuthenticatedSender,
+    issue_id: web::Path<i32>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, DraftError> {
+    sender.require_permission(Permission::PublishNewsletters)?;
+    let issue_id = issue_id.into_inner();
+    let changed = storage.cancel_issue(issue_id).await;
+    let issue = edit_issue(storage.get_ref(), issue_id, changed).await?;
+    Ok(HttpResponse::Ok().json(issue))
+}
@@ -0,0 +1,116 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use serde::Deserialize;
+use serde_json::json;
+use validator::Validate;
+
+use crate::{authentication::{AuthError, AuthenticatedSender, Permission}, storage::{Storage, StorageError}};
+
+use super::{error_chain_fmt};
+
+#[derive(thiserror::Error)]
+pub enum IssueError {
+    #[error("Validation error(s): {0}")]
+    ValidationError(String),
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
+    #[error("Newsletter issue not found")]
+    NotFound,
+    #[error("Failed to access the newsletter store")]
+    StorageError(#[from]StorageError),
+}
+
+impl std::fmt::Debug for IssueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for IssueError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            IssueError::ValidationError(errors) => {
+                HttpResponse::BadRequest().json(json!({ "message": errors }))
+            }
+            IssueError::AuthenticationError(err) => err.error_response(),
+            IssueError::NotFound => {
+                HttpResponse::NotFound().json(json!({ "error": self.to_string() }))
+            }
+            IssueError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct Pagination {
+    #[validate(range(min = 1))]
+    #[serde(default = "default_page")]
+    page: i64,
+    #[validate(range(min = 1, max = 100))]
+    #[serde(default = "default_per_page")]
+    per_page: i64,
+}
+
+fn default_page() -> i64 {
+    1
+}
+
+fn default_per_page() -> i64 {
+    20
+}
+
+/// Newest first, without the content, with the delivery counts of every issue.
+#[tracing::instrument(
+    name = "Listing the newsletter issues",
+    skip(sender, pagination, storage),
+    fields(page = pagination.page, per_page = pagination.per_page)
+)]
+pub async fn list_newsletter_issues(
+    sender: AuthenticatedSender,
+    pagination: web::Query<Pagination>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, IssueError> {
+    sender.require_permission(Permission::PublishNewsletters)?;
+    if let Err(errors) = pagination.validate() {
+        return Err(IssueError::ValidationError(errors.to_string()));
+    }
+    let offset = (pagination.page - 1).saturating_mul(pagination.per_page);
+    let issues = storage.list_newsletter_issues(pagination.per_page, offset).await?;
+    let mut summaries = Vec::with_capacity(issues.len());
+    for issue in issues {
+        let deliveries = storage.count_issue_deliveries(issue.id).await?;
+        summaries.push(json!({
+            "id": issue.id,
+            "sender_id": issue.sender_id,
+            "title": issue.title,
+            "created_at": issue.created_at,
+            "published_at": issue.published_at,
+            "deliveries": deliveries,
+        }));
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "issues": summaries,
+        "page": pagination.page,
+        "per_page": pagination.per_page,
+        "total": storage.count_newsletter_issues().await?,
+    })))
+}
+
+#[tracing::instrument(
+    name = "Fetching a newsletter issue",
+    skip(sender, storage)
+)]
+pub async fn get_newsletter_issue(
+    sender: AuthenticatedSender,
+    issue_id: web::Path<i32>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, IssueError> {
+    sender.require_permission(Permission::PublishNewsletters)?;
+    let issue = storage.find_newsletter_issue(issue_id.into_inner()).await?
+        .ok_or(IssueError::NotFound)?;
+    let deliveries = storage.count_issue_deliveries(issue.id).await?;
+    let mut body = serde_json::to_value(&issue).expect("An issue is plain data");
+    body["deliveries"] = json!(deliveries);
+    Ok(HttpResponse::Ok().json(body))
+}
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, HashMap};
+
+use actix_web::{web, HttpResponse, ResponseError};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{authentication::{AuthError, AuthenticatedSender, Permission}, email_client::{EmailClient, OutgoingEmail}, startup::ApplicationBaseUrl, storage::{Storage, StorageError}};
+use crate::configuration::ContentLimitsProperties;
+use crate::templating::{NewsletterLayout, RecipientContext, RenderedEmail, TemplateError};
+
+use super::{error_chain_fmt, ContentError, FieldErrors, NewsletterRequest};
+
+#[derive(thiserror::Error)]
+pub enum PreviewError {
+    #[error("Validation error(s): {0}")]
+    ValidationError(String),
+    #[error("Invalid content: {0}")]
+    InvalidContent(FieldErrors),
+    #[error("Invalid template(s): {}", .0.join("; "))]
+    InvalidTemplate(Vec<String>),
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
+    #[error("Not one of your test addresses: {}", .0.join(", "))]
+    RecipientsNotAllowed(Vec<String>),
+    #[error("Failed to render the newsletter")]
+    TemplateError(#[from]TemplateError),
+    #[error("Failed to access the senders store")]
+    StorageError(#[from]StorageError),
+}
+
+impl From<ContentError> for PreviewError {
+    fn from(e: ContentError) -> Self {
+        match e {
+            ContentError::Fields(errors) => PreviewError::InvalidContent(errors),
+            ContentError::Template(problems) => PreviewError::InvalidTemplate(problems),
+        }
+    }
+}
+
+impl std::fmt::Debug for PreviewError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreviewError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PreviewError::ValidationError(errors) => {
+                HttpResponse::BadRequest().json(json!({ "message": errors }))
+            }
+            PreviewError::InvalidContent(errors) => {
+                HttpResponse::BadRequest().json(json!({ "message": "Validation error(s)", "errors": errors }))
+            }
+            PreviewError::InvalidTemplate(problems) => {
+                HttpResponse::BadRequest().json(json!({ "message": "Invalid template(s)", "problems": problems }))
+            }
+            PreviewError::AuthenticationError(err) => err.error_response(),
+            PreviewError::RecipientsNotAllowed(_) => {
+                HttpResponse::Forbidden().json(json!({ "error": self.to_string() }))
+            }
+            PreviewError::TemplateError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+            PreviewError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TestSendRequest {
+    #[serde(flatten)]
+    newsletter: NewsletterRequest,
+    recipients: Vec<String>,
+}
+
+/// Test emails say so, they must never be mistaken for the real issue.
+const TEST_SUBJECT_PREFIX: &str = "[Test] ";
+
+/// Who a preview is rendered for, there is no real subscriber behind it.
+const PREVIEW_USERNAME: &str = "Ursula Le Guin";
+const PREVIEW_EMAIL: &str = "ursula_le_guin@example.com";
+
+/// Test recipients are not subscribers, their unsubscribe link goes to the landing page without a token.
+fn placeholder_unsubscribe_url(base_url: &str) -> String {
+    format!("{}/subscriptions/unsubscribe", base_url)
+}
+
+#[tracing::instrument(
+    name = "Previewing a newsletter",
+    skip(sender, req, layout, limits, base_url),
+    fields(%req.title)
+)]
+pub async fn preview_newsletter(
+    sender: AuthenticatedSender,
+    req: web::Json<NewsletterRequest>,
+    layout: web::Data<NewsletterLayout>,
+    limits: web::Data<ContentLimitsProperties>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, PreviewError> {
+    sender.require_permission(Permission::PublishNewsletters)?;
+    let content = req.prepare(&layout, &limits)?;
+    let rendered = content.template.render(&RecipientContext {
+        username: PREVIEW_USERNAME,
+        email: PREVIEW_EMAIL,
+        unsubscribe_url: &placeholder_unsubscribe_url(&base_url.0),
+        fields: &BTreeMap::new(),
+    })?;
+    Ok(HttpResponse::Ok().json(json!({
+        "subject": rendered.subject,
+        "html": rendered.html,
+        "text": rendered.text,
+        "warnings": content.warnings,
+    })))
+}
+
+/// Deliver the issue right away, only to the sender's own test addresses.
+/// Subscribers, the delivery queue and the issue history are left alone.
+#[tracing::instrument(
+    name = "Sending a test newsletter",
+    skip(sender, req, storage, email_client, layout, limits, base_url),
+    fields(title = %req.newsletter.title)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn test_send_newsletter(
+    sender: AuthenticatedSender,
+    req: web::Json<TestSendRequest>,
+    storage: web::Data<dyn Storage>,
+    email_client: web::Data<EmailClient>,
+    layout: web::Data<NewsletterLayout>,
+    limits: web::Data<ContentLimitsProperties>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, PreviewError> {
+    sender.require_permission(Permission::PublishNewsletters)?;
+    let content = req.newsletter.prepare(&layout, &limits)?;
+    if req.recipients.is_empty() {
+        return Err(PreviewError::ValidationError("recipients cannot be empty".to_string()));
+    }
+    let allowed = storage.list_sender_test_addresses(sender.id).await?;
+    let not_allowed: Vec<String> = req.recipients.iter()
+        .filter(|recipient| !allowed.contains(recipient))
+        .cloned()
+        .collect();
+    if !not_allowed.is_empty() {
+        return Err(PreviewError::RecipientsNotAllowed(not_allowed));
+    }
+
+    // Rendered for the sender, at each of their addresses
+    let unsubscribe_url = placeholder_unsubscribe_url(&base_url.0);
+    let no_fields = BTreeMap::new();
+    let rendered: Vec<RenderedEmail> = req.recipients.iter()
+        .map(|email| content.template.render(&RecipientContext {
+            username: &sender.display_name,
+            email,
+            unsubscribe_url: &unsubscribe_url,
+            fields: &no_fields,
+        }))
+        .collect::<Result<_, _>>()?;
+    let subjects: Vec<String> = rendered.iter()
+        .map(|rendered| format!("{}{}", TEST_SUBJECT_PREFIX, rendered.subject))
+        .collect();
+    let messages: Vec<OutgoingEmail> = req.recipients.iter().zip(&rendered).zip(&subjects)
+        .map(|((recipient, rendered), subject)| OutgoingEmail {
+            recipient,
+            subject,
+            html_content: &rendered.html,
+            text_content: &rendered.text,
+            headers: HashMap::new(),
+        })
+        .collect();
+    let outcomes = email_client.send_emails(&messages).await;
+    let mut sent = Vec::new();
+    let mut failed = Vec::new();
+    for (recipient, outcome) in req.recipients.iter().zip(outcomes) {
+        match outcome {
+            Ok(()) => sent.push(recipient.clone()),
+            Err(e) => {
+                tracing::warn!("Test email to {} failed: {:?}", recipient, e);
+                failed.push(json!({ "email": recipient, "error": e.to_string() }));
+            }
+        }
+    }
+    Ok(HttpResponse::Ok().json(json!({ "sent": sent, "failed": failed })))
+}
@@ -1,33 +1,38 @@
-use actix_web::{http::{self, header::{self, HeaderMap, HeaderValue}, StatusCode}, web, HttpRequest, HttpResponse, ResponseError};
-use base64::Engine;
-use serde::Deserialize;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use validator::Validate;
-use secrecy::{ExposeSecret, SecretString};
-use sha3::Digest;
+use sha2::Digest;
 
-use crate::{email_client::EmailClient, in_memory::AppState};
+use crate::{authentication::{AuthError, AuthenticatedSender, Permission}, storage::{NewsletterIssue, Sender, Storage, StorageError}};
+use crate::clock::Clock;
+use crate::configuration::{ContentLimitsProperties, IdempotencyProperties};
+use crate::idempotency::{save_response, try_processing, IdempotencyError, IdempotencyKey, NextAction};
+use crate::{email_html, markdown};
+use crate::templating::{NewsletterLayout, NewsletterTemplate, TemplateError};
 
-use super::error_chain_fmt;
+use super::{error_chain_fmt, FieldErrors};
 
 #[derive(thiserror::Error)]
 pub enum PublishError {
-    #[error("Validation error(s): {0}")]
-    ValidationError(String),
-    #[error("Failed to send newsletter email")]
-    SendEmailError(#[from]reqwest::Error),
-    #[error("Missing Authorization header")]
-    MissingAuthorizationHeader,
-    #[error("Failed to decode Authorization header")]
-    DecodeAuthorizationHeaderError(#[from]base64::DecodeError),
-    #[error("Failed to parse Authorization header")]
-    ParseAuthorizationHeaderError(#[from]std::string::FromUtf8Error),
-    #[error("The 'Authorization' header was not a valid UTF8 string.")]
-    InvalidAuthorizationHeaderUTFString(#[from]http::header::ToStrError),
-    #[error("Unauthorized sender username")]
-    UnauthorizedSenderUsernameError,
-    #[error("Wrong sender password")]
-    WrongSenderPasswordError,
+    #[error("Invalid content: {0}")]
+    InvalidContent(FieldErrors),
+    #[error("Invalid template(s): {}", .0.join("; "))]
+    InvalidTemplate(Vec<String>),
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
+    #[error("Failed to honour the Idempotency-Key")]
+    IdempotencyError(#[from]IdempotencyError),
+    #[error("Failed to access the newsletter store")]
+    StorageError(#[from]StorageError),
+}
+
+impl From<ContentError> for PublishError {
+    fn from(e: ContentError) -> Self {
+        match e {
+            ContentError::Fields(errors) => PublishError::InvalidContent(errors),
+            ContentError::Template(problems) => PublishError::InvalidTemplate(problems),
+        }
+    }
 }
 
 impl std::fmt::Debug for PublishError {
@@ -36,169 +41,221 @@ impl std::fmt::Debug for PublishError {
     }
 }
 
-fn create_response_for_auth_error(status: u16) -> HttpResponse {
-    let mut response = HttpResponse::new(StatusCode::from_u16(status).unwrap());
-    let header_value = HeaderValue::from_str(r#"Basic realm="publish""#)
-    .unwrap();
-    response
-    .headers_mut()
-    // actix_web::http::header provides a collection of constants
-    // for the names of several well-known/standard HTTP headers
-    .insert(header::WWW_AUTHENTICATE, header_value);
-    response
-}
-
 impl ResponseError for PublishError {
     fn error_response(&self) -> HttpResponse {
         match self {
-            PublishError::ValidationError(errors) => {
-                HttpResponse::BadRequest().json(json!({ "message": errors }))
-            }
-            PublishError::SendEmailError(_err) => {
-                HttpResponse::BadGateway().finish()
-            }
-            PublishError::MissingAuthorizationHeader => {
-                create_response_for_auth_error(401)
-            }
-            PublishError::DecodeAuthorizationHeaderError(_err) => {
-                create_response_for_auth_error(400)
-            }
-            PublishError::ParseAuthorizationHeaderError(_err) => {
-                create_response_for_auth_error(400)
+            PublishError::InvalidContent(errors) => {
+                HttpResponse::BadRequest().json(json!({ "message": "Validation error(s)", "errors": errors }))
             }
-            PublishError::InvalidAuthorizationHeaderUTFString(_err) => {
-                create_response_for_auth_error(400)
+            PublishError::InvalidTemplate(problems) => {
+                HttpResponse::BadRequest().json(json!({ "message": "Invalid template(s)", "problems": problems }))
             }
-            PublishError::UnauthorizedSenderUsernameError => {
-                create_response_for_auth_error(401)
-            }
-            PublishError::WrongSenderPasswordError => {
-                create_response_for_auth_error(401)
+            PublishError::AuthenticationError(err) => err.error_response(),
+            PublishError::IdempotencyError(err) => match err {
+                IdempotencyError::InvalidKey(_) => {
+                    HttpResponse::BadRequest().json(json!({ "message": err.to_string() }))
+                }
+                IdempotencyError::RequestMismatch => {
+                    HttpResponse::UnprocessableEntity().json(json!({ "error": err.to_string() }))
+                }
+                IdempotencyError::InFlight => {
+                    HttpResponse::Conflict().json(json!({ "error": err.to_string() }))
+                }
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+            PublishError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
             }
         }
     }
 }
 
-#[derive(Deserialize, Validate)]
+/// Lengths are checked against `ContentLimitsProperties` by `prepare`.
+#[derive(Deserialize, Serialize)]
 pub struct NewsletterRequest {
-    #[validate(length(min = 5, max = 50))]
-    title: String,
-    #[validate()]
-    content: Content,
+    pub(crate) title: String,
+    pub(crate) content: Content,
 }
 
-#[derive(Deserialize, Validate)]
-struct Content {
-    #[validate(length(min = 10, max = 500))]
-    text: String,
-    #[validate(length(min = 10, max = 500))]
-    html: String,
+/// Either `markdown`, or both `html` and `text` written by hand.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct Content {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) html: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) markdown: Option<String>,
 }
 
-struct Credentials {
-    username: String,
-    password: SecretString,
+const MISSING_CONTENT: &str = "needs either `markdown`, or both `html` and `text`";
+
+/// The html and text parts of an issue as they are stored, checked as templates.
+pub(crate) struct IssueContent {
+    pub(crate) html: String,
+    pub(crate) text: String,
+    pub(crate) template: NewsletterTemplate,
+    /// What the sanitizer took out of hand-written html.
+    pub(crate) warnings: Vec<String>,
 }
 
-fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, PublishError> {
-    let header = headers
-        .get("Authorization")
-        .ok_or(PublishError::MissingAuthorizationHeader)?
-        .to_str()
-        .map_err(|err| PublishError::InvalidAuthorizationHeaderUTFString(err))?;
-    // lose the Basic keyword and decode the base64 encoded string
-    let parts: Vec<&str> = header.split_whitespace().collect();
-    let decoded = base64::engine::general_purpose::STANDARD.decode(parts[1])
-        .map_err(PublishError::DecodeAuthorizationHeaderError)?;
-
-    let decoded_str = String::from_utf8(decoded)
-        .map_err(PublishError::ParseAuthorizationHeaderError)?;
-
-    let mut creds= decoded_str.splitn(2 ,":");
-    Ok(Credentials {
-        username: creds.next().unwrap().to_string(),
-        password: SecretString::new(Box::from(creds.next().unwrap().to_string()))
-    })
+pub(crate) enum ContentError {
+    /// Every field that is missing or out of `ContentLimitsProperties`.
+    Fields(FieldErrors),
+    /// Every syntax error and unknown variable of the templates.
+    Template(Vec<String>),
 }
 
-// #[tracing::instrument(
-//     name = "Validating sender credentials",
-//     skip(cred, app_state),
-// )]
-// async fn validate_sender_credentials(username: &str, password: &SecretString, app_state: &AppState) -> Result<(), PublishError> {
-//     let senders = app_state.senders.read().expect("RwLock poisoned");
-//     let sender = senders
-//         .iter().find(|s| s.username == *username);
-//     let hashed_value_of_password = format!("{:x}", sha3::Sha3_256::digest(password.expose_secret().as_bytes()));
-//     if sender.unwrap().pwd != hashed_value_of_password {
-//         return Err(PublishError::WrongSenderPasswordError);
-//     }
-//     Ok(())
-// }
-#[tracing::instrument(
-    name = "Validating sender credentials",
-    skip(username, password, app_state),
-)]
-async fn validate_sender_credentials(username: &str, password: &SecretString, app_state: &AppState) -> Result<(), PublishError> {
-    let senders = app_state.senders.read().expect("RwLock poisoned");
-    let sender = senders
-        .iter().find(|s| s.username == *username);
-    let hashed_value_of_password = format!("{:x}", sha3::Sha3_256::digest(password.expose_secret().as_bytes()));
-    if sender.unwrap().pwd != hashed_value_of_password {
-        return Err(PublishError::WrongSenderPasswordError);
+impl NewsletterRequest {
+    /// The parts to store, rendered from markdown inside `layout` if that is how the issue was written,
+    /// sanitized if the html was written by hand.
+    /// The title and every part given are checked against `limits`, and what markdown renders to as well:
+    /// that is what gets sent.
+    pub(crate) fn prepare(&self, layout: &NewsletterLayout, limits: &ContentLimitsProperties) -> Result<IssueContent, ContentError> {
+        let mut errors = FieldErrors::default();
+        errors.check_length("title", &self.title, limits.min_title_characters, limits.max_title_bytes);
+        let parts = [("content.markdown", &self.content.markdown), ("content.html", &self.content.html), ("content.text", &self.content.text)];
+        for (field, part) in parts {
+            if let Some(part) = part {
+                errors.check_length(field, part, limits.min_body_characters, limits.max_body_bytes);
+            }
+        }
+        match (&self.content.markdown, &self.content.html, &self.content.text) {
+            (Some(_), None, None) | (None, Some(_), Some(_)) => {}
+            (Some(_), _, _) => errors.add("content", "conflict", "`markdown` cannot be combined with `html` or `text`".to_string()),
+            _ => errors.add("content", "required", MISSING_CONTENT.to_string()),
+        }
+        if !errors.is_empty() {
+            return Err(ContentError::Fields(errors));
+        }
+
+        let (html, text, warnings) = match (&self.content.markdown, &self.content.html, &self.content.text) {
+            (Some(source), _, _) => {
+                let rendered = markdown::render(source);
+                let html = layout.render(&self.title, &rendered.html)
+                    .map_err(|e| ContentError::Template(vec![format!("layout: {}", template_error_message(&e))]))?;
+                for (part, rendered) in [("html", &html), ("text", &rendered.text)] {
+                    if rendered.len() > limits.max_body_bytes {
+                        errors.add("content.markdown", "rendered_too_long", format!(
+                            "renders to {} bytes of {}, at most {} are allowed", rendered.len(), part, limits.max_body_bytes));
+                    }
+                }
+                if !errors.is_empty() {
+                    return Err(ContentError::Fields(errors));
+                }
+                (inline_css(layout, html), rendered.text, Vec::new())
+            }
+            // Inlined first, the sanitizer drops `<style>` blocks
+            (None, Some(html), Some(text)) => {
+                let sanitized = email_html::sanitize(&inline_css(layout, html.clone()));
+                (sanitized.html, text.clone(), sanitized.warnings)
+            }
+            _ => unreachable!("the form of the content was checked above"),
+        };
+        let template = NewsletterTemplate::compile(&self.title, &html, &text)
+            .map_err(|e| ContentError::Template(match e {
+                TemplateError::Invalid(problems) => problems,
+                e => vec![template_error_message(&e)],
+            }))?;
+        Ok(IssueContent { html, text, template, warnings })
+    }
+}
+
+fn inline_css(layout: &NewsletterLayout, html: String) -> String {
+    if layout.inlines_css() {
+        email_html::inline_css(&html)
+    } else {
+        html
     }
-    Ok(())
 }
 
+fn template_error_message(e: &TemplateError) -> String {
+    match std::error::Error::source(e) {
+        Some(source) => format!("{}: {}", e, source),
+        None => e.to_string(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(
     name = "Publishing a newsletter",
-    skip(req, email_client, app_state),
+    skip(sender, req, storage, layout, limits, idempotency, clock, request),
     fields(
         %req.title,
     )
 )]
 pub async fn publish_newsletter(
+    sender: AuthenticatedSender,
     req: web::Json<NewsletterRequest>,
-    email_client: web::Data<EmailClient>,
-    app_state: web::Data<AppState>,
+    storage: web::Data<dyn Storage>,
+    layout: web::Data<NewsletterLayout>,
+    limits: web::Data<ContentLimitsProperties>,
+    idempotency: web::Data<IdempotencyProperties>,
+    clock: web::Data<dyn Clock>,
     request: HttpRequest) -> Result<HttpResponse, PublishError> {
-    let _credentials = basic_authentication(request.headers())
-        .map_err(|err| {
-            tracing::warn!("Failed to authenticate request: {:?}", err);
-            err
-        })?;
-    let app_state_clone = app_state.clone();
-    let validation_handle = tokio::spawn(async move {
-        validate_sender_credentials(&_credentials.username, &_credentials.password, &app_state_clone).await
-    });
-
-    
-    match req.validate() {
-        Ok(_) => match req.content.validate(){
-            Ok(_) => println!("Request for publish passed validation"),
-            Err(errors) => {return Err(PublishError::ValidationError(errors.to_string()));}
-        },
-        Err(errors) => {return Err(PublishError::ValidationError(errors.to_string()));}
-    }
+    sender.require_permission(Permission::PublishNewsletters)?;
+    let content = req.prepare(&layout, &limits)?;
 
-    match validation_handle.await {
-        Ok(result) => result?,
-        Err(_) => return Err(PublishError::UnauthorizedSenderUsernameError),
+    // Keys are scoped to the sender, two senders never see each other's responses
+    let idempotency_key = match request.headers().get("Idempotency-Key") {
+        Some(value) => {
+            let value = value.to_str()
+                .map_err(|_| IdempotencyError::InvalidKey("it must be a visible ASCII string".to_string()))?;
+            Some(IdempotencyKey::try_from(value.to_string())?)
+        }
+        None => None,
+    };
+    publish_once(storage.get_ref(), &sender, idempotency_key.as_ref(), &req, &content, &idempotency, clock.get_ref(), |issue| {
+        // Unsubscribed (and pending) subscribers are left out, the delivery worker
+        // takes it from here so the request does not wait for the email provider.
+        HttpResponse::Accepted().json(json!({ "id": issue.id, "warnings": content.warnings }))
+    }).await
+}
+
+/// Queue the issue, unless `idempotency_key` was used for it already: then the response saved
+/// the first time is replayed. `respond` builds the response of a newly queued issue.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn publish_once(
+    storage: &dyn Storage,
+    sender: &Sender,
+    idempotency_key: Option<&IdempotencyKey>,
+    req: &NewsletterRequest,
+    content: &IssueContent,
+    idempotency: &IdempotencyProperties,
+    clock: &dyn Clock,
+    respond: impl FnOnce(&NewsletterIssue) -> HttpResponse,
+) -> Result<HttpResponse, PublishError> {
+    if let Some(key) = idempotency_key {
+        match try_processing(storage, sender.id, key, &request_hash(req), idempotency, clock).await? {
+            NextAction::StartProcessing => {}
+            NextAction::ReturnSavedResponse(saved_response) => {
+                tracing::info!("Replaying the response for Idempotency-Key `{}`", key.as_ref());
+                return Ok(saved_response);
+            }
+        }
     }
 
-    let subscriptions = app_state.subscriptions.read().expect("RwLock poisoned");
-    let recipients: Vec<String> = subscriptions.iter()
-        .filter(|s| s.status == "confirmed")
-        .map(|s| s.email.clone()).collect();
-    if !recipients.is_empty(){
-        email_client.send_email(
-            recipients,
-            &req.title,
-            &req.content.html,
-            &req.content.text
-        ).await?;
+    let issue = match storage.add_newsletter_issue(sender.id, &req.title, &content.text, &content.html).await {
+        Ok(issue) => issue,
+        Err(e) => {
+            // Nothing was queued, let the client retry with the same key
+            if let Some(key) = idempotency_key {
+                storage.release_idempotency_key(sender.id, key.as_ref()).await?;
+            }
+            return Err(e.into());
+        }
+    };
+    tracing::info!("Newsletter issue {} queued by `{}`", issue.id, sender.username);
+
+    let response = respond(&issue);
+    match idempotency_key {
+        Some(key) => Ok(save_response(storage, sender.id, key, response).await?),
+        None => Ok(response),
     }
-    
+}
 
-    Ok(HttpResponse::Ok().finish())
-}
\ No newline at end of file
+/// Fingerprint of the parsed body, so formatting differences do not count as a different request.
+fn request_hash(req: &NewsletterRequest) -> String {
+    let body = serde_json::to_vec(req).expect("The request is plain strings");
+    hex::encode(sha2::Sha256::digest(&body))
+}
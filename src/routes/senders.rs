@@ -0,0 +1,267 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use secrecy::SecretString;
+use serde::Deserialize;
+use serde_json::json;
+use validator::{Validate, ValidateEmail};
+
+use crate::{authentication::{AuthError, AuthMethod, AuthenticatedSender, PasswordHashing, Permission}, storage::{SenderRole, Storage, StorageError}};
+
+use super::error_chain_fmt;
+
+static SENDER_USERNAME_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^[a-zA-Z0-9_.-]+$").unwrap()
+});
+
+#[derive(thiserror::Error)]
+pub enum SenderError {
+    #[error("Validation error(s): {0}")]
+    ValidationError(String),
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
+    #[error("Sender username is already taken: {0}")]
+    UsernameTaken(String),
+    #[error("Sender not found")]
+    NotFound,
+    #[error("You cannot disable, delete or change the role of your own account")]
+    OwnAccount,
+    #[error("Failed to access the senders store")]
+    StorageError(#[from]StorageError),
+}
+
+impl std::fmt::Debug for SenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SenderError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SenderError::ValidationError(errors) => {
+                HttpResponse::BadRequest().json(json!({ "message": errors }))
+            }
+            SenderError::AuthenticationError(err) => err.error_response(),
+            SenderError::UsernameTaken(_) => {
+                HttpResponse::Conflict().json(json!({ "error": self.to_string() }))
+            }
+            SenderError::NotFound => {
+                HttpResponse::NotFound().json(json!({ "error": self.to_string() }))
+            }
+            SenderError::OwnAccount => {
+                HttpResponse::Conflict().json(json!({ "error": self.to_string() }))
+            }
+            SenderError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct CreateSenderRequest {
+    #[validate(length(min = 3, max = 50))]
+    #[validate(regex(path = *SENDER_USERNAME_REGEX))]
+    username: String,
+    #[validate(length(min = 1, max = 100))]
+    display_name: String,
+    #[validate(length(min = 8, max = 128))]
+    password: String,
+    #[serde(default = "default_role")]
+    role: SenderRole,
+}
+
+/// Most senders are there to write newsletters.
+fn default_role() -> SenderRole {
+    SenderRole::Editor
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleRequest {
+    role: SenderRole,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ChangePasswordRequest {
+    #[validate(length(min = 8, max = 128))]
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct TestAddressesRequest {
+    addresses: Vec<String>,
+}
+
+/// More than enough for a team's inboxes, without turning test sends into a mailing list.
+const MAX_TEST_ADDRESSES: usize = 20;
+
+#[tracing::instrument(
+    name = "Creating a sender",
+    skip(sender, req, storage, password_hashing),
+    fields(%req.username)
+)]
+pub async fn create_sender(
+    sender: AuthenticatedSender,
+    req: web::Json<CreateSenderRequest>,
+    storage: web::Data<dyn Storage>,
+    password_hashing: web::Data<PasswordHashing>,
+) -> Result<HttpResponse, SenderError> {
+    sender.require_permission(Permission::ManageSenders)?;
+    if let Err(errors) = req.validate() {
+        return Err(SenderError::ValidationError(errors.to_string()));
+    }
+    if storage.find_sender(&req.username).await?.is_some() {
+        return Err(SenderError::UsernameTaken(req.username.clone()));
+    }
+    let pwd = password_hashing.hash(SecretString::from(req.password.clone())).await?;
+    // Someone else may have taken the username since it was looked up
+    let sender = match storage.add_sender(&req.username, &req.display_name, &pwd, req.role).await {
+        Err(StorageError::UniqueViolation(_)) => return Err(SenderError::UsernameTaken(req.username.clone())),
+        outcome => outcome?,
+    };
+    Ok(HttpResponse::Created().json(sender))
+}
+
+#[tracing::instrument(
+    name = "Listing the senders",
+    skip(sender, storage)
+)]
+pub async fn list_senders(
+    sender: AuthenticatedSender,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, SenderError> {
+    sender.require_permission(Permission::ManageSenders)?;
+    Ok(HttpResponse::Ok().json(storage.list_senders().await?))
+}
+
+#[tracing::instrument(
+    name = "Disabling a sender",
+    skip(current, storage)
+)]
+pub async fn disable_sender(
+    current: AuthenticatedSender,
+    sender_id: web::Path<i32>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, SenderError> {
+    current.require_permission(Permission::ManageSenders)?;
+    let sender_id = sender_id.into_inner();
+    // Nobody can lock themselves (possibly the last admin) out
+    if current.id == sender_id {
+        return Err(SenderError::OwnAccount);
+    }
+    if !storage.set_sender_enabled(sender_id, false).await? {
+        return Err(SenderError::NotFound);
+    }
+    let sender = storage.find_sender_by_id(sender_id).await?
+        .ok_or(SenderError::NotFound)?;
+    Ok(HttpResponse::Ok().json(sender))
+}
+
+#[tracing::instrument(
+    name = "Changing a sender's role",
+    skip(current, req, storage),
+    fields(role = %req.role)
+)]
+pub async fn set_sender_role(
+    current: AuthenticatedSender,
+    sender_id: web::Path<i32>,
+    req: web::Json<SetRoleRequest>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, SenderError> {
+    current.require_permission(Permission::ManageSenders)?;
+    let sender_id = sender_id.into_inner();
+    // An admin demoting themselves could leave nobody to manage the senders
+    if current.id == sender_id {
+        return Err(SenderError::OwnAccount);
+    }
+    if !storage.set_sender_role(sender_id, req.role).await? {
+        return Err(SenderError::NotFound);
+    }
+    let sender = storage.find_sender_by_id(sender_id).await?
+        .ok_or(SenderError::NotFound)?;
+    Ok(HttpResponse::Ok().json(sender))
+}
+
+/// The sender's past issues are kept, without an author.
+#[tracing::instrument(
+    name = "Deleting a sender",
+    skip(current, storage)
+)]
+pub async fn delete_sender(
+    current: AuthenticatedSender,
+    sender_id: web::Path<i32>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, SenderError> {
+    current.require_permission(Permission::ManageSenders)?;
+    let sender_id = sender_id.into_inner();
+    if current.id == sender_id {
+        return Err(SenderError::OwnAccount);
+    }
+    if !storage.delete_sender(sender_id).await? {
+        return Err(SenderError::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The current password is the one in the `Authorization` header, a session is not enough.
+#[tracing::instrument(
+    name = "Changing a sender's password",
+    skip(current, req, storage, password_hashing)
+)]
+pub async fn change_own_password(
+    current: AuthenticatedSender,
+    req: web::Json<ChangePasswordRequest>,
+    storage: web::Data<dyn Storage>,
+    password_hashing: web::Data<PasswordHashing>,
+) -> Result<HttpResponse, SenderError> {
+    current.require_permission(Permission::ManageOwnAccount)?;
+    if current.method != AuthMethod::Basic {
+        return Err(AuthError::Forbidden("Changing the password needs the current one as Basic credentials".to_string()).into());
+    }
+    if let Err(errors) = req.validate() {
+        return Err(SenderError::ValidationError(errors.to_string()));
+    }
+    let pwd = password_hashing.hash(SecretString::from(req.new_password.clone())).await?;
+    storage.update_sender_password(&current.username, &pwd).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument(
+    name = "Listing a sender's test addresses",
+    skip(current, storage)
+)]
+pub async fn list_own_test_addresses(
+    current: AuthenticatedSender,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, SenderError> {
+    current.require_permission(Permission::ManageOwnAccount)?;
+    let addresses = storage.list_sender_test_addresses(current.id).await?;
+    Ok(HttpResponse::Ok().json(json!({ "addresses": addresses })))
+}
+
+/// The only addresses `POST /newsletters/test-send` delivers to for this sender.
+#[tracing::instrument(
+    name = "Setting a sender's test addresses",
+    skip(current, req, storage)
+)]
+pub async fn set_own_test_addresses(
+    current: AuthenticatedSender,
+    req: web::Json<TestAddressesRequest>,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, SenderError> {
+    current.require_permission(Permission::ManageOwnAccount)?;
+    if req.addresses.len() > MAX_TEST_ADDRESSES {
+        return Err(SenderError::ValidationError(format!("at most {} test addresses are allowed", MAX_TEST_ADDRESSES)));
+    }
+    let invalid: Vec<&str> = req.addresses.iter()
+        .filter(|address| !address.validate_email())
+        .map(String::as_str)
+        .collect();
+    if !invalid.is_empty() {
+        return Err(SenderError::ValidationError(format!("invalid email address(es): {}", invalid.join(", "))));
+    }
+    storage.set_sender_test_addresses(current.id, &req.addresses).await?;
+    let addresses = storage.list_sender_test_addresses(current.id).await?;
+    Ok(HttpResponse::Ok().json(json!({ "addresses": addresses })))
+}
@@ -1,18 +1,28 @@
+use std::collections::{BTreeMap, HashMap};
+
 use actix_web::{web, HttpResponse, ResponseError};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
 use once_cell::sync::Lazy;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use regex::Regex;
 
-use crate::{email_client::EmailClient, in_memory::{AppState, Subscription}, startup::ApplicationBaseUrl};
+use crate::{authentication::{AuthError, AuthenticatedSender, Permission}, email_client::{EmailClient, EmailError}, startup::{ApplicationBaseUrl, ConfirmationTokenExpiry}, storage::{Storage, StorageError}};
+use crate::templating::{ConfirmationContext, ConfirmationTemplate, TemplateError};
 
 use super::error_chain_fmt;
 
 static NAME_REGEX: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"^[\sa-zA-Z0-9_]+$").unwrap()
 });
+/// Custom field names have to work as `fields.<name>` in a template.
+static FIELD_NAME_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^[a-z_][a-z0-9_]{0,31}$").unwrap()
+});
+const MAX_CUSTOM_FIELDS: usize = 20;
+const MAX_CUSTOM_FIELD_LENGTH: usize = 200;
 // TODO: should we export this to another file?
 #[derive(thiserror::Error)]
 pub enum SubscriptionError {
@@ -20,8 +30,14 @@ pub enum SubscriptionError {
     ValidationError(String),
     #[error("Subscription already exists: {0}")]
     AlreadyExists(serde_json::Value),
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
     #[error("Failed to send email")]
-    SendEmailError(#[from]reqwest::Error),
+    SendEmailError(#[from]EmailError),
+    #[error("Failed to render the confirmation email")]
+    TemplateError(#[from]TemplateError),
+    #[error("Failed to access the subscriptions store")]
+    StorageError(#[from]StorageError),
 }
 
 
@@ -42,9 +58,16 @@ impl ResponseError for SubscriptionError {
             SubscriptionError::AlreadyExists(message) => {
                 HttpResponse::BadRequest().json(message)
             }
+            SubscriptionError::AuthenticationError(err) => err.error_response(),
             SubscriptionError::SendEmailError(_err) => {
                 HttpResponse::BadGateway().finish()
             }
+            SubscriptionError::TemplateError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+            SubscriptionError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
         }
     }
 }
@@ -57,38 +80,81 @@ pub struct SubscriptionRequest {
     username: String,
     #[validate(email)]
     email: String,
+    /// Anything else the newsletter templates can use, e.g. `{"city": "Portland"}`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[validate(custom(function = "validate_custom_fields"))]
+    custom_fields: BTreeMap<String, String>,
+}
+
+fn validate_custom_fields(fields: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    if fields.len() > MAX_CUSTOM_FIELDS {
+        return Err(ValidationError::new("custom_fields")
+            .with_message(format!("at most {} custom fields are allowed", MAX_CUSTOM_FIELDS).into()));
+    }
+    for (name, value) in fields {
+        if !FIELD_NAME_REGEX.is_match(name) {
+            return Err(ValidationError::new("custom_fields")
+                .with_message(format!("`{}` is not a valid field name, use lowercase letters, digits and `_`", name).into()));
+        }
+        if value.chars().count() > MAX_CUSTOM_FIELD_LENGTH {
+            return Err(ValidationError::new("custom_fields")
+                .with_message(format!("`{}` is longer than {} characters", name, MAX_CUSTOM_FIELD_LENGTH).into()));
+        }
+    }
+    Ok(())
+}
+
+/// Generate a random 25-characters-long case-sensitive subscription token.
+/// `thread_rng` is a cryptographically secure generator, so tokens cannot be guessed.
+fn generate_subscription_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
 }
 
 async fn send_confirmation_email(
     email_client: &EmailClient, 
-    recepient: String,
-    subscription_id: i32,
+    template: &ConfirmationTemplate,
+    recepient: &SubscriptionRequest,
+    subscription_token: &str,
     base_url: &String) 
-    -> Result<(), reqwest::Error> {
-    let confirmation_link = format!("{}/subscriptions/confirm?subscription_token={}", base_url, subscription_id);
+    -> Result<(), SubscriptionError> {
+    let confirmation_link = format!("{}/subscriptions/confirm?subscription_token={}", base_url, subscription_token);
+    let (html_content, text_content) = template.render(&ConfirmationContext {
+        username: &recepient.username,
+        confirmation_link: &confirmation_link,
+    })?;
     email_client
         .send_email(
-            vec![recepient],
+            &recepient.email,
             "Welcome!",
-            &format!("Welcome to our newsletter!<br />\
-                            Click <a href=\"{}\">here</a> to confirm your subscription.", confirmation_link),
-            &format!("Welcome to our newsletter!\nPlease confirm your subscription by clicking on the link: {}", confirmation_link),
+            &html_content,
+            &text_content,
+            HashMap::new(),
         )
-        .await
+        .await?;
+    Ok(())
 }
 
 
 
 impl SubscriptionRequest {
     pub fn new(username: String, email: String) -> Self {
-        SubscriptionRequest { username, email }
+        SubscriptionRequest { username, email, custom_fields: BTreeMap::new() }
+    }
+
+    pub fn with_custom_fields(mut self, custom_fields: BTreeMap<String, String>) -> Self {
+        self.custom_fields = custom_fields;
+        self
     }
 }
 
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(info, app_state, email_client, base_url),
+    skip(info, storage, email_client, confirmation_template, base_url, token_expiry),
     fields(
     %info.email,
     %info.username
@@ -96,41 +162,39 @@ impl SubscriptionRequest {
 )]
 pub async fn subscribe(
     info: web::Json<SubscriptionRequest>, 
-    app_state: web::Data<AppState>,
+    storage: web::Data<dyn Storage>,
     email_client: web::Data<EmailClient>,
+    confirmation_template: web::Data<ConfirmationTemplate>,
     base_url: web::Data<ApplicationBaseUrl>,
+    token_expiry: web::Data<ConfirmationTokenExpiry>,
 ) -> Result<HttpResponse, SubscriptionError> {
     match info.validate() {
         Ok(_) => println!("Request for subscribe passed validation"),
         Err(errors) => {return Err(SubscriptionError::ValidationError(errors.to_string()));}
     }
-    let mut subscriptions = app_state.subscriptions.write().expect("RwLock poisoned");
     let email = &info.email;
-    // Find the subscription with the matching token
-    let mut new_id: Option<i32> = None;
-    if let Some(subscription) = subscriptions.iter_mut().find(|s| s.email == *email) {
+    let subscription_id = match storage.find_subscription_by_email(email).await? {
         // Subscription already exists
-        match subscription.status.as_str() {
+        Some(subscription) => match subscription.status.as_str() {
             "confirmed" => {return Err(SubscriptionError::AlreadyExists(serde_json::json!({ "message": format!("Subscription with email {} is already confirmed", email) })));},
-            _ =>  {new_id = Some(subscription.id)}, // If email not confirmed resend the confimation link
-        }
-    } else {
-        let id = app_state.get_id();
-        new_id = Some(id);
-        let subscription = Subscription{
-            id: id,
-            username: info.username.to_string(),
-            email: info.email.to_string(),
-            status: "pending_confirmation".to_string(),
-        };
-    
-        subscriptions.push(subscription);
-    }
-
-    let _response = send_confirmation_email(&email_client, info.email.clone(), new_id.unwrap(), &base_url.0)
+            // Coming back after leaving the list starts the double opt-in all over again
+            "unsubscribed" => {
+                storage.update_subscription_status(subscription.id, "pending_confirmation").await?;
+                subscription.id
+            },
+            _ =>  subscription.id, // If email not confirmed send a fresh confirmation link
+        },
+        None => storage.add_subscription(&info.username, email, &info.custom_fields).await?.id,
+    };
+    // A new token replaces the previous one, so older links stop working
+    let subscription_token = generate_subscription_token();
+    let expires_at = chrono::Utc::now() + token_expiry.0;
+    storage.rotate_confirmation_token(subscription_id, &subscription_token, expires_at).await?;
+
+    send_confirmation_email(&email_client, &confirmation_template, &info, &subscription_token, &base_url.0)
         .await?;
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({ "id": new_id.unwrap() })))
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "id": subscription_id })))
 }
 
 #[derive(serde::Deserialize)]
@@ -138,14 +202,19 @@ pub struct SubscriptionParameters {
     subscription_id: String
 }
 
+/// For senders only, it tells whoever asks who is on the list.
 pub async fn get_subscription(
-    app_state: web::Data<AppState>,
+    sender: AuthenticatedSender,
+    storage: web::Data<dyn Storage>,
     id: web::Query<SubscriptionParameters>
-) -> HttpResponse {
-    let subscriptions = app_state.subscriptions.read().expect("RwLock poisoned");
-    if let Some(subscription) = subscriptions.iter().find(|&s| s.id.to_string() == id.subscription_id) {
-        return HttpResponse::Ok().json(subscription);
+) -> Result<HttpResponse, SubscriptionError> {
+    sender.require_permission(Permission::ReadSubscribers)?;
+    let subscription = match id.subscription_id.parse::<i32>() {
+        Ok(id) => storage.find_subscription_by_id(id).await?,
+        Err(_) => None,
+    };
+    match subscription {
+        Some(subscription) => Ok(HttpResponse::Ok().json(subscription)),
+        None => Ok(HttpResponse::NotFound().finish()),
     }
-    return HttpResponse::NotFound().finish();
 }
-
@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use validator::Validate;
 
-use crate::in_memory::AppState;
+use crate::storage::{Storage, StorageError, TokenOutcome};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ConfirmError {
@@ -11,6 +11,14 @@ pub enum ConfirmError {
     ValidationError(String),
     #[error("Email was not confirmed: {0}")]
     NotFound(serde_json::Value),
+    #[error("Unknown subscription token")]
+    UnknownToken,
+    #[error("Subscription token was already used")]
+    UsedToken,
+    #[error("Subscription token has expired")]
+    ExpiredToken,
+    #[error("Failed to access the subscriptions store")]
+    StorageError(#[from]StorageError),
 }
 impl ResponseError for ConfirmError {
     fn error_response(&self) -> HttpResponse {
@@ -21,34 +29,47 @@ impl ResponseError for ConfirmError {
             ConfirmError::NotFound(message) => {
                 HttpResponse::NotFound().json(message)
             }
+            ConfirmError::UnknownToken => {
+                HttpResponse::Unauthorized().json(json!({ "error": self.to_string() }))
+            }
+            ConfirmError::UsedToken | ConfirmError::ExpiredToken => {
+                HttpResponse::Gone().json(json!({ "error": self.to_string() }))
+            }
+            ConfirmError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
         }
     }
 }
 
 #[derive(Deserialize,Serialize,Debug,Validate)]
 pub struct Parameters {
-    #[validate(length(min = 1, max = 8))]
+    #[validate(length(min = 1, max = 64))]
     subscription_token: String
 }
 
 #[tracing::instrument(
     name = "confirming a new subscriber",
-    skip(app_state),
-    fields(%parameters.subscription_token)
+    // The token is still good until it is used, it stays out of the logs
+    skip(storage, parameters)
 )]
-pub async fn subscription_confirm(app_state: web::Data<AppState>,
+pub async fn subscription_confirm(storage: web::Data<dyn Storage>,
     parameters: web::Query<Parameters>
 ) -> Result<HttpResponse, ConfirmError> {
     match parameters.validate() {
         Ok(_) => println!("Request for confirm passed validation"),
         Err(errors) => {return Err(ConfirmError::ValidationError(errors.to_string()));}
     }
-    let mut subscriptions = app_state.subscriptions.write().expect("RwLock poisoned");
-    let num_token = parameters.subscription_token.parse::<i32>().unwrap();
-    // Find the subscription with the matching token
-    if let Some(subscription) = subscriptions.iter_mut().find(|s| s.id == num_token) {
-        // Update the status to "confirmed"
-        subscription.status = "confirmed".to_string();
+    let subscription_id = match storage
+        .consume_confirmation_token(&parameters.subscription_token, chrono::Utc::now())
+        .await? {
+        TokenOutcome::Valid(subscription_id) => subscription_id,
+        TokenOutcome::Unknown => return Err(ConfirmError::UnknownToken),
+        TokenOutcome::AlreadyUsed => return Err(ConfirmError::UsedToken),
+        TokenOutcome::Expired => return Err(ConfirmError::ExpiredToken),
+    };
+    // Update the status of the subscription the token belongs to to "confirmed"
+    if storage.update_subscription_status(subscription_id, "confirmed").await? {
         Ok(HttpResponse::Ok().json(json!({
             "message": "Subscription confirmed successfully"
         })))
@@ -57,4 +78,4 @@ pub async fn subscription_confirm(app_state: web::Data<AppState>,
             "error": "Subscription not found"
         })))
     }
-}
\ No newline at end of file
+}
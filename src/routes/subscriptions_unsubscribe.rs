@@ -0,0 +1,161 @@
+use actix_web::{http::header::ContentType, web, HttpResponse, ResponseError};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::storage::{Storage, StorageError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum UnsubscribeError {
+    #[error("Invalid unsubscribe token")]
+    InvalidToken,
+    #[error("Subscription not found")]
+    NotFound,
+    #[error("Failed to access the subscriptions store")]
+    StorageError(#[from]StorageError),
+}
+
+impl ResponseError for UnsubscribeError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            UnsubscribeError::InvalidToken => {
+                HttpResponse::Unauthorized().json(json!({ "error": self.to_string() }))
+            }
+            UnsubscribeError::NotFound => {
+                HttpResponse::NotFound().json(json!({ "error": self.to_string() }))
+            }
+            UnsubscribeError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+/// Signs the per-subscriber unsubscribe links with HMAC-SHA256, so only the
+/// subscriber who received our email can remove themselves from the list.
+pub struct UnsubscribeSigner {
+    secret: SecretString,
+}
+
+impl UnsubscribeSigner {
+    pub fn new(secret: SecretString) -> Self {
+        Self { secret }
+    }
+
+    fn mac(&self, subscription_id: i32) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(format!("unsubscribe:{}", subscription_id).as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, subscription_id: i32) -> String {
+        hex::encode(self.mac(subscription_id).finalize().into_bytes())
+    }
+
+    /// Constant time comparison of `token` against the expected signature.
+    pub fn verify(&self, subscription_id: i32, token: &str) -> bool {
+        match hex::decode(token) {
+            Ok(signature) => self.mac(subscription_id).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn unsubscribe_link(&self, base_url: &str, subscription_id: i32) -> String {
+        format!("{}/subscriptions/unsubscribe?subscription_id={}&token={}",
+            base_url, subscription_id, self.sign(subscription_id))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnsubscribeParameters {
+    subscription_id: i32,
+    token: String,
+}
+
+/// The page the `List-Unsubscribe` link opens in a browser.
+/// Per RFC 8058 a GET must never unsubscribe on its own (mail scanners follow links),
+/// so it only renders a form that POSTs back to the one-click endpoint.
+#[tracing::instrument(
+    name = "Showing the unsubscribe page",
+    skip(storage, signer, parameters),
+    fields(%parameters.subscription_id)
+)]
+pub async fn unsubscribe_landing(
+    storage: web::Data<dyn Storage>,
+    signer: web::Data<UnsubscribeSigner>,
+    parameters: web::Query<UnsubscribeParameters>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !signer.verify(parameters.subscription_id, &parameters.token) {
+        return Err(UnsubscribeError::InvalidToken);
+    }
+    let subscription = storage.find_subscription_by_id(parameters.subscription_id).await?
+        .ok_or(UnsubscribeError::NotFound)?;
+    // The token was verified above, so it is plain hex and safe to embed
+    let body = format!(r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Unsubscribe</title></head>
+<body>
+<p>Do you want to stop receiving our newsletter?</p>
+<form method="post" action="/subscriptions/unsubscribe?subscription_id={}&amp;token={}">
+<input type="hidden" name="List-Unsubscribe" value="One-Click">
+<button type="submit">Unsubscribe</button>
+</form>
+</body>
+</html>"#, subscription.id, parameters.token);
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(body))
+}
+
+/// The RFC 8058 one-click endpoint, mail clients POST `List-Unsubscribe=One-Click` to it.
+#[tracing::instrument(
+    name = "Unsubscribing a subscriber",
+    skip(storage, signer, parameters),
+    fields(%parameters.subscription_id)
+)]
+pub async fn unsubscribe(
+    storage: web::Data<dyn Storage>,
+    signer: web::Data<UnsubscribeSigner>,
+    parameters: web::Query<UnsubscribeParameters>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !signer.verify(parameters.subscription_id, &parameters.token) {
+        return Err(UnsubscribeError::InvalidToken);
+    }
+    if !storage.update_subscription_status(parameters.subscription_id, "unsubscribed").await? {
+        return Err(UnsubscribeError::NotFound);
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "You have been unsubscribed"
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::SecretString;
+
+    use super::UnsubscribeSigner;
+
+    fn signer(secret: &str) -> UnsubscribeSigner {
+        UnsubscribeSigner::new(SecretString::from(secret))
+    }
+
+    #[test]
+    fn a_signed_token_is_accepted_for_its_own_subscription_only() {
+        let signer = signer("secret");
+        let token = signer.sign(1);
+        assert!(signer.verify(1, &token));
+        assert!(!signer.verify(2, &token));
+    }
+
+    #[test]
+    fn tokens_signed_with_another_key_are_rejected() {
+        let token = signer("another secret").sign(1);
+        assert!(!signer("secret").verify(1, &token));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        assert!(!signer("secret").verify(1, "not hex at all"));
+    }
+}
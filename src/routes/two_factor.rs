@@ -0,0 +1,166 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use serde::Deserialize;
+use serde_json::json;
+use totp_rs::TotpUrlError;
+
+use crate::authentication::{confirm_enrolment, generate_totp_secret, renew_recovery_codes, totp_uri, AuthError, AuthenticatedSender, Permission};
+use crate::clock::Clock;
+use crate::configuration::TwoFactorProperties;
+use crate::storage::{Storage, StorageError};
+
+use super::error_chain_fmt;
+
+#[derive(thiserror::Error)]
+pub enum TwoFactorError {
+    #[error("The code is not valid")]
+    InvalidCode,
+    #[error("Two-factor authentication is already enabled, disable it first")]
+    AlreadyEnabled,
+    #[error("There is no enrolment waiting for confirmation")]
+    NotEnrolling,
+    #[error("Two-factor authentication is not enabled")]
+    NotEnabled,
+    #[error("Two-factor authentication is required for every sender")]
+    Required,
+    #[error("Failed to authenticate the sender")]
+    AuthenticationError(#[from]AuthError),
+    #[error("Failed to build the TOTP URI")]
+    TotpError(#[from]TotpUrlError),
+    #[error("Failed to access the two-factor store")]
+    StorageError(#[from]StorageError),
+}
+
+impl std::fmt::Debug for TwoFactorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for TwoFactorError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            TwoFactorError::InvalidCode => {
+                HttpResponse::BadRequest().json(json!({ "error": self.to_string() }))
+            }
+            TwoFactorError::AlreadyEnabled
+            | TwoFactorError::NotEnrolling
+            | TwoFactorError::NotEnabled
+            | TwoFactorError::Required => {
+                HttpResponse::Conflict().json(json!({ "error": self.to_string() }))
+            }
+            TwoFactorError::AuthenticationError(err) => err.error_response(),
+            TwoFactorError::TotpError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+            TwoFactorError::StorageError(_err) => {
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmTwoFactorRequest {
+    code: String,
+}
+
+#[tracing::instrument(
+    name = "Showing a sender's two-factor status",
+    skip(current, storage, properties)
+)]
+pub async fn two_factor_status(
+    current: AuthenticatedSender,
+    storage: web::Data<dyn Storage>,
+    properties: web::Data<TwoFactorProperties>,
+) -> Result<HttpResponse, TwoFactorError> {
+    current.require_password()?;
+    let enabled = storage.find_totp_enrolment(current.id).await?
+        .is_some_and(|enrolment| enrolment.confirmed_at.is_some());
+    let recovery_codes_left = storage.count_unused_recovery_codes(current.id).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "enabled": enabled,
+        "required": properties.required,
+        "recovery_codes_left": recovery_codes_left,
+    })))
+}
+
+/// A new secret for the sender's authenticator app, it takes effect once a code from it is confirmed.
+/// Starting over replaces a secret that was never confirmed.
+#[tracing::instrument(
+    name = "Enrolling a sender in two-factor authentication",
+    skip(current, storage, properties)
+)]
+pub async fn enrol_two_factor(
+    current: AuthenticatedSender,
+    storage: web::Data<dyn Storage>,
+    properties: web::Data<TwoFactorProperties>,
+) -> Result<HttpResponse, TwoFactorError> {
+    current.require_password()?;
+    let secret = generate_totp_secret();
+    let otpauth_uri = totp_uri(&secret, &properties.issuer, &current.username)?;
+    if !storage.start_totp_enrolment(current.id, &secret).await? {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+    Ok(HttpResponse::Created().json(json!({ "secret": secret, "otpauth_uri": otpauth_uri })))
+}
+
+/// The recovery codes are in this response only.
+#[tracing::instrument(
+    name = "Confirming a sender's two-factor enrolment",
+    skip(current, req, storage, clock)
+)]
+pub async fn confirm_two_factor(
+    current: AuthenticatedSender,
+    req: web::Json<ConfirmTwoFactorRequest>,
+    storage: web::Data<dyn Storage>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, TwoFactorError> {
+    current.require_password()?;
+    let enrolment = storage.find_totp_enrolment(current.id).await?
+        .filter(|enrolment| enrolment.confirmed_at.is_none())
+        .ok_or(TwoFactorError::NotEnrolling)?;
+    let recovery_codes = confirm_enrolment(storage.get_ref(), &enrolment, &req.code, clock.now()).await?
+        .ok_or(TwoFactorError::InvalidCode)?;
+    tracing::info!("`{}` enabled two-factor authentication", current.username);
+    Ok(HttpResponse::Ok().json(json!({ "recovery_codes": recovery_codes })))
+}
+
+/// Replaces every recovery code, used or not.
+#[tracing::instrument(
+    name = "Renewing a sender's recovery codes",
+    skip(current, storage)
+)]
+pub async fn regenerate_recovery_codes(
+    current: AuthenticatedSender,
+    storage: web::Data<dyn Storage>,
+) -> Result<HttpResponse, TwoFactorError> {
+    current.require_permission(Permission::ManageOwnAccount)?;
+    let enabled = storage.find_totp_enrolment(current.id).await?
+        .is_some_and(|enrolment| enrolment.confirmed_at.is_some());
+    if !enabled {
+        return Err(TwoFactorError::NotEnabled);
+    }
+    let recovery_codes = renew_recovery_codes(storage.get_ref(), current.id).await?;
+    Ok(HttpResponse::Ok().json(json!({ "recovery_codes": recovery_codes })))
+}
+
+/// Only possible while `auth.two_factor.required` is off.
+#[tracing::instrument(
+    name = "Disabling a sender's two-factor authentication",
+    skip(current, storage, properties)
+)]
+pub async fn disable_two_factor(
+    current: AuthenticatedSender,
+    storage: web::Data<dyn Storage>,
+    properties: web::Data<TwoFactorProperties>,
+) -> Result<HttpResponse, TwoFactorError> {
+    current.require_permission(Permission::ManageOwnAccount)?;
+    if properties.required {
+        return Err(TwoFactorError::Required);
+    }
+    if !storage.delete_totp_enrolment(current.id).await? {
+        return Err(TwoFactorError::NotEnabled);
+    }
+    tracing::info!("`{}` disabled two-factor authentication", current.username);
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,2 @@
+mod sqlite_store;
+pub use sqlite_store::*;
\ No newline at end of file
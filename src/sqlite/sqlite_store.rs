@@ -0,0 +1,815 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, ConnectOptions, Connection, Row, SqlitePool};
+
+use crate::storage::{ApiKey, ApiKeyStore, ConfirmationToken, ConfirmationTokenStore, DeliveryCounts, DeliveryQueueStore, DeliveryTask, IdempotencyRecord, IdempotencyStore, NewsletterIssue, NewsletterIssueStore, Sender, SenderRole, SenderStore, SavedResponse, SessionStore, StorageError, Subscription, SubscriptionStore, TokenOutcome, TotpEnrolment, TwoFactorStore};
+
+/// A file-backed store, so subscribers and senders survive a restart.
+/// The schema lives in `migrations/sqlite` and is embedded in the binary.
+#[derive(Clone)]
+pub struct SqliteStore{
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database file at `path` and bring its schema up to date.
+    pub async fn connect(path: &str) -> Result<Self, StorageError> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        // Migrations run in a transaction, where foreign keys cannot be switched off, so they get
+        // a connection without them: rebuilding a table, the only way to change a column in SQLite,
+        // would otherwise delete every row that references it
+        let mut migrating = options.clone().foreign_keys(false).connect().await?;
+        sqlx::migrate!("./migrations/sqlite").run(&mut migrating).await?;
+        migrating.close().await?;
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await?;
+        Ok(Self { pool })
+    }
+}
+
+fn subscription_from_row(row: sqlx::sqlite::SqliteRow) -> Subscription {
+    Subscription{
+        id: row.get("id"),
+        username: row.get("username"),
+        email: row.get("email"),
+        status: row.get("status"),
+        custom_fields: serde_json::from_str(row.get("custom_fields")).unwrap_or_default(),
+    }
+}
+
+fn sender_from_row(row: sqlx::sqlite::SqliteRow) -> Sender {
+    Sender{
+        id: row.get("id"),
+        username: row.get("username"),
+        display_name: row.get("display_name"),
+        pwd: row.get("pwd"),
+        enabled: row.get("enabled"),
+        // A role this version does not know about grants the least
+        role: row.get::<String, _>("role").parse().unwrap_or(SenderRole::Viewer),
+        created_at: row.get("created_at"),
+    }
+}
+
+fn api_key_from_row(row: sqlx::sqlite::SqliteRow) -> ApiKey {
+    ApiKey{
+        id: row.get("id"),
+        sender_id: row.get("sender_id"),
+        name: row.get("name"),
+        prefix: row.get("prefix"),
+        key_hash: row.get("key_hash"),
+        scopes: row.get::<String, _>("scopes").split_whitespace().map(str::to_string).collect(),
+        expires_at: row.get("expires_at"),
+        last_used_at: row.get("last_used_at"),
+        created_at: row.get("created_at"),
+    }
+}
+
+fn newsletter_issue_from_row(row: sqlx::sqlite::SqliteRow) -> NewsletterIssue {
+    NewsletterIssue{
+        id: row.get("id"),
+        sender_id: row.get("sender_id"),
+        title: row.get("title"),
+        text_content: row.get("text_content"),
+        html_content: row.get("html_content"),
+        status: row.get("status"),
+        scheduled_for: row.get("scheduled_for"),
+        created_at: row.get("created_at"),
+        published_at: row.get("published_at"),
+    }
+}
+
+fn delivery_task_from_row(row: sqlx::sqlite::SqliteRow) -> DeliveryTask {
+    DeliveryTask{
+        id: row.get("id"),
+        issue_id: row.get("issue_id"),
+        subscription_id: row.get("subscription_id"),
+        email: row.get("email"),
+        status: row.get("status"),
+        attempts: row.get("attempts"),
+        execute_after: row.get("execute_after"),
+        claimed_until: row.get("claimed_until"),
+        last_error: row.get("last_error"),
+    }
+}
+
+fn idempotency_record_from_row(row: sqlx::sqlite::SqliteRow) -> IdempotencyRecord {
+    let status_code: Option<i64> = row.get("response_status_code");
+    let headers: Option<String> = row.get("response_headers");
+    let body: Option<Vec<u8>> = row.get("response_body");
+    let response = match (status_code, headers, body) {
+        (Some(status_code), Some(headers), Some(body)) => Some(SavedResponse{
+            status_code: status_code as u16,
+            headers: serde_json::from_str(&headers).unwrap_or_default(),
+            body,
+        }),
+        _ => None,
+    };
+    IdempotencyRecord{
+        request_hash: row.get("request_hash"),
+        response,
+    }
+}
+
+#[async_trait]
+impl SubscriptionStore for SqliteStore {
+    async fn add_subscription(&self, username: &str, email: &str, custom_fields: &BTreeMap<String, String>) -> Result<Subscription, StorageError> {
+        let row = sqlx::query(
+            "INSERT INTO subscriptions (username, email, custom_fields, status) VALUES (?, ?, ?, 'pending_confirmation') \
+             RETURNING id, username, email, status, custom_fields")
+            .bind(username)
+            .bind(email)
+            .bind(serde_json::to_string(custom_fields).expect("A map of strings"))
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(subscription_from_row(row))
+    }
+
+    async fn find_subscription_by_id(&self, id: i32) -> Result<Option<Subscription>, StorageError> {
+        let row = sqlx::query("SELECT id, username, email, status, custom_fields FROM subscriptions WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(subscription_from_row))
+    }
+
+    async fn find_subscription_by_email(&self, email: &str) -> Result<Option<Subscription>, StorageError> {
+        let row = sqlx::query("SELECT id, username, email, status, custom_fields FROM subscriptions WHERE email = ?")
+            .bind(email)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(subscription_from_row))
+    }
+
+    async fn update_subscription_status(&self, id: i32, status: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query("UPDATE subscriptions SET status = ? WHERE id = ?")
+            .bind(status)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn confirmed_subscriptions(&self) -> Result<Vec<Subscription>, StorageError> {
+        let rows = sqlx::query("SELECT id, username, email, status, custom_fields FROM subscriptions WHERE status = 'confirmed'")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(subscription_from_row).collect())
+    }
+}
+
+#[async_trait]
+impl SenderStore for SqliteStore {
+    async fn add_sender(&self, username: &str, display_name: &str, pwd: &str, role: SenderRole) -> Result<Sender, StorageError> {
+        let row = sqlx::query(
+            "INSERT INTO senders (username, display_name, pwd, enabled, role, created_at) VALUES (?, ?, ?, TRUE, ?, ?) \
+             RETURNING id, username, display_name, pwd, enabled, role, created_at")
+            .bind(username)
+            .bind(display_name)
+            .bind(pwd)
+            .bind(role.as_str())
+            .bind(Utc::now())
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(sender_from_row(row))
+    }
+
+    async fn find_sender(&self, username: &str) -> Result<Option<Sender>, StorageError> {
+        let row = sqlx::query("SELECT id, username, display_name, pwd, enabled, role, created_at FROM senders WHERE username = ?")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(sender_from_row))
+    }
+
+    async fn find_sender_by_id(&self, id: i32) -> Result<Option<Sender>, StorageError> {
+        let row = sqlx::query("SELECT id, username, display_name, pwd, enabled, role, created_at FROM senders WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(sender_from_row))
+    }
+
+    async fn list_senders(&self) -> Result<Vec<Sender>, StorageError> {
+        let rows = sqlx::query("SELECT id, username, display_name, pwd, enabled, role, created_at FROM senders ORDER BY id")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(sender_from_row).collect())
+    }
+
+    async fn set_sender_enabled(&self, id: i32, enabled: bool) -> Result<bool, StorageError> {
+        let result = sqlx::query("UPDATE senders SET enabled = ? WHERE id = ?")
+            .bind(enabled)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_sender_role(&self, id: i32, role: SenderRole) -> Result<bool, StorageError> {
+        let result = sqlx::query("UPDATE senders SET role = ? WHERE id = ?")
+            .bind(role.as_str())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_sender(&self, id: i32) -> Result<bool, StorageError> {
+        let result = sqlx::query("DELETE FROM senders WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn update_sender_password(&self, username: &str, pwd: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query("UPDATE senders SET pwd = ? WHERE username = ?")
+            .bind(pwd)
+            .bind(username)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_sender_test_addresses(&self, sender_id: i32, emails: &[String]) -> Result<(), StorageError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM sender_test_addresses WHERE sender_id = ?")
+            .bind(sender_id)
+            .execute(&mut *transaction)
+            .await?;
+        for email in emails {
+            sqlx::query("INSERT INTO sender_test_addresses (sender_id, email) VALUES (?, ?) ON CONFLICT DO NOTHING")
+                .bind(sender_id)
+                .bind(email)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn list_sender_test_addresses(&self, sender_id: i32) -> Result<Vec<String>, StorageError> {
+        let rows = sqlx::query("SELECT email FROM sender_test_addresses WHERE sender_id = ? ORDER BY email")
+            .bind(sender_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get("email")).collect())
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for SqliteStore {
+    async fn add_api_key(&self, sender_id: i32, name: &str, prefix: &str, key_hash: &str, scopes: &[String], expires_at: Option<DateTime<Utc>>) -> Result<ApiKey, StorageError> {
+        let row = sqlx::query(
+            "INSERT INTO api_keys (sender_id, name, prefix, key_hash, scopes, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?) \
+             RETURNING id, sender_id, name, prefix, key_hash, scopes, expires_at, last_used_at, created_at")
+            .bind(sender_id)
+            .bind(name)
+            .bind(prefix)
+            .bind(key_hash)
+            .bind(scopes.join(" "))
+            .bind(expires_at)
+            .bind(Utc::now())
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(api_key_from_row(row))
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, StorageError> {
+        let row = sqlx::query("SELECT id, sender_id, name, prefix, key_hash, scopes, expires_at, last_used_at, created_at FROM api_keys WHERE key_hash = ?")
+            .bind(key_hash)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(api_key_from_row))
+    }
+
+    async fn list_api_keys(&self, sender_id: i32) -> Result<Vec<ApiKey>, StorageError> {
+        let rows = sqlx::query("SELECT id, sender_id, name, prefix, key_hash, scopes, expires_at, last_used_at, created_at FROM api_keys WHERE sender_id = ? ORDER BY id")
+            .bind(sender_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(api_key_from_row).collect())
+    }
+
+    async fn delete_api_key(&self, sender_id: i32, id: i32) -> Result<bool, StorageError> {
+        let result = sqlx::query("DELETE FROM api_keys WHERE sender_id = ? AND id = ?")
+            .bind(sender_id)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn touch_api_key(&self, id: i32, used_at: DateTime<Utc>) -> Result<(), StorageError> {
+        sqlx::query("UPDATE api_keys SET last_used_at = ? WHERE id = ?")
+            .bind(used_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TwoFactorStore for SqliteStore {
+    async fn start_totp_enrolment(&self, sender_id: i32, secret: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "INSERT INTO sender_totp (sender_id, secret, created_at) VALUES (?, ?, ?) \
+             ON CONFLICT (sender_id) DO UPDATE SET secret = excluded.secret, created_at = excluded.created_at, last_used_step = NULL \
+             WHERE sender_totp.confirmed_at IS NULL")
+            .bind(sender_id)
+            .bind(secret)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn find_totp_enrolment(&self, sender_id: i32) -> Result<Option<TotpEnrolment>, StorageError> {
+        let row = sqlx::query("SELECT sender_id, secret, confirmed_at, last_used_step, failed_attempts, locked_until FROM sender_totp WHERE sender_id = ?")
+            .bind(sender_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| TotpEnrolment {
+            sender_id: row.get("sender_id"),
+            secret: row.get("secret"),
+            confirmed_at: row.get("confirmed_at"),
+            last_used_step: row.get("last_used_step"),
+            failed_attempts: row.get("failed_attempts"),
+            locked_until: row.get("locked_until"),
+        }))
+    }
+
+    async fn confirm_totp_enrolment(&self, sender_id: i32, confirmed_at: DateTime<Utc>) -> Result<bool, StorageError> {
+        let result = sqlx::query("UPDATE sender_totp SET confirmed_at = ? WHERE sender_id = ? AND confirmed_at IS NULL")
+            .bind(confirmed_at)
+            .bind(sender_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn use_totp_step(&self, sender_id: i32, step: i64) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE sender_totp SET last_used_step = ? WHERE sender_id = ? AND (last_used_step IS NULL OR last_used_step < ?)")
+            .bind(step)
+            .bind(sender_id)
+            .bind(step)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn start_second_factor_attempt(&self, sender_id: i32, max_attempts: i32, now: DateTime<Utc>, locked_until: DateTime<Utc>) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE sender_totp SET \
+                 failed_attempts = CASE WHEN failed_attempts + 1 >= ? THEN 0 ELSE failed_attempts + 1 END, \
+                 locked_until = CASE WHEN failed_attempts + 1 >= ? THEN ? ELSE locked_until END \
+             WHERE sender_id = ? AND (locked_until IS NULL OR locked_until <= ?)")
+            .bind(max_attempts)
+            .bind(max_attempts)
+            .bind(locked_until)
+            .bind(sender_id)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn clear_second_factor_attempts(&self, sender_id: i32) -> Result<(), StorageError> {
+        sqlx::query("UPDATE sender_totp SET failed_attempts = 0, locked_until = NULL WHERE sender_id = ?")
+            .bind(sender_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_totp_enrolment(&self, sender_id: i32) -> Result<bool, StorageError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM sender_recovery_codes WHERE sender_id = ?")
+            .bind(sender_id)
+            .execute(&mut *transaction)
+            .await?;
+        let result = sqlx::query("DELETE FROM sender_totp WHERE sender_id = ?")
+            .bind(sender_id)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn replace_recovery_codes(&self, sender_id: i32, code_hashes: &[String]) -> Result<(), StorageError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM sender_recovery_codes WHERE sender_id = ?")
+            .bind(sender_id)
+            .execute(&mut *transaction)
+            .await?;
+        for code_hash in code_hashes {
+            sqlx::query("INSERT INTO sender_recovery_codes (sender_id, code_hash) VALUES (?, ?) ON CONFLICT DO NOTHING")
+                .bind(sender_id)
+                .bind(code_hash)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn use_recovery_code(&self, sender_id: i32, code_hash: &str, used_at: DateTime<Utc>) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE sender_recovery_codes SET used_at = ? WHERE sender_id = ? AND code_hash = ? AND used_at IS NULL")
+            .bind(used_at)
+            .bind(sender_id)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn count_unused_recovery_codes(&self, sender_id: i32) -> Result<i64, StorageError> {
+        let row = sqlx::query("SELECT COUNT(*) AS count FROM sender_recovery_codes WHERE sender_id = ? AND used_at IS NULL")
+            .bind(sender_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.get("count"))
+    }
+}
+
+#[async_trait]
+impl ConfirmationTokenStore for SqliteStore {
+    async fn rotate_confirmation_token(&self, subscription_id: i32, token: &str, expires_at: DateTime<Utc>) -> Result<(), StorageError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM subscription_tokens WHERE subscription_id = ?")
+            .bind(subscription_id)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query("INSERT INTO subscription_tokens (token, subscription_id, expires_at) VALUES (?, ?, ?)")
+            .bind(token)
+            .bind(subscription_id)
+            .bind(expires_at)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn consume_confirmation_token(&self, token: &str, now: DateTime<Utc>) -> Result<TokenOutcome, StorageError> {
+        let row = sqlx::query("SELECT token, subscription_id, expires_at, used_at FROM subscription_tokens WHERE token = ?")
+            .bind(token)
+            .fetch_optional(&self.pool)
+            .await?;
+        let Some(row) = row else {
+            return Ok(TokenOutcome::Unknown);
+        };
+        let stored = ConfirmationToken{
+            token: row.get("token"),
+            subscription_id: row.get("subscription_id"),
+            expires_at: row.get("expires_at"),
+            used_at: row.get("used_at"),
+        };
+        let outcome = stored.outcome(now);
+        if let TokenOutcome::Valid(_) = outcome {
+            // Guard on `used_at` so two concurrent requests cannot both consume the token
+            let result = sqlx::query("UPDATE subscription_tokens SET used_at = ? WHERE token = ? AND used_at IS NULL")
+                .bind(now)
+                .bind(token)
+                .execute(&self.pool)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Ok(TokenOutcome::AlreadyUsed);
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+/// One delivery task per confirmed subscriber, in the transaction publishing the issue.
+async fn enqueue_deliveries(transaction: &mut sqlx::Transaction<'_, sqlx::Sqlite>, issue_id: i32, now: DateTime<Utc>) -> Result<(), StorageError> {
+    sqlx::query(
+        "INSERT INTO issue_delivery_queue (issue_id, subscription_id, email, status, attempts, execute_after) \
+         SELECT ?, id, email, 'queued', 0, ? FROM subscriptions WHERE status = 'confirmed'")
+        .bind(issue_id)
+        .bind(now)
+        .execute(&mut **transaction)
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl NewsletterIssueStore for SqliteStore {
+    async fn add_newsletter_issue(&self, sender_id: i32, title: &str, text_content: &str, html_content: &str) -> Result<NewsletterIssue, StorageError> {
+        let now = Utc::now();
+        let mut transaction = self.pool.begin().await?;
+        let row = sqlx::query(
+            "INSERT INTO newsletter_issues (sender_id, title, text_content, html_content, status, created_at, published_at) VALUES (?, ?, ?, ?, 'published', ?, ?) \
+             RETURNING id, sender_id, title, text_content, html_content, status, scheduled_for, created_at, published_at")
+            .bind(sender_id)
+            .bind(title)
+            .bind(text_content)
+            .bind(html_content)
+            .bind(now)
+            .bind(now)
+            .fetch_one(&mut *transaction)
+            .await?;
+        let issue = newsletter_issue_from_row(row);
+        enqueue_deliveries(&mut transaction, issue.id, now).await?;
+        transaction.commit().await?;
+        Ok(issue)
+    }
+
+    async fn find_newsletter_issue(&self, id: i32) -> Result<Option<NewsletterIssue>, StorageError> {
+        let row = sqlx::query("SELECT id, sender_id, title, text_content, html_content, status, scheduled_for, created_at, published_at FROM newsletter_issues WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(newsletter_issue_from_row))
+    }
+
+    async fn list_newsletter_issues(&self, limit: i64, offset: i64) -> Result<Vec<NewsletterIssue>, StorageError> {
+        let rows = sqlx::query(
+            "SELECT id, sender_id, title, text_content, html_content, status, scheduled_for, created_at, published_at FROM newsletter_issues \
+             ORDER BY id DESC LIMIT ? OFFSET ?")
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(newsletter_issue_from_row).collect())
+    }
+
+    async fn count_newsletter_issues(&self) -> Result<i64, StorageError> {
+        let row = sqlx::query("SELECT COUNT(*) AS count FROM newsletter_issues")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.get("count"))
+    }
+
+    async fn add_draft_issue(&self, sender_id: i32, title: &str, text_content: &str, html_content: &str) -> Result<NewsletterIssue, StorageError> {
+        let row = sqlx::query(
+            "INSERT INTO newsletter_issues (sender_id, title, text_content, html_content, status, created_at) VALUES (?, ?, ?, ?, 'draft', ?) \
+             RETURNING id, sender_id, title, text_content, html_content, status, scheduled_for, created_at, published_at")
+            .bind(sender_id)
+            .bind(title)
+            .bind(text_content)
+            .bind(html_content)
+            .bind(Utc::now())
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(newsletter_issue_from_row(row))
+    }
+
+    async fn update_draft_issue(&self, id: i32, title: &str, text_content: &str, html_content: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE newsletter_issues SET title = ?, text_content = ?, html_content = ? \
+             WHERE id = ? AND status IN ('draft', 'scheduled')")
+            .bind(title)
+            .bind(text_content)
+            .bind(html_content)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn schedule_issue(&self, id: i32, send_at: DateTime<Utc>) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE newsletter_issues SET status = 'scheduled', scheduled_for = ? WHERE id = ? AND status IN ('draft', 'scheduled')")
+            .bind(send_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn cancel_issue(&self, id: i32) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE newsletter_issues SET status = 'cancelled' WHERE id = ? AND status IN ('draft', 'scheduled')")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn publish_due_issue(&self, now: DateTime<Utc>) -> Result<Option<NewsletterIssue>, StorageError> {
+        let mut transaction = self.pool.begin().await?;
+        // The status guard makes this a no-op for whoever comes second
+        let row = sqlx::query(
+            "UPDATE newsletter_issues SET status = 'published', published_at = ? \
+             WHERE status = 'scheduled' AND id = (SELECT id FROM newsletter_issues \
+                 WHERE status = 'scheduled' AND scheduled_for <= ? \
+                 ORDER BY scheduled_for, id LIMIT 1) \
+             RETURNING id, sender_id, title, text_content, html_content, status, scheduled_for, created_at, published_at")
+            .bind(now)
+            .bind(now)
+            .fetch_optional(&mut *transaction)
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let issue = newsletter_issue_from_row(row);
+        enqueue_deliveries(&mut transaction, issue.id, now).await?;
+        transaction.commit().await?;
+        Ok(Some(issue))
+    }
+}
+
+#[async_trait]
+impl DeliveryQueueStore for SqliteStore {
+    async fn claim_delivery_tasks(&self, now: DateTime<Utc>, lease_until: DateTime<Utc>, limit: i64) -> Result<Vec<DeliveryTask>, StorageError> {
+        // A single statement, so two workers can never lease the same task
+        let rows = sqlx::query(
+            "UPDATE issue_delivery_queue SET claimed_until = ? \
+             WHERE id IN (SELECT id FROM issue_delivery_queue \
+                 WHERE status = 'queued' AND execute_after <= ? AND (claimed_until IS NULL OR claimed_until <= ?) \
+                 ORDER BY id LIMIT ?) \
+             RETURNING id, issue_id, subscription_id, email, status, attempts, execute_after, claimed_until, last_error")
+            .bind(lease_until)
+            .bind(now)
+            .bind(now)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        // RETURNING does not keep the order of the subquery
+        let mut tasks: Vec<DeliveryTask> = rows.into_iter().map(delivery_task_from_row).collect();
+        tasks.sort_by_key(|task| task.id);
+        Ok(tasks)
+    }
+
+    async fn complete_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE issue_delivery_queue SET status = 'sent', attempts = attempts + 1, claimed_until = NULL, last_error = NULL \
+             WHERE id = ? AND claimed_until = ? AND claimed_until > ?")
+            .bind(id)
+            .bind(lease_until)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn retry_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, execute_after: DateTime<Utc>, error: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE issue_delivery_queue SET attempts = attempts + 1, execute_after = ?, claimed_until = NULL, last_error = ? \
+             WHERE id = ? AND claimed_until = ? AND claimed_until > ?")
+            .bind(execute_after)
+            .bind(error)
+            .bind(id)
+            .bind(lease_until)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn fail_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, error: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE issue_delivery_queue SET status = 'failed', attempts = attempts + 1, claimed_until = NULL, last_error = ? \
+             WHERE id = ? AND claimed_until = ? AND claimed_until > ?")
+            .bind(error)
+            .bind(id)
+            .bind(lease_until)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn bounce_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, error: &str) -> Result<bool, StorageError> {
+        let result = sqlx::query(
+            "UPDATE issue_delivery_queue SET status = 'bounced', attempts = attempts + 1, claimed_until = NULL, last_error = ? \
+             WHERE id = ? AND claimed_until = ? AND claimed_until > ?")
+            .bind(error)
+            .bind(id)
+            .bind(lease_until)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn count_issue_deliveries(&self, issue_id: i32) -> Result<DeliveryCounts, StorageError> {
+        let rows = sqlx::query("SELECT status, COUNT(*) AS count FROM issue_delivery_queue WHERE issue_id = ? GROUP BY status")
+            .bind(issue_id)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut counts = DeliveryCounts::default();
+        for row in rows {
+            counts.add(row.get("status"), row.get("count"));
+        }
+        Ok(counts)
+    }
+
+    async fn count_pending_delivery_tasks(&self) -> Result<i64, StorageError> {
+        let row = sqlx::query("SELECT COUNT(*) AS pending FROM issue_delivery_queue WHERE status = 'queued'")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.get("pending"))
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for SqliteStore {
+    async fn reserve_idempotency_key(&self, sender_id: i32, key: &str, request_hash: &str, now: DateTime<Utc>, expired_before: DateTime<Utc>) -> Result<Option<IdempotencyRecord>, StorageError> {
+        sqlx::query("DELETE FROM idempotency WHERE created_at < ?")
+            .bind(expired_before)
+            .execute(&self.pool)
+            .await?;
+        // The primary key makes this atomic: only one of two concurrent requests inserts a row
+        let inserted = sqlx::query(
+            "INSERT INTO idempotency (sender_id, idempotency_key, request_hash, created_at) VALUES (?, ?, ?, ?) \
+             ON CONFLICT DO NOTHING")
+            .bind(sender_id)
+            .bind(key)
+            .bind(request_hash)
+            .bind(now)
+            .execute(&self.pool)
+            .await?
+            .rows_affected() > 0;
+        if inserted {
+            return Ok(None);
+        }
+        self.find_idempotency_record(sender_id, key).await
+    }
+
+    async fn find_idempotency_record(&self, sender_id: i32, key: &str) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let row = sqlx::query(
+            "SELECT request_hash, response_status_code, response_headers, response_body FROM idempotency WHERE sender_id = ? AND idempotency_key = ?")
+            .bind(sender_id)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(idempotency_record_from_row))
+    }
+
+    async fn save_idempotent_response(&self, sender_id: i32, key: &str, response: &SavedResponse) -> Result<(), StorageError> {
+        let headers = serde_json::to_string(&response.headers).expect("Headers are plain strings");
+        sqlx::query(
+            "UPDATE idempotency SET response_status_code = ?, response_headers = ?, response_body = ? WHERE sender_id = ? AND idempotency_key = ?")
+            .bind(response.status_code as i64)
+            .bind(headers)
+            .bind(&response.body)
+            .bind(sender_id)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, sender_id: i32, key: &str) -> Result<(), StorageError> {
+        sqlx::query("DELETE FROM idempotency WHERE sender_id = ? AND idempotency_key = ?")
+            .bind(sender_id)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn load_session(&self, key_hash: &str, now: DateTime<Utc>) -> Result<Option<String>, StorageError> {
+        let row = sqlx::query("SELECT state FROM sessions WHERE key_hash = ? AND expires_at >= ?")
+            .bind(key_hash)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.get("state")))
+    }
+
+    async fn save_session(&self, key_hash: &str, state: &str, expires_at: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), StorageError> {
+        sqlx::query("DELETE FROM sessions WHERE expires_at < ?")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO sessions (key_hash, state, expires_at) VALUES (?, ?, ?) \
+             ON CONFLICT (key_hash) DO UPDATE SET state = excluded.state, expires_at = excluded.expires_at")
+            .bind(key_hash)
+            .bind(state)
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn extend_session(&self, key_hash: &str, expires_at: DateTime<Utc>) -> Result<(), StorageError> {
+        sqlx::query("UPDATE sessions SET expires_at = ? WHERE key_hash = ?")
+            .bind(expires_at)
+            .bind(key_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_session(&self, key_hash: &str) -> Result<(), StorageError> {
+        sqlx::query("DELETE FROM sessions WHERE key_hash = ?")
+            .bind(key_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -1,82 +1,249 @@
 use std::net::TcpListener;
+use std::sync::Arc;
+use actix_session::config::{BrowserSession, CookieContentSecurity, TtlExtensionPolicy};
+use actix_session::SessionMiddleware;
 use actix_web::{
-    dev::Server, web::{self, Data}, App, HttpServer 
+    cookie::{Key, SameSite}, dev::Server, web::{self, Data}, App, HttpServer 
 };
+use secrecy::ExposeSecret;
+use tokio::task::JoinHandle;
 use tracing_actix_web::TracingLogger;
-use sha3::Digest;
-use crate::{email_client::EmailClient, in_memory::Sender, routes::{get_subscription, publish_newsletter, subscription_confirm}};
-use crate::configuration::Properties;
-use crate::{in_memory::AppState, routes::{greet, health_check, subscribe}};
+use crate::{email_client::{EmailClient, EmailProvider, FileOutboxProvider, MailjetProvider, SmtpProvider}, routes::{admin_dashboard, cancel_draft, create_api_key, compose_newsletter, change_own_password, confirm_two_factor, disable_two_factor, enrol_two_factor, create_draft, create_sender, delete_sender, disable_sender, get_newsletter_issue, get_subscription, list_api_keys, list_newsletter_issues, list_own_test_addresses, list_senders, log_out, login, login_form, preview_newsletter, publish_composed_newsletter, publish_newsletter, regenerate_recovery_codes, revoke_api_key, schedule_draft, set_own_test_addresses, set_sender_role, subscription_confirm, test_send_newsletter, two_factor_form, two_factor_login, two_factor_status, unsubscribe, unsubscribe_landing, update_draft, UnsubscribeSigner}};
+use crate::authentication::{AuthError, PasswordHashing, SessionBackend};
+use crate::clock::{Clock, SystemClock};
+use crate::issue_delivery_worker::DeliveryWorker;
+use crate::issue_scheduler::IssueScheduler;
+use crate::configuration::{BootstrapAdminProperties, ContentLimitsProperties, EmailProviderKind, IdempotencyProperties, Properties, SessionProperties, SessionStoreKind, TwoFactorProperties};
+use crate::in_memory::InMemorySessions;
+use crate::routes::{greet, health_check, subscribe};
+use crate::storage::{build_storage, SenderRole, SessionStore, Storage};
+use crate::templating::{AdminPages, ConfirmationTemplate, NewsletterLayout};
 
 
 pub struct Application {
     port: u16,
     server: Server,
+    storage: web::Data<dyn Storage>,
+    worker: JoinHandle<()>,
+    scheduler: JoinHandle<()>,
 }
 
 impl Application {
     // We have converted the `build` function into a constructor for
     // `Application`.
     pub async fn build(configuration: Properties) -> Result<Self, std::io::Error> {
-        let app_state: AppState = AppState::new();
-        // TODO: Remove this hardcoded sender after writing an endpoint to register one
-        app_state.senders.write().unwrap().push(Sender{
-            username: "admin".to_string(),
-            pwd: format!("{:x}",sha3::Sha3_256::digest("admin".as_bytes())),
-        });
-        let data_store_shared = web::Data::new(app_state);
+        Self::build_with_clock(configuration, Arc::new(SystemClock)).await
+    }
+
+    /// `clock` drives the scheduler and the delivery worker, tests pass one they can fast-forward.
+    pub async fn build_with_clock(configuration: Properties, clock: Arc<dyn Clock>) -> Result<Self, std::io::Error> {
+        let storage = build_storage(&configuration)
+            .await
+            .map_err(std::io::Error::other)?;
+        let password_params = configuration.auth.password_hashing.params()
+            .map_err(std::io::Error::other)?;
+        let password_hashing = PasswordHashing::new(password_params)
+            .map_err(std::io::Error::other)?;
+        if let Some(admin) = &configuration.auth.bootstrap_admin {
+            bootstrap_admin(storage.as_ref(), &password_hashing, admin)
+                .await
+                .map_err(std::io::Error::other)?;
+        }
+        let session_store: Arc<dyn SessionStore> = match configuration.session.store {
+            SessionStoreKind::InMemory => Arc::new(InMemorySessions::default()),
+            SessionStoreKind::Storage => storage.clone(),
+        };
+        let sessions = SessionBackend::new(session_store, clock.clone());
+        let data_store_shared: web::Data<dyn Storage> = web::Data::from(storage);
+        let confirmation_template = ConfirmationTemplate::load(&configuration.templates)
+            .map_err(std::io::Error::other)?;
+        let newsletter_layout = NewsletterLayout::load(&configuration.templates)
+            .map_err(std::io::Error::other)?;
 
-        let sender_email = configuration
-            .email_client
-            .sender;
-        
+        let email_properties = &configuration.email_client;
+        let email_provider: Box<dyn EmailProvider> = match email_properties.provider {
+            // Fail fast rather than on the first email if the credentials are missing
+            EmailProviderKind::Mailjet => Box::new(
+                MailjetProvider::new(
+                    email_properties.base_url.clone(),
+                    email_properties.api_key.clone(),
+                    email_properties.api_secret.clone(),
+                ).map_err(std::io::Error::other)?
+            ),
+            EmailProviderKind::Smtp => Box::new(
+                SmtpProvider::new(&email_properties.smtp).map_err(std::io::Error::other)?
+            ),
+            EmailProviderKind::FileOutbox => Box::new(FileOutboxProvider::new(&email_properties.outbox_dir)),
+        };
         let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
+            email_properties.sender.clone(),
+            email_provider,
         );
         let address = format!(
             "{}:{}",
-            configuration.server_host, configuration.server_port
+            configuration.server.host, configuration.server.port
         );
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
-        let server = run(listener, data_store_shared, email_client, configuration.base_url)?;
+        let token_expiry = ConfirmationTokenExpiry(
+            chrono::Duration::minutes(configuration.auth.confirmation_token_expiry_minutes)
+        );
+        let unsubscribe_signer = Data::new(UnsubscribeSigner::new(configuration.auth.unsubscribe_secret));
+        let email_client = Data::new(email_client);
+        // The worker shares the store, client and signer with the HTTP handlers
+        let worker = tokio::spawn(DeliveryWorker {
+            storage: data_store_shared.clone(),
+            email_client: email_client.clone(),
+            base_url: configuration.server.base_url.clone(),
+            unsubscribe_signer: unsubscribe_signer.clone(),
+            properties: configuration.delivery,
+            clock: clock.clone(),
+        }.run_until_stopped());
+        let scheduler = tokio::spawn(IssueScheduler {
+            storage: data_store_shared.clone(),
+            clock: clock.clone(),
+            poll_interval: configuration.scheduler.poll_interval(),
+        }.run_until_stopped());
+        let server = run(listener, data_store_shared.clone(), email_client, confirmation_template, newsletter_layout, configuration.server.base_url, token_expiry, unsubscribe_signer, password_hashing, configuration.idempotency, configuration.content_limits, sessions, configuration.session, configuration.auth.two_factor, clock)?;
         // We "save" the bound port in one of `Application`'s fields
-        Ok(Self { port, server })
+        Ok(Self { port, server, storage: data_store_shared, worker, scheduler })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    pub fn storage(&self) -> web::Data<dyn Storage> {
+        self.storage.clone()
+    }
     
     // A more expressive name that makes it clear that
     // this function only returns when the application is stopped.
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let outcome = self.server.await;
+        self.worker.abort();
+        self.scheduler.abort();
+        outcome
     }
 }
 
+/// Create the first sender, so there is someone to create the others through the API.
+/// Once any sender exists this does nothing, a durable backend keeps it across restarts.
+async fn bootstrap_admin(
+    storage: &dyn Storage,
+    password_hashing: &PasswordHashing,
+    admin: &BootstrapAdminProperties,
+) -> Result<(), AuthError> {
+    if !storage.list_senders().await?.is_empty() {
+        return Ok(());
+    }
+    let pwd = password_hashing.hash(admin.password.clone()).await?;
+    storage.add_sender(&admin.username, &admin.display_name, &pwd, SenderRole::Admin).await?;
+    tracing::info!("Created the bootstrap admin sender `{}`", admin.username);
+    Ok(())
+}
+
 pub struct ApplicationBaseUrl(pub String);
 
+pub struct ConfirmationTokenExpiry(pub chrono::Duration);
+
+#[allow(clippy::too_many_arguments)]
 pub fn run(listener: TcpListener, 
-    app_state:web::Data<AppState>,
-    email_client: EmailClient,
-    base_url_str: String) -> Result<Server, std::io::Error> {
-    let email_client = Data::new(email_client);
+    storage: web::Data<dyn Storage>,
+    email_client: web::Data<EmailClient>,
+    confirmation_template: ConfirmationTemplate,
+    newsletter_layout: NewsletterLayout,
+    base_url_str: String,
+    token_expiry: ConfirmationTokenExpiry,
+    unsubscribe_signer: web::Data<UnsubscribeSigner>,
+    password_hashing: PasswordHashing,
+    idempotency: IdempotencyProperties,
+    content_limits: ContentLimitsProperties,
+    sessions: SessionBackend,
+    session_properties: SessionProperties,
+    two_factor: TwoFactorProperties,
+    clock: Arc<dyn Clock>) -> Result<Server, std::io::Error> {
+    let session_key = Key::try_from(session_properties.secret.expose_secret().as_bytes())
+        .map_err(std::io::Error::other)?;
     let base_url = Data::new(ApplicationBaseUrl(base_url_str));
+    let confirmation_template = Data::new(confirmation_template);
+    let newsletter_layout = Data::new(newsletter_layout);
+    let token_expiry = Data::new(token_expiry);
+    let password_hashing = Data::new(password_hashing);
+    let idempotency = Data::new(idempotency);
+    let content_limits = Data::new(content_limits);
+    let two_factor = Data::new(two_factor);
+    let clock: Data<dyn Clock> = Data::from(clock);
+    let admin_pages = Data::new(AdminPages::new());
     let server = HttpServer::new(move|| {
+        // Signed rather than encrypted, the cookie only holds the session key
+        let session_middleware = SessionMiddleware::builder(sessions.clone(), session_key.clone())
+            .cookie_name("session".to_string())
+            .cookie_secure(session_properties.secure_cookie)
+            .cookie_same_site(SameSite::Lax)
+            .cookie_content_security(CookieContentSecurity::Signed)
+            .session_lifecycle(BrowserSession::default()
+                .state_ttl(session_properties.idle_timeout())
+                .state_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest))
+            .build();
         App::new()
+            .wrap(session_middleware)
             .wrap(TracingLogger::default())
-            .app_data(app_state.clone())
+            .app_data(storage.clone())
             .app_data(email_client.clone()) // each app will get a shared reference to same client (to use the same connection pool created by reqwest under the hood)
+            .app_data(confirmation_template.clone())
+            .app_data(newsletter_layout.clone())
             .app_data(base_url.clone())
+            .app_data(token_expiry.clone())
+            .app_data(unsubscribe_signer.clone())
+            .app_data(password_hashing.clone())
+            .app_data(idempotency.clone())
+            .app_data(content_limits.clone())
+            .app_data(two_factor.clone())
+            .app_data(clock.clone())
+            .app_data(admin_pages.clone())
             .route("/health_check", web::get().to(health_check))
-            .route("/", web::get().to(greet))
-            .route("/{name}", web::get().to(greet))
             .route("/subscriptions/find",web::get().to(get_subscription))
             .route("/subscriptions", web::post().to(subscribe))
             .route("/subscriptions/confirm", web::get().to(subscription_confirm))
+            .route("/subscriptions/unsubscribe", web::get().to(unsubscribe_landing))
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route("/newsletters", web::get().to(list_newsletter_issues))
             .route("/newsletters", web::post().to(publish_newsletter))
+            .route("/newsletters/{issue_id}", web::get().to(get_newsletter_issue))
+            .route("/newsletters/preview", web::post().to(preview_newsletter))
+            .route("/newsletters/test-send", web::post().to(test_send_newsletter))
+            .route("/newsletters/drafts", web::post().to(create_draft))
+            .route("/newsletters/drafts/{issue_id}", web::put().to(update_draft))
+            .route("/newsletters/drafts/{issue_id}/schedule", web::post().to(schedule_draft))
+            .route("/newsletters/drafts/{issue_id}/cancel", web::post().to(cancel_draft))
+            .route("/senders", web::get().to(list_senders))
+            .route("/senders", web::post().to(create_sender))
+            .route("/senders/me/password", web::put().to(change_own_password))
+            .route("/senders/me/test-addresses", web::get().to(list_own_test_addresses))
+            .route("/senders/me/test-addresses", web::put().to(set_own_test_addresses))
+            .route("/senders/me/api-keys", web::get().to(list_api_keys))
+            .route("/senders/me/api-keys", web::post().to(create_api_key))
+            .route("/senders/me/api-keys/{key_id}", web::delete().to(revoke_api_key))
+            .route("/senders/me/two-factor", web::get().to(two_factor_status))
+            .route("/senders/me/two-factor", web::post().to(enrol_two_factor))
+            .route("/senders/me/two-factor", web::delete().to(disable_two_factor))
+            .route("/senders/me/two-factor/confirm", web::post().to(confirm_two_factor))
+            .route("/senders/me/two-factor/recovery-codes", web::post().to(regenerate_recovery_codes))
+            .route("/senders/{sender_id}/disable", web::post().to(disable_sender))
+            .route("/senders/{sender_id}/role", web::put().to(set_sender_role))
+            .route("/senders/{sender_id}", web::delete().to(delete_sender))
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            .route("/login/two-factor", web::get().to(two_factor_form))
+            .route("/login/two-factor", web::post().to(two_factor_login))
+            .route("/admin/dashboard", web::get().to(admin_dashboard))
+            .route("/admin/newsletters", web::get().to(compose_newsletter))
+            .route("/admin/newsletters", web::post().to(publish_composed_newsletter))
+            .route("/admin/logout", web::post().to(log_out))
+            // `/{name}` matches any single segment GET, so it has to come last
+            .route("/", web::get().to(greet))
+            .route("/{name}", web::get().to(greet))
             
             
     })
@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::{Properties, StorageBackend};
+use crate::in_memory::AppState;
+use crate::postgres::PostgresStore;
+use crate::sqlite::SqliteStore;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Subscription{
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub status: String,
+    /// Extra values the subscriber gave, available to the newsletter templates as `fields`.
+    pub custom_fields: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Sender{
+    pub id: i32,
+    pub username: String,
+    pub display_name: String,
+    /// An Argon2id PHC string, or a legacy unsalted SHA3-256 hex digest
+    /// that is upgraded on the next successful login.
+    #[serde(skip_serializing)]
+    pub pwd: String,
+    /// Disabled senders can no longer log in, their past issues are kept.
+    pub enabled: bool,
+    pub role: SenderRole,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What a sender may do, each role can do everything the ones below it can.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum SenderRole {
+    /// Reads subscriber stats.
+    Viewer,
+    /// Writes, schedules and publishes newsletters.
+    Editor,
+    /// Manages the senders.
+    Admin,
+}
+
+impl SenderRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SenderRole::Viewer => "viewer",
+            SenderRole::Editor => "editor",
+            SenderRole::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for SenderRole {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [SenderRole::Viewer, SenderRole::Editor, SenderRole::Admin].into_iter()
+            .find(|role| role.as_str() == value)
+            .ok_or_else(|| format!("unknown role `{}`", value))
+    }
+}
+
+impl std::fmt::Display for SenderRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A sender's RFC 6238 secret, base32 encoded like authenticator apps take it.
+#[derive(Clone, Debug)]
+pub struct TotpEnrolment {
+    pub sender_id: i32,
+    pub secret: String,
+    /// `None` until the sender showed a code from their app, the second factor is not asked for before.
+    pub confirmed_at: Option<DateTime<Utc>>,
+    /// The time step of the last code accepted.
+    pub last_used_step: Option<i64>,
+    /// Codes tried since the last right one, or since the last lockout.
+    pub failed_attempts: i32,
+    /// No code is accepted before then.
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// A key a sender issued for a machine client. Only a hash of it is kept,
+/// `prefix` is the part of the key the sender can still recognise it by.
+#[derive(Serialize, Clone, Debug)]
+pub struct ApiKey{
+    pub id: i32,
+    pub sender_id: i32,
+    pub name: String,
+    pub prefix: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    /// What the key may be used for, e.g. `newsletters:publish`.
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A newsletter, from draft to published, and who wrote it.
+#[derive(Serialize, Clone, Debug)]
+pub struct NewsletterIssue{
+    pub id: i32,
+    /// `None` once the sender who published it has been deleted.
+    pub sender_id: Option<i32>,
+    pub title: String,
+    pub text_content: String,
+    pub html_content: String,
+    /// `draft`, `scheduled`, `published` or `cancelled`.
+    pub status: String,
+    pub scheduled_for: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub published_at: Option<DateTime<Utc>>,
+}
+
+impl NewsletterIssue {
+    /// Drafts and scheduled issues can still be edited, scheduled (again) or cancelled.
+    pub fn is_editable(&self) -> bool {
+        self.status == "draft" || self.status == "scheduled"
+    }
+}
+
+/// How the deliveries of one issue are doing, by task status.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct DeliveryCounts{
+    pub queued: i64,
+    pub sent: i64,
+    pub failed: i64,
+    /// Rejected for good by the provider, e.g. an address that does not exist.
+    pub bounced: i64,
+}
+
+impl DeliveryCounts {
+    pub(crate) fn add(&mut self, status: &str, count: i64) {
+        match status {
+            "queued" => self.queued += count,
+            "sent" => self.sent += count,
+            "failed" => self.failed += count,
+            "bounced" => self.bounced += count,
+            _ => {}
+        }
+    }
+}
+
+/// A single-use link token sent in the confirmation email.
+#[derive(Clone, Debug)]
+pub struct ConfirmationToken{
+    pub token: String,
+    pub subscription_id: i32,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+/// What happened when a confirmation token was presented.
+#[derive(Debug, PartialEq)]
+pub enum TokenOutcome {
+    /// The token was valid and is now used up.
+    Valid(i32),
+    Unknown,
+    AlreadyUsed,
+    Expired,
+}
+
+impl ConfirmationToken {
+    /// Decide the outcome of presenting this token at `now`, without consuming it.
+    pub fn outcome(&self, now: DateTime<Utc>) -> TokenOutcome {
+        if self.used_at.is_some() {
+            TokenOutcome::AlreadyUsed
+        } else if self.expires_at <= now {
+            TokenOutcome::Expired
+        } else {
+            TokenOutcome::Valid(self.subscription_id)
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    #[error("Database query failed")]
+    Database(#[source] sqlx::Error),
+    /// A value that has to be unique (e.g. a sender's username) is taken,
+    /// checking beforehand does not rule it out for concurrent requests.
+    #[error("{0} is already taken")]
+    UniqueViolation(String),
+    #[error("Failed to run database migrations")]
+    Migration(#[from] sqlx::migrate::MigrateError),
+}
+
+impl From<sqlx::Error> for StorageError {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                StorageError::UniqueViolation(e.constraint().unwrap_or("A unique value").to_string())
+            }
+            _ => StorageError::Database(error),
+        }
+    }
+}
+
+/// Everything the handlers need to know about subscriptions,
+/// regardless of where they are actually kept.
+#[async_trait]
+pub trait SubscriptionStore: Send + Sync {
+    /// Insert a new subscription in the `pending_confirmation` state.
+    async fn add_subscription(&self, username: &str, email: &str, custom_fields: &BTreeMap<String, String>) -> Result<Subscription, StorageError>;
+    async fn find_subscription_by_id(&self, id: i32) -> Result<Option<Subscription>, StorageError>;
+    async fn find_subscription_by_email(&self, email: &str) -> Result<Option<Subscription>, StorageError>;
+    /// Returns `false` if there is no subscription with the given id.
+    async fn update_subscription_status(&self, id: i32, status: &str) -> Result<bool, StorageError>;
+    async fn confirmed_subscriptions(&self) -> Result<Vec<Subscription>, StorageError>;
+}
+
+#[async_trait]
+pub trait SenderStore: Send + Sync {
+    /// New senders start enabled. A taken username is a `StorageError::UniqueViolation`.
+    async fn add_sender(&self, username: &str, display_name: &str, pwd: &str, role: SenderRole) -> Result<Sender, StorageError>;
+    async fn find_sender(&self, username: &str) -> Result<Option<Sender>, StorageError>;
+    async fn find_sender_by_id(&self, id: i32) -> Result<Option<Sender>, StorageError>;
+    /// Ordered by id.
+    async fn list_senders(&self) -> Result<Vec<Sender>, StorageError>;
+    /// Returns `false` if there is no sender with the given id.
+    async fn set_sender_enabled(&self, id: i32, enabled: bool) -> Result<bool, StorageError>;
+    /// Returns `false` if there is no sender with the given id.
+    async fn set_sender_role(&self, id: i32, role: SenderRole) -> Result<bool, StorageError>;
+    /// Returns `false` if there is no sender with the given id.
+    async fn delete_sender(&self, id: i32) -> Result<bool, StorageError>;
+    /// Returns `false` if there is no sender with the given username.
+    async fn update_sender_password(&self, username: &str, pwd: &str) -> Result<bool, StorageError>;
+    /// Replace the addresses the sender may send test emails to.
+    async fn set_sender_test_addresses(&self, sender_id: i32, emails: &[String]) -> Result<(), StorageError>;
+    /// Sorted.
+    async fn list_sender_test_addresses(&self, sender_id: i32) -> Result<Vec<String>, StorageError>;
+}
+
+#[async_trait]
+pub trait ApiKeyStore: Send + Sync {
+    async fn add_api_key(&self, sender_id: i32, name: &str, prefix: &str, key_hash: &str, scopes: &[String], expires_at: Option<DateTime<Utc>>) -> Result<ApiKey, StorageError>;
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, StorageError>;
+    /// Ordered by id.
+    async fn list_api_keys(&self, sender_id: i32) -> Result<Vec<ApiKey>, StorageError>;
+    /// Returns `false` if the sender has no key with the given id.
+    async fn delete_api_key(&self, sender_id: i32, id: i32) -> Result<bool, StorageError>;
+    async fn touch_api_key(&self, id: i32, used_at: DateTime<Utc>) -> Result<(), StorageError>;
+}
+
+#[async_trait]
+pub trait TwoFactorStore: Send + Sync {
+    /// Replaces an enrolment that was not confirmed yet. Returns `false`, changing nothing,
+    /// if the sender already has a confirmed one.
+    async fn start_totp_enrolment(&self, sender_id: i32, secret: &str) -> Result<bool, StorageError>;
+    async fn find_totp_enrolment(&self, sender_id: i32) -> Result<Option<TotpEnrolment>, StorageError>;
+    /// Returns `false` if there is no enrolment waiting for confirmation.
+    async fn confirm_totp_enrolment(&self, sender_id: i32, confirmed_at: DateTime<Utc>) -> Result<bool, StorageError>;
+    /// Accepts a code of `step` if none of it or of a later step was accepted before,
+    /// so every code works once.
+    async fn use_totp_step(&self, sender_id: i32, step: i64) -> Result<bool, StorageError>;
+    /// Counts a code that is about to be checked. Returns `false`, counting nothing, while the sender
+    /// is locked out. The attempt that makes `max_attempts` locks them out until `locked_until`,
+    /// so parallel requests cannot try more codes than that.
+    async fn start_second_factor_attempt(&self, sender_id: i32, max_attempts: i32, now: DateTime<Utc>, locked_until: DateTime<Utc>) -> Result<bool, StorageError>;
+    /// After a right code: the attempts so far no longer count, and a lockout they caused is lifted.
+    async fn clear_second_factor_attempts(&self, sender_id: i32) -> Result<(), StorageError>;
+    /// Drops the recovery codes too. Returns `false` if there was no enrolment.
+    async fn delete_totp_enrolment(&self, sender_id: i32) -> Result<bool, StorageError>;
+    /// Replaces every recovery code of the sender, used or not.
+    async fn replace_recovery_codes(&self, sender_id: i32, code_hashes: &[String]) -> Result<(), StorageError>;
+    /// Returns `false` if the sender has no such code left.
+    async fn use_recovery_code(&self, sender_id: i32, code_hash: &str, used_at: DateTime<Utc>) -> Result<bool, StorageError>;
+    async fn count_unused_recovery_codes(&self, sender_id: i32) -> Result<i64, StorageError>;
+}
+
+#[async_trait]
+pub trait ConfirmationTokenStore: Send + Sync {
+    /// Store a new token for the subscription, dropping any previous one
+    /// so only the latest confirmation link works.
+    async fn rotate_confirmation_token(&self, subscription_id: i32, token: &str, expires_at: DateTime<Utc>) -> Result<(), StorageError>;
+    /// Mark the token as used if it is still valid at `now`.
+    /// A token can only ever be consumed once.
+    async fn consume_confirmation_token(&self, token: &str, now: DateTime<Utc>) -> Result<TokenOutcome, StorageError>;
+}
+
+/// One recipient of one issue, waiting in (or done with) the delivery queue.
+#[derive(Clone, Debug)]
+pub struct DeliveryTask{
+    pub id: i32,
+    pub issue_id: i32,
+    pub subscription_id: i32,
+    pub email: String,
+    /// `queued`, `sent` or `failed`
+    pub status: String,
+    /// How many times sending was tried so far.
+    pub attempts: i32,
+    pub execute_after: DateTime<Utc>,
+    pub claimed_until: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+#[async_trait]
+pub trait NewsletterIssueStore: Send + Sync {
+    /// Store the issue and queue one delivery task per confirmed subscriber, atomically.
+    async fn add_newsletter_issue(&self, sender_id: i32, title: &str, text_content: &str, html_content: &str) -> Result<NewsletterIssue, StorageError>;
+    async fn find_newsletter_issue(&self, id: i32) -> Result<Option<NewsletterIssue>, StorageError>;
+    /// Newest first.
+    async fn list_newsletter_issues(&self, limit: i64, offset: i64) -> Result<Vec<NewsletterIssue>, StorageError>;
+    async fn count_newsletter_issues(&self) -> Result<i64, StorageError>;
+    /// Store an issue to be published later, nothing is queued.
+    async fn add_draft_issue(&self, sender_id: i32, title: &str, text_content: &str, html_content: &str) -> Result<NewsletterIssue, StorageError>;
+    /// The methods below only touch an issue that `is_editable`, and return whether they did.
+    async fn update_draft_issue(&self, id: i32, title: &str, text_content: &str, html_content: &str) -> Result<bool, StorageError>;
+    async fn schedule_issue(&self, id: i32, send_at: DateTime<Utc>) -> Result<bool, StorageError>;
+    async fn cancel_issue(&self, id: i32) -> Result<bool, StorageError>;
+    /// Atomically publish one scheduled issue that is due and queue its deliveries, like
+    /// `add_newsletter_issue`. An issue is only ever published once, whoever else is polling.
+    async fn publish_due_issue(&self, now: DateTime<Utc>) -> Result<Option<NewsletterIssue>, StorageError>;
+}
+
+/// The queue drained by the delivery worker. Claims are leases: a task stays
+/// invisible to other workers until `lease_until`, so a crashed worker's tasks come back.
+#[async_trait]
+pub trait DeliveryQueueStore: Send + Sync {
+    /// Atomically lease up to `limit` of the oldest queued tasks that are due and not leased by someone else,
+    /// ordered by id.
+    async fn claim_delivery_tasks(&self, now: DateTime<Utc>, lease_until: DateTime<Utc>, limit: i64) -> Result<Vec<DeliveryTask>, StorageError>;
+    /// The outcome of a task is only recorded while the caller holds the lease it claimed the task with,
+    /// `lease_until` must still be the task's and later than `now`. Otherwise nothing changes and `false`
+    /// is returned: the task is someone else's now, or will be.
+    async fn complete_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>) -> Result<bool, StorageError>;
+    /// Release the task so it is tried again from `execute_after`.
+    async fn retry_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, execute_after: DateTime<Utc>, error: &str) -> Result<bool, StorageError>;
+    /// Give up on the task for good.
+    async fn fail_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, error: &str) -> Result<bool, StorageError>;
+    /// Give up on the task for good, the provider will never accept it.
+    async fn bounce_delivery_task(&self, id: i32, lease_until: DateTime<Utc>, now: DateTime<Utc>, error: &str) -> Result<bool, StorageError>;
+    async fn count_issue_deliveries(&self, issue_id: i32) -> Result<DeliveryCounts, StorageError>;
+    /// Tasks still `queued`, leased or not.
+    async fn count_pending_delivery_tasks(&self) -> Result<i64, StorageError>;
+}
+
+/// A response kept to be replayed for a repeated `Idempotency-Key`.
+#[derive(Clone, Debug)]
+pub struct SavedResponse{
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IdempotencyRecord{
+    pub request_hash: String,
+    /// `None` while the first request is still in flight.
+    pub response: Option<SavedResponse>,
+}
+
+#[async_trait]
+pub trait IdempotencyStore: Send + Sync {
+    /// Atomically reserve the key for this request, after dropping the keys created before `expired_before`.
+    /// Returns `None` if the key is now ours, or the record that already holds it.
+    async fn reserve_idempotency_key(&self, sender_id: i32, key: &str, request_hash: &str, now: DateTime<Utc>, expired_before: DateTime<Utc>) -> Result<Option<IdempotencyRecord>, StorageError>;
+    async fn find_idempotency_record(&self, sender_id: i32, key: &str) -> Result<Option<IdempotencyRecord>, StorageError>;
+    async fn save_idempotent_response(&self, sender_id: i32, key: &str, response: &SavedResponse) -> Result<(), StorageError>;
+    /// Give the key up when the request failed, so it can be retried.
+    async fn release_idempotency_key(&self, sender_id: i32, key: &str) -> Result<(), StorageError>;
+}
+
+/// The state of the admin pages' sessions, by a hash of the key in the session cookie.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// `None` if there is no such session, or it expired before `now`.
+    async fn load_session(&self, key_hash: &str, now: DateTime<Utc>) -> Result<Option<String>, StorageError>;
+    /// Insert or replace the session, after dropping the ones that expired before `now`.
+    async fn save_session(&self, key_hash: &str, state: &str, expires_at: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), StorageError>;
+    async fn extend_session(&self, key_hash: &str, expires_at: DateTime<Utc>) -> Result<(), StorageError>;
+    async fn delete_session(&self, key_hash: &str) -> Result<(), StorageError>;
+}
+
+/// The full storage backend shared by the application as `web::Data<dyn Storage>`.
+pub trait Storage: SubscriptionStore + SenderStore + ApiKeyStore + TwoFactorStore + ConfirmationTokenStore + NewsletterIssueStore + DeliveryQueueStore + IdempotencyStore + SessionStore {}
+
+impl<T: SubscriptionStore + SenderStore + ApiKeyStore + TwoFactorStore + ConfirmationTokenStore + NewsletterIssueStore + DeliveryQueueStore + IdempotencyStore + SessionStore> Storage for T {}
+
+/// Build the storage backend selected in the configuration.
+pub async fn build_storage(configuration: &Properties) -> Result<Arc<dyn Storage>, StorageError> {
+    let storage: Arc<dyn Storage> = match configuration.storage.backend {
+        StorageBackend::InMemory => Arc::new(AppState::new()),
+        StorageBackend::Sqlite => Arc::new(SqliteStore::connect(&configuration.storage.sqlite_path).await?),
+        StorageBackend::Postgres => Arc::new(PostgresStore::connect(&configuration.database).await?),
+    };
+    Ok(storage)
+}
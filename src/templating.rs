@@ -0,0 +1,455 @@
+//! Newsletter issues and the confirmation email are minijinja templates.
+//! Values are HTML-escaped in the html parts, and a template may only use the
+//! variables it is rendered with, which is checked once, before anything is sent.
+//! An issue is sanitized before it is rendered, so nothing it prints may escape
+//! the escaping: no `safe`, no `autoescape`, and no values inside HTML tags.
+
+use std::collections::BTreeMap;
+
+use minijinja::{context, Environment, Value};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::configuration::TemplateProperties;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TemplateError {
+    #[error("{}", .0.join("; "))]
+    Invalid(Vec<String>),
+    #[error("Failed to read the template file `{0}`")]
+    Read(String, #[source] std::io::Error),
+    #[error("Failed to render the template")]
+    Render(#[from] minijinja::Error),
+}
+
+/// What a newsletter template knows about the subscriber it is rendered for.
+pub struct RecipientContext<'a> {
+    pub username: &'a str,
+    pub email: &'a str,
+    pub unsubscribe_url: &'a str,
+    pub fields: &'a BTreeMap<String, String>,
+}
+
+impl RecipientContext<'_> {
+    pub const VARIABLES: [&'static str; 4] = ["username", "email", "unsubscribe_url", "fields"];
+}
+
+/// One subscriber's copy of an issue.
+pub struct RenderedEmail {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+/// The title and both content parts of an issue, checked and ready to be rendered per recipient.
+#[derive(Debug)]
+pub struct NewsletterTemplate {
+    environment: Environment<'static>,
+}
+
+impl NewsletterTemplate {
+    /// Every syntax error, unknown variable and way around the escaping of the three parts, at once.
+    pub fn compile(title: &str, html: &str, text: &str) -> Result<Self, TemplateError> {
+        let parts = [("title", title), ("content.html", html), ("content.text", text)];
+        let mut unsafe_tags: Vec<String> = parts.iter()
+            .flat_map(|(name, source)| disallowed_statements(name, source))
+            .collect();
+        unsafe_tags.extend(tags_inside_html_tags("content.html", html));
+        let mut environment = match compile(parts, &RecipientContext::VARIABLES) {
+            Ok(environment) if unsafe_tags.is_empty() => environment,
+            Ok(_) => return Err(TemplateError::Invalid(unsafe_tags)),
+            Err(TemplateError::Invalid(mut problems)) => {
+                problems.extend(unsafe_tags);
+                return Err(TemplateError::Invalid(problems));
+            }
+            Err(e) => return Err(e),
+        };
+        // The sanitizer never saw what a value turns into: `safe` marks nothing as safe,
+        // only what is escaped already (or is a link we built) goes out as it is
+        environment.add_filter("safe", |value: Value| value);
+        Ok(Self { environment })
+    }
+
+    pub fn render(&self, recipient: &RecipientContext<'_>) -> Result<RenderedEmail, TemplateError> {
+        let context = context! {
+            username => recipient.username,
+            email => recipient.email,
+            unsubscribe_url => trusted_url(recipient.unsubscribe_url),
+            fields => recipient.fields,
+        };
+        Ok(RenderedEmail {
+            subject: render(&self.environment, "title", &context)?,
+            html: render(&self.environment, "content.html", &context)?,
+            text: render(&self.environment, "content.text", &context)?,
+        })
+    }
+}
+
+/// What the confirmation email template knows.
+pub struct ConfirmationContext<'a> {
+    pub username: &'a str,
+    pub confirmation_link: &'a str,
+}
+
+impl ConfirmationContext<'_> {
+    pub const VARIABLES: [&'static str; 2] = ["username", "confirmation_link"];
+}
+
+/// The confirmation email, from `templates/` unless the configuration points at other files.
+#[derive(Debug)]
+pub struct ConfirmationTemplate {
+    environment: Environment<'static>,
+}
+
+impl ConfirmationTemplate {
+    pub fn load(properties: &TemplateProperties) -> Result<Self, TemplateError> {
+        let html = read_or(&properties.confirmation_email_html, include_str!("../templates/confirmation_email.html"))?;
+        let text = read_or(&properties.confirmation_email_text, include_str!("../templates/confirmation_email.txt"))?;
+        let environment = compile(
+            [("confirmation_email.html", html.as_str()), ("confirmation_email.txt", text.as_str())],
+            &ConfirmationContext::VARIABLES,
+        )?;
+        Ok(Self { environment })
+    }
+
+    /// The html and the text body.
+    pub fn render(&self, confirmation: &ConfirmationContext<'_>) -> Result<(String, String), TemplateError> {
+        let context = context! {
+            username => confirmation.username,
+            confirmation_link => trusted_url(confirmation.confirmation_link),
+        };
+        Ok((
+            render(&self.environment, "confirmation_email.html", &context)?,
+            render(&self.environment, "confirmation_email.txt", &context)?,
+        ))
+    }
+}
+
+/// The links we build ourselves go in as they are, escaping would only mangle them.
+fn trusted_url(url: &str) -> Value {
+    Value::from_safe_string(url.to_string())
+}
+
+/// The page a newsletter written in markdown is put in, `templates/newsletter_layout.html`
+/// unless the configuration points at another file.
+#[derive(Debug)]
+pub struct NewsletterLayout {
+    environment: Environment<'static>,
+    inline_css: bool,
+}
+
+impl NewsletterLayout {
+    pub const VARIABLES: [&'static str; 2] = ["title", "content"];
+
+    pub fn load(properties: &TemplateProperties) -> Result<Self, TemplateError> {
+        let html = read_or(&properties.newsletter_layout_html, include_str!("../templates/newsletter_layout.html"))?;
+        let environment = compile([("newsletter_layout.html", html.as_str())], &Self::VARIABLES)?;
+        Ok(Self { environment, inline_css: properties.inline_css })
+    }
+
+    /// `content` is HTML already, only the title is escaped.
+    pub fn render(&self, title: &str, content: &str) -> Result<String, TemplateError> {
+        let context = context! {
+            title => title,
+            content => Value::from_safe_string(content.to_string()),
+        };
+        render(&self.environment, "newsletter_layout.html", &context)
+    }
+
+    /// Whether the `<style>` rules of an issue go in `style` attributes before it is stored.
+    pub fn inlines_css(&self) -> bool {
+        self.inline_css
+    }
+}
+
+/// The server-rendered pages of `/login` and `/admin`, built into the binary.
+#[derive(Debug)]
+pub struct AdminPages {
+    environment: Environment<'static>,
+}
+
+impl AdminPages {
+    pub fn new() -> Self {
+        let mut environment = Environment::new();
+        let pages = [
+            ("admin/base.html", include_str!("../templates/admin/base.html")),
+            ("admin/login.html", include_str!("../templates/admin/login.html")),
+            ("admin/two_factor.html", include_str!("../templates/admin/two_factor.html")),
+            ("admin/dashboard.html", include_str!("../templates/admin/dashboard.html")),
+            ("admin/newsletters.html", include_str!("../templates/admin/newsletters.html")),
+        ];
+        for (name, source) in pages {
+            environment.add_template(name, source).expect("The admin templates are valid");
+        }
+        Self { environment }
+    }
+
+    pub fn render(&self, name: &str, context: impl Serialize) -> Result<String, TemplateError> {
+        render(&self.environment, name, context)
+    }
+}
+
+impl Default for AdminPages {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read_or(path: &Option<String>, built_in: &str) -> Result<String, TemplateError> {
+    match path {
+        Some(path) => std::fs::read_to_string(path).map_err(|e| TemplateError::Read(path.clone(), e)),
+        None => Ok(built_in.to_string()),
+    }
+}
+
+/// The template names decide the escaping: `.html` ones escape every value they print.
+fn compile<const N: usize>(parts: [(&'static str, &str); N], variables: &[&str]) -> Result<Environment<'static>, TemplateError> {
+    let mut environment = Environment::new();
+    environment.set_keep_trailing_newline(true);
+    let mut problems = Vec::new();
+    for (name, source) in parts {
+        if let Err(e) = environment.add_template_owned(name, source.to_string()) {
+            problems.push(format!("{}: {}", name, e));
+            continue;
+        }
+        let template = environment.get_template(name)?;
+        let mut unknown: Vec<String> = template.undeclared_variables(false).into_iter()
+            // `range`, `dict`... are functions every template can call
+            .filter(|variable| !variables.contains(&variable.as_str()) && environment.globals().all(|(global, _)| global != variable))
+            .collect();
+        unknown.sort();
+        problems.extend(unknown.into_iter().map(|variable| format!("{}: unknown variable `{}`", name, variable)));
+    }
+    if problems.is_empty() {
+        Ok(environment)
+    } else {
+        Err(TemplateError::Invalid(problems))
+    }
+}
+
+fn render(environment: &Environment<'static>, name: &str, context: impl Serialize) -> Result<String, TemplateError> {
+    Ok(environment.get_template(name)?.render(context)?)
+}
+
+static TEMPLATE_TAG: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?s)\{\{.*?\}\}|\{%.*?%\}|\{#.*?#\}").unwrap()
+});
+
+/// `autoescape` turns the escaping off, the others pull in templates that are not escaped
+/// (the text part) or that were never checked.
+const DISALLOWED_STATEMENTS: [&str; 5] = ["autoescape", "include", "import", "from", "extends"];
+
+fn disallowed_statements(name: &str, source: &str) -> Vec<String> {
+    TEMPLATE_TAG.find_iter(source)
+        .filter_map(|tag| {
+            let statement = tag.as_str().strip_prefix("{%")?.trim_start_matches(['-', '+']).split_whitespace().next()?;
+            DISALLOWED_STATEMENTS.contains(&statement)
+                .then(|| format!("{}: `{{% {} %}}` is not allowed", name, statement))
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum HtmlPosition {
+    Text,
+    InTag,
+    Quoted(char),
+}
+
+/// Escaping keeps a value from closing an attribute, not from being a `javascript:` link
+/// or, unquoted, from adding attributes of its own. Only the link we sign ourselves goes there.
+fn tags_inside_html_tags(name: &str, html: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut position = HtmlPosition::Text;
+    let mut last = 0;
+    for tag in TEMPLATE_TAG.find_iter(html) {
+        position = html_position_after(position, &html[last..tag.start()]);
+        last = tag.end();
+        let expression = tag.as_str().trim_start_matches("{{").trim_end_matches("}}").trim_matches(['-', '+', ' ', '\t', '\n']);
+        if position != HtmlPosition::Text && !(tag.as_str().starts_with("{{") && expression == "unsubscribe_url") {
+            problems.push(format!("{}: `{}` is inside an HTML tag, only `{{{{ unsubscribe_url }}}}` can be", name, tag.as_str()));
+        }
+    }
+    problems
+}
+
+fn html_position_after(mut position: HtmlPosition, html: &str) -> HtmlPosition {
+    let mut chars = html.chars().peekable();
+    while let Some(c) = chars.next() {
+        position = match (position, c) {
+            // A `<` right before a template tag counts too, the tag could name the element
+            (HtmlPosition::Text, '<') if chars.peek().is_none_or(|next| next.is_ascii_alphabetic() || matches!(next, '/' | '!' | '?')) => HtmlPosition::InTag,
+            (HtmlPosition::InTag, '"' | '\'') => HtmlPosition::Quoted(c),
+            (HtmlPosition::InTag, '>') => HtmlPosition::Text,
+            (HtmlPosition::Quoted(quote), c) if c == quote => HtmlPosition::InTag,
+            (position, _) => position,
+        };
+    }
+    position
+}
+
+/// Template tags swapped for placeholders markdown and HTML parsers have no opinion about.
+pub(crate) struct TemplateTags {
+    marker: String,
+    tags: Vec<String>,
+}
+
+pub(crate) fn protect_template_tags(source: &str) -> (String, TemplateTags) {
+    let marker = format!("tpl{}", uuid::Uuid::new_v4().simple());
+    let mut tags = Vec::new();
+    let protected = TEMPLATE_TAG.replace_all(source, |captures: &regex::Captures| {
+        tags.push(captures[0].to_string());
+        format!("{}{}z", marker, tags.len() - 1)
+    });
+    (protected.into_owned(), TemplateTags { marker, tags })
+}
+
+impl TemplateTags {
+    pub(crate) fn restore(&self, mut rendered: String) -> String {
+        for (index, tag) in self.tags.iter().enumerate() {
+            rendered = rendered.replace(&format!("{}{}z", self.marker, index), tag);
+        }
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use claims::{assert_err, assert_ok};
+
+    use crate::configuration::TemplateProperties;
+
+    use super::{AdminPages, ConfirmationContext, ConfirmationTemplate, NewsletterLayout, NewsletterTemplate, RecipientContext, TemplateError};
+
+    fn recipient(fields: &BTreeMap<String, String>) -> RecipientContext<'_> {
+        RecipientContext {
+            username: "<b>ursula</b>",
+            email: "ursula@example.com",
+            unsubscribe_url: "https://example.com/unsubscribe?token=a&id=1",
+            fields,
+        }
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_only() {
+        let template = NewsletterTemplate::compile("Hi {{ username }}", "<p>Hi {{ username }}</p>", "Hi {{ username }}").unwrap();
+
+        let email = template.render(&recipient(&BTreeMap::new())).unwrap();
+
+        assert_eq!(email.subject, "Hi <b>ursula</b>");
+        assert_eq!(email.html, "<p>Hi &lt;b&gt;ursula&lt;&#x2f;b&gt;</p>");
+        assert_eq!(email.text, "Hi <b>ursula</b>");
+    }
+
+    #[test]
+    fn conditionals_and_loops_over_the_custom_fields_are_supported() {
+        let fields = BTreeMap::from([("city".to_string(), "Portland".to_string()), ("plan".to_string(), "pro".to_string())]);
+        let text = "{% if fields.plan == 'pro' %}Thanks!{% endif %}{% for name, value in fields|items %} {{ name }}={{ value }}{% endfor %}";
+        let template = NewsletterTemplate::compile("Title", "<p>Body</p>", text).unwrap();
+
+        let email = template.render(&recipient(&fields)).unwrap();
+
+        assert_eq!(email.text, "Thanks! city=Portland plan=pro");
+    }
+
+    #[test]
+    fn every_unknown_variable_and_syntax_error_is_reported() {
+        let outcome = NewsletterTemplate::compile("Hi {{ name }}", "<p>{{ username }} {% if %}</p>", "{{ coupon }} {{ unsubscribe_link }}");
+
+        let Err(TemplateError::Invalid(problems)) = outcome else {
+            panic!("The template was accepted");
+        };
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].starts_with("title: unknown variable `name`"));
+        assert!(problems[1].starts_with("content.html: syntax error"));
+        assert_eq!(problems[2], "content.text: unknown variable `coupon`");
+        assert_eq!(problems[3], "content.text: unknown variable `unsubscribe_link`");
+    }
+
+    #[test]
+    fn nothing_an_issue_prints_escapes_the_escaping() {
+        let template = NewsletterTemplate::compile("Title", "<p>{{ '<script>x</script>'|safe }}</p>{% filter safe %}{{ username }}{% endfilter %}", "Text").unwrap();
+
+        let email = template.render(&recipient(&BTreeMap::new())).unwrap();
+
+        assert_eq!(email.html, "<p>&lt;script&gt;x&lt;&#x2f;script&gt;</p>&lt;b&gt;ursula&lt;&#x2f;b&gt;");
+    }
+
+    #[test]
+    fn values_inside_html_tags_and_unescaped_statements_are_rejected() {
+        let html = "<a href=\"{{ unsubscribe_url }}\">Bye</a><a href=\"{{ 'javascript:alert(1)' }}\">x</a><p class={{ fields.class }} {% if email %}hidden{% endif %}>{% autoescape false %}{{ username }}{% endautoescape %}</p>";
+
+        let outcome = NewsletterTemplate::compile("Title", html, "{% include 'content.html' %}");
+
+        let Err(TemplateError::Invalid(problems)) = outcome else {
+            panic!("The template was accepted");
+        };
+        assert_eq!(problems, vec![
+            "content.html: `{% autoescape %}` is not allowed",
+            "content.text: `{% include %}` is not allowed",
+            "content.html: `{{ 'javascript:alert(1)' }}` is inside an HTML tag, only `{{ unsubscribe_url }}` can be",
+            "content.html: `{{ fields.class }}` is inside an HTML tag, only `{{ unsubscribe_url }}` can be",
+            "content.html: `{% if email %}` is inside an HTML tag, only `{{ unsubscribe_url }}` can be",
+            "content.html: `{% endif %}` is inside an HTML tag, only `{{ unsubscribe_url }}` can be",
+        ]);
+    }
+
+    #[test]
+    fn loop_variables_and_builtin_functions_are_not_unknown() {
+        assert_ok!(NewsletterTemplate::compile("Title", "{% for i in range(3) %}{{ i }}{{ loop.index }}{% endfor %}", "{% set greeting = 'Hi' %}{{ greeting }}"));
+    }
+
+    #[test]
+    fn the_confirmation_template_can_be_overridden() {
+        let directory = std::env::temp_dir().join(format!("templates-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let html = directory.join("confirm.html");
+        std::fs::write(&html, "<a href=\"{{ confirmation_link }}\">Yes, {{ username }}</a>").unwrap();
+        let properties = TemplateProperties {
+            confirmation_email_html: Some(html.to_string_lossy().into_owned()),
+            ..TemplateProperties::default()
+        };
+
+        let template = ConfirmationTemplate::load(&properties).unwrap();
+        let (html, text) = template.render(&ConfirmationContext { username: "le guin", confirmation_link: "https://example.com/confirm" }).unwrap();
+
+        assert_eq!(html, "<a href=\"https://example.com/confirm\">Yes, le guin</a>");
+        assert!(text.contains("https://example.com/confirm"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn the_layout_wraps_the_content_without_escaping_it() {
+        let layout = NewsletterLayout::load(&TemplateProperties::default()).unwrap();
+
+        let html = layout.render("Fish & chips", "<p>Hello</p>").unwrap();
+
+        assert!(html.contains("<title>Fish &amp; chips</title>"));
+        assert!(html.contains("<p>Hello</p>"));
+    }
+
+    #[test]
+    fn admin_pages_escape_what_they_are_given() {
+        let pages = AdminPages::new();
+
+        let html = pages.render("admin/dashboard.html", minijinja::context! { sender => "<i>ursula</i>", subscribers => 3, issues => 1 }).unwrap();
+
+        assert!(html.contains("Welcome &lt;i&gt;ursula&lt;&#x2f;i&gt;"));
+        assert!(html.contains("action=\"/admin/logout\""));
+    }
+
+    #[test]
+    fn a_confirmation_template_with_an_unknown_variable_is_rejected() {
+        let directory = std::env::temp_dir().join(format!("templates-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let text = directory.join("confirm.txt");
+        std::fs::write(&text, "{{ confirmation_url }}").unwrap();
+        let properties = TemplateProperties { confirmation_email_text: Some(text.to_string_lossy().into_owned()), ..TemplateProperties::default() };
+
+        assert_err!(ConfirmationTemplate::load(&properties));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use reqwest::header::LOCATION;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::SessionStoreKind;
+
+use crate::common::{browser, spawn_app, spawn_app_with, TestApp};
+
+async fn get(client: &reqwest::Client, app: &TestApp, page: &str) -> reqwest::Response {
+    client
+        .get(format!("{}{}", &app.address, page))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn post_form(client: &reqwest::Client, app: &TestApp, page: &str, form: &[(&str, &str)]) -> reqwest::Response {
+    client
+        .post(format!("{}{}", &app.address, page))
+        .form(form)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn assert_redirects_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers()[LOCATION], location);
+}
+
+#[tokio::test]
+async fn the_login_form_is_rendered() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = get(&browser(), &app, "/login").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["Content-Type"].to_str().unwrap().starts_with("text/html"));
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"<form method="post" action="/login">"#));
+    assert!(html.contains(r#"name="password""#));
+}
+
+#[tokio::test]
+async fn a_failed_login_redirects_to_the_login_form_with_a_flash_message_shown_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = browser();
+
+    // Act
+    let response = app.log_in(&client, "admin", "not the password").await;
+
+    // Assert
+    assert_redirects_to(&response, "/login");
+    let html = get(&client, &app, "/login").await.text().await.unwrap();
+    assert!(html.contains("Authentication failed."));
+    let html = get(&client, &app, "/login").await.text().await.unwrap();
+    assert!(!html.contains("Authentication failed."));
+}
+
+#[tokio::test]
+async fn a_logged_in_sender_sees_the_subscriber_and_issue_counts() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let response = app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "markdown": "# Hello\n\nNewsletter body" }
+    })).await;
+    assert_eq!(response.status().as_u16(), 202);
+    let client = browser();
+
+    // Act
+    let response = app.log_in(&client, "admin", "admin").await;
+
+    // Assert
+    assert_redirects_to(&response, "/admin/dashboard");
+    let set_cookie = response.headers()["Set-Cookie"].to_str().unwrap();
+    assert!(set_cookie.starts_with("session="));
+    assert!(set_cookie.contains("HttpOnly"));
+    let response = get(&client, &app, "/admin/dashboard").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("Welcome admin"));
+    assert!(html.contains(r#"<dd id="subscribers">1</dd>"#));
+    assert!(html.contains(r#"<dd id="issues">1</dd>"#));
+}
+
+#[tokio::test]
+async fn admin_pages_redirect_to_the_login_form_without_a_session() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = browser();
+
+    // Act
+    let responses = vec![
+        get(&client, &app, "/admin/dashboard").await,
+        get(&client, &app, "/admin/newsletters").await,
+        post_form(&client, &app, "/admin/newsletters", &[("title", "Newsletter title"), ("markdown", "Newsletter body"), ("idempotency_key", "key")]).await,
+    ];
+
+    // Assert
+    for response in responses {
+        assert_redirects_to(&response, "/login");
+    }
+    let html = get(&client, &app, "/login").await.text().await.unwrap();
+    assert!(html.contains("Please log in to continue."));
+    assert_eq!(app.storage.count_newsletter_issues().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn a_tampered_session_cookie_is_not_accepted() {
+    // Arrange
+    let app = spawn_app().await;
+    let response = app.log_in(&browser(), "admin", "admin").await;
+    let cookie = response.headers()["Set-Cookie"].to_str().unwrap().split(';').next().unwrap().to_string();
+    // The signature comes first, the session key after it
+    let mut tampered = cookie.clone();
+    tampered.pop();
+    tampered.push(if cookie.ends_with('A') { 'B' } else { 'A' });
+
+    // Act
+    let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(format!("{}/admin/dashboard", &app.address))
+        .header("Cookie", tampered)
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_redirects_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn logging_out_ends_the_session() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+    assert_eq!(get(&client, &app, "/admin/dashboard").await.status().as_u16(), 200);
+
+    // Act
+    let response = post_form(&client, &app, "/admin/logout", &[]).await;
+
+    // Assert
+    assert_redirects_to(&response, "/login");
+    let html = get(&client, &app, "/login").await.text().await.unwrap();
+    assert!(html.contains("You have successfully logged out."));
+    assert_redirects_to(&get(&client, &app, "/admin/dashboard").await, "/login");
+}
+
+#[tokio::test]
+async fn a_disabled_sender_is_logged_out() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+    let admin = app.storage.find_sender("admin").await.unwrap().unwrap();
+
+    // Act
+    app.storage.set_sender_enabled(admin.id, false).await.unwrap();
+
+    // Assert
+    assert_redirects_to(&get(&client, &app, "/admin/dashboard").await, "/login");
+}
+
+#[tokio::test]
+async fn a_newsletter_composed_in_the_admin_pages_is_published_once() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+    let html = get(&client, &app, "/admin/newsletters").await.text().await.unwrap();
+    let idempotency_key = html.split(r#"name="idempotency_key" value=""#).nth(1).unwrap()
+        .split('"').next().unwrap().to_string();
+    let form = [
+        ("title", "Newsletter title"),
+        ("markdown", "# Hello\n\nNewsletter body"),
+        ("html", ""),
+        ("text", ""),
+        ("idempotency_key", idempotency_key.as_str()),
+    ];
+
+    // Act
+    let first = post_form(&client, &app, "/admin/newsletters", &form).await;
+    let second = post_form(&client, &app, "/admin/newsletters", &form).await;
+
+    // Assert
+    assert_redirects_to(&first, "/admin/newsletters");
+    assert_redirects_to(&second, "/admin/newsletters");
+    let html = get(&client, &app, "/admin/newsletters").await.text().await.unwrap();
+    assert!(html.contains("The newsletter issue has been published."));
+    assert_eq!(app.storage.count_newsletter_issues().await.unwrap(), 1);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn an_invalid_composed_newsletter_is_shown_again_with_what_is_wrong() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+
+    // Act
+    let response = post_form(&client, &app, "/admin/newsletters", &[
+        ("title", "Newsletter <title>"),
+        ("markdown", ""),
+        ("html", "<p>Newsletter body</p>"),
+        ("text", ""),
+        ("idempotency_key", "a-key"),
+    ]).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<li>content: needs either `markdown`, or both `html` and `text`</li>"));
+    assert!(html.contains(r#"value="Newsletter &lt;title&gt;""#));
+    assert!(html.contains("&lt;p&gt;Newsletter body&lt;&#x2f;p&gt;</textarea>"));
+    assert!(html.contains(r#"name="idempotency_key" value="a-key""#));
+    assert_eq!(app.storage.count_newsletter_issues().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn sessions_can_be_kept_in_memory_instead_of_the_storage_backend() {
+    // Arrange
+    let app = spawn_app_with(|c| c.session.store = SessionStoreKind::InMemory).await;
+    let client = browser();
+
+    // Act
+    app.log_in(&client, "admin", "admin").await;
+
+    // Assert
+    assert_eq!(get(&client, &app, "/admin/dashboard").await.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn an_idle_session_expires() {
+    // Arrange
+    let app = spawn_app_with(|c| c.session.idle_timeout_minutes = 30).await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+    app.clock.advance(chrono::Duration::minutes(20));
+    // Using the session pushes its expiry back
+    assert_eq!(get(&client, &app, "/admin/dashboard").await.status().as_u16(), 200);
+    app.clock.advance(chrono::Duration::minutes(20));
+    assert_eq!(get(&client, &app, "/admin/dashboard").await.status().as_u16(), 200);
+
+    // Act
+    app.clock.advance(chrono::Duration::minutes(31));
+
+    // Assert
+    assert_redirects_to(&get(&client, &app, "/admin/dashboard").await, "/login");
+}
@@ -0,0 +1,210 @@
+use crate::common::{spawn_app, TestApp};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+impl TestApp {
+    async fn post_api_key(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/senders/me/api-keys", &self.address))
+            .basic_auth("admin", Some("admin"))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Issues a key for `admin` and returns its id and the key itself.
+    async fn issue_api_key(&self, scopes: &[&str]) -> (i64, String) {
+        let response = self.post_api_key(serde_json::json!({ "name": "CI", "scopes": scopes })).await;
+        assert_eq!(response.status().as_u16(), 201);
+        let body: serde_json::Value = response.json().await.unwrap();
+        (body["id"].as_i64().unwrap(), body["key"].as_str().unwrap().to_string())
+    }
+
+    async fn publish_with_api_key(&self, key: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .bearer_auth(key)
+            .json(&newsletter_request_body())
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+}
+
+#[tokio::test]
+async fn an_issued_key_publishes_newsletters() {
+    // Arrange
+    let app = spawn_app().await;
+    let (_, key) = app.issue_api_key(&["newsletters:publish"]).await;
+
+    // Act
+    let response = app.publish_with_api_key(&key).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let keys = app.storage.list_api_keys(1).await.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert!(keys[0].last_used_at.is_some());
+    assert!(key.starts_with(&keys[0].prefix));
+}
+
+#[tokio::test]
+async fn a_key_without_the_scope_is_forbidden() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let (_, key) = app.issue_api_key(&["subscribers:read"]).await;
+
+    // Act
+    let response = app.publish_with_api_key(&key).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 403);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "The API key does not have the `newsletters:publish` scope");
+    let subscription = reqwest::Client::new()
+        .get(format!("{}/subscriptions/find?subscription_id=1", &app.address))
+        .bearer_auth(&key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(subscription.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn an_expired_key_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let expires_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    let response = app.post_api_key(serde_json::json!({
+        "name": "CI",
+        "scopes": ["newsletters:publish"],
+        "expires_at": expires_at,
+    })).await;
+    let key = response.json::<serde_json::Value>().await.unwrap()["key"].as_str().unwrap().to_string();
+    assert_eq!(app.publish_with_api_key(&key).await.status().as_u16(), 202);
+
+    // Act
+    app.clock.advance(chrono::Duration::hours(2));
+    let response = app.publish_with_api_key(&key).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_revoked_key_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let (id, key) = app.issue_api_key(&["newsletters:publish"]).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .delete(format!("{}/senders/me/api-keys/{}", &app.address, id))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(app.publish_with_api_key(&key).await.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn revoking_an_unknown_key_is_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .delete(format!("{}/senders/me/api-keys/42", &app.address))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn api_keys_cannot_manage_senders_or_keys() {
+    // Arrange
+    let app = spawn_app().await;
+    let (_, key) = app.issue_api_key(&["newsletters:publish", "subscribers:read"]).await;
+    let client = reqwest::Client::new();
+    let test_cases = vec![
+        (client.get(format!("{}/senders", &app.address)), "list the senders"),
+        (client.get(format!("{}/senders/me/api-keys", &app.address)), "list the keys"),
+        (client.post(format!("{}/senders/me/api-keys", &app.address))
+            .json(&serde_json::json!({ "name": "another", "scopes": ["newsletters:publish"] })), "issue a key"),
+    ];
+
+    for (request, description) in test_cases {
+        // Act
+        let response = request.bearer_auth(&key).send().await.unwrap();
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 403, "An API key could {}", description);
+    }
+}
+
+#[tokio::test]
+async fn listing_the_keys_does_not_show_them() {
+    // Arrange
+    let app = spawn_app().await;
+    app.issue_api_key(&["newsletters:publish"]).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/senders/me/api-keys", &app.address))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let keys = body.as_array().unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0]["name"], "CI");
+    assert_eq!(keys[0]["scopes"], serde_json::json!(["newsletters:publish"]));
+    assert!(keys[0].get("key").is_none());
+    assert!(keys[0].get("key_hash").is_none());
+}
+
+#[tokio::test]
+async fn issuing_a_key_returns_a_400_for_invalid_data() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (serde_json::json!({ "name": "", "scopes": ["newsletters:publish"] }), "empty name"),
+        (serde_json::json!({ "name": "CI", "scopes": [] }), "no scopes"),
+        (serde_json::json!({ "name": "CI", "scopes": ["newsletters:delete"] }), "unknown scope"),
+        (serde_json::json!({ "name": "CI", "scopes": ["subscribers:write"] }), "scope nothing checks"),
+        (serde_json::json!({
+            "name": "CI",
+            "scopes": ["newsletters:publish"],
+            "expires_at": chrono::Utc::now() - chrono::Duration::hours(1),
+        }), "expiry in the past"),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = app.post_api_key(body).await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "The API did not fail with a 400 for {}", description);
+    }
+}
@@ -0,0 +1,145 @@
+use reqwest::header::WWW_AUTHENTICATE;
+
+use crate::common::{browser, spawn_app, TestApp};
+
+fn challenges(response: &reqwest::Response) -> Vec<String> {
+    response.headers().get_all(WWW_AUTHENTICATE).iter()
+        .map(|value| value.to_str().unwrap().to_string())
+        .collect()
+}
+
+fn find_subscription(app: &TestApp, client: &reqwest::Client) -> reqwest::RequestBuilder {
+    client.get(format!("{}/subscriptions/find?subscription_id=1", &app.address))
+}
+
+#[tokio::test]
+async fn finding_a_subscription_requires_authentication() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+
+    // Act
+    let response = find_subscription(&app, &reqwest::Client::new()).send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(challenges(&response), vec![r#"Basic realm="publish""#, r#"Bearer realm="publish""#]);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "Missing Authorization header");
+    assert_eq!(app.get_subscription("1").await.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn protected_routes_answer_the_same_way_to_bad_credentials() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_cases = vec![
+        (client.get(format!("{}/subscriptions/find?subscription_id=1", &app.address)), "find a subscription"),
+        (client.get(format!("{}/senders", &app.address)), "list the senders"),
+        (client.get(format!("{}/newsletters", &app.address)), "list the issues"),
+        (client.post(format!("{}/newsletters/preview", &app.address)).json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Newsletter body" }
+        })), "preview an issue"),
+    ];
+
+    for (request, description) in test_cases {
+        // Act
+        let response = request.basic_auth("admin", Some("not the password")).send().await.unwrap();
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 401, "Could {} with a wrong password", description);
+        assert_eq!(challenges(&response), vec![r#"Basic realm="publish""#, r#"Bearer realm="publish""#]);
+    }
+}
+
+#[tokio::test]
+async fn an_unparseable_authorization_header_is_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = find_subscription(&app, &reqwest::Client::new())
+        .header("Authorization", "Basic not-base64!")
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(challenges(&response)[0], r#"Basic realm="publish""#);
+}
+
+#[tokio::test]
+async fn an_unknown_bearer_token_is_rejected_as_invalid() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = find_subscription(&app, &reqwest::Client::new())
+        .bearer_auth("not a key")
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(challenges(&response)[1], r#"Bearer realm="publish", error="invalid_token""#);
+}
+
+#[tokio::test]
+async fn the_session_of_the_admin_pages_authenticates_api_requests_too() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+
+    // Act
+    let response = find_subscription(&app, &client).send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let senders = client.get(format!("{}/senders", &app.address)).send().await.unwrap();
+    assert_eq!(senders.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn credentials_in_the_header_win_over_the_session() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+
+    // Act
+    let response = client.get(format!("{}/senders", &app.address))
+        .basic_auth("admin", Some("not the password"))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_session_is_not_enough_to_change_the_password() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+
+    // Act
+    let response = client.put(format!("{}/senders/me/password", &app.address))
+        .json(&serde_json::json!({ "new_password": "a brand new password" }))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 403);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "Changing the password needs the current one as Basic credentials");
+    assert_eq!(app.log_in(&browser(), "admin", "admin").await.headers()["Location"], "/admin/dashboard");
+}
@@ -1,12 +1,17 @@
 use serde_json::Value;
-use uuid::Uuid;
 use zero2prod::routes::SubscriptionRequest;
 use zero2prod::startup::Application;
 use wiremock::{matchers::{method, path}, Mock, MockServer, ResponseTemplate};
 use actix_web::web;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 use std::sync::LazyLock;
-use zero2prod::configuration::get_configuration;
+use zero2prod::configuration::{get_configuration, DatabaseProperties, EmailProviderKind, Properties, StorageBackend};
+use sqlx::{Connection, Executor, PgConnection};
+use secrecy::SecretString;
+use uuid::Uuid;
+use zero2prod::clock::AdjustableClock;
+use zero2prod::storage::Storage;
+use std::sync::Arc;
 
 /// The closure passed to LazyLock::new is executed only once, when TRACING is first accessed.
 /// This ensures that the tracing stack is initialized only once.
@@ -40,6 +45,9 @@ pub struct TestApp{
     pub address: String,
     pub mock_email_server: MockServer,
     pub port: u16,
+    pub storage: web::Data<dyn Storage>,
+    /// Drives the scheduler and the delivery worker, `advance` it to fast-forward.
+    pub clock: Arc<AdjustableClock>,
 }
 
 pub struct ConfirmationLinks {
@@ -52,7 +60,7 @@ impl TestApp {
         let request = web::Json(body);
         let json_payload = serde_json::to_string(&request).unwrap();
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/json")
             .body(json_payload)
             .send()
@@ -62,7 +70,8 @@ impl TestApp {
 
     pub async fn get_subscription(&self, subscription_id: &str) -> reqwest::Response {
         reqwest::Client::new()
-            .get(&format!("{}/subscriptions/find?subscription_id={}", &self.address, subscription_id))
+            .get(format!("{}/subscriptions/find?subscription_id={}", &self.address, subscription_id))
+            .basic_auth("admin", Some("admin"))
             .header("Content-Type", "application/json")
             .send()
             .await
@@ -90,14 +99,25 @@ impl TestApp {
             confirmation_link.set_port(Some(self.port)).unwrap();
             confirmation_link
         };
-        let html = get_link(&body["Html-part"].as_str().unwrap());
-        let plain_text = get_link(&body["Text-part"].as_str().unwrap());
+        let html = get_link(body["Html-part"].as_str().unwrap());
+        let plain_text = get_link(body["Text-part"].as_str().unwrap());
         ConfirmationLinks {
             html,
             plain_text
         }
     }
 
+    /// Extract the one-click unsubscribe link from the `List-Unsubscribe` header of a newsletter.
+    pub fn get_unsubscribe_link(&self, email_request: &wiremock::Request) -> reqwest::Url {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        let header = body["Headers"]["List-Unsubscribe"].as_str().unwrap();
+        let raw_link = header.trim_start_matches('<').trim_end_matches('>');
+        let mut unsubscribe_link = reqwest::Url::parse(raw_link).unwrap();
+        assert_eq!(unsubscribe_link.host_str().unwrap(), "127.0.0.1");
+        unsubscribe_link.set_port(Some(self.port)).unwrap();
+        unsubscribe_link
+    }
+
     pub async fn create_unconfirmed_subscription(&self) -> ConfirmationLinks{
         let _mock_send_confirmation = Mock::given(path("/v3/send"))
             .and(method("POST"))
@@ -120,7 +140,7 @@ impl TestApp {
         .pop()
         .unwrap();
         
-        self.get_confirmation_links(&email_request)
+        self.get_confirmation_links(email_request)
             
     }
 
@@ -131,13 +151,48 @@ impl TestApp {
         let _response = reqwest::get(confirmation_link.html).await.unwrap();
     }
 
+    /// Wait until the background worker has emptied the delivery queue,
+    /// whether the deliveries succeeded or were given up on.
+    pub async fn dispatch_all_pending_emails(&self) {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while self.storage.count_pending_delivery_tasks().await.unwrap() > 0 {
+            assert!(std::time::Instant::now() < deadline, "The delivery queue was not drained in time");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Log in through the form, `client` keeps the session cookie.
+    pub async fn log_in(&self, client: &reqwest::Client, username: &str, password: &str) -> reqwest::Response {
+        client
+            .post(format!("{}/login", &self.address))
+            .form(&[("username", username), ("password", password)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub async fn post_newsletters(
         &self,
         body: serde_json::Value
         ) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/newsletters", &self.address))
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth("admin", Some("admin"))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+        }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+        ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
             .basic_auth("admin", Some("admin"))
+            .header("Idempotency-Key", idempotency_key)
             .json(&body)
             .send()
             .await
@@ -145,7 +200,61 @@ impl TestApp {
         }
 }
 
+/// A client that keeps cookies like a browser, but shows us the redirects instead of following them.
+pub fn browser() -> reqwest::Client {
+    reqwest::Client::builder()
+        .cookie_store(true)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+/// Every test gets its own fresh store: an in-memory one by default, or a new
+/// sqlite file / logical postgres database when `TEST_STORAGE` is set to
+/// `sqlite` / `postgres`.
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Like `spawn_app`, but lets the test tweak the configuration first.
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Properties)) -> TestApp {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    customise(&mut configuration);
+    match std::env::var("TEST_STORAGE").as_deref() {
+        Ok("sqlite") => {
+            configuration.storage.backend = StorageBackend::Sqlite;
+            configuration.storage.sqlite_path = temporary_sqlite_path();
+        }
+        Ok("postgres") => {
+            configuration.storage.backend = StorageBackend::Postgres;
+            configuration.database.database_name = Uuid::new_v4().to_string();
+            configure_database(&configuration.database).await;
+        }
+        _ => configuration.storage.backend = StorageBackend::InMemory,
+    }
+    spawn_app_with_configuration(configuration).await
+}
+
+pub fn temporary_sqlite_path() -> String {
+    std::env::temp_dir()
+        .join(format!("zero2prod-{}.db", Uuid::new_v4()))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Create a brand new logical database, `Application::build` takes care of the migrations.
+pub async fn configure_database(properties: &DatabaseProperties) {
+    let mut connection = PgConnection::connect_with(&properties.without_db())
+        .await
+        .expect("Failed to connect to Postgres");
+    connection
+        .execute(format!(r#"CREATE DATABASE "{}";"#, properties.database_name).as_str())
+        .await
+        .expect("Failed to create database.");
+}
+
+pub async fn spawn_app_with_configuration(configuration: Properties) -> TestApp {
     // This line forces the initialization of the TRACING variable if it has not already been initialized.
     // If we call it again later on in the code - it will do nothing.
     LazyLock::force(&TRACING);
@@ -153,28 +262,43 @@ pub async fn spawn_app() -> TestApp {
     let email_server = MockServer::start().await;
     // Randomise configuration to ensure test isolation
     let configuration = {
-        let mut c = get_configuration().expect("Failed to read configuration.");
+        let mut c = configuration;
         // Use a random OS port
-        c.server_port = 0;
+        c.server.port = 0;
         // Use the mock server as email API
+        c.email_client.provider = EmailProviderKind::Mailjet;
         c.email_client.base_url = email_server.uri();
+        c.email_client.api_key = Some(SecretString::from("api-key"));
+        c.email_client.api_secret = Some(SecretString::from("api-secret"));
+        // The cheapest Argon2 parameters, the tests are not the place to pay for the real ones
+        c.auth.password_hashing.memory_kib = 8;
+        c.auth.password_hashing.iterations = 1;
+        // Keep the delivery worker snappy
+        c.delivery.poll_interval_milliseconds = 10;
+        c.delivery.base_backoff_milliseconds = 10;
+        c.delivery.max_backoff_milliseconds = 40;
+        c.scheduler.poll_interval_milliseconds = 10;
         c
     };
     // let app_state: AppState = AppState::new();
     // let data_store_shared = web::Data::new(app_state);
     // let email_client = EmailClient::new(configuration.email_client.base_url, configuration.email_client.sender);
     // let server = zero2prod::startup::run(listener, data_store_shared, email_client).expect("Failed to bind address");
-    let application = Application::build(configuration.clone())
+    let clock = Arc::new(AdjustableClock::default());
+    let application = Application::build_with_clock(configuration.clone(), clock.clone())
         .await
         .expect("Failed to build application.");
     let port = application.port();
     let address = format!("http://127.0.0.1:{}", port);
-    let _ = tokio::spawn(application.run_until_stopped());
+    let storage = application.storage();
+    tokio::spawn(application.run_until_stopped());
 
     TestApp { 
-        address: address,
+        address,
         mock_email_server: email_server,
-        port: port,
+        port,
+        storage,
+        clock,
      }
 }
 
@@ -184,5 +308,5 @@ pub fn get_id_from_response(response_body: String) -> String{
     // Extract the "id" field
     json["id"].as_u64()
                                 .map(|id| id.to_string())
-                                .unwrap_or_else(|| "".to_string())
+                                .unwrap_or_default()
 }
\ No newline at end of file
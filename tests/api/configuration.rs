@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use secrecy::SecretString;
+use zero2prod::configuration::{get_configuration, ConfigurationError, EmailProviderKind, Properties, StorageBackend};
+
+#[test]
+fn the_default_configuration_is_valid() {
+    let configuration = get_configuration().expect("Failed to read configuration.");
+    assert!(configuration.validate().is_ok());
+}
+
+#[test]
+fn every_configuration_problem_is_reported_together() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.server.base_url = "not a url".to_string();
+    configuration.email_client.sender = "not an email".to_string();
+    configuration.auth.confirmation_token_expiry_minutes = 0;
+
+    // Act
+    let outcome = configuration.validate();
+
+    // Assert
+    let Err(ConfigurationError::Invalid(problems)) = outcome else {
+        panic!("The configuration was accepted");
+    };
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+    assert!(problems[0].starts_with("server.base_url"));
+    assert!(problems[1].starts_with("email_client.sender"));
+    assert!(problems[2].starts_with("auth.confirmation_token_expiry_minutes"));
+}
+
+#[test]
+fn only_the_selected_provider_and_backend_are_validated() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.email_client.provider = EmailProviderKind::FileOutbox;
+    configuration.email_client.base_url = "not a url".to_string();
+    configuration.storage.backend = StorageBackend::InMemory;
+    configuration.database.max_connections = 0;
+
+    // Act
+    let outcome = configuration.validate();
+
+    // Assert
+    assert!(outcome.is_ok());
+}
+
+#[test]
+fn the_mailjet_provider_requires_credentials_and_a_base_url() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.email_client.provider = EmailProviderKind::Mailjet;
+    configuration.email_client.base_url = "ftp://api.mailjet.com".to_string();
+    configuration.email_client.api_key = Some(SecretString::from("api-key"));
+    configuration.email_client.api_secret = None;
+
+    // Act
+    let error = configuration.validate().expect_err("The configuration was accepted");
+
+    // Assert
+    let message = error.to_string();
+    assert!(message.contains("email_client.base_url"), "{}", message);
+    assert!(message.contains("email_client.api_secret"), "{}", message);
+    assert!(!message.contains("email_client.api_key"), "{}", message);
+}
+
+#[test]
+fn content_limits_must_fit_in_an_email() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.content_limits.max_title_bytes = 999;
+    configuration.content_limits.min_body_characters = 200;
+    configuration.content_limits.max_body_bytes = 100;
+
+    // Act
+    let error = configuration.validate().expect_err("The configuration was accepted");
+
+    // Assert
+    let message = error.to_string();
+    assert!(message.contains("content_limits.max_title_bytes"), "{}", message);
+    assert!(message.contains("content_limits.min_body_characters"), "{}", message);
+}
+
+#[test]
+fn delivery_backoff_doubles_up_to_the_maximum() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.delivery.base_backoff_milliseconds = 1000;
+    configuration.delivery.max_backoff_milliseconds = 5000;
+
+    // Act
+    let backoffs: Vec<i64> = (1..=5).map(|attempts| configuration.delivery.backoff(attempts).num_milliseconds()).collect();
+
+    // Assert
+    assert_eq!(backoffs, vec![1000, 2000, 4000, 5000, 5000]);
+}
+
+#[test]
+fn the_postgres_backend_requires_a_database_password() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.storage.backend = StorageBackend::Postgres;
+    configuration.database.password = None;
+
+    // Act
+    let error = configuration.validate().expect_err("The configuration was accepted");
+
+    // Assert
+    let message = error.to_string();
+    assert!(message.contains("database.password is required by the postgres backend"), "{}", message);
+}
+
+#[test]
+fn css_is_inlined_unless_the_templates_section_says_otherwise() {
+    // Arrange
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("configuration");
+    let base = std::fs::read_to_string(directory.join("base.yaml")).unwrap();
+    let (without_templates, _) = base.split_once("\ntemplates:").expect("base.yaml has no templates section");
+    let load = |yaml: &str| -> Properties {
+        config::Config::builder()
+            .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+            .add_source(config::File::from(directory.join("local.yaml")))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    };
+
+    // Act
+    let no_section = load(without_templates);
+    let no_key = load(&format!("{}\ntemplates:\n  newsletter_layout_html: ~\n", without_templates));
+
+    // Assert
+    assert!(no_section.templates.inline_css);
+    assert!(no_key.templates.inline_css);
+}
@@ -1,5 +1,18 @@
+mod admin;
+mod api_keys;
+mod authentication;
 mod common;
+mod configuration;
 mod health_check;
 mod subscribe;
 mod subscriptions_confirm;
-mod newsletter;
\ No newline at end of file
+mod two_factor;
+mod newsletter;
+mod newsletter_drafts;
+mod newsletter_issues;
+mod newsletter_preview;
+mod roles;
+mod senders;
+mod storage;
+mod unsubscribe;
+mod startup;
\ No newline at end of file
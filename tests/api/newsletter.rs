@@ -1,7 +1,21 @@
+use std::collections::BTreeMap;
+
 use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, ResponseTemplate};
 
-use crate::common::spawn_app;
+use zero2prod::routes::SubscriptionRequest;
+
+use crate::common::{spawn_app, spawn_app_with};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
 
 #[tokio::test]
 async fn newsletters_are_not_sent_to_unconfirmed_subscribers() {
@@ -27,7 +41,8 @@ async fn newsletters_are_not_sent_to_unconfirmed_subscribers() {
     let response = app.post_newsletters(body).await;
 
     // Assert
-    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
     // Mock verifies on Drop that we haven't sent the newsletter email
 }
 
@@ -54,8 +69,9 @@ async fn newletter_is_sent_to_confirmed_subscribers(){
 
     let response = app.post_newsletters(body).await;
     // Assert
-    assert_eq!(response.status().as_u16(), 200);
-    // Mock verifies on Drop that we haven't sent the newsletter email
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we have sent the newsletter email
 }
 
 #[tokio::test]
@@ -89,12 +105,20 @@ async fn newsletters_returns_a_400_for_invalid_data(){
         }), "missing the html in content"),
 
         (serde_json::json!({
-            "title": "this is a very long title, really too long, what are you doing, are you crazy?",
+            "title": "a".repeat(999),
             "content": {
                 "text": "Newsletter body as plain text",
                 "html": "<p>Newsletter body as HTML</p>",
             }
-        }), "title is too long"),
+        }), "title is longer than a header line can be"),
+
+        (serde_json::json!({
+            "title": "é".repeat(500),
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }), "title is 500 characters, but 1000 bytes"),
 
         (serde_json::json!({
             "title": "short title",
@@ -119,30 +143,82 @@ async fn newsletters_returns_a_400_for_invalid_data(){
 }
 
 #[tokio::test]
-async fn newsletters_returns_a_502_on_send_email_error(){
+async fn publishing_returns_a_202_with_the_issue_id_before_anything_is_sent(){
     // Arrange
     let app = spawn_app().await;
-    let body = serde_json::json!({
-        "title": "Newsletter title",
-        "content": {
-            "text": "Newsletter body as plain text",
-            "html": "<p>Newsletter body as HTML</p>",
-        }
-    });
-    
     app.create_confirmed_subscription().await;
-
+    // The email API is so slow the request would time out if it waited for it
     let _mock_send_newsletter = Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(30)))
+        .mount(&app.mock_email_server)
+        .await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth("admin", Some("admin"))
+        .json(&newsletter_request_body())
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["id"].is_i64());
+}
+
+#[tokio::test]
+async fn a_failed_delivery_is_retried(){
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
         .and(method("POST"))
         .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&app.mock_email_server)
+        .await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
         .expect(1)
         .mount(&app.mock_email_server)
         .await;
 
-    let response = app.post_newsletters(body).await;
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
     // Assert
-    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    // Mocks verify on Drop: two failures, then one success
 }
+
+#[tokio::test]
+async fn delivery_is_given_up_after_the_maximum_number_of_attempts(){
+    // Arrange
+    let app = spawn_app_with(|c| c.delivery.max_attempts = 3).await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(3)
+        .mount(&app.mock_email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that there was no fourth attempt
+}
+
 #[tokio::test]
 async fn request_with_no_authorization_is_rejected() {
     // Arrange
@@ -165,7 +241,7 @@ async fn request_with_no_authorization_is_rejected() {
 
     // Act
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .json(&body)
         .send()
         .await
@@ -198,7 +274,7 @@ async fn request_with_bearer_authorization_is_rejected() {
 
     // Act
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .header("Authorization", "Bearer your_token_here")
         .json(&body)
         .send()
@@ -206,7 +282,541 @@ async fn request_with_bearer_authorization_is_rejected() {
         .expect("Failed to execute request."); 
 
     // Assert
-    assert_eq!(response.status().as_u16(), 400 );
+    assert_eq!(response.status().as_u16(), 401 );
     assert_eq!(r#"Basic realm="publish""#, response.headers()["WWW-Authenticate"]);
 }
 
+
+#[tokio::test]
+async fn unknown_sender_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth("nobody", Some("admin"))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(r#"Basic realm="publish""#, response.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth("admin", Some("not the password"))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(r#"Basic realm="publish""#, response.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn a_repeated_request_with_the_same_idempotency_key_is_published_once() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+    // Act
+    let first_response = app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key).await;
+    let second_response = app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key).await;
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 202);
+    assert_eq!(second_response.status().as_u16(), 202);
+    assert_eq!(
+        first_response.headers()["Content-Type"],
+        second_response.headers()["Content-Type"]
+    );
+    assert_eq!(first_response.text().await.unwrap(), second_response.text().await.unwrap());
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that the newsletter was sent only once
+}
+
+#[tokio::test]
+async fn concurrent_requests_with_the_same_idempotency_key_are_published_once() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+    // Act
+    let (first_response, second_response) = tokio::join!(
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key),
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key),
+    );
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 202);
+    assert_eq!(second_response.status().as_u16(), 202);
+    assert_eq!(first_response.text().await.unwrap(), second_response.text().await.unwrap());
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn reusing_an_idempotency_key_for_a_different_body_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    let mut other_body = newsletter_request_body();
+    other_body["title"] = "Another newsletter title".into();
+
+    // Act
+    let first_response = app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key).await;
+    let second_response = app.post_newsletters_with_idempotency_key(other_body, &idempotency_key).await;
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 202);
+    assert_eq!(second_response.status().as_u16(), 422);
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_scoped_to_the_sender() {
+    // Arrange
+    let app = spawn_app().await;
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    let response = reqwest::Client::new()
+        .post(format!("{}/senders", &app.address))
+        .basic_auth("admin", Some("admin"))
+        .json(&serde_json::json!({
+            "username": "editor",
+            "display_name": "Editor",
+            "password": "editor-password",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 201);
+
+    // Act
+    let first_response = app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key).await;
+    let second_response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth("editor", Some("editor-password"))
+        .header("Idempotency-Key", &idempotency_key)
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 202);
+    assert_eq!(second_response.status().as_u16(), 202);
+    assert_ne!(first_response.text().await.unwrap(), second_response.text().await.unwrap());
+}
+
+#[tokio::test]
+async fn an_invalid_idempotency_key_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.post_newsletters_with_idempotency_key(newsletter_request_body(), &"a".repeat(100)).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn every_subscriber_gets_a_message_of_their_own() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let _mock_send_confirmation = Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount_as_scoped(&app.mock_email_server)
+        .await;
+    app.post_subscriptions(&SubscriptionRequest::new("octavia".to_string(), "octavia_butler@gmail.com".to_string()))
+        .await
+        .error_for_status()
+        .unwrap();
+    let confirmation_request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+    reqwest::get(app.get_confirmation_links(&confirmation_request).html).await.unwrap();
+    drop(_mock_send_confirmation);
+    let already_received = app.mock_email_server.received_requests().await.unwrap().len();
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let mut recipients = Vec::new();
+    for request in &app.mock_email_server.received_requests().await.unwrap()[already_received..] {
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        let messages = match body.get("Messages") {
+            Some(messages) => messages.as_array().unwrap().clone(),
+            None => vec![body],
+        };
+        for message in messages {
+            let message_recipients = message["Recipients"].as_array().unwrap();
+            assert_eq!(message_recipients.len(), 1);
+            recipients.push(message_recipients[0]["email"].as_str().unwrap().to_string());
+        }
+    }
+    recipients.sort();
+    assert_eq!(recipients, vec!["octavia_butler@gmail.com", "ursula_le_guin@gmail.com"]);
+}
+
+#[tokio::test]
+async fn a_newsletter_is_personalised_for_each_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let _mock_send_confirmation = Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount_as_scoped(&app.mock_email_server)
+        .await;
+    let custom_fields = BTreeMap::from([("city".to_string(), "Pasadena".to_string())]);
+    app.post_subscriptions(&SubscriptionRequest::new("octavia".to_string(), "octavia_butler@gmail.com".to_string()).with_custom_fields(custom_fields))
+        .await
+        .error_for_status()
+        .unwrap();
+    let confirmation_request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+    reqwest::get(app.get_confirmation_links(&confirmation_request).html).await.unwrap();
+    drop(_mock_send_confirmation);
+    let already_received = app.mock_email_server.received_requests().await.unwrap().len();
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_email_server)
+        .await;
+    let body = serde_json::json!({
+        "title": "News for {{ username }}",
+        "content": {
+            "text": "Hello {{ username }}{% if fields.city %} in {{ fields.city }}{% endif %}!",
+            "html": "<p>Hello {{ username }}</p><a href=\"{{ unsubscribe_url }}\">Unsubscribe</a>",
+        }
+    });
+
+    // Act
+    let response = app.post_newsletters(body).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let mut messages = BTreeMap::new();
+    for request in &app.mock_email_server.received_requests().await.unwrap()[already_received..] {
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        let batch = match body.get("Messages") {
+            Some(messages) => messages.as_array().unwrap().clone(),
+            None => vec![body],
+        };
+        for message in batch {
+            messages.insert(message["Recipients"][0]["email"].as_str().unwrap().to_string(), message);
+        }
+    }
+    let octavia = &messages["octavia_butler@gmail.com"];
+    assert_eq!(octavia["Subject"], "News for octavia");
+    assert_eq!(octavia["Text-part"], "Hello octavia in Pasadena!");
+    let ursula = &messages["ursula_le_guin@gmail.com"];
+    assert_eq!(ursula["Text-part"], "Hello le guin!");
+    let html = ursula["Html-part"].as_str().unwrap();
+    assert!(html.starts_with("<p>Hello le guin</p>"));
+    assert!(html.contains("/subscriptions/unsubscribe?subscription_id="));
+}
+
+#[tokio::test]
+async fn a_newsletter_with_unknown_template_variables_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let body = serde_json::json!({
+        "title": "News for {{ name }}",
+        "content": {
+            "text": "Your coupon: {{ coupon }}",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    // Act
+    let response = app.post_newsletters(body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["problems"], serde_json::json!([
+        "title: unknown variable `name`",
+        "content.text: unknown variable `coupon`",
+    ]));
+    assert_eq!(app.storage.count_newsletter_issues().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn a_newsletter_written_in_markdown_is_sent_with_both_parts() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "markdown": "Hello **{{ username }}**, read [this](https://example.com)." }
+    });
+
+    // Act
+    let response = app.post_newsletters(body).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+    let message: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert!(message["Html-part"].as_str().unwrap().contains("<p>Hello <strong>le guin</strong>, read <a href=\"https://example.com\">this</a>.</p>"));
+    assert_eq!(message["Text-part"], "Hello le guin, read this [1].\n\n[1] https://example.com");
+}
+
+#[tokio::test]
+async fn newsletters_need_either_markdown_or_both_html_and_text() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (serde_json::json!({ "markdown": "Some markdown", "html": "<p>Some html</p>" }), "markdown and html"),
+        (serde_json::json!({ "html": "<p>Some html</p>" }), "html without text"),
+        (serde_json::json!({}), "no content at all"),
+    ];
+    for (content, description) in test_cases {
+        // Act
+        let response = app.post_newsletters(serde_json::json!({ "title": "Newsletter title", "content": content })).await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "The API did not reject {}", description);
+    }
+}
+
+#[tokio::test]
+async fn invalid_content_is_reported_field_by_field() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = serde_json::json!({
+        "title": "Tiny",
+        "content": {
+            "text": "bla",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    // Act
+    let response = app.post_newsletters(body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["message"], "Validation error(s)");
+    let errors = body["errors"].as_object().unwrap();
+    assert_eq!(errors.keys().collect::<Vec<_>>(), vec!["content.text", "title"]);
+    assert_eq!(errors["title"][0]["code"], "too_short");
+    assert_eq!(errors["content.text"][0]["code"], "too_short");
+}
+
+#[tokio::test]
+async fn the_content_limits_come_from_the_configuration() {
+    // Arrange
+    let app = spawn_app_with(|c| c.content_limits.max_body_bytes = 100).await;
+    let fits = serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "text": "a".repeat(100), "html": "<p>Newsletter body as HTML</p>" }
+    });
+    let too_long = serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "text": "a".repeat(101), "html": "<p>Newsletter body as HTML</p>" }
+    });
+    // Short as markdown, but the layout makes the html a lot longer
+    let renders_too_long = serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "markdown": "Some markdown" }
+    });
+
+    // Act
+    let fits = app.post_newsletters(fits).await;
+    let too_long = app.post_newsletters(too_long).await;
+    let renders_too_long = app.post_newsletters(renders_too_long).await;
+
+    // Assert
+    assert_eq!(fits.status().as_u16(), 202);
+    assert_eq!(too_long.status().as_u16(), 400);
+    let body: serde_json::Value = too_long.json().await.unwrap();
+    assert_eq!(body["errors"]["content.text"][0]["code"], "too_long");
+    assert_eq!(renders_too_long.status().as_u16(), 400);
+    let body: serde_json::Value = renders_too_long.json().await.unwrap();
+    assert_eq!(body["errors"]["content.markdown"][0]["code"], "rendered_too_long");
+}
+
+#[tokio::test]
+async fn hand_written_html_is_sanitized_and_the_publisher_is_told_what_was_removed() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p onmouseover=\"steal()\">Hello {{ username }}</p><script>steal()</script><a href=\"javascript:steal()\">Read more</a>",
+        }
+    });
+
+    // Act
+    let response = app.post_newsletters(body).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["warnings"], serde_json::json!([
+        "removed the `onmouseover` handler from <p>",
+        "removed <script> and its content",
+        "removed the `javascript:` link from <a>",
+    ]));
+    let request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+    let message: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    let html = message["Html-part"].as_str().unwrap();
+    assert!(html.starts_with("<p>Hello le guin</p>"), "{}", html);
+    assert!(!html.contains("steal()"), "{}", html);
+}
+
+#[tokio::test]
+async fn template_tags_cannot_bring_back_what_the_sanitizer_removed() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>{{ '<script>x</script>'|safe }}</p>",
+        }
+    });
+
+    // Act
+    let response = app.post_newsletters(body).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+    let message: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    let html = message["Html-part"].as_str().unwrap();
+    assert_eq!(html, "<p>&lt;script&gt;x&lt;&#x2f;script&gt;</p>");
+}
+
+#[tokio::test]
+async fn template_tags_inside_html_tags_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "markdown": "[My site]({{ fields.website }}) and [unsubscribe]({{ unsubscribe_url }})" }
+    });
+
+    // Act
+    let response = app.post_newsletters(body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["problems"], serde_json::json!([
+        "content.html: `{{ fields.website }}` is inside an HTML tag, only `{{ unsubscribe_url }}` can be",
+    ]));
+    assert_eq!(app.storage.count_newsletter_issues().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn style_blocks_are_inlined_unless_the_configuration_says_otherwise() {
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<style>p { color: #333333 }</style><p>Newsletter body as HTML</p>",
+        }
+    });
+    for (inline_css, expected_html) in [
+        (true, "<p style=\"color: #333333\">Newsletter body as HTML</p>"),
+        // Without inlining the block is only sanitized away
+        (false, "<p>Newsletter body as HTML</p>"),
+    ] {
+        // Arrange
+        let app = spawn_app_with(|c| c.templates.inline_css = inline_css).await;
+        app.create_confirmed_subscription().await;
+        Mock::given(path("/v3/send"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&app.mock_email_server)
+            .await;
+
+        // Act
+        let response = app.post_newsletters(body.clone()).await;
+        app.dispatch_all_pending_emails().await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 202);
+        let request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+        let message: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(message["Html-part"], expected_html);
+    }
+}
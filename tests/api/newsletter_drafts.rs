@@ -0,0 +1,210 @@
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::common::{spawn_app, TestApp};
+
+fn newsletter_request_body(title: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": title,
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+async fn send(request: reqwest::RequestBuilder) -> (u16, serde_json::Value) {
+    let response = request
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let status = response.status().as_u16();
+    (status, response.json().await.unwrap_or(serde_json::Value::Null))
+}
+
+async fn create_draft(app: &TestApp, title: &str) -> serde_json::Value {
+    let (status, draft) = send(reqwest::Client::new()
+        .post(format!("{}/newsletters/drafts", &app.address))
+        .json(&newsletter_request_body(title))).await;
+    assert_eq!(status, 201);
+    draft
+}
+
+async fn schedule(app: &TestApp, issue_id: i64, send_at: chrono::DateTime<chrono::Utc>) -> (u16, serde_json::Value) {
+    send(reqwest::Client::new()
+        .post(format!("{}/newsletters/drafts/{}/schedule", &app.address, issue_id))
+        .json(&serde_json::json!({ "send_at": send_at.to_rfc3339() }))).await
+}
+
+async fn cancel(app: &TestApp, issue_id: i64) -> (u16, serde_json::Value) {
+    send(reqwest::Client::new()
+        .post(format!("{}/newsletters/drafts/{}/cancel", &app.address, issue_id))).await
+}
+
+async fn get_issue(app: &TestApp, issue_id: i64) -> serde_json::Value {
+    let (status, issue) = send(reqwest::Client::new()
+        .get(format!("{}/newsletters/{}", &app.address, issue_id))).await;
+    assert_eq!(status, 200);
+    issue
+}
+
+/// Give the scheduler a few of its (10ms) ticks.
+async fn let_the_scheduler_run() {
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn a_draft_is_saved_without_being_sent() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_email_server)
+        .await;
+
+    // Act
+    let draft = create_draft(&app, "Newsletter title").await;
+
+    // Assert
+    assert_eq!(draft["status"], "draft");
+    assert!(draft["published_at"].is_null());
+    let issue = get_issue(&app, draft["id"].as_i64().unwrap()).await;
+    assert_eq!(issue["deliveries"]["queued"], 0);
+    let_the_scheduler_run().await;
+}
+
+#[tokio::test]
+async fn a_draft_can_be_edited() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft = create_draft(&app, "Newsletter title").await;
+    let issue_id = draft["id"].as_i64().unwrap();
+
+    // Act
+    let (status, edited) = send(reqwest::Client::new()
+        .put(format!("{}/newsletters/drafts/{}", &app.address, issue_id))
+        .json(&newsletter_request_body("A better title"))).await;
+
+    // Assert
+    assert_eq!(status, 200);
+    assert_eq!(edited["title"], "A better title");
+    assert_eq!(get_issue(&app, issue_id).await["title"], "A better title");
+}
+
+#[tokio::test]
+async fn an_invalid_draft_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let (status, _) = send(reqwest::Client::new()
+        .post(format!("{}/newsletters/drafts", &app.address))
+        .json(&newsletter_request_body("Tiny"))).await;
+
+    // Assert
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn a_draft_cannot_be_scheduled_in_the_past() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft = create_draft(&app, "Newsletter title").await;
+
+    // Act
+    let (status, _) = schedule(&app, draft["id"].as_i64().unwrap(), chrono::Utc::now() - chrono::Duration::minutes(1)).await;
+
+    // Assert
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn a_scheduled_issue_is_published_once_it_is_due() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+    let draft = create_draft(&app, "Newsletter title").await;
+    let issue_id = draft["id"].as_i64().unwrap();
+    let (status, scheduled) = schedule(&app, issue_id, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+    assert_eq!(status, 200);
+    assert_eq!(scheduled["status"], "scheduled");
+    let_the_scheduler_run().await;
+    assert_eq!(get_issue(&app, issue_id).await["status"], "scheduled");
+
+    // Act
+    app.clock.advance(chrono::Duration::hours(2));
+    let_the_scheduler_run().await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let issue = get_issue(&app, issue_id).await;
+    assert_eq!(issue["status"], "published");
+    assert!(issue["published_at"].is_string());
+    assert_eq!(issue["deliveries"]["sent"], 1);
+    // Mock verifies on Drop that it went out exactly once
+}
+
+#[tokio::test]
+async fn a_cancelled_issue_is_never_sent() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_email_server)
+        .await;
+    let draft = create_draft(&app, "Newsletter title").await;
+    let issue_id = draft["id"].as_i64().unwrap();
+    schedule(&app, issue_id, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+
+    // Act
+    let (status, cancelled) = cancel(&app, issue_id).await;
+    app.clock.advance(chrono::Duration::hours(2));
+    let_the_scheduler_run().await;
+
+    // Assert
+    assert_eq!(status, 200);
+    assert_eq!(cancelled["status"], "cancelled");
+    assert_eq!(get_issue(&app, issue_id).await["status"], "cancelled");
+}
+
+#[tokio::test]
+async fn a_cancelled_issue_can_no_longer_be_changed() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft = create_draft(&app, "Newsletter title").await;
+    let issue_id = draft["id"].as_i64().unwrap();
+    cancel(&app, issue_id).await;
+
+    // Act
+    let (edit_status, _) = send(reqwest::Client::new()
+        .put(format!("{}/newsletters/drafts/{}", &app.address, issue_id))
+        .json(&newsletter_request_body("A better title"))).await;
+    let (schedule_status, _) = schedule(&app, issue_id, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+
+    // Assert
+    assert_eq!(edit_status, 409);
+    assert_eq!(schedule_status, 409);
+}
+
+#[tokio::test]
+async fn an_unknown_draft_is_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let (status, _) = cancel(&app, 42).await;
+
+    // Assert
+    assert_eq!(status, 404);
+}
@@ -0,0 +1,141 @@
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::common::{spawn_app, TestApp};
+
+fn newsletter_request_body(title: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": title,
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+async fn get_json(app: &TestApp, endpoint: &str) -> (u16, serde_json::Value) {
+    let response = reqwest::Client::new()
+        .get(format!("{}{}", &app.address, endpoint))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let status = response.status().as_u16();
+    (status, response.json().await.unwrap_or(serde_json::Value::Null))
+}
+
+#[tokio::test]
+async fn the_issue_history_requires_authentication() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!("{}/newsletters", &app.address)).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_published_issue_is_kept_with_its_delivery_counts() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_email_server)
+        .await;
+    let response = app.post_newsletters(newsletter_request_body("Newsletter title")).await;
+    let issue_id = response.json::<serde_json::Value>().await.unwrap()["id"].as_i64().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Act
+    let (status, issue) = get_json(&app, &format!("/newsletters/{}", issue_id)).await;
+
+    // Assert
+    assert_eq!(status, 200);
+    assert_eq!(issue["title"], "Newsletter title");
+    assert_eq!(issue["text_content"], "Newsletter body as plain text");
+    assert_eq!(issue["html_content"], "<p>Newsletter body as HTML</p>");
+    assert!(issue["sender_id"].is_i64());
+    assert!(issue["created_at"].is_string());
+    assert!(issue["published_at"].is_string());
+    assert_eq!(issue["deliveries"], serde_json::json!({ "queued": 0, "sent": 1, "failed": 0, "bounced": 0 }));
+}
+
+#[tokio::test]
+async fn a_rejected_address_is_counted_as_bounced_and_not_retried() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(400))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+    let response = app.post_newsletters(newsletter_request_body("Newsletter title")).await;
+    let issue_id = response.json::<serde_json::Value>().await.unwrap()["id"].as_i64().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Act
+    let (status, issue) = get_json(&app, &format!("/newsletters/{}", issue_id)).await;
+
+    // Assert
+    assert_eq!(status, 200);
+    assert_eq!(issue["deliveries"]["bounced"], 1);
+    assert_eq!(issue["deliveries"]["sent"], 0);
+}
+
+#[tokio::test]
+async fn the_issue_history_is_paginated_newest_first() {
+    // Arrange
+    let app = spawn_app().await;
+    for title in ["First issue", "Second issue", "Third issue"] {
+        let response = app.post_newsletters(newsletter_request_body(title)).await;
+        assert_eq!(response.status().as_u16(), 202);
+    }
+
+    // Act
+    let (first_status, first_page) = get_json(&app, "/newsletters?per_page=2").await;
+    let (second_status, second_page) = get_json(&app, "/newsletters?page=2&per_page=2").await;
+
+    // Assert
+    assert_eq!(first_status, 200);
+    assert_eq!(second_status, 200);
+    let titles = |page: &serde_json::Value| -> Vec<String> {
+        page["issues"].as_array().unwrap().iter()
+            .map(|issue| issue["title"].as_str().unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(titles(&first_page), vec!["Third issue", "Second issue"]);
+    assert_eq!(titles(&second_page), vec!["First issue"]);
+    assert_eq!(first_page["total"], 3);
+    // No subscribers, nothing to deliver
+    assert_eq!(first_page["issues"][0]["deliveries"]["queued"], 0);
+}
+
+#[tokio::test]
+async fn an_invalid_page_size_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let (status, _) = get_json(&app, "/newsletters?per_page=0").await;
+
+    // Assert
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn an_unknown_issue_is_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let (status, _) = get_json(&app, "/newsletters/42").await;
+
+    // Assert
+    assert_eq!(status, 404);
+}
@@ -0,0 +1,196 @@
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::common::{spawn_app, TestApp};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+async fn send(request: reqwest::RequestBuilder) -> (u16, serde_json::Value) {
+    let response = request
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let status = response.status().as_u16();
+    (status, response.json().await.unwrap_or(serde_json::Value::Null))
+}
+
+async fn set_test_addresses(app: &TestApp, addresses: &[&str]) -> (u16, serde_json::Value) {
+    send(reqwest::Client::new()
+        .put(format!("{}/senders/me/test-addresses", &app.address))
+        .json(&serde_json::json!({ "addresses": addresses }))).await
+}
+
+async fn test_send(app: &TestApp, recipients: &[&str]) -> (u16, serde_json::Value) {
+    let mut body = newsletter_request_body();
+    body["recipients"] = serde_json::json!(recipients);
+    send(reqwest::Client::new()
+        .post(format!("{}/newsletters/test-send", &app.address))
+        .json(&body)).await
+}
+
+#[tokio::test]
+async fn a_preview_returns_the_rendered_email_without_sending_or_storing_it() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_email_server)
+        .await;
+
+    // Act
+    let (status, preview) = send(reqwest::Client::new()
+        .post(format!("{}/newsletters/preview", &app.address))
+        .json(&newsletter_request_body())).await;
+
+    // Assert
+    assert_eq!(status, 200);
+    assert_eq!(preview["subject"], "Newsletter title");
+    assert_eq!(preview["html"], "<p>Newsletter body as HTML</p>");
+    assert_eq!(preview["text"], "Newsletter body as plain text");
+    assert_eq!(app.storage.count_newsletter_issues().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn a_preview_requires_an_authenticated_sender() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters/preview", &app.address))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(r#"Basic realm="publish""#, response.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn an_invalid_newsletter_cannot_be_previewed() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut body = newsletter_request_body();
+    body["title"] = serde_json::json!("Tiny");
+
+    // Act
+    let (status, _) = send(reqwest::Client::new()
+        .post(format!("{}/newsletters/preview", &app.address))
+        .json(&body)).await;
+
+    // Assert
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn test_addresses_can_be_set_and_listed() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let (status, _) = set_test_addresses(&app, &["zoe@example.com", "adam@example.com"]).await;
+    let (_, listed) = send(reqwest::Client::new()
+        .get(format!("{}/senders/me/test-addresses", &app.address))).await;
+
+    // Assert
+    assert_eq!(status, 200);
+    assert_eq!(listed["addresses"], serde_json::json!(["adam@example.com", "zoe@example.com"]));
+}
+
+#[tokio::test]
+async fn an_invalid_test_address_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let (status, _) = set_test_addresses(&app, &["adam@example.com", "not-an-email"]).await;
+
+    // Assert
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn a_test_send_only_reaches_the_test_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    set_test_addresses(&app, &["me@example.com"]).await;
+    Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.mock_email_server)
+        .await;
+
+    // Act
+    let (status, outcome) = test_send(&app, &["me@example.com"]).await;
+
+    // Assert
+    assert_eq!(status, 200);
+    assert_eq!(outcome["sent"], serde_json::json!(["me@example.com"]));
+    let request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(body["Recipients"][0]["email"], "me@example.com");
+    assert_eq!(body["Subject"], "[Test] Newsletter title");
+    // No issue, no delivery for the subscriber
+    assert_eq!(app.storage.count_newsletter_issues().await.unwrap(), 0);
+    assert_eq!(app.storage.count_pending_delivery_tasks().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn a_test_send_to_another_address_is_forbidden() {
+    // Arrange
+    let app = spawn_app().await;
+    set_test_addresses(&app, &["me@example.com"]).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_email_server)
+        .await;
+
+    // Act
+    let (status, body) = test_send(&app, &["me@example.com", "someone@example.com"]).await;
+
+    // Assert
+    assert_eq!(status, 403);
+    assert!(body["error"].as_str().unwrap().contains("someone@example.com"));
+}
+
+#[tokio::test]
+async fn a_markdown_newsletter_is_previewed_in_the_layout_with_a_text_part() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "markdown": "# Hello {{ username }}\n\nRead the [news](https://example.com/news).<script>alert(1)</script>",
+        }
+    });
+
+    // Act
+    let (status, preview) = send(reqwest::Client::new()
+        .post(format!("{}/newsletters/preview", &app.address))
+        .json(&body)).await;
+
+    // Assert
+    assert_eq!(status, 200);
+    let html = preview["html"].as_str().unwrap();
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<h1>Hello Ursula Le Guin</h1>"));
+    assert!(html.contains("<a href=\"https://example.com/news\">news</a>"));
+    assert!(!html.contains("<script>"));
+    assert_eq!(preview["text"], "# Hello Ursula Le Guin\n\nRead the news [1].<script>alert(1)</script>\n\n[1] https://example.com/news");
+}
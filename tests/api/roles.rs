@@ -0,0 +1,216 @@
+use serde_json::Value;
+
+use crate::common::{browser, spawn_app, TestApp};
+
+const PASSWORD: &str = "a long enough password";
+
+impl TestApp {
+    /// Created by the bootstrap admin, logs in with `PASSWORD`.
+    async fn create_sender_with_role(&self, username: &str, role: &str) -> Value {
+        let response = reqwest::Client::new()
+            .post(format!("{}/senders", &self.address))
+            .basic_auth("admin", Some("admin"))
+            .json(&serde_json::json!({
+                "username": username,
+                "display_name": username,
+                "password": PASSWORD,
+                "role": role,
+            }))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(response.status().as_u16(), 201);
+        response.json().await.unwrap()
+    }
+
+    async fn set_role(&self, auth: (&str, &str), sender_id: &Value, role: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!("{}/senders/{}/role", &self.address, sender_id))
+            .basic_auth(auth.0, Some(auth.1))
+            .json(&serde_json::json!({ "role": role }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    async fn publish_as(&self, username: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(username, Some(PASSWORD))
+            .json(&serde_json::json!({
+                "title": "Newsletter title",
+                "content": { "markdown": "Newsletter body" }
+            }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+}
+
+#[tokio::test]
+async fn a_viewer_reads_subscribers_but_cannot_publish() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    app.create_sender_with_role("viewer", "viewer").await;
+
+    // Act
+    let response = app.publish_as("viewer").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 403);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "The `viewer` role cannot publish newsletters");
+    let subscription = reqwest::Client::new()
+        .get(format!("{}/subscriptions/find?subscription_id=1", &app.address))
+        .basic_auth("viewer", Some(PASSWORD))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(subscription.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn an_editor_publishes_but_cannot_manage_senders() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_sender_with_role("editor", "editor").await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/senders", &app.address))
+        .basic_auth("editor", Some(PASSWORD))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 403);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "The `editor` role cannot manage senders");
+    assert_eq!(app.publish_as("editor").await.status().as_u16(), 202);
+}
+
+#[tokio::test]
+async fn every_role_manages_its_own_account() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_sender_with_role("viewer", "viewer").await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let api_key = client.post(format!("{}/senders/me/api-keys", &app.address))
+        .basic_auth("viewer", Some(PASSWORD))
+        .json(&serde_json::json!({ "name": "reports", "scopes": ["subscribers:read"] }))
+        .send()
+        .await
+        .unwrap();
+    let test_addresses = client.put(format!("{}/senders/me/test-addresses", &app.address))
+        .basic_auth("viewer", Some(PASSWORD))
+        .json(&serde_json::json!({ "addresses": ["viewer@example.com"] }))
+        .send()
+        .await
+        .unwrap();
+    let password = client.put(format!("{}/senders/me/password", &app.address))
+        .basic_auth("viewer", Some(PASSWORD))
+        .json(&serde_json::json!({ "new_password": "another long enough password" }))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(api_key.status().as_u16(), 201);
+    assert!(test_addresses.status().is_success(), "{}", test_addresses.status());
+    assert_eq!(password.status().as_u16(), 204);
+}
+
+#[tokio::test]
+async fn a_role_change_applies_at_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let editor = app.create_sender_with_role("editor", "editor").await;
+    assert_eq!(app.publish_as("editor").await.status().as_u16(), 202);
+
+    // Act
+    let response = app.set_role(("admin", "admin"), &editor["id"], "viewer").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let sender: Value = response.json().await.unwrap();
+    assert_eq!(sender["role"], "viewer");
+    assert_eq!(app.publish_as("editor").await.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn only_admins_change_roles_and_never_their_own() {
+    // Arrange
+    let app = spawn_app().await;
+    let editor = app.create_sender_with_role("editor", "editor").await;
+    let senders: Vec<Value> = reqwest::Client::new()
+        .get(format!("{}/senders", &app.address))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Act
+    let own_role = app.set_role(("admin", "admin"), &senders[0]["id"], "viewer").await;
+    let by_an_editor = app.set_role(("editor", PASSWORD), &editor["id"], "admin").await;
+
+    // Assert
+    assert_eq!(own_role.status().as_u16(), 409);
+    assert_eq!(by_an_editor.status().as_u16(), 403);
+    assert_eq!(app.set_role(("admin", "admin"), &serde_json::json!(42), "viewer").await.status().as_u16(), 404);
+    assert_eq!(app.set_role(("admin", "admin"), &editor["id"], "owner").await.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn an_api_key_cannot_do_more_than_the_role_of_its_sender() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_sender_with_role("viewer", "viewer").await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/senders/me/api-keys", &app.address))
+        .basic_auth("viewer", Some(PASSWORD))
+        .json(&serde_json::json!({ "name": "CI", "scopes": ["newsletters:publish"] }))
+        .send()
+        .await
+        .unwrap();
+    let key = response.json::<Value>().await.unwrap()["key"].as_str().unwrap().to_string();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .bearer_auth(&key)
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Newsletter body" }
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn a_viewer_sees_the_dashboard_but_not_the_compose_form() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_sender_with_role("viewer", "viewer").await;
+    let client = browser();
+    app.log_in(&client, "viewer", PASSWORD).await;
+
+    // Act
+    let dashboard = client.get(format!("{}/admin/dashboard", &app.address)).send().await.unwrap();
+    let compose = client.get(format!("{}/admin/newsletters", &app.address)).send().await.unwrap();
+
+    // Assert
+    assert_eq!(dashboard.status().as_u16(), 200);
+    assert!(!dashboard.text().await.unwrap().contains(r#"href="/admin/newsletters""#));
+    assert_eq!(compose.status().as_u16(), 403);
+}
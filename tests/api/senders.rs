@@ -0,0 +1,198 @@
+use serde_json::Value;
+
+use crate::common::{spawn_app, TestApp};
+
+async fn post_sender(app: &TestApp, auth: (&str, &str), body: Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/senders", &app.address))
+        .basic_auth(auth.0, Some(auth.1))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn list_senders(app: &TestApp, auth: (&str, &str)) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/senders", &app.address))
+        .basic_auth(auth.0, Some(auth.1))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn new_sender() -> Value {
+    serde_json::json!({
+        "username": "editor",
+        "display_name": "The Editor",
+        "password": "a long enough password",
+    })
+}
+
+#[tokio::test]
+async fn the_bootstrap_admin_can_create_a_sender_who_can_then_log_in() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = post_sender(&app, ("admin", "admin"), new_sender()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 201);
+    let sender: Value = response.json().await.unwrap();
+    assert_eq!(sender["username"], "editor");
+    assert_eq!(sender["display_name"], "The Editor");
+    assert_eq!(sender["enabled"], true);
+    assert!(sender["id"].is_i64());
+    assert!(sender["created_at"].is_string());
+    assert!(sender.get("pwd").is_none());
+    assert_eq!(sender["role"], "editor");
+    let response = reqwest::Client::new()
+        .get(format!("{}/newsletters", &app.address))
+        .basic_auth("editor", Some("a long enough password"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status().as_u16(), 200);
+    let senders: Vec<Value> = list_senders(&app, ("admin", "admin")).await.json().await.unwrap();
+    let usernames: Vec<_> = senders.iter().map(|s| s["username"].as_str().unwrap()).collect();
+    assert_eq!(usernames, vec!["admin", "editor"]);
+}
+
+#[tokio::test]
+async fn sender_endpoints_require_authentication() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/senders", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(r#"Basic realm="publish""#, response.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn creating_a_sender_with_a_taken_username_returns_a_409() {
+    // Arrange
+    let app = spawn_app().await;
+    post_sender(&app, ("admin", "admin"), new_sender()).await.error_for_status().unwrap();
+
+    // Act
+    let response = post_sender(&app, ("admin", "admin"), new_sender()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn creating_a_sender_with_invalid_data_returns_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (serde_json::json!({"username": "editor", "display_name": "The Editor", "password": "short"}), "password too short"),
+        (serde_json::json!({"username": "a b", "display_name": "The Editor", "password": "a long enough password"}), "username with a space"),
+        (serde_json::json!({"username": "editor", "display_name": "", "password": "a long enough password"}), "empty display name"),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = post_sender(&app, ("admin", "admin"), body).await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "The API did not fail with 400 when the payload had a {}.", description);
+    }
+}
+
+#[tokio::test]
+async fn a_disabled_sender_can_no_longer_log_in() {
+    // Arrange
+    let app = spawn_app().await;
+    let sender: Value = post_sender(&app, ("admin", "admin"), new_sender()).await.json().await.unwrap();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/senders/{}/disable", &app.address, sender["id"]))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let disabled: Value = response.json().await.unwrap();
+    assert_eq!(disabled["enabled"], false);
+    let response = list_senders(&app, ("editor", "a long enough password")).await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_deleted_sender_is_gone() {
+    // Arrange
+    let app = spawn_app().await;
+    let sender: Value = post_sender(&app, ("admin", "admin"), new_sender()).await.json().await.unwrap();
+    let delete = || reqwest::Client::new()
+        .delete(format!("{}/senders/{}", &app.address, sender["id"]))
+        .basic_auth("admin", Some("admin"))
+        .send();
+
+    // Act
+    let first_response = delete().await.expect("Failed to execute request.");
+    let second_response = delete().await.expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 204);
+    assert_eq!(second_response.status().as_u16(), 404);
+    let senders: Vec<Value> = list_senders(&app, ("admin", "admin")).await.json().await.unwrap();
+    assert_eq!(senders.len(), 1);
+}
+
+#[tokio::test]
+async fn senders_cannot_disable_or_delete_themselves() {
+    // Arrange
+    let app = spawn_app().await;
+    let senders: Vec<Value> = list_senders(&app, ("admin", "admin")).await.json().await.unwrap();
+    let admin_id = &senders[0]["id"];
+
+    // Act
+    let disable_response = reqwest::Client::new()
+        .post(format!("{}/senders/{}/disable", &app.address, admin_id))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let delete_response = reqwest::Client::new()
+        .delete(format!("{}/senders/{}", &app.address, admin_id))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(disable_response.status().as_u16(), 409);
+    assert_eq!(delete_response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn a_sender_can_change_their_own_password() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .put(format!("{}/senders/me/password", &app.address))
+        .basic_auth("admin", Some("admin"))
+        .json(&serde_json::json!({ "new_password": "a brand new password" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(list_senders(&app, ("admin", "admin")).await.status().as_u16(), 401);
+    assert_eq!(list_senders(&app, ("admin", "a brand new password")).await.status().as_u16(), 200);
+}
@@ -0,0 +1,38 @@
+use zero2prod::configuration::{get_configuration, get_configuration_with, EmailProviderKind, StorageBackend};
+use zero2prod::startup::Application;
+
+#[tokio::test]
+async fn application_fails_to_start_without_mailjet_credentials() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.server.port = 0;
+    configuration.storage.backend = StorageBackend::InMemory;
+    configuration.email_client.provider = EmailProviderKind::Mailjet;
+    configuration.email_client.api_key = None;
+    configuration.email_client.api_secret = None;
+
+    // Act
+    let outcome = Application::build(configuration).await;
+
+    // Assert
+    let error = outcome.err().expect("The application started without credentials");
+    assert!(error.to_string().contains("email_client.api_key"));
+}
+
+#[test]
+fn an_extra_config_file_overrides_the_configuration_directory() {
+    // Arrange
+    let path = std::env::temp_dir().join(format!("zero2prod-{}.yaml", uuid::Uuid::new_v4()));
+    std::fs::write(&path, "server:\n  port: 9123\nstorage:\n  backend: in_memory\n").unwrap();
+
+    // Act
+    let configuration = get_configuration_with(Some(&path));
+
+    // Assert
+    let _ = std::fs::remove_file(&path);
+    let configuration = configuration.expect("Failed to read configuration.");
+    assert_eq!(configuration.server.port, 9123);
+    assert!(matches!(configuration.storage.backend, StorageBackend::InMemory));
+    // Everything else still comes from `configuration/`
+    assert_eq!(configuration.server.host, get_configuration().unwrap().server.host);
+}
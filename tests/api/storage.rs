@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+
+use sha3::Digest;
+use zero2prod::configuration::{get_configuration, Properties, StorageBackend};
+use zero2prod::sqlite::SqliteStore;
+use zero2prod::in_memory::AppState;
+use zero2prod::storage::{DeliveryQueueStore, IdempotencyStore, NewsletterIssueStore, SavedResponse, SenderRole, SenderStore, Storage, StorageError, SubscriptionStore};
+
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::common::{get_id_from_response, spawn_app, spawn_app_with_configuration, temporary_sqlite_path};
+
+fn sqlite_configuration(path: &str) -> Properties {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.storage.backend = StorageBackend::Sqlite;
+    configuration.storage.sqlite_path = path.to_string();
+    configuration
+}
+
+#[tokio::test]
+async fn confirmed_subscriptions_survive_an_application_restart() {
+    // Arrange
+    let db_path = &temporary_sqlite_path();
+    let app = spawn_app_with_configuration(sqlite_configuration(db_path)).await;
+    app.create_confirmed_subscription().await;
+
+    // Act - a second application pointed at the same database file
+    let restarted_app = spawn_app_with_configuration(sqlite_configuration(db_path)).await;
+    let response = restarted_app.get_subscription("1").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscription: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(subscription["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(subscription["status"], "confirmed");
+    let _ = std::fs::remove_file(db_path);
+}
+
+#[tokio::test]
+async fn sqlite_backend_assigns_sequential_subscription_ids() {
+    // Arrange
+    let db_path = &temporary_sqlite_path();
+    let app = spawn_app_with_configuration(sqlite_configuration(db_path)).await;
+
+    // Act
+    app.create_unconfirmed_subscription().await;
+    let response = app.get_subscription("1").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(get_id_from_response(response.text().await.unwrap()), "1");
+    let _ = std::fs::remove_file(db_path);
+}
+
+#[tokio::test]
+async fn legacy_sha3_password_is_upgraded_to_argon2id_on_login() {
+    // Arrange - a database written before the switch to Argon2
+    let db_path = &temporary_sqlite_path();
+    let store = SqliteStore::connect(db_path).await.unwrap();
+    let legacy_hash = format!("{:x}", sha3::Sha3_256::digest("admin".as_bytes()));
+    store.add_sender("admin", "admin", &legacy_hash, SenderRole::Admin).await.unwrap();
+    let app = spawn_app_with_configuration(sqlite_configuration(db_path)).await;
+    app.create_confirmed_subscription().await;
+    let _mock_send_newsletter = Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.mock_email_server)
+        .await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    // Act
+    let first_response = app.post_newsletters(body.clone()).await;
+    let second_response = app.post_newsletters(body).await;
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 202);
+    assert_eq!(second_response.status().as_u16(), 202);
+    let sender = store.find_sender("admin").await.unwrap().unwrap();
+    assert!(sender.pwd.starts_with("$argon2id$"), "{}", sender.pwd);
+    let issue_id = get_id_from_response(first_response.text().await.unwrap());
+    let issue = store.find_newsletter_issue(issue_id.parse().unwrap()).await.unwrap().unwrap();
+    assert_eq!(issue.sender_id, Some(sender.id));
+    let _ = std::fs::remove_file(db_path);
+}
+
+#[tokio::test]
+async fn every_backend_refuses_a_second_sender_with_the_same_username() {
+    // Arrange
+    let app = spawn_app().await;
+    app.storage.add_sender("editor", "Editor", "irrelevant", SenderRole::Editor).await.unwrap();
+
+    // Act
+    let outcome = app.storage.add_sender("editor", "Another editor", "irrelevant", SenderRole::Editor).await;
+
+    // Assert
+    assert!(matches!(outcome, Err(StorageError::UniqueViolation(_))), "{:?}", outcome.map(|s| s.username));
+}
+
+#[tokio::test]
+async fn a_claimed_delivery_task_is_invisible_until_its_lease_runs_out() {
+    // Arrange
+    let db_path = &temporary_sqlite_path();
+    let store = SqliteStore::connect(db_path).await.unwrap();
+    let subscription = store.add_subscription("le guin", "ursula_le_guin@gmail.com", &BTreeMap::new()).await.unwrap();
+    store.update_subscription_status(subscription.id, "confirmed").await.unwrap();
+    let sender = store.add_sender("admin", "admin", "irrelevant", SenderRole::Admin).await.unwrap();
+    store.add_newsletter_issue(sender.id, "Title", "Text", "<p>Html</p>").await.unwrap();
+    let now = chrono::Utc::now();
+    let lease_until = now + chrono::Duration::seconds(60);
+
+    // Act
+    let first_claim = store.claim_delivery_tasks(now, lease_until, 10).await.unwrap();
+    let second_claim = store.claim_delivery_tasks(now, lease_until, 10).await.unwrap();
+    let claim_after_the_lease = store.claim_delivery_tasks(lease_until, lease_until + chrono::Duration::seconds(60), 10).await.unwrap();
+
+    // Assert
+    assert_eq!(first_claim.len(), 1, "The queued task was not claimed");
+    assert_eq!(first_claim[0].email, "ursula_le_guin@gmail.com");
+    assert!(second_claim.is_empty());
+    assert_eq!(claim_after_the_lease[0].id, first_claim[0].id);
+    let _ = std::fs::remove_file(db_path);
+}
+
+/// Claims the only task after the first lease ran out, as a second worker would.
+async fn delivery_outcomes_are_only_recorded_under_the_current_lease(store: &dyn Storage) {
+    // Arrange
+    let subscription = store.add_subscription("le guin", "ursula_le_guin@gmail.com", &BTreeMap::new()).await.unwrap();
+    store.update_subscription_status(subscription.id, "confirmed").await.unwrap();
+    let sender = store.add_sender("admin", "admin", "irrelevant", SenderRole::Admin).await.unwrap();
+    let issue = store.add_newsletter_issue(sender.id, "Title", "Text", "<p>Html</p>").await.unwrap();
+    let now = chrono::Utc::now();
+    let first_lease = now + chrono::Duration::seconds(60);
+    let first_claim = store.claim_delivery_tasks(now, first_lease, 10).await.unwrap();
+    let second_lease = first_lease + chrono::Duration::seconds(60);
+    let second_claim = store.claim_delivery_tasks(first_lease, second_lease, 10).await.unwrap();
+    let task_id = first_claim[0].id;
+    assert_eq!(second_claim[0].claimed_until, Some(second_lease));
+
+    // Act
+    let late_first_worker = store.retry_delivery_task(task_id, first_lease, first_lease, second_lease, "timed out").await.unwrap();
+    let second_worker = store.complete_delivery_task(task_id, second_lease, first_lease).await.unwrap();
+    let repeat = store.bounce_delivery_task(task_id, second_lease, first_lease, "bounced").await.unwrap();
+
+    // Assert
+    assert!(!late_first_worker);
+    assert!(second_worker);
+    assert!(!repeat, "The lease was not released");
+    assert_eq!(store.count_issue_deliveries(issue.id).await.unwrap().sent, 1);
+}
+
+#[tokio::test]
+async fn a_worker_whose_lease_ran_out_cannot_record_an_outcome() {
+    let db_path = &temporary_sqlite_path();
+    delivery_outcomes_are_only_recorded_under_the_current_lease(&AppState::new()).await;
+    delivery_outcomes_are_only_recorded_under_the_current_lease(&SqliteStore::connect(db_path).await.unwrap()).await;
+    let _ = std::fs::remove_file(db_path);
+}
+
+#[tokio::test]
+async fn a_saved_idempotent_response_is_kept_until_the_key_expires() {
+    // Arrange
+    let db_path = &temporary_sqlite_path();
+    let store = SqliteStore::connect(db_path).await.unwrap();
+    let sender = store.add_sender("admin", "admin", "irrelevant", SenderRole::Admin).await.unwrap();
+    let now = chrono::Utc::now();
+    let response = SavedResponse {
+        status_code: 202,
+        headers: vec![("content-type".to_string(), "application/json".to_string())],
+        body: br#"{"id":1}"#.to_vec(),
+    };
+
+    // Act
+    let first_reservation = store.reserve_idempotency_key(sender.id, "key", "hash", now, now).await.unwrap();
+    store.save_idempotent_response(sender.id, "key", &response).await.unwrap();
+    let repeat = store.reserve_idempotency_key(sender.id, "key", "hash", now, now).await.unwrap();
+    let later = now + chrono::Duration::minutes(5);
+    let after_expiry = store.reserve_idempotency_key(sender.id, "key", "hash", later, later).await.unwrap();
+
+    // Assert
+    assert!(first_reservation.is_none());
+    let saved = repeat.expect("The key was reserved twice").response.expect("The response was not saved");
+    assert_eq!(saved.status_code, 202);
+    assert_eq!(saved.headers, response.headers);
+    assert_eq!(saved.body, response.body);
+    assert!(after_expiry.is_none());
+    let _ = std::fs::remove_file(db_path);
+}
+
+#[tokio::test]
+async fn a_claim_leases_at_most_the_batch_size_oldest_tasks_first() {
+    // Arrange
+    let db_path = &temporary_sqlite_path();
+    let store = SqliteStore::connect(db_path).await.unwrap();
+    for i in 0..3 {
+        let subscription = store.add_subscription("reader", &format!("reader{}@example.com", i), &BTreeMap::new()).await.unwrap();
+        store.update_subscription_status(subscription.id, "confirmed").await.unwrap();
+    }
+    let sender = store.add_sender("admin", "admin", "irrelevant", SenderRole::Admin).await.unwrap();
+    store.add_newsletter_issue(sender.id, "Title", "Text", "<p>Html</p>").await.unwrap();
+    let now = chrono::Utc::now();
+    let lease_until = now + chrono::Duration::seconds(60);
+
+    // Act
+    let first_claim = store.claim_delivery_tasks(now, lease_until, 2).await.unwrap();
+    let second_claim = store.claim_delivery_tasks(now, lease_until, 2).await.unwrap();
+
+    // Assert
+    let emails: Vec<&str> = first_claim.iter().map(|task| task.email.as_str()).collect();
+    assert_eq!(emails, vec!["reader0@example.com", "reader1@example.com"]);
+    assert_eq!(second_claim.len(), 1);
+    assert_eq!(second_claim[0].email, "reader2@example.com");
+    let _ = std::fs::remove_file(db_path);
+}
+
+#[tokio::test]
+async fn a_scheduled_issue_is_published_exactly_once_across_instances() {
+    // Arrange - two stores on the same file, as two instances or a restart would be
+    let db_path = &temporary_sqlite_path();
+    let first_instance = SqliteStore::connect(db_path).await.unwrap();
+    let second_instance = SqliteStore::connect(db_path).await.unwrap();
+    let subscription = first_instance.add_subscription("le guin", "ursula_le_guin@gmail.com", &BTreeMap::new()).await.unwrap();
+    first_instance.update_subscription_status(subscription.id, "confirmed").await.unwrap();
+    let sender = first_instance.add_sender("admin", "admin", "irrelevant", SenderRole::Admin).await.unwrap();
+    let draft = first_instance.add_draft_issue(sender.id, "Title", "Text", "<p>Html</p>").await.unwrap();
+    let now = chrono::Utc::now();
+    first_instance.schedule_issue(draft.id, now + chrono::Duration::minutes(5)).await.unwrap();
+
+    // Act
+    let too_early = first_instance.publish_due_issue(now).await.unwrap();
+    let later = now + chrono::Duration::minutes(10);
+    let (first, second) = tokio::join!(
+        first_instance.publish_due_issue(later),
+        second_instance.publish_due_issue(later),
+    );
+
+    // Assert
+    assert!(too_early.is_none());
+    let published: Vec<_> = [first.unwrap(), second.unwrap()].into_iter().flatten().collect();
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0].status, "published");
+    let counts = first_instance.count_issue_deliveries(draft.id).await.unwrap();
+    assert_eq!(counts.queued, 1);
+    let _ = std::fs::remove_file(db_path);
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use reqwest::Url;
 use zero2prod::routes::SubscriptionRequest;
 use crate::common::spawn_app;
@@ -41,6 +43,10 @@ async fn subscribe_returns_a_400_when_data_is_missing() {
         (SubscriptionRequest::new("Boo".to_string(), "my-gosh-not-an-email".to_string()), "invalid email"),
         (SubscriptionRequest::new("G".to_string(), "g@mail.com".to_string()), "too short of a name"),
         (SubscriptionRequest::new("the%estna^^e".to_string(), "mine@yahoo.com".to_string()), "invalid name"),
+        (SubscriptionRequest::new("le guin".to_string(), "ursula_le_guin@gmail.com".to_string())
+            .with_custom_fields(BTreeMap::from([("Home City".to_string(), "Portland".to_string())])), "invalid custom field name"),
+        (SubscriptionRequest::new("le guin".to_string(), "ursula_le_guin@gmail.com".to_string())
+            .with_custom_fields(BTreeMap::from([("city".to_string(), "x".repeat(201))])), "too long a custom field"),
     ];
     for (invalid_body, error_message) in test_cases {
         // Act
@@ -125,7 +131,7 @@ async fn second_subscribe_after_confirmation_returns_bad_request(){
         assert_eq!(links.len(), 1);
         links[0].as_str().to_owned()
     };
-    let raw_confirmation_link = &get_link(&body["Html-part"].as_str().unwrap());
+    let raw_confirmation_link = &get_link(body["Html-part"].as_str().unwrap());
     let mut confirmation_link = Url::parse(raw_confirmation_link).unwrap();
     // Let's make sure we don't call random APIs on the web
     assert_eq!(confirmation_link.host_str().unwrap(), "127.0.0.1");
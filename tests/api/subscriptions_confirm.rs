@@ -3,7 +3,7 @@ use wiremock::{ResponseTemplate, Mock};
 use wiremock::matchers::{path, method};
 use zero2prod::routes::SubscriptionRequest;
 
-use crate::common::{get_id_from_response, spawn_app};
+use crate::common::{get_id_from_response, spawn_app, spawn_app_with};
 
 #[tokio::test]
 async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
@@ -20,7 +20,7 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
     let response = app.post_subscriptions(&request_body).await;
     // Assert
     let email_request = &app.mock_email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     // The two links should be identical
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
     assert_eq!(response.status().as_u16(), 200);
@@ -58,3 +58,84 @@ async fn confirmations_without_token_are_rejected_with_a_400() {
     // Assert
     assert_eq!(response.status().as_u16(), 400);
 }
+
+#[tokio::test]
+async fn the_confirmation_link_carries_a_random_token_and_not_the_subscription_id() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let confirmation_links = app.create_unconfirmed_subscription().await;
+
+    // Assert
+    let (_, token) = confirmation_links.html.query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap();
+    assert!(token.len() >= 25);
+    assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+    assert_ne!(token, "1");
+}
+
+#[tokio::test]
+async fn confirmations_with_an_unknown_token_are_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscription().await;
+
+    // Act - guessing the old sequential id no longer works
+    let response = reqwest::get(format!("{}/subscriptions/confirm?subscription_token=1", app.address))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    let subscription: serde_json::Value = serde_json::from_str(&app.get_subscription("1").await.text().await.unwrap()).unwrap();
+    assert_eq!(subscription["status"], "pending_confirmation");
+}
+
+#[tokio::test]
+async fn a_confirmation_link_can_only_be_used_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscription().await;
+    let first_response = reqwest::get(confirmation_links.html.clone()).await.unwrap();
+    assert_eq!(first_response.status().as_u16(), 200);
+
+    // Act
+    let second_response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(second_response.status().as_u16(), 410);
+}
+
+#[tokio::test]
+async fn an_expired_confirmation_link_is_rejected_with_a_410() {
+    // Arrange
+    let app = spawn_app_with(|c| c.auth.confirmation_token_expiry_minutes = 0).await;
+    let confirmation_links = app.create_unconfirmed_subscription().await;
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+    let subscription: serde_json::Value = serde_json::from_str(&app.get_subscription("1").await.text().await.unwrap()).unwrap();
+    assert_eq!(subscription["status"], "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribing_again_while_pending_rotates_the_confirmation_token() {
+    // Arrange
+    let app = spawn_app().await;
+    let first_links = app.create_unconfirmed_subscription().await;
+
+    // Act
+    let second_links = app.create_unconfirmed_subscription().await;
+
+    // Assert
+    assert_ne!(first_links.html, second_links.html);
+    let response = reqwest::get(first_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let response = reqwest::get(second_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
@@ -0,0 +1,294 @@
+use serde_json::Value;
+use totp_rs::{Algorithm, Secret, TOTP};
+use zero2prod::clock::Clock;
+
+use crate::common::{browser, spawn_app, spawn_app_with, TestApp};
+
+impl TestApp {
+    async fn two_factor_request(&self, method: reqwest::Method, path: &str, code: Option<&str>) -> reqwest::RequestBuilder {
+        let request = reqwest::Client::new()
+            .request(method, format!("{}/senders/me/two-factor{}", &self.address, path))
+            .basic_auth("admin", Some("admin"));
+        match code {
+            Some(code) => request.header("Two-Factor-Code", code),
+            None => request,
+        }
+    }
+
+    /// Enrols `admin` and returns their secret.
+    async fn enrol_admin(&self) -> String {
+        let response = self.two_factor_request(reqwest::Method::POST, "", None).await.send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 201);
+        let body: Value = response.json().await.unwrap();
+        body["secret"].as_str().unwrap().to_string()
+    }
+
+    /// Enrols and confirms `admin`, returns their secret and recovery codes.
+    async fn enable_two_factor_for_admin(&self) -> (String, Vec<String>) {
+        let secret = self.enrol_admin().await;
+        let response = self.two_factor_request(reqwest::Method::POST, "/confirm", None).await
+            .json(&serde_json::json!({ "code": self.current_code(&secret) }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let body: Value = response.json().await.unwrap();
+        let recovery_codes = body["recovery_codes"].as_array().unwrap().iter()
+            .map(|code| code.as_str().unwrap().to_string())
+            .collect();
+        (secret, recovery_codes)
+    }
+
+    /// What the authenticator app shows, by the application's clock.
+    /// Every code works once, move the clock on before using the next one.
+    fn current_code(&self, secret: &str) -> String {
+        let secret = Secret::Encoded(secret.to_string()).to_bytes().unwrap();
+        let totp = TOTP::new(Algorithm::SHA1, 6, 0, 30, secret, None, String::new()).unwrap();
+        totp.generate(self.clock.now().timestamp() as u64)
+    }
+
+    fn next_code(&self, secret: &str) -> String {
+        self.clock.advance(chrono::Duration::seconds(30));
+        self.current_code(secret)
+    }
+
+    async fn list_senders_with_code(&self, code: Option<&str>) -> reqwest::Response {
+        let request = reqwest::Client::new()
+            .get(format!("{}/senders", &self.address))
+            .basic_auth("admin", Some("admin"));
+        let request = match code {
+            Some(code) => request.header("Two-Factor-Code", code),
+            None => request,
+        };
+        request.send().await.unwrap()
+    }
+
+    async fn submit_second_factor(&self, client: &reqwest::Client, code: &str) -> reqwest::Response {
+        client.post(format!("{}/login/two-factor", &self.address))
+            .form(&[("code", code)])
+            .send()
+            .await
+            .unwrap()
+    }
+}
+
+#[tokio::test]
+async fn enrolling_returns_an_otpauth_uri_and_confirming_it_returns_recovery_codes() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.two_factor_request(reqwest::Method::POST, "", None).await.send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 201);
+    let body: Value = response.json().await.unwrap();
+    let secret = body["secret"].as_str().unwrap();
+    assert_eq!(body["otpauth_uri"], format!("otpauth://totp/Newsletter:admin?secret={}&issuer=Newsletter", secret));
+    let wrong_code = app.two_factor_request(reqwest::Method::POST, "/confirm", None).await
+        .json(&serde_json::json!({ "code": "000000" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(wrong_code.status().as_u16(), 400);
+    let confirmed = app.two_factor_request(reqwest::Method::POST, "/confirm", None).await
+        .json(&serde_json::json!({ "code": app.current_code(secret) }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(confirmed.status().as_u16(), 200);
+    let body: Value = confirmed.json().await.unwrap();
+    assert_eq!(body["recovery_codes"].as_array().unwrap().len(), 10);
+    let status: Value = app.two_factor_request(reqwest::Method::GET, "", Some(&app.next_code(secret))).await
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(status, serde_json::json!({ "enabled": true, "required": false, "recovery_codes_left": 10 }));
+}
+
+#[tokio::test]
+async fn once_enabled_basic_credentials_need_a_fresh_code() {
+    // Arrange
+    let app = spawn_app().await;
+    let (secret, _) = app.enable_two_factor_for_admin().await;
+
+    // Act
+    let without_code = app.list_senders_with_code(None).await;
+    let code = app.next_code(&secret);
+    let with_code = app.list_senders_with_code(Some(&code)).await;
+    let replayed = app.list_senders_with_code(Some(&code)).await;
+
+    // Assert
+    assert_eq!(without_code.status().as_u16(), 401);
+    let body: Value = without_code.json().await.unwrap();
+    assert_eq!(body["error"], "A valid two-factor code is required in the `Two-Factor-Code` header");
+    assert_eq!(with_code.status().as_u16(), 200);
+    assert_eq!(replayed.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_recovery_code_works_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let (secret, recovery_codes) = app.enable_two_factor_for_admin().await;
+
+    // Act
+    let first_use = app.list_senders_with_code(Some(&recovery_codes[0])).await;
+    let second_use = app.list_senders_with_code(Some(&recovery_codes[0])).await;
+
+    // Assert
+    assert_eq!(first_use.status().as_u16(), 200);
+    assert_eq!(second_use.status().as_u16(), 401);
+    let status: Value = app.two_factor_request(reqwest::Method::GET, "", Some(&app.next_code(&secret))).await
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(status["recovery_codes_left"], 9);
+}
+
+#[tokio::test]
+async fn the_login_form_asks_for_the_second_factor() {
+    // Arrange
+    let app = spawn_app().await;
+    let (secret, _) = app.enable_two_factor_for_admin().await;
+    let client = browser();
+
+    // Act
+    let login = app.log_in(&client, "admin", "admin").await;
+
+    // Assert
+    assert_eq!(login.status().as_u16(), 303);
+    assert_eq!(login.headers()["Location"], "/login/two-factor");
+    let dashboard = client.get(format!("{}/admin/dashboard", &app.address)).send().await.unwrap();
+    assert_eq!(dashboard.headers()["Location"], "/login");
+    let form = client.get(format!("{}/login/two-factor", &app.address)).send().await.unwrap();
+    assert!(form.text().await.unwrap().contains("Enter the code from your authenticator app"));
+    let wrong_code = app.submit_second_factor(&client, "000000").await;
+    assert_eq!(wrong_code.headers()["Location"], "/login/two-factor");
+    let right_code = app.submit_second_factor(&client, &app.next_code(&secret)).await;
+    assert_eq!(right_code.headers()["Location"], "/admin/dashboard");
+    let dashboard = client.get(format!("{}/admin/dashboard", &app.address)).send().await.unwrap();
+    assert_eq!(dashboard.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn too_many_wrong_codes_end_the_login() {
+    // Arrange
+    let app = spawn_app().await;
+    let (secret, _) = app.enable_two_factor_for_admin().await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+
+    // Act
+    for _ in 0..4 {
+        let response = app.submit_second_factor(&client, "000000").await;
+        assert_eq!(response.headers()["Location"], "/login/two-factor");
+    }
+    let last_attempt = app.submit_second_factor(&client, "000000").await;
+
+    // Assert
+    assert_eq!(last_attempt.headers()["Location"], "/login");
+    let right_code = app.submit_second_factor(&client, &app.next_code(&secret)).await;
+    assert_eq!(right_code.headers()["Location"], "/login");
+}
+
+#[tokio::test]
+async fn when_required_senders_have_to_set_it_up_before_anything_else() {
+    // Arrange
+    let app = spawn_app_with(|c| c.auth.two_factor.required = true).await;
+
+    // Act
+    let before = app.list_senders_with_code(None).await;
+    let (secret, _) = app.enable_two_factor_for_admin().await;
+    let after = app.list_senders_with_code(Some(&app.next_code(&secret))).await;
+
+    // Assert
+    assert_eq!(before.status().as_u16(), 403);
+    let body: Value = before.json().await.unwrap();
+    assert_eq!(body["error"], "Two-factor authentication is required, set it up through `/senders/me/two-factor` first");
+    assert_eq!(after.status().as_u16(), 200);
+    let disable = app.two_factor_request(reqwest::Method::DELETE, "", Some(&app.next_code(&secret))).await
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(disable.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn when_required_the_login_form_sets_it_up_and_shows_the_recovery_codes() {
+    // Arrange
+    let app = spawn_app_with(|c| c.auth.two_factor.required = true).await;
+    let client = browser();
+    let login = app.log_in(&client, "admin", "admin").await;
+    assert_eq!(login.headers()["Location"], "/login/two-factor");
+
+    // Act
+    let page = client.get(format!("{}/login/two-factor", &app.address)).send().await.unwrap().text().await.unwrap();
+    let secret = page.split(r#"<code id="secret">"#).nth(1).unwrap().split('<').next().unwrap().to_string();
+    let reloaded = client.get(format!("{}/login/two-factor", &app.address)).send().await.unwrap().text().await.unwrap();
+    let response = app.submit_second_factor(&client, &app.current_code(&secret)).await;
+
+    // Assert
+    assert!(page.contains("otpauth:&#x2f;&#x2f;totp&#x2f;Newsletter:admin?secret="));
+    assert!(reloaded.contains(&secret), "Reloading the page changed the secret");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.text().await.unwrap().matches("<li><code>").count(), 10);
+    let dashboard = client.get(format!("{}/admin/dashboard", &app.address)).send().await.unwrap();
+    assert_eq!(dashboard.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn disabling_it_lets_the_password_alone_in_again() {
+    // Arrange
+    let app = spawn_app().await;
+    let (secret, _) = app.enable_two_factor_for_admin().await;
+
+    // Act
+    let response = app.two_factor_request(reqwest::Method::DELETE, "", Some(&app.next_code(&secret))).await
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(app.list_senders_with_code(None).await.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn enrolling_again_while_enabled_is_a_409() {
+    // Arrange
+    let app = spawn_app().await;
+    let (secret, _) = app.enable_two_factor_for_admin().await;
+
+    // Act
+    let response = app.two_factor_request(reqwest::Method::POST, "", Some(&app.next_code(&secret))).await
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn too_many_wrong_codes_lock_the_second_factor_for_a_while() {
+    // Arrange
+    let app = spawn_app().await;
+    let (secret, recovery_codes) = app.enable_two_factor_for_admin().await;
+    for _ in 0..5 {
+        assert_eq!(app.list_senders_with_code(Some("000000")).await.status().as_u16(), 401);
+    }
+
+    // Act
+    let right_code = app.list_senders_with_code(Some(&app.next_code(&secret))).await;
+    let recovery_code = app.list_senders_with_code(Some(&recovery_codes[0])).await;
+    let client = browser();
+    app.log_in(&client, "admin", "admin").await;
+    let new_login = app.submit_second_factor(&client, &app.next_code(&secret)).await;
+
+    // Assert
+    assert_eq!(right_code.status().as_u16(), 401);
+    assert_eq!(recovery_code.status().as_u16(), 401);
+    assert_eq!(new_login.headers()["Location"], "/login/two-factor");
+    app.clock.advance(chrono::Duration::minutes(15));
+    assert_eq!(app.list_senders_with_code(Some(&app.next_code(&secret))).await.status().as_u16(), 200);
+    assert_eq!(app.list_senders_with_code(Some(&recovery_codes[0])).await.status().as_u16(), 200);
+}
@@ -0,0 +1,168 @@
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::routes::SubscriptionRequest;
+
+use crate::common::{spawn_app, TestApp};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+/// Publish a newsletter to the single confirmed subscriber and return its unsubscribe link
+async fn unsubscribe_link_from_a_newsletter(app: &TestApp) -> reqwest::Url {
+    let _mock_send_newsletter = Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.mock_email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+    app.get_unsubscribe_link(&email_request)
+}
+
+async fn subscription_status(app: &TestApp) -> String {
+    let subscription: serde_json::Value = serde_json::from_str(&app.get_subscription("1").await.text().await.unwrap()).unwrap();
+    subscription["status"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn newsletters_carry_list_unsubscribe_headers() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+
+    // Act
+    unsubscribe_link_from_a_newsletter(&app).await;
+
+    // Assert
+    let email_request = app.mock_email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Headers"]["List-Unsubscribe-Post"], "List-Unsubscribe=One-Click");
+    let list_unsubscribe = body["Headers"]["List-Unsubscribe"].as_str().unwrap();
+    assert!(list_unsubscribe.starts_with("<http://127.0.0.1"));
+    assert!(list_unsubscribe.ends_with('>'));
+}
+
+#[tokio::test]
+async fn the_unsubscribe_landing_page_does_not_unsubscribe() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let unsubscribe_link = unsubscribe_link_from_a_newsletter(&app).await;
+
+    // Act
+    let response = reqwest::get(unsubscribe_link).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains(r#"<form method="post""#));
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_stops_newsletters() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let unsubscribe_link = unsubscribe_link_from_a_newsletter(&app).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(unsubscribe_link)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscription_status(&app).await, "unsubscribed");
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_email_server)
+        .await;
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn unsubscribing_after_an_issue_was_queued_stops_its_delivery() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let unsubscribe_link = unsubscribe_link_from_a_newsletter(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.mock_email_server)
+        .await;
+    // Tasks are queued as of now, the worker only claims them once its clock catches up
+    app.clock.advance(chrono::Duration::hours(-1));
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(unsubscribe_link)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await
+        .unwrap();
+    app.clock.advance(chrono::Duration::hours(2));
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that the queued copy was not sent
+}
+
+#[tokio::test]
+async fn unsubscribe_with_a_tampered_token_is_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/unsubscribe?subscription_id=1&token=deadbeef", app.address))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribing_again_after_unsubscribing_restarts_double_opt_in() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscription().await;
+    let unsubscribe_link = unsubscribe_link_from_a_newsletter(&app).await;
+    reqwest::Client::new().post(unsubscribe_link).send().await.unwrap().error_for_status().unwrap();
+
+    // Act
+    let _mock_send_confirmation = Mock::given(path("/v3/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.mock_email_server)
+        .await;
+    let response = app.post_subscriptions(&SubscriptionRequest::new(
+        "le guin".to_string(), "ursula_le_guin@gmail.com".to_string())).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscription_status(&app).await, "pending_confirmation");
+}